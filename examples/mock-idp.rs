@@ -0,0 +1,180 @@
+//! A stand-in OpenID Connect provider for trying `/auth/*` locally.
+//!
+//! It approves every authorization request at once as `MOCK_IDP_USER`
+//! (default `alice@example.com`, in group `family`) and signs ID tokens with
+//! a fixed ES256 key. Client secrets are not checked. Run it next to the box:
+//!
+//! ```bash
+//! cargo build --target wasm32-wasip2 --example mock-idp
+//! wasmtime serve --addr=127.0.0.1:9090 -Scli ./target/wasm32-wasip2/debug/examples/mock-idp.wasm
+//! ```
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use sha2::{Digest, Sha256};
+use spore_box::url::{percent_encode, query_param};
+use wstd::http::body::IncomingBody;
+use wstd::http::server::{Finished, Responder};
+use wstd::http::{IntoBody, Request, Response, StatusCode};
+use wstd::io::empty;
+
+const KEY_ID: &str = "mock-1";
+
+fn signing_key() -> SigningKey {
+    let secret: [u8; 32] = std::array::from_fn(|i| i as u8 + 1);
+    SigningKey::from_bytes(&secret.into()).expect("a valid P-256 scalar")
+}
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+#[wstd::http_server]
+async fn main(mut request: Request<IncomingBody>, responder: Responder) -> Finished {
+    let host = request
+        .headers()
+        .get("host")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("127.0.0.1:9090")
+        .to_string();
+    let issuer = format!("http://{}", host);
+    let query = request.uri().query().unwrap_or("").to_string();
+
+    match request.uri().path() {
+        "/.well-known/openid-configuration" => {
+            json(
+                serde_json::json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{}/authorize", issuer),
+                    "token_endpoint": format!("{}/token", issuer),
+                    "jwks_uri": format!("{}/jwks", issuer),
+                    "response_types_supported": ["code"],
+                    "id_token_signing_alg_values_supported": ["ES256"],
+                    "code_challenge_methods_supported": ["S256"],
+                    "token_endpoint_auth_methods_supported": ["client_secret_basic"],
+                }),
+                responder,
+            )
+            .await
+        }
+        "/jwks" => {
+            let point = signing_key().verifying_key().to_encoded_point(false);
+            json(
+                serde_json::json!({ "keys": [{
+                    "kty": "EC",
+                    "crv": "P-256",
+                    "kid": KEY_ID,
+                    "use": "sig",
+                    "alg": "ES256",
+                    "x": b64(point.x().unwrap()),
+                    "y": b64(point.y().unwrap()),
+                }]}),
+                responder,
+            )
+            .await
+        }
+        "/authorize" => {
+            let param = |key: &str| query_param(&query, key).unwrap_or_default();
+            // The code carries everything the token endpoint needs to check.
+            let code = b64(serde_json::json!({
+                "clientId": param("client_id"),
+                "redirectUri": param("redirect_uri"),
+                "nonce": param("nonce"),
+                "challenge": param("code_challenge"),
+            })
+            .to_string());
+            let location = format!(
+                "{}?code={}&state={}",
+                param("redirect_uri"),
+                code,
+                percent_encode(&param("state"))
+            );
+            let response = Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header("Location", location)
+                .body(empty())
+                .unwrap();
+            responder.respond(response).await
+        }
+        "/token" => {
+            let body = request.body_mut().bytes().await.unwrap_or_default();
+            let form = String::from_utf8_lossy(&body).into_owned();
+            match issue_id_token(&issuer, &form) {
+                Ok(id_token) => {
+                    json(
+                        serde_json::json!({
+                            "access_token": "mock",
+                            "token_type": "Bearer",
+                            "id_token": id_token,
+                        }),
+                        responder,
+                    )
+                    .await
+                }
+                Err(msg) => {
+                    let response = Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .header("Content-Type", "application/json")
+                        .body(
+                            serde_json::json!({ "error": "invalid_grant", "error_description": msg })
+                                .to_string()
+                                .into_body(),
+                        )
+                        .unwrap();
+                    responder.respond(response).await
+                }
+            }
+        }
+        _ => {
+            let response = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(empty())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
+
+fn issue_id_token(issuer: &str, form: &str) -> Result<String, String> {
+    let param = |key: &str| query_param(form, key).ok_or(format!("no {}", key));
+    let code = URL_SAFE_NO_PAD
+        .decode(param("code")?)
+        .map_err(|e| e.to_string())?;
+    let code: serde_json::Value = serde_json::from_slice(&code).map_err(|e| e.to_string())?;
+    if code["redirectUri"].as_str() != Some(param("redirect_uri")?.as_str()) {
+        return Err("redirect_uri differs from the authorization request".to_string());
+    }
+    if code["challenge"].as_str() != Some(b64(Sha256::digest(param("code_verifier")?)).as_str()) {
+        return Err("code_verifier does not match the challenge".to_string());
+    }
+
+    let user = std::env::var("MOCK_IDP_USER").unwrap_or_else(|_| "alice@example.com".to_string());
+    let now = chrono::Utc::now().timestamp();
+    let header = serde_json::json!({ "alg": "ES256", "typ": "JWT", "kid": KEY_ID });
+    let claims = serde_json::json!({
+        "iss": issuer,
+        "sub": format!("mock-{}", user),
+        "aud": code["clientId"],
+        "exp": now + 300,
+        "iat": now,
+        "nonce": code["nonce"],
+        "email": user,
+        "email_verified": true,
+        "preferred_username": user.split('@').next().unwrap_or(&user),
+        "groups": ["family"],
+    });
+    let signing_input = format!("{}.{}", b64(header.to_string()), b64(claims.to_string()));
+    let signature: Signature = signing_key().sign(signing_input.as_bytes());
+    Ok(format!("{}.{}", signing_input, b64(signature.to_bytes())))
+}
+
+async fn json(value: serde_json::Value, responder: Responder) -> Finished {
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(value.to_string().into_body())
+        .unwrap();
+    responder.respond(response).await
+}
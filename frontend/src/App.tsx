@@ -1,11 +1,71 @@
-import React from 'react';
+import React, { useCallback, useEffect, useState } from 'react';
 import Chat from './Chat';
+import Login from './components/Login';
+import { api } from './api';
+import { SessionStatus } from './types';
 import './App.css';
 
 function App() {
+  const [session, setSession] = useState<SessionStatus | null>(null);
+
+  const checkSession = useCallback(async () => {
+    try {
+      setSession(await api.getSession());
+    } catch (error) {
+      console.error('Failed to check session:', error);
+      setSession({
+        authRequired: false,
+        passphraseLogin: false,
+        oidcLogin: false,
+        totpRequired: false,
+        authenticated: true,
+      });
+    }
+  }, []);
+
+  useEffect(() => {
+    const redeemPairingCode = async () => {
+      const params = new URLSearchParams(window.location.search);
+      const code = params.get('pair');
+      if (code) {
+        try {
+          await api.redeemPairingCode(code);
+        } catch (error) {
+          console.error('Failed to redeem pairing code:', error);
+        }
+        params.delete('pair');
+        const query = params.toString();
+        window.history.replaceState(null, '', query ? `/?${query}` : '/');
+      }
+      checkSession();
+    };
+    redeemPairingCode();
+  }, [checkSession]);
+
+  const handleLogout = async () => {
+    await api.logout();
+    checkSession();
+  };
+
+  if (!session) {
+    return <div className="App" />;
+  }
+
   return (
     <div className="App">
-      <Chat />
+      {session.authenticated ? (
+        <Chat
+          device={session.device}
+          onLogout={session.authRequired ? handleLogout : undefined}
+          twoFactor={session.passphraseLogin}
+        />
+      ) : (
+        <Login
+          onLogin={checkSession}
+          passphraseLogin={session.passphraseLogin}
+          oidcLogin={session.oidcLogin}
+        />
+      )}
     </div>
   );
 }
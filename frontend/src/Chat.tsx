@@ -1,7 +1,9 @@
 import React, { useState, useEffect, useRef } from 'react';
 import MessageItem from './components/MessageItem';
 import MessageInput from './components/MessageInput';
-import { Message } from './types';
+import PairDevice from './components/PairDevice';
+import TwoFactor from './components/TwoFactor';
+import { DEFAULT_ROOM, Device, Message, Quote, Reaction, Room } from './types';
 import { api } from './api';
 
 // Polling configuration constants
@@ -13,10 +15,90 @@ const POLLING_CONFIG = {
     ERROR_MULTIPLIER: 2,         // Double interval on error
 };
 
-const Chat: React.FC = () => {
+interface ChatProps {
+    device?: Device;
+    onLogout?: () => void;
+    /** Whether to offer two-factor settings, which apply to passphrase logins. */
+    twoFactor?: boolean;
+}
+
+const ROOM_KEY = 'spore-box-room';
+
+const EXCERPT_CHARS = 140;
+
+/** What the server quotes for a parent, for replies that arrive by polling. */
+const quoteOf = (message: Message): Quote => ({
+    id: message.id,
+    sender: message.sender,
+    type: message.type,
+    excerpt: message.type === 'text'
+        ? message.content.length > EXCERPT_CHARS
+            ? message.content.slice(0, EXCERPT_CHARS) + '…'
+            : message.content
+        : message.filename || message.content,
+});
+
+/** Applies a polled reaction event the way the server folds them. */
+const applyReaction = (
+    reactions: Reaction[] = [],
+    event: Message,
+    emoji: string,
+    added: boolean
+): Reaction[] => {
+    const taken = reactions.map(r => {
+        if (r.emoji !== emoji) return r;
+        const byDevice = event.deviceId !== undefined && (r.devices || []).includes(event.deviceId);
+        const index = byDevice || !event.deviceId ? r.by.indexOf(event.sender) : -1;
+        if (index < 0) return r;
+        return {
+            ...r,
+            count: r.count - 1,
+            by: r.by.filter((_, i) => i !== index),
+            devices: (r.devices || []).filter(d => d !== event.deviceId),
+        };
+    });
+    if (!added) return taken.filter(r => r.count > 0);
+    const existing = taken.find(r => r.emoji === emoji);
+    const devices = event.deviceId ? [event.deviceId] : [];
+    return existing
+        ? taken.map(r => r === existing
+            ? { ...r, count: r.count + 1, by: [...r.by, event.sender], devices: [...(r.devices || []), ...devices] }
+            : r)
+        : [...taken, { emoji, count: 1, by: [event.sender], devices }];
+};
+
+/** Adds polled messages to `prev` and applies polled events to it. */
+const applyPolled = (prev: Message[], polled: Message[]): Message[] => {
+    let next = prev;
+    for (const message of polled) {
+        const event = message.event;
+        if (event?.kind === 'pin') {
+            next = next.map(m => m.id === event.target ? { ...m, pinned: event.pinned } : m);
+        } else if (event?.kind === 'react') {
+            next = next.map(m => m.id === event.target
+                ? { ...m, reactions: applyReaction(m.reactions, message, event.emoji, event.added) }
+                : m);
+        } else if (!event && !next.some(m => m.id === message.id)) {
+            next = [...next, message];
+        }
+    }
+    return next;
+};
+
+const Chat: React.FC<ChatProps> = ({ device, onLogout, twoFactor }) => {
     const [messages, setMessages] = useState<Message[]>([]);
+    const [rooms, setRooms] = useState<Room[]>([]);
+    const [room, setRoom] = useState(localStorage.getItem(ROOM_KEY) || DEFAULT_ROOM);
     const [loading, setLoading] = useState(false);
-    const [deviceName, setDeviceName] = useState('Browser');
+    const [deviceName, setDeviceName] = useState(device?.name || 'Browser');
+    const [devices, setDevices] = useState<Map<string, Device>>(new Map());
+    const [showPairing, setShowPairing] = useState(false);
+    const [showTwoFactor, setShowTwoFactor] = useState(false);
+    const [inboxOnly, setInboxOnly] = useState(false);
+    const [pinnedOnly, setPinnedOnly] = useState(false);
+    const [replyingTo, setReplyingTo] = useState<Message | null>(null);
+    /** Where the unread messages began when the room was opened. */
+    const [firstUnreadId, setFirstUnreadId] = useState<string | null>(null);
     const [shouldAutoScroll, setShouldAutoScroll] = useState(true);
     const [imagesLoaded, setImagesLoaded] = useState(0);
     const messagesEndRef = useRef<HTMLDivElement>(null);
@@ -24,18 +106,72 @@ const Chat: React.FC = () => {
 
     useEffect(() => {
         const initDevice = async () => {
-            const name = await api.getDeviceName();
-            setDeviceName(name);
+            if (device) {
+                setDeviceName(device.name);
+            } else {
+                setDeviceName(await api.getDeviceName());
+            }
+            try {
+                const list = await api.getDevices();
+                setDevices(new Map(list.map(d => [d.id, d])));
+            } catch (error) {
+                console.error('Failed to load devices:', error);
+            }
         };
         initDevice();
+    }, [device]);
+
+    useEffect(() => {
+        api.getRooms()
+            .then((list) => {
+                setRooms(list);
+                if (!list.some((r) => r.id === room)) {
+                    setRoom(DEFAULT_ROOM);
+                }
+            })
+            .catch((error) => console.error('Failed to load rooms:', error));
+        // Only on mount: switching rooms doesn't change the list.
+        // eslint-disable-next-line react-hooks/exhaustive-deps
     }, []);
 
+    const selectRoom = async (value: string) => {
+        if (value !== '+') {
+            localStorage.setItem(ROOM_KEY, value);
+            setRoom(value);
+            return;
+        }
+        const name = prompt('Room name');
+        if (!name) return;
+        try {
+            const created = await api.createRoom(name);
+            setRooms(prev => [...prev, created]);
+            localStorage.setItem(ROOM_KEY, created.id);
+            setRoom(created.id);
+        } catch (error) {
+            console.error('Failed to create room:', error);
+            alert('Could not create the room.');
+        }
+    };
+
+    const isOwnMessage = (message: Message) =>
+        device && message.deviceId
+            ? message.deviceId === device.id
+            : message.sender === deviceName;
+
     useEffect(() => {
         const loadMessages = async () => {
             try {
                 setLoading(true);
-                const msgs = await api.getMessages();
+                setMessages([]);
+                const msgs = await api.getMessages(room);
                 setMessages(msgs);
+                if (device) {
+                    const unread = await api.getUnread(room);
+                    setFirstUnreadId(unread.firstUnread?.id ?? null);
+                    if (unread.firstUnread) {
+                        setShouldAutoScroll(false);
+                    }
+                }
             } catch (error) {
                 console.error('Failed to load messages:', error);
             } finally {
@@ -43,7 +179,24 @@ const Chat: React.FC = () => {
             }
         };
         loadMessages();
-    }, []);
+        // Reloads per room; the device does not change while signed in.
+        // eslint-disable-next-line react-hooks/exhaustive-deps
+    }, [room]);
+
+    useEffect(() => {
+        if (firstUnreadId) {
+            document.getElementById(`message-${firstUnreadId}`)?.scrollIntoView({ block: 'start' });
+        }
+    }, [firstUnreadId]);
+
+    // Whatever is on screen at the bottom of the list has been read.
+    useEffect(() => {
+        if (!device || !shouldAutoScroll || document.hidden || messages.length === 0) return;
+        const timer = setTimeout(() => {
+            api.markRead(room).catch(error => console.error('Failed to mark read:', error));
+        }, 1000);
+        return () => clearTimeout(timer);
+    }, [device, room, messages.length, shouldAutoScroll]);
 
     useEffect(() => {
         let pollInterval: NodeJS.Timeout | null = null;
@@ -55,18 +208,9 @@ const Chat: React.FC = () => {
             if (!isActive) return;
 
             try {
-                const response = await api.pollMessages(lastTimestamp);
+                const response = await api.pollMessages(room, lastTimestamp);
                 if (response.messages && response.messages.length > 0) {
-                    setMessages(prev => {
-                        const existingIds = new Set(prev.map(m => m.id));
-                        const newMessages = response.messages.filter(m => !existingIds.has(m.id));
-                        
-                        // Update messages only if there are new ones
-                        if (newMessages.length > 0) {
-                            return [...prev, ...newMessages];
-                        }
-                        return prev; // No new messages, return previous state
-                    });
+                    setMessages(prev => applyPolled(prev, response.messages));
                     
                     currentPollInterval = POLLING_CONFIG.INITIAL_INTERVAL;
                 } else {
@@ -76,6 +220,18 @@ const Chat: React.FC = () => {
                     );
                 }
                 
+                if (response.presence && response.presence.length > 0) {
+                    const changes = response.presence;
+                    setDevices(prev => {
+                        const next = new Map(prev);
+                        for (const { deviceId, status, lastSeen } of changes) {
+                            const known = next.get(deviceId);
+                            if (known) next.set(deviceId, { ...known, status, lastSeen });
+                        }
+                        return next;
+                    });
+                }
+
                 lastTimestamp = response.timestamp;
             } catch (error) {
                 console.error('Polling failed:', error);
@@ -117,7 +273,7 @@ const Chat: React.FC = () => {
             }
             document.removeEventListener('visibilitychange', handleVisibilityChange);
         };
-    }, []);
+    }, [room]);
 
     useEffect(() => {
         const checkIfAtBottom = () => {
@@ -160,24 +316,64 @@ const Chat: React.FC = () => {
         }
     }, [loading, messages.length, shouldAutoScroll]);
 
-    const handleSendMessage = async (content: string, type: 'text' | 'image' | 'file', file?: File) => {
+    // The inbox leaves out what this device sent to other devices.
+    const shown = messages
+        .filter(m => !inboxOnly || !device || !m.recipients?.length || m.recipients.includes(device.id))
+        .filter(m => !pinnedOnly || m.pinned);
+
+    const quoteFor = (message: Message): Quote | undefined => {
+        if (message.quote || !message.replyTo) return message.quote;
+        const parent = messages.find(m => m.id === message.replyTo);
+        return parent ? quoteOf(parent) : undefined;
+    };
+
+    const handleReact = async (message: Message, emoji: string) => {
+        const mine = message.reactions?.find(r => r.emoji === emoji && (device
+            ? (r.devices || []).includes(device.id)
+            : r.by.includes(deviceName)));
+        try {
+            const updated = await api.react(room, message.id, emoji, !mine, deviceName);
+            setMessages(prev => prev.map(m => m.id === updated.id ? { ...m, reactions: updated.reactions } : m));
+        } catch (error) {
+            console.error('Failed to react:', error);
+        }
+    };
+
+    const handleTogglePin = async (message: Message) => {
+        try {
+            const updated = await api.pinMessage(room, message.id, !message.pinned);
+            setMessages(prev => prev.map(m => m.id === updated.id ? { ...m, pinned: updated.pinned } : m));
+        } catch (error) {
+            console.error('Failed to pin message:', error);
+        }
+    };
+
+    const handleSendMessage = async (
+        content: string,
+        type: 'text' | 'image' | 'file',
+        file?: File,
+        recipients: string[] = []
+    ) => {
         try {
             setLoading(true);
             let newMessage: Message;
 
             if (type === 'text') {
-                newMessage = await api.sendMessage({
+                newMessage = await api.sendMessage(room, {
                     content,
                     sender: deviceName,
-                    type
+                    type,
+                    recipients,
+                    replyTo: replyingTo?.id
                 });
             } else if (file) {
-                newMessage = await api.uploadFile(file, deviceName);
+                newMessage = await api.uploadFile(room, file, deviceName, recipients, replyingTo?.id);
             } else {
                 return;
             }
 
             setMessages(prev => [...prev, newMessage]);
+            setReplyingTo(null);
             setShouldAutoScroll(true);
         } catch (error) {
             console.error('Failed to send message:', error);
@@ -189,17 +385,75 @@ const Chat: React.FC = () => {
 
     return (
         <div className="fixed inset-0 flex flex-col bg-gray-50">
-            <header className="bg-white border-b border-gray-200 p-4 flex-shrink-0">
-                <h1 className="text-xl font-semibold text-gray-800">Spore Box</h1>
-                <p className="text-sm text-gray-500">Device: {deviceName}</p>
+            <header className="bg-white border-b border-gray-200 p-4 flex-shrink-0 flex items-center justify-between">
+                <div>
+                    <h1 className="text-xl font-semibold text-gray-800">Spore Box</h1>
+                    <p className="text-sm text-gray-500">Device: {deviceName}</p>
+                </div>
+                <div className="flex items-center gap-4">
+                    <select
+                        value={room}
+                        onChange={(e) => selectRoom(e.target.value)}
+                        className="text-sm text-gray-700 border border-gray-300 rounded px-2 py-1"
+                        title="Room"
+                    >
+                        {rooms.length === 0 && <option value={room}>{room}</option>}
+                        {rooms.map((r) => (
+                            <option key={r.id} value={r.id}>{r.name}</option>
+                        ))}
+                        <option value="+">New room…</option>
+                    </select>
+                    <label className="text-sm text-gray-500 flex items-center gap-1">
+                        <input
+                            type="checkbox"
+                            checked={pinnedOnly}
+                            onChange={(e) => setPinnedOnly(e.target.checked)}
+                        />
+                        Pinned
+                    </label>
+                    {device && (
+                        <label className="text-sm text-gray-500 flex items-center gap-1">
+                            <input
+                                type="checkbox"
+                                checked={inboxOnly}
+                                onChange={(e) => setInboxOnly(e.target.checked)}
+                            />
+                            Inbox
+                        </label>
+                    )}
+                    <button
+                        onClick={() => setShowPairing(true)}
+                        className="text-sm text-gray-500 hover:text-gray-700"
+                    >
+                        Pair device
+                    </button>
+                    {twoFactor && (
+                        <button
+                            onClick={() => setShowTwoFactor(true)}
+                            className="text-sm text-gray-500 hover:text-gray-700"
+                        >
+                            Two-factor
+                        </button>
+                    )}
+                    {onLogout && (
+                        <button
+                            onClick={onLogout}
+                            className="text-sm text-gray-500 hover:text-gray-700"
+                        >
+                            Sign out
+                        </button>
+                    )}
+                </div>
             </header>
+            {showPairing && <PairDevice onClose={() => setShowPairing(false)} />}
+            {showTwoFactor && <TwoFactor onClose={() => setShowTwoFactor(false)} />}
 
             <div ref={messagesContainerRef} className="flex-1 overflow-y-auto p-4" style={{ paddingBottom: '80px' }}>
-                {loading && messages.length === 0 ? (
+                {loading && shown.length === 0 ? (
                     <div className="flex items-center justify-center h-full">
                         <div className="text-gray-500">Loading messages...</div>
                     </div>
-                ) : messages.length === 0 ? (
+                ) : shown.length === 0 ? (
                     <div className="flex items-center justify-center h-full">
                         <div className="text-center">
                             <div className="text-gray-500 mb-2">No messages yet</div>
@@ -207,20 +461,54 @@ const Chat: React.FC = () => {
                         </div>
                     </div>
                 ) : (
-                    messages.map((message) => (
+                    shown.map((message) => (
+                        <React.Fragment key={message.id}>
+                        {message.id === firstUnreadId && (
+                            <div className="flex items-center gap-2 my-2 text-xs text-red-500">
+                                <div className="flex-1 border-t border-red-300" />
+                                New
+                                <div className="flex-1 border-t border-red-300" />
+                            </div>
+                        )}
                         <MessageItem
-                            key={message.id}
                             message={message}
-                            isOwn={message.sender === deviceName}
+                            isOwn={isOwnMessage(message)}
+                            senderDevice={message.deviceId ? devices.get(message.deviceId) : undefined}
+                            recipientNames={message.recipients?.map(id => devices.get(id)?.name || id)}
+                            room={room}
+                            onTogglePin={() => handleTogglePin(message)}
+                            quote={quoteFor(message)}
+                            onReply={() => setReplyingTo(message)}
+                            onReact={(emoji) => handleReact(message, emoji)}
+                            otherRooms={rooms.filter((r) => r.id !== room)}
+                            onMoved={(moved) => setMessages(prev => prev.filter(m => m.id !== moved.id))}
+                            onShowReceipts={isOwnMessage(message) && device
+                                ? () => api.getReadReceipts(room, message.id)
+                                : undefined}
                             onImageLoad={handleImageLoad}
                         />
+                        </React.Fragment>
                     ))
                 )}
                 <div ref={messagesEndRef} />
             </div>
 
             <div className="fixed bottom-0 left-0 right-0 bg-white border-t border-gray-200">
-                <MessageInput onSendMessage={handleSendMessage} disabled={loading} />
+                {replyingTo && (
+                    <div className="px-4 pt-2 flex items-center justify-between text-sm text-gray-500">
+                        <span className="truncate">
+                            Replying to {replyingTo.sender}: {quoteOf(replyingTo).excerpt}
+                        </span>
+                        <button onClick={() => setReplyingTo(null)} className="ml-2 hover:text-gray-700">
+                            Cancel
+                        </button>
+                    </div>
+                )}
+                <MessageInput
+                    onSendMessage={handleSendMessage}
+                    disabled={loading}
+                    devices={Array.from(devices.values()).filter(d => d.id !== device?.id && !d.revokedAt)}
+                />
             </div>
         </div>
     );
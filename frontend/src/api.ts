@@ -1,25 +1,196 @@
 import axios from "axios";
-import { Message, SendMessageRequest } from "./types";
+import {
+  Device,
+  DevicePresence,
+  Message,
+  DEFAULT_ROOM,
+  PairingCode,
+  Receipts,
+  Room,
+  SendMessageRequest,
+  SessionStatus,
+  ShareLink,
+  ShareOptions,
+  TotpEnrolment,
+  TotpStatus,
+  Unread,
+} from "./types";
 
 const API_BASE = "/api";
+const DEVICE_ID_KEY = "spore-box-device-id";
+
+/** Where a room's messages and uploads are; the default room's are at the top. */
+function roomBase(room: string): string {
+  return room === DEFAULT_ROOM
+    ? API_BASE
+    : `${API_BASE}/rooms/${encodeURIComponent(room)}`;
+}
+
+/** The `error` code of a JSON error response, if the request failed with one. */
+export function apiErrorCode(error: unknown): string | undefined {
+  return axios.isAxiosError(error) ? error.response?.data?.error : undefined;
+}
 
 export const api = {
-  async getMessages(): Promise<Message[]> {
-    const response = await axios.get(`${API_BASE}/messages`);
+  async getSession(): Promise<SessionStatus> {
+    const response = await axios.get(`${API_BASE}/session`);
+    // Logins through the identity provider end in a redirect, so this is
+    // where the browser learns which device it became.
+    if (response.data.device) {
+      localStorage.setItem(DEVICE_ID_KEY, response.data.device.id);
+    }
+    // Requests that change something must prove they come from this page.
+    if (response.data.csrfToken) {
+      axios.defaults.headers.common["X-CSRF-Token"] = response.data.csrfToken;
+    } else {
+      delete axios.defaults.headers.common["X-CSRF-Token"];
+    }
+    return response.data;
+  },
+
+  /** Where to send the browser to sign in with OpenID Connect. */
+  oidcLoginUrl(): string {
+    const params = new URLSearchParams();
+    const deviceId = localStorage.getItem(DEVICE_ID_KEY);
+    const deviceName = new URLSearchParams(window.location.search).get("device");
+    if (deviceId) params.set("deviceId", deviceId);
+    if (deviceName) params.set("device", deviceName);
+    const query = params.toString();
+    return query ? `/auth/login?${query}` : "/auth/login";
+  },
+
+  async login(passphrase: string, otp?: string): Promise<void> {
+    const deviceId = localStorage.getItem(DEVICE_ID_KEY) || undefined;
+    const deviceName = await api.getDeviceName();
+    const response = await axios.post(`${API_BASE}/login`, {
+      passphrase,
+      deviceId,
+      deviceName,
+      otp,
+    });
+    localStorage.setItem(DEVICE_ID_KEY, response.data.device.id);
+  },
+
+  async logout(): Promise<void> {
+    await axios.post(`${API_BASE}/logout`);
+  },
+
+  async getRooms(): Promise<Room[]> {
+    const response = await axios.get(`${API_BASE}/rooms`);
+    return response.data;
+  },
+
+  async createRoom(name: string): Promise<Room> {
+    const response = await axios.post(`${API_BASE}/rooms`, { name });
+    return response.data;
+  },
+
+  async getMessages(room: string): Promise<Message[]> {
+    const response = await axios.get(`${roomBase(room)}/messages`);
+    return response.data;
+  },
+
+  async sendMessage(room: string, data: SendMessageRequest): Promise<Message> {
+    const response = await axios.post(`${roomBase(room)}/messages`, data);
     return response.data;
   },
 
-  async sendMessage(data: SendMessageRequest): Promise<Message> {
-    const response = await axios.post(`${API_BASE}/messages`, data);
+  /** Copies or moves a message into room `to`, returning it as it is there. */
+  async transferMessage(
+    room: string,
+    id: string,
+    mode: "copy" | "move",
+    to: string
+  ): Promise<Message> {
+    const response = await axios.post(
+      `${roomBase(room)}/messages/${encodeURIComponent(id)}/${mode}`,
+      { room: to }
+    );
+    return response.data;
+  },
+
+  /** A message and every reply under it. */
+  async getThread(
+    room: string,
+    id: string
+  ): Promise<{ message: Message; replies: Message[] }> {
+    const response = await axios.get(
+      `${roomBase(room)}/messages/${encodeURIComponent(id)}/thread`
+    );
     return response.data;
   },
 
-  async uploadFile(file: File, sender: string): Promise<Message> {
+  async getPinned(room: string): Promise<Message[]> {
+    const response = await axios.get(`${roomBase(room)}/messages/pinned`);
+    return response.data;
+  },
+
+  /** Pins or unpins a message, or toggles it if `pinned` is not given. */
+  async pinMessage(room: string, id: string, pinned?: boolean): Promise<Message> {
+    const response = await axios.post(
+      `${roomBase(room)}/messages/${encodeURIComponent(id)}/pin`,
+      { pinned }
+    );
+    return response.data;
+  },
+
+  /** Adds this device's `emoji` reaction, or takes it back. */
+  async react(
+    room: string,
+    id: string,
+    emoji: string,
+    added: boolean,
+    sender: string
+  ): Promise<Message> {
+    const response = await axios.post(
+      `${roomBase(room)}/messages/${encodeURIComponent(id)}/reactions`,
+      { emoji, added, sender }
+    );
+    return response.data;
+  },
+
+  async getUnread(room: string): Promise<Unread> {
+    const response = await axios.get(`${roomBase(room)}/messages/unread`);
+    return response.data;
+  },
+
+  /** Marks messages read up to `messageId`, or all of them. */
+  async markRead(room: string, messageId?: string): Promise<Unread> {
+    const response = await axios.post(`${roomBase(room)}/messages/read`, {
+      messageId,
+    });
+    return response.data;
+  },
+
+  async getReadReceipts(room: string, id: string): Promise<Receipts> {
+    const response = await axios.get(
+      `${roomBase(room)}/messages/${encodeURIComponent(id)}/reads`
+    );
+    return response.data;
+  },
+
+  fileUrl(room: string, file: string): string {
+    return `${roomBase(room)}/files/${file}`;
+  },
+
+  async uploadFile(
+    room: string,
+    file: File,
+    sender: string,
+    recipients: string[] = [],
+    replyTo?: string
+  ): Promise<Message> {
     const formData = new FormData();
     formData.append("file", file);
     formData.append("sender", sender);
+    if (recipients.length > 0) {
+      formData.append("recipients", recipients.join(","));
+    }
+    if (replyTo) {
+      formData.append("replyTo", replyTo);
+    }
 
-    const response = await axios.post(`${API_BASE}/upload`, formData, {
+    const response = await axios.post(`${roomBase(room)}/upload`, formData, {
       headers: {
         "Content-Type": "multipart/form-data",
       },
@@ -28,14 +199,85 @@ export const api = {
   },
 
   async pollMessages(
+    room: string,
     since: string
-  ): Promise<{ messages: Message[]; timestamp: string }> {
+  ): Promise<{
+    messages: Message[];
+    timestamp: string;
+    presence?: DevicePresence[];
+  }> {
     const response = await axios.get(
-      `${API_BASE}/messages/poll?since=${encodeURIComponent(since)}`
+      `${roomBase(room)}/messages/poll?since=${encodeURIComponent(
+        since
+      )}&presence=1`
     );
     return response.data;
   },
 
+  async createPairingCode(deviceName?: string): Promise<PairingCode> {
+    const response = await axios.post(`${API_BASE}/pairing`, { deviceName });
+    return response.data;
+  },
+
+  async redeemPairingCode(code: string): Promise<void> {
+    const params = new URLSearchParams(window.location.search);
+    const response = await axios.post(`${API_BASE}/pairing/redeem`, {
+      code,
+      deviceName: params.get("device") || undefined,
+    });
+    localStorage.setItem(DEVICE_ID_KEY, response.data.device.id);
+  },
+
+  async getDevices(): Promise<Device[]> {
+    const response = await axios.get(`${API_BASE}/devices`);
+    return response.data;
+  },
+
+  async shareFile(
+    room: string,
+    file: string,
+    options: ShareOptions
+  ): Promise<ShareLink> {
+    const response = await axios.post(
+      `${roomBase(room)}/files/${encodeURIComponent(file)}/share`,
+      options
+    );
+    return response.data;
+  },
+
+  async getShareLinks(): Promise<ShareLink[]> {
+    const response = await axios.get(`${API_BASE}/shares`);
+    return response.data;
+  },
+
+  async revokeShareLink(id: string): Promise<void> {
+    await axios.delete(`${API_BASE}/shares/${encodeURIComponent(id)}`);
+  },
+
+  async getTotpStatus(): Promise<TotpStatus> {
+    const response = await axios.get(`${API_BASE}/totp`);
+    return response.data;
+  },
+
+  async enrollTotp(): Promise<TotpEnrolment> {
+    const response = await axios.post(`${API_BASE}/totp/enroll`);
+    return response.data;
+  },
+
+  async confirmTotp(code: string): Promise<string[]> {
+    const response = await axios.post(`${API_BASE}/totp/confirm`, { code });
+    return response.data.recoveryCodes;
+  },
+
+  async disableTotp(code: string): Promise<void> {
+    await axios.post(`${API_BASE}/totp/disable`, { code });
+  },
+
+  async regenerateRecoveryCodes(code: string): Promise<string[]> {
+    const response = await axios.post(`${API_BASE}/totp/recovery-codes`, { code });
+    return response.data.recoveryCodes;
+  },
+
   async getDeviceName(): Promise<string> {
     const params = new URLSearchParams(window.location.search);
     return params.get("device") || "Browser";
@@ -0,0 +1,112 @@
+import React, { useState } from 'react';
+import { api, apiErrorCode } from '../api';
+
+interface LoginProps {
+    onLogin: () => void;
+    passphraseLogin: boolean;
+    oidcLogin: boolean;
+}
+
+const Login: React.FC<LoginProps> = ({ onLogin, passphraseLogin, oidcLogin }) => {
+    const [passphrase, setPassphrase] = useState('');
+    const [error, setError] = useState('');
+    const [submitting, setSubmitting] = useState(false);
+    // Without a passphrase, the form is only for pairing codes.
+    const [pairing, setPairing] = useState(!passphraseLogin);
+    const [otp, setOtp] = useState('');
+    const [needsOtp, setNeedsOtp] = useState(false);
+
+    const handleSubmit = async (e: React.FormEvent) => {
+        e.preventDefault();
+        if (!passphrase || submitting) return;
+
+        try {
+            setSubmitting(true);
+            setError('');
+            if (pairing) {
+                await api.redeemPairingCode(passphrase);
+            } else {
+                await api.login(passphrase, needsOtp ? otp : undefined);
+            }
+            onLogin();
+        } catch (error) {
+            console.error('Login failed:', error);
+            const code = apiErrorCode(error);
+            if (code === 'totp-required') {
+                // The passphrase was right; ask for the second factor.
+                setNeedsOtp(true);
+            } else if (code === 'invalid-totp-code') {
+                setError('Wrong or already used code');
+                setOtp('');
+            } else {
+                setError(pairing ? 'Unknown or expired pairing code' : 'Wrong passphrase');
+                setPassphrase('');
+                setOtp('');
+                setNeedsOtp(false);
+            }
+        } finally {
+            setSubmitting(false);
+        }
+    };
+
+    return (
+        <div className="fixed inset-0 flex items-center justify-center bg-gray-50">
+            <form onSubmit={handleSubmit} className="bg-white border border-gray-200 rounded-lg p-6 w-80">
+                <h1 className="text-xl font-semibold text-gray-800 mb-4">Spore Box</h1>
+                {oidcLogin && (
+                    <a
+                        href={api.oidcLoginUrl()}
+                        className="block w-full mb-4 px-4 py-2 text-center bg-gray-800 text-white rounded-lg hover:bg-gray-900"
+                    >
+                        Sign in with single sign-on
+                    </a>
+                )}
+                <input
+                    type={pairing ? 'text' : 'password'}
+                    value={passphrase}
+                    onChange={(e) => setPassphrase(e.target.value)}
+                    placeholder={pairing ? 'Pairing code' : 'Passphrase'}
+                    autoFocus
+                    className="w-full px-3 py-2 border border-gray-300 rounded-lg focus:outline-none focus:ring-2 focus:ring-blue-500"
+                />
+                {needsOtp && !pairing && (
+                    <input
+                        type="text"
+                        inputMode="numeric"
+                        autoComplete="one-time-code"
+                        value={otp}
+                        onChange={(e) => setOtp(e.target.value)}
+                        placeholder="Authenticator or recovery code"
+                        autoFocus
+                        className="w-full mt-2 px-3 py-2 border border-gray-300 rounded-lg focus:outline-none focus:ring-2 focus:ring-blue-500"
+                    />
+                )}
+                {error && <p className="text-sm text-red-500 mt-2">{error}</p>}
+                <button
+                    type="submit"
+                    disabled={!passphrase || (needsOtp && !pairing && !otp) || submitting}
+                    className="w-full mt-4 px-4 py-2 bg-blue-500 text-white rounded-lg hover:bg-blue-600 disabled:opacity-50"
+                >
+                    {submitting ? 'Signing in...' : 'Sign in'}
+                </button>
+                {passphraseLogin && (
+                    <button
+                        type="button"
+                        onClick={() => {
+                            setPairing(!pairing);
+                            setPassphrase('');
+                            setOtp('');
+                            setNeedsOtp(false);
+                            setError('');
+                        }}
+                        className="w-full mt-2 text-sm text-gray-500 hover:text-gray-700"
+                    >
+                        {pairing ? 'Use the passphrase instead' : 'I have a pairing code'}
+                    </button>
+                )}
+            </form>
+        </div>
+    );
+};
+
+export default Login;
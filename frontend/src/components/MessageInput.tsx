@@ -1,13 +1,23 @@
 import React, { useState, useRef } from 'react';
 import { Send, Paperclip, ImageIcon } from 'lucide-react';
+import { Device } from '../types';
 
 interface MessageInputProps {
-    onSendMessage: (content: string, type: 'text' | 'image' | 'file', file?: File) => void;
+    onSendMessage: (
+        content: string,
+        type: 'text' | 'image' | 'file',
+        file?: File,
+        recipients?: string[]
+    ) => void;
     disabled?: boolean;
+    /** Devices a message can be sent to directly instead of to everyone. */
+    devices?: Device[];
 }
 
-const MessageInput: React.FC<MessageInputProps> = ({ onSendMessage, disabled }) => {
+const MessageInput: React.FC<MessageInputProps> = ({ onSendMessage, disabled, devices = [] }) => {
     const [message, setMessage] = useState('');
+    const [recipient, setRecipient] = useState('');
+    const recipients = recipient ? [recipient] : [];
     const fileInputRef = useRef<HTMLInputElement>(null);
     const imageInputRef = useRef<HTMLInputElement>(null);
     const textInputRef = useRef<HTMLInputElement>(null);
@@ -26,7 +36,7 @@ const MessageInput: React.FC<MessageInputProps> = ({ onSendMessage, disabled })
             setMessage(''); // Clear message first
 
             // Send the message
-            onSendMessage(content, 'text');
+            onSendMessage(content, 'text', undefined, recipients);
 
             // Refocus the input after a short delay to ensure it works
             requestAnimationFrame(() => {
@@ -40,7 +50,7 @@ const MessageInput: React.FC<MessageInputProps> = ({ onSendMessage, disabled })
     const handleFileSelect = (e: React.ChangeEvent<HTMLInputElement>, type: 'image' | 'file') => {
         const file = e.target.files?.[0];
         if (file) {
-            onSendMessage('', type, file);
+            onSendMessage('', type, file, recipients);
             e.target.value = '';
 
             // Refocus the text input after file upload
@@ -59,7 +69,7 @@ const MessageInput: React.FC<MessageInputProps> = ({ onSendMessage, disabled })
             if (item.type.indexOf('image') !== -1) {
                 const file = item.getAsFile();
                 if (file) {
-                    onSendMessage('', 'image', file);
+                    onSendMessage('', 'image', file, recipients);
 
                     // Refocus the text input after paste
                     requestAnimationFrame(() => {
@@ -108,6 +118,21 @@ const MessageInput: React.FC<MessageInputProps> = ({ onSendMessage, disabled })
                     <ImageIcon size={20} />
                 </button>
 
+                {devices.length > 0 && (
+                    <select
+                        value={recipient}
+                        onChange={(e) => setRecipient(e.target.value)}
+                        disabled={disabled}
+                        className="flex-shrink-0 max-w-[8rem] border border-gray-300 rounded-lg px-2 py-2 text-sm text-gray-700"
+                        title="Send to"
+                    >
+                        <option value="">Everyone</option>
+                        {devices.map((d) => (
+                            <option key={d.id} value={d.id}>{d.name}</option>
+                        ))}
+                    </select>
+                )}
+
                 <input
                     ref={textInputRef}
                     type="text"
@@ -2,17 +2,67 @@ import React, { useState } from 'react';
 import ReactMarkdown from 'react-markdown';
 import { Prism as SyntaxHighlighter } from 'react-syntax-highlighter';
 import { dark } from 'react-syntax-highlighter/dist/esm/styles/prism';
-import { Message } from '../types';
-import { Download, FileText, Image, Copy, Check } from 'lucide-react';
+import { Device, Message, Quote, Receipts, Room } from '../types';
+import { api } from '../api';
+import { Download, FileText, Image, Copy, Check, Share2, Pin, Reply, Smile, Eye } from 'lucide-react';
+import ShareFile from './ShareFile';
 
 interface MessageItemProps {
     message: Message;
     isOwn: boolean;
+    senderDevice?: Device;
+    /** Names of the devices a direct message was sent to. */
+    recipientNames?: string[];
+    room: string;
+    /** Rooms the message can be copied or moved to. */
+    otherRooms: Room[];
+    onMoved?: (message: Message) => void;
+    onTogglePin?: () => void;
+    /** The message this one replies to. */
+    quote?: Quote;
+    onReply?: () => void;
+    /** Adds the emoji reaction, or takes it back if it is this device's. */
+    onReact?: (emoji: string) => void;
+    /** Which devices have read the message; offered on own messages. */
+    onShowReceipts?: () => Promise<Receipts>;
     onImageLoad?: () => void;
 }
 
-const MessageItem: React.FC<MessageItemProps> = ({ message, isOwn, onImageLoad }) => {
+const QUICK_REACTIONS = ['👍', '❤️', '😂', '🎉', '✅'];
+
+const MessageItem: React.FC<MessageItemProps> = ({
+    message,
+    isOwn,
+    senderDevice,
+    recipientNames,
+    room,
+    otherRooms,
+    onMoved,
+    onTogglePin,
+    quote,
+    onReply,
+    onReact,
+    onShowReceipts,
+    onImageLoad,
+}) => {
     const [copied, setCopied] = useState(false);
+    const [sharing, setSharing] = useState(false);
+    const [reacting, setReacting] = useState(false);
+    const [receipts, setReceipts] = useState<Receipts | null>(null);
+
+    const handleShowReceipts = async () => {
+        if (!onShowReceipts) return;
+        if (receipts) {
+            setReceipts(null);
+            return;
+        }
+        try {
+            setReceipts(await onShowReceipts());
+        } catch (err) {
+            console.error('Failed to load read receipts: ', err);
+        }
+    };
+    const fileUrl = api.fileUrl(room, message.content);
 
     const formatTime = (timestamp: string) => {
         return new Date(timestamp).toLocaleTimeString([], {
@@ -38,16 +88,29 @@ const MessageItem: React.FC<MessageItemProps> = ({ message, isOwn, onImageLoad }
         }
     };
 
+    const handleTransfer = async (value: string) => {
+        const [mode, to] = value.split(':') as ['copy' | 'move', string];
+        try {
+            await api.transferMessage(room, message.id, mode, to);
+            if (mode === 'move') {
+                onMoved?.(message);
+            }
+        } catch (err) {
+            console.error(`Failed to ${mode} message: `, err);
+            alert(`Could not ${mode} the message.`);
+        }
+    };
+
     const renderContent = () => {
         switch (message.type) {
             case 'image':
                 return (
                     <div className="space-y-2">
                         <img
-                            src={`/api/files/${message.content}`}
+                            src={fileUrl}
                             alt={message.filename}
                             className="max-w-sm rounded-lg cursor-pointer"
-                            onClick={() => window.open(`/api/files/${message.content}`, '_blank')}
+                            onClick={() => window.open(fileUrl, '_blank')}
                             onLoad={onImageLoad}
                         />
                         <div className={`text-xs flex items-center gap-1 ${
@@ -55,6 +118,9 @@ const MessageItem: React.FC<MessageItemProps> = ({ message, isOwn, onImageLoad }
                         }`}>
                             <Image size={12} />
                             {message.filename} ({formatFileSize(message.fileSize)})
+                            <button onClick={() => setSharing(true)} title="Share link" className="ml-1">
+                                <Share2 size={12} />
+                            </button>
                         </div>
                     </div>
                 );
@@ -80,7 +146,7 @@ const MessageItem: React.FC<MessageItemProps> = ({ message, isOwn, onImageLoad }
                             {formatFileSize(message.fileSize)}
                         </div>
                         <a
-                            href={`/api/files/${message.content}`}
+                            href={fileUrl}
                             download={message.filename}
                             className={`inline-flex items-center gap-1 text-sm font-medium ${
                                 isOwn 
@@ -91,6 +157,17 @@ const MessageItem: React.FC<MessageItemProps> = ({ message, isOwn, onImageLoad }
                             <Download size={12} />
                             Download
                         </a>
+                        <button
+                            onClick={() => setSharing(true)}
+                            className={`inline-flex items-center gap-1 text-sm font-medium ml-3 ${
+                                isOwn
+                                    ? 'text-blue-700 hover:text-blue-800'
+                                    : 'text-gray-700 hover:text-gray-900'
+                            }`}
+                        >
+                            <Share2 size={12} />
+                            Share
+                        </button>
                     </div>
                 );
 
@@ -126,7 +203,7 @@ const MessageItem: React.FC<MessageItemProps> = ({ message, isOwn, onImageLoad }
     };
 
     return (
-        <div className={`flex ${isOwn ? 'justify-end' : 'justify-start'} mb-4`}>
+        <div id={`message-${message.id}`} className={`flex ${isOwn ? 'justify-end' : 'justify-start'} mb-4`}>
             <div className={`max-w-[85%] sm:max-w-[70%] ${isOwn ? 'order-1' : 'order-2'}`}>
                 <div
                     className={`rounded-lg px-4 py-2 break-words ${isOwn
@@ -134,13 +211,133 @@ const MessageItem: React.FC<MessageItemProps> = ({ message, isOwn, onImageLoad }
                         : 'bg-gray-100 text-gray-900'
                         }`}
                 >
+                    {quote && (
+                        <button
+                            onClick={() =>
+                                document
+                                    .getElementById(`message-${quote.id}`)
+                                    ?.scrollIntoView({ behavior: 'smooth', block: 'center' })
+                            }
+                            className={`block w-full text-left text-xs border-l-2 pl-2 mb-1 truncate ${
+                                isOwn ? 'border-blue-200 text-blue-100' : 'border-gray-400 text-gray-500'
+                            }`}
+                        >
+                            {quote.sender}: {quote.excerpt}
+                        </button>
+                    )}
                     {renderContent()}
                 </div>
+                {(message.reactions?.length || reacting) && onReact ? (
+                    <div className={`flex flex-wrap gap-1 mt-1 ${isOwn ? 'justify-end' : 'justify-start'}`}>
+                        {message.reactions?.map((r) => (
+                            <button
+                                key={r.emoji}
+                                onClick={() => onReact(r.emoji)}
+                                className="text-xs bg-white border border-gray-200 rounded-full px-2 py-0.5"
+                                title={r.by.join(', ')}
+                            >
+                                {r.emoji} {r.count}
+                            </button>
+                        ))}
+                        {reacting && QUICK_REACTIONS.map((emoji) => (
+                            <button
+                                key={`add-${emoji}`}
+                                onClick={() => {
+                                    onReact(emoji);
+                                    setReacting(false);
+                                }}
+                                className="text-xs rounded-full px-1 hover:bg-gray-200"
+                            >
+                                {emoji}
+                            </button>
+                        ))}
+                    </div>
+                ) : null}
+                {receipts && (
+                    <div className={`text-xs text-gray-500 mt-1 ${isOwn ? 'text-right' : ''}`}>
+                        {receipts.seenBy.length > 0
+                            ? `Seen by ${receipts.seenBy.map(r => r.name).join(', ')}`
+                            : 'Not seen yet'}
+                        {receipts.notSeenBy.length > 0 && receipts.seenBy.length > 0 &&
+                            ` · not by ${receipts.notSeenBy.map(r => r.name).join(', ')}`}
+                    </div>
+                )}
+                {sharing && <ShareFile room={room} message={message} onClose={() => setSharing(false)} />}
                 <div
                     className={`flex items-center justify-between text-xs text-gray-500 mt-1`}
                 >
                     <span className={isOwn ? 'order-2' : 'order-1'}>
-                        {message.sender} · {formatTime(message.timestamp)}
+                        {senderDevice && (
+                            <span className="mr-1" style={{ color: senderDevice.color }}>
+                                {senderDevice.icon || '●'}
+                            </span>
+                        )}
+                        {message.sender}
+                        {senderDevice?.status && !isOwn && (
+                            <span
+                                className={`inline-block w-2 h-2 rounded-full ml-1 ${
+                                    senderDevice.status === 'online' ? 'bg-green-500'
+                                        : senderDevice.status === 'idle' ? 'bg-yellow-400' : 'bg-gray-300'
+                                }`}
+                                title={senderDevice.lastSeen
+                                    ? `${senderDevice.status} · last seen ${new Date(senderDevice.lastSeen).toLocaleString()}`
+                                    : senderDevice.status}
+                            />
+                        )}
+                        {recipientNames && recipientNames.length > 0 && ` → ${recipientNames.join(', ')}`}
+                        {' · '}{formatTime(message.timestamp)}
+                        {onShowReceipts && (
+                            <button
+                                onClick={handleShowReceipts}
+                                className="ml-2 text-gray-400 hover:text-gray-600"
+                                title="Seen by"
+                            >
+                                <Eye size={12} />
+                            </button>
+                        )}
+                        {onReact && (
+                            <button
+                                onClick={() => setReacting(!reacting)}
+                                className="ml-2 text-gray-400 hover:text-gray-600"
+                                title="React"
+                            >
+                                <Smile size={12} />
+                            </button>
+                        )}
+                        {onReply && (
+                            <button
+                                onClick={onReply}
+                                className="ml-2 text-gray-400 hover:text-gray-600"
+                                title="Reply"
+                            >
+                                <Reply size={12} />
+                            </button>
+                        )}
+                        {onTogglePin && (
+                            <button
+                                onClick={onTogglePin}
+                                className={`ml-2 ${message.pinned ? 'text-yellow-600' : 'text-gray-400 hover:text-gray-600'}`}
+                                title={message.pinned ? 'Unpin' : 'Pin (kept through retention)'}
+                            >
+                                <Pin size={12} />
+                            </button>
+                        )}
+                        {otherRooms.length > 0 && (
+                            <select
+                                value=""
+                                onChange={(e) => e.target.value && handleTransfer(e.target.value)}
+                                className="ml-2 bg-transparent text-gray-500"
+                                title="Copy or move to another room"
+                            >
+                                <option value="">…</option>
+                                {otherRooms.map((r) => (
+                                    <React.Fragment key={r.id}>
+                                        <option value={`copy:${r.id}`}>Copy to {r.name}</option>
+                                        <option value={`move:${r.id}`}>Move to {r.name}</option>
+                                    </React.Fragment>
+                                ))}
+                            </select>
+                        )}
                     </span>
                     {message.type === 'text' && (
                         <button
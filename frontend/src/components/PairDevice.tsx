@@ -0,0 +1,77 @@
+import React, { useState } from 'react';
+import { X } from 'lucide-react';
+import { api } from '../api';
+import { PairingCode } from '../types';
+
+interface PairDeviceProps {
+    onClose: () => void;
+}
+
+const PairDevice: React.FC<PairDeviceProps> = ({ onClose }) => {
+    const [deviceName, setDeviceName] = useState('');
+    const [pairingCode, setPairingCode] = useState<PairingCode | null>(null);
+    const [error, setError] = useState('');
+
+    const handleCreate = async (e: React.FormEvent) => {
+        e.preventDefault();
+        try {
+            setError('');
+            setPairingCode(await api.createPairingCode(deviceName.trim() || undefined));
+        } catch (error) {
+            console.error('Failed to create pairing code:', error);
+            setError('Could not create a pairing code');
+        }
+    };
+
+    return (
+        <div className="fixed inset-0 z-10 flex items-center justify-center bg-black bg-opacity-40">
+            <div className="bg-white rounded-lg p-6 w-80 relative">
+                <button
+                    onClick={onClose}
+                    className="absolute top-3 right-3 text-gray-500 hover:text-gray-700"
+                    title="Close"
+                >
+                    <X size={16} />
+                </button>
+                <h2 className="text-lg font-semibold text-gray-800 mb-4">Pair a new device</h2>
+                {pairingCode ? (
+                    <div className="text-center">
+                        <img
+                            src={`data:image/svg+xml;utf8,${encodeURIComponent(pairingCode.qrSvg)}`}
+                            alt="Pairing QR code"
+                            className="mx-auto"
+                        />
+                        <p className="text-2xl font-mono tracking-widest mt-4">{pairingCode.code}</p>
+                        <p className="text-sm text-gray-500 mt-2">
+                            Scan the code or enter it on the new device before{' '}
+                            {new Date(pairingCode.expiresAt).toLocaleTimeString([], {
+                                hour: '2-digit',
+                                minute: '2-digit',
+                            })}
+                            . It works once.
+                        </p>
+                    </div>
+                ) : (
+                    <form onSubmit={handleCreate}>
+                        <input
+                            type="text"
+                            value={deviceName}
+                            onChange={(e) => setDeviceName(e.target.value)}
+                            placeholder="Name of the new device (optional)"
+                            className="w-full px-3 py-2 border border-gray-300 rounded-lg focus:outline-none focus:ring-2 focus:ring-blue-500"
+                        />
+                        {error && <p className="text-sm text-red-500 mt-2">{error}</p>}
+                        <button
+                            type="submit"
+                            className="w-full mt-4 px-4 py-2 bg-blue-500 text-white rounded-lg hover:bg-blue-600"
+                        >
+                            Create pairing code
+                        </button>
+                    </form>
+                )}
+            </div>
+        </div>
+    );
+};
+
+export default PairDevice;
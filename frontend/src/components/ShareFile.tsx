@@ -0,0 +1,116 @@
+import React, { useState } from 'react';
+import { X } from 'lucide-react';
+import { api } from '../api';
+import { Message, ShareLink } from '../types';
+
+interface ShareFileProps {
+    room: string;
+    message: Message;
+    onClose: () => void;
+}
+
+const LIFETIMES = [
+    { label: '1 hour', seconds: 60 * 60 },
+    { label: '1 day', seconds: 24 * 60 * 60 },
+    { label: '1 week', seconds: 7 * 24 * 60 * 60 },
+    { label: '30 days', seconds: 30 * 24 * 60 * 60 },
+];
+
+const ShareFile: React.FC<ShareFileProps> = ({ room, message, onClose }) => {
+    const [expiresIn, setExpiresIn] = useState(LIFETIMES[2].seconds);
+    const [maxDownloads, setMaxDownloads] = useState('');
+    const [password, setPassword] = useState('');
+    const [link, setLink] = useState<ShareLink | null>(null);
+    const [error, setError] = useState('');
+
+    const handleCreate = async (e: React.FormEvent) => {
+        e.preventDefault();
+        try {
+            setError('');
+            setLink(
+                await api.shareFile(room, message.content, {
+                    expiresIn,
+                    maxDownloads: maxDownloads ? Number(maxDownloads) : undefined,
+                    password: password || undefined,
+                })
+            );
+        } catch (error) {
+            console.error('Failed to share file:', error);
+            setError('Could not create a share link');
+        }
+    };
+
+    return (
+        <div className="fixed inset-0 z-10 flex items-center justify-center bg-black bg-opacity-40">
+            <div className="bg-white rounded-lg p-6 w-80 relative text-gray-800">
+                <button
+                    onClick={onClose}
+                    className="absolute top-3 right-3 text-gray-500 hover:text-gray-700"
+                    title="Close"
+                >
+                    <X size={16} />
+                </button>
+                <h2 className="text-lg font-semibold mb-4 truncate">Share {message.filename}</h2>
+                {link ? (
+                    <div>
+                        <input
+                            type="text"
+                            readOnly
+                            value={link.url}
+                            onFocus={(e) => e.target.select()}
+                            className="w-full px-3 py-2 border border-gray-300 rounded-lg text-sm"
+                        />
+                        <button
+                            onClick={() => navigator.clipboard.writeText(link.url)}
+                            className="w-full mt-2 px-4 py-2 bg-blue-500 text-white rounded-lg hover:bg-blue-600"
+                        >
+                            Copy link
+                        </button>
+                        <p className="text-sm text-gray-500 mt-2">
+                            Works until {new Date(link.expiresAt).toLocaleString()}
+                            {link.maxDownloads ? ` for ${link.maxDownloads} downloads` : ''}.
+                        </p>
+                    </div>
+                ) : (
+                    <form onSubmit={handleCreate} className="space-y-2">
+                        <select
+                            value={expiresIn}
+                            onChange={(e) => setExpiresIn(Number(e.target.value))}
+                            className="w-full px-3 py-2 border border-gray-300 rounded-lg"
+                        >
+                            {LIFETIMES.map((l) => (
+                                <option key={l.seconds} value={l.seconds}>
+                                    Expires in {l.label}
+                                </option>
+                            ))}
+                        </select>
+                        <input
+                            type="number"
+                            min={1}
+                            value={maxDownloads}
+                            onChange={(e) => setMaxDownloads(e.target.value)}
+                            placeholder="Download limit (optional)"
+                            className="w-full px-3 py-2 border border-gray-300 rounded-lg"
+                        />
+                        <input
+                            type="password"
+                            value={password}
+                            onChange={(e) => setPassword(e.target.value)}
+                            placeholder="Password (optional)"
+                            className="w-full px-3 py-2 border border-gray-300 rounded-lg"
+                        />
+                        {error && <p className="text-sm text-red-500">{error}</p>}
+                        <button
+                            type="submit"
+                            className="w-full mt-2 px-4 py-2 bg-blue-500 text-white rounded-lg hover:bg-blue-600"
+                        >
+                            Create link
+                        </button>
+                    </form>
+                )}
+            </div>
+        </div>
+    );
+};
+
+export default ShareFile;
@@ -0,0 +1,155 @@
+import React, { useEffect, useState } from 'react';
+import { X } from 'lucide-react';
+import { api, apiErrorCode } from '../api';
+import { TotpEnrolment, TotpStatus } from '../types';
+
+interface TwoFactorProps {
+    onClose: () => void;
+}
+
+const TwoFactor: React.FC<TwoFactorProps> = ({ onClose }) => {
+    const [status, setStatus] = useState<TotpStatus | null>(null);
+    const [enrolment, setEnrolment] = useState<TotpEnrolment | null>(null);
+    const [recoveryCodes, setRecoveryCodes] = useState<string[] | null>(null);
+    const [code, setCode] = useState('');
+    const [error, setError] = useState('');
+
+    useEffect(() => {
+        api.getTotpStatus()
+            .then(setStatus)
+            .catch((error) => {
+                console.error('Failed to load two-factor status:', error);
+                setError('Could not load the two-factor settings');
+            });
+    }, []);
+
+    const run = async (action: () => Promise<void>) => {
+        try {
+            setError('');
+            await action();
+            setCode('');
+            setStatus(await api.getTotpStatus());
+        } catch (error) {
+            console.error('Two-factor request failed:', error);
+            setError(
+                apiErrorCode(error) === 'invalid-totp-code'
+                    ? 'Wrong or already used code'
+                    : 'Something went wrong, please try again'
+            );
+        }
+    };
+
+    const handleSubmit = (e: React.FormEvent) => {
+        e.preventDefault();
+        if (!code) return;
+        if (enrolment) {
+            run(async () => {
+                setRecoveryCodes(await api.confirmTotp(code));
+                setEnrolment(null);
+            });
+        } else {
+            run(() => api.disableTotp(code));
+        }
+    };
+
+    const codeInput = (
+        <input
+            type="text"
+            inputMode="numeric"
+            autoComplete="one-time-code"
+            value={code}
+            onChange={(e) => setCode(e.target.value)}
+            placeholder={enrolment ? 'Code from the app' : 'Authenticator or recovery code'}
+            className="w-full px-3 py-2 border border-gray-300 rounded-lg focus:outline-none focus:ring-2 focus:ring-blue-500"
+        />
+    );
+
+    return (
+        <div className="fixed inset-0 z-10 flex items-center justify-center bg-black bg-opacity-40">
+            <div className="bg-white rounded-lg p-6 w-80 relative">
+                <button
+                    onClick={onClose}
+                    className="absolute top-3 right-3 text-gray-500 hover:text-gray-700"
+                    title="Close"
+                >
+                    <X size={16} />
+                </button>
+                <h2 className="text-lg font-semibold text-gray-800 mb-4">Two-factor login</h2>
+                {recoveryCodes ? (
+                    <div>
+                        <p className="text-sm text-gray-500 mb-2">
+                            Keep these recovery codes somewhere safe. Each works once, and they
+                            are not shown again.
+                        </p>
+                        <ul className="font-mono text-sm grid grid-cols-2 gap-1">
+                            {recoveryCodes.map((c) => (
+                                <li key={c}>{c}</li>
+                            ))}
+                        </ul>
+                        <button
+                            onClick={() => setRecoveryCodes(null)}
+                            className="w-full mt-4 px-4 py-2 bg-blue-500 text-white rounded-lg hover:bg-blue-600"
+                        >
+                            Done
+                        </button>
+                    </div>
+                ) : enrolment ? (
+                    <form onSubmit={handleSubmit} className="text-center">
+                        <img
+                            src={`data:image/svg+xml;utf8,${encodeURIComponent(enrolment.qrSvg)}`}
+                            alt="Authenticator QR code"
+                            className="mx-auto"
+                        />
+                        <p className="text-xs font-mono break-all text-gray-500 my-2">{enrolment.secret}</p>
+                        {codeInput}
+                        <button
+                            type="submit"
+                            disabled={!code}
+                            className="w-full mt-4 px-4 py-2 bg-blue-500 text-white rounded-lg hover:bg-blue-600 disabled:opacity-50"
+                        >
+                            Turn on
+                        </button>
+                    </form>
+                ) : status?.enabled ? (
+                    <form onSubmit={handleSubmit}>
+                        <p className="text-sm text-gray-500 mb-2">
+                            Signing in asks for a code from your authenticator app.{' '}
+                            {status.recoveryCodesLeft} recovery codes left.
+                        </p>
+                        {codeInput}
+                        <button
+                            type="submit"
+                            disabled={!code}
+                            className="w-full mt-4 px-4 py-2 bg-red-500 text-white rounded-lg hover:bg-red-600 disabled:opacity-50"
+                        >
+                            Turn off
+                        </button>
+                        <button
+                            type="button"
+                            disabled={!code}
+                            onClick={() => run(async () => setRecoveryCodes(await api.regenerateRecoveryCodes(code)))}
+                            className="w-full mt-2 text-sm text-gray-500 hover:text-gray-700 disabled:opacity-50"
+                        >
+                            New recovery codes
+                        </button>
+                    </form>
+                ) : status ? (
+                    <div>
+                        <p className="text-sm text-gray-500 mb-2">
+                            Ask for a code from an authenticator app as well as the passphrase.
+                        </p>
+                        <button
+                            onClick={() => run(async () => setEnrolment(await api.enrollTotp()))}
+                            className="w-full mt-2 px-4 py-2 bg-blue-500 text-white rounded-lg hover:bg-blue-600"
+                        >
+                            Set up
+                        </button>
+                    </div>
+                ) : null}
+                {error && <p className="text-sm text-red-500 mt-2">{error}</p>}
+            </div>
+        </div>
+    );
+};
+
+export default TwoFactor;
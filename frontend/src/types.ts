@@ -1,12 +1,45 @@
+/** A change to another message; polls deliver these as they happen. */
+export type MessageEvent =
+  | { kind: 'pin'; target: string; pinned: boolean }
+  | { kind: 'react'; target: string; emoji: string; added: boolean };
+
+export interface Reaction {
+  emoji: string;
+  count: number;
+  /** Names of those who reacted. */
+  by: string[];
+  /** Ids of the devices among them. */
+  devices?: string[];
+}
+
+/** The start of a message, shown above replies to it. */
+export interface Quote {
+  id: string;
+  sender: string;
+  type: Message['type'];
+  excerpt: string;
+}
+
 export interface Message {
   id: string;
   content: string;
   sender: string;
   timestamp: string;
-  type: 'text' | 'image' | 'file';
+  type: 'text' | 'image' | 'file' | 'event';
   filename?: string;
   fileSize?: number;
   mimeType?: string;
+  broken?: string;
+  deviceId?: string;
+  /** Device ids the message was sent to; absent when it is for everyone. */
+  recipients?: string[];
+  /** Set on records of type `event`. */
+  event?: MessageEvent;
+  pinned?: boolean;
+  replyTo?: string;
+  /** The parent of a reply, in full listings. */
+  quote?: Quote;
+  reactions?: Reaction[];
 }
 
 export interface SendMessageRequest {
@@ -14,4 +47,127 @@ export interface SendMessageRequest {
   sender: string;
   type: 'text' | 'image' | 'file';
   filename?: string;
+  recipients?: string[];
+  replyTo?: string;
+}
+
+export interface Device {
+  id: string;
+  name: string;
+  color: string;
+  icon?: string;
+  createdAt: string;
+  revokedAt?: string;
+  status?: PresenceStatus;
+  /** When the device last polled. */
+  lastSeen?: string;
+}
+
+export type PresenceStatus = "online" | "idle" | "offline";
+
+export interface DevicePresence {
+  deviceId: string;
+  status: PresenceStatus;
+  lastSeen?: string;
+}
+
+export interface PairingCode {
+  code: string;
+  expiresAt: string;
+  url: string;
+  qrSvg: string;
+}
+
+export interface TotpStatus {
+  enabled: boolean;
+  enrolling: boolean;
+  recoveryCodesLeft: number;
+}
+
+export interface TotpEnrolment {
+  secret: string;
+  otpauthUrl: string;
+  qrSvg: string;
+}
+
+export interface SessionStatus {
+  authRequired: boolean;
+  passphraseLogin: boolean;
+  oidcLogin: boolean;
+  totpRequired: boolean;
+  authenticated: boolean;
+  device?: Device;
+  /** Echoed in `X-CSRF-Token` while a session cookie is in use. */
+  csrfToken?: string;
+}
+
+export interface ShareAccess {
+  at: string;
+  outcome: string;
+  client?: string;
+  userAgent?: string;
+}
+
+export interface ShareLink {
+  id: string;
+  room: string;
+  file: string;
+  filename: string;
+  createdAt: string;
+  createdBy?: string;
+  expiresAt: string;
+  maxDownloads?: number;
+  downloads: number;
+  hasPassword: boolean;
+  revokedAt?: string;
+  accesses: ShareAccess[];
+  /** Only returned when the link is created. */
+  url: string;
+}
+
+export interface RoomRetention {
+  archiveAfterDays?: number;
+  purgeAfterDays?: number;
+}
+
+export interface Room {
+  id: string;
+  name: string;
+  createdAt: string;
+  createdBy?: string;
+  /** Device ids; empty when every device may use the room. */
+  members: string[];
+  retention?: RoomRetention;
+}
+
+export const DEFAULT_ROOM = "default";
+
+export interface ReadPosition {
+  messageId: string;
+  timestamp: string;
+  readAt: string;
+}
+
+export interface Unread {
+  count: number;
+  firstUnread?: Message;
+  position?: ReadPosition;
+}
+
+export interface Receipt {
+  deviceId: string;
+  name: string;
+  readAt?: string;
+}
+
+export interface Receipts {
+  messageId: string;
+  seenBy: Receipt[];
+  notSeenBy: Receipt[];
+}
+
+export interface ShareOptions {
+  expiresIn?: number;
+  maxDownloads?: number;
+  password?: string;
 }
@@ -0,0 +1,480 @@
+//! Append-only record of logins, credential changes, share links and
+//! maintenance, kept apart from the message log so that deleting messages
+//! cannot delete the trace of it. No endpoint deletes or restores single
+//! messages; they leave a room only by being moved (`message.move`) or with
+//! their segment (`admin.purge`), and both are recorded.
+//!
+//! On disk the log is `audit.jsonl` in the data directory; key-value hosts
+//! keep entries under `audit/{seq}` like messages. Nothing in the server
+//! rewrites or truncates it.
+
+use crate::jsonl;
+use crate::kv::Bucket;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::io::Error;
+use std::path::PathBuf;
+
+const ENTRY_PREFIX: &str = "audit/";
+const NEXT_SEQ_KEY: &str = "meta/next-audit-seq";
+
+/// Entries a query returns unless it asks for fewer.
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub id: String,
+    pub at: String,
+    /// What was attempted, such as `login` or `device.revoke`.
+    pub action: String,
+    /// The device acting, if known. Failed logins have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    /// What the action was applied to: a device, upload, share or segment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub outcome: Outcome,
+    /// The HTTP request, or none for `spore-admin` commands.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<RequestInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestInfo {
+    pub method: String,
+    pub path: String,
+    /// `X-Forwarded-For` and `User-Agent`, when the request had them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Outcome {
+    pub success: bool,
+    /// The response status; `spore-admin` commands have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+}
+
+impl Outcome {
+    pub fn from_status(status: u16) -> Outcome {
+        Outcome {
+            success: status < 400,
+            status: Some(status),
+        }
+    }
+}
+
+impl Entry {
+    pub fn new(action: &str, outcome: Outcome, now: DateTime<Utc>) -> Entry {
+        Entry {
+            id: uuid::Uuid::new_v4().to_string(),
+            at: now.to_rfc3339(),
+            action: action.to_string(),
+            actor: None,
+            target: None,
+            outcome,
+            request: None,
+        }
+    }
+}
+
+/// The action an API request performs, and what it targets, if it is one
+/// worth auditing. Reads are not.
+pub fn action_for(method: &str, path: &str) -> Option<(&'static str, Option<String>)> {
+    let target = |prefix: &str, suffix: &str| {
+        path.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .map(str::to_string)
+    };
+    let action = match (method, path) {
+        ("POST", "/api/login") => "login",
+        ("GET", crate::oidc::CALLBACK_PATH) => "login.oidc",
+        ("POST", "/api/logout") => "logout",
+        ("POST", "/api/pairing") => "pairing.create",
+        ("POST", "/api/pairing/redeem") => "pairing.redeem",
+        ("POST", "/api/devices") => "device.create",
+        ("POST", "/api/totp/enroll") => "totp.enroll",
+        ("POST", "/api/totp/confirm") => "totp.confirm",
+        ("POST", "/api/totp/disable") => "totp.disable",
+        ("POST", "/api/totp/recovery-codes") => "totp.recovery-codes",
+        ("POST", "/api/admin/fsck") => "admin.fsck-repair",
+        ("POST", "/api/admin/migrate") => "admin.migrate",
+        ("POST", "/api/admin/retention") => "admin.retention",
+        ("PATCH", _) if path.starts_with("/api/devices/") => {
+            return Some(("device.edit", target("/api/devices/", "")));
+        }
+        ("DELETE", _) if path.starts_with("/api/devices/") => {
+            return Some(("device.revoke", target("/api/devices/", "")));
+        }
+        ("POST", "/api/rooms") => "room.create",
+        ("PATCH", _) if path.starts_with("/api/rooms/") => {
+            return Some(("room.edit", target("/api/rooms/", "")));
+        }
+        ("POST", _) if path.starts_with("/api/messages/") && path.ends_with("/move") => {
+            return Some(("message.move", target("/api/messages/", "/move")));
+        }
+        ("POST", _) if path.starts_with("/api/messages/") && path.ends_with("/copy") => {
+            return Some(("message.copy", target("/api/messages/", "/copy")));
+        }
+        ("POST", _) if path.starts_with("/api/files/") && path.ends_with("/share") => {
+            return Some(("share.create", target("/api/files/", "/share")));
+        }
+        ("DELETE", _) if path.starts_with("/api/shares/") => {
+            return Some(("share.revoke", target("/api/shares/", "")));
+        }
+        ("POST", _) if path.starts_with("/api/admin/segments/") && path.ends_with("/archive") => {
+            return Some(("admin.archive", target("/api/admin/segments/", "/archive")));
+        }
+        ("DELETE", _) if path.starts_with("/api/admin/segments/") => {
+            return Some(("admin.purge", target("/api/admin/segments/", "")));
+        }
+        _ => return None,
+    };
+    Some((action, None))
+}
+
+pub trait AuditLog {
+    fn append(&self, entry: &Entry) -> Result<(), Error>;
+
+    /// Every entry, oldest first.
+    fn read(&self) -> Result<Vec<Entry>, Error>;
+}
+
+/// `<data dir>/audit.jsonl`, appended under the same kind of lock file as
+/// the message log.
+pub struct JsonlAuditLog {
+    path: PathBuf,
+}
+
+impl JsonlAuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> JsonlAuditLog {
+        JsonlAuditLog { path: path.into() }
+    }
+}
+
+impl AuditLog for JsonlAuditLog {
+    fn append(&self, entry: &Entry) -> Result<(), Error> {
+        jsonl::append(&self.path, entry)
+    }
+
+    fn read(&self) -> Result<Vec<Entry>, Error> {
+        let (entries, corrupt) = jsonl::read(&self.path)?;
+        for line in &corrupt {
+            eprintln!(
+                "Skipping unreadable audit entry at line {}: {}",
+                line.line_number, line.error
+            );
+        }
+        Ok(entries)
+    }
+}
+
+pub struct KvAuditLog<B: Bucket> {
+    bucket: B,
+}
+
+impl<B: Bucket> KvAuditLog<B> {
+    pub fn new(bucket: B) -> KvAuditLog<B> {
+        KvAuditLog { bucket }
+    }
+}
+
+impl<B: Bucket> AuditLog for KvAuditLog<B> {
+    fn append(&self, entry: &Entry) -> Result<(), Error> {
+        let seq = self.bucket.increment(NEXT_SEQ_KEY, 1)?;
+        self.bucket.set(
+            &format!("{}{:020}", ENTRY_PREFIX, seq),
+            &serde_json::to_vec(entry)?,
+        )
+    }
+
+    fn read(&self) -> Result<Vec<Entry>, Error> {
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) = self.bucket.list_keys(cursor)?;
+            keys.extend(page.into_iter().filter(|k| k.starts_with(ENTRY_PREFIX)));
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        keys.sort();
+
+        let mut entries = Vec::new();
+        for key in keys {
+            let Some(value) = self.bucket.get(&key)? else {
+                continue;
+            };
+            match serde_json::from_slice(&value) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => eprintln!("Skipping unreadable audit entry {}: {}", key, e),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryAuditLog {
+    entries: RefCell<Vec<Entry>>,
+}
+
+impl AuditLog for MemoryAuditLog {
+    fn append(&self, entry: &Entry) -> Result<(), Error> {
+        self.entries.borrow_mut().push(entry.clone());
+        Ok(())
+    }
+
+    fn read(&self) -> Result<Vec<Entry>, Error> {
+        Ok(self.entries.borrow().clone())
+    }
+}
+
+/// Filters for `GET /api/audit`. Unset fields match everything.
+#[derive(Debug, Default)]
+pub struct Query {
+    /// An action, or the part before its dot: `device` matches
+    /// `device.create` and `device.revoke`.
+    pub action: Option<String>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub success: Option<bool>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    /// Reads `action`, `actor`, `target`, `outcome` (`success` or
+    /// `failure`), `since`, `until` (RFC 3339) and `limit` from a query
+    /// string.
+    pub fn parse(query: &str) -> Result<Query, String> {
+        use crate::url::query_param;
+        let time = |key: &str| -> Result<Option<DateTime<Utc>>, String> {
+            query_param(query, key)
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    DateTime::parse_from_rfc3339(&v)
+                        .map(|t| t.with_timezone(&Utc))
+                        .map_err(|_| format!("{} must be an RFC 3339 timestamp", key))
+                })
+                .transpose()
+        };
+        let success = match query_param(query, "outcome").as_deref() {
+            None | Some("") => None,
+            Some("success") => Some(true),
+            Some("failure") => Some(false),
+            Some(_) => return Err("outcome must be success or failure".to_string()),
+        };
+        let limit = match query_param(query, "limit").filter(|v| !v.is_empty()) {
+            Some(limit) => Some(
+                limit
+                    .parse()
+                    .ok()
+                    .filter(|l| *l > 0)
+                    .ok_or("limit must be a positive number")?,
+            ),
+            None => None,
+        };
+        let text = |key: &str| query_param(query, key).filter(|v| !v.is_empty());
+        Ok(Query {
+            action: text("action"),
+            actor: text("actor"),
+            target: text("target"),
+            success,
+            since: time("since")?,
+            until: time("until")?,
+            limit,
+        })
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        if let Some(action) = &self.action
+            && entry.action != *action
+            && entry.action.split('.').next() != Some(action.as_str())
+        {
+            return false;
+        }
+        if self.actor.is_some() && entry.actor != self.actor {
+            return false;
+        }
+        if self.target.is_some() && entry.target != self.target {
+            return false;
+        }
+        if self.success.is_some_and(|s| s != entry.outcome.success) {
+            return false;
+        }
+        if self.since.is_some() || self.until.is_some() {
+            let Ok(at) = DateTime::parse_from_rfc3339(&entry.at) else {
+                return false;
+            };
+            if self.since.is_some_and(|since| at <= since)
+                || self.until.is_some_and(|until| at > until)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The newest entries matching `query`, newest first.
+pub fn query(log: &dyn AuditLog, query: &Query) -> Result<Vec<Entry>, Error> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    Ok(log
+        .read()?
+        .into_iter()
+        .rev()
+        .filter(|entry| query.matches(entry))
+        .take(limit)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::MemoryBucket;
+
+    fn entry(action: &str, success: bool, at: &str) -> Entry {
+        Entry {
+            at: at.to_string(),
+            ..Entry::new(
+                action,
+                Outcome::from_status(if success { 200 } else { 401 }),
+                Utc::now(),
+            )
+        }
+    }
+
+    #[test]
+    fn audits_changes_but_not_reads() {
+        assert_eq!(action_for("POST", "/api/login"), Some(("login", None)));
+        assert_eq!(
+            action_for("DELETE", "/api/devices/d1"),
+            Some(("device.revoke", Some("d1".to_string())))
+        );
+        assert_eq!(
+            action_for("POST", "/api/messages/m1/move"),
+            Some(("message.move", Some("m1".to_string())))
+        );
+        assert_eq!(
+            action_for("POST", "/api/files/a.png/share"),
+            Some(("share.create", Some("a.png".to_string())))
+        );
+        assert_eq!(
+            action_for("POST", "/api/admin/segments/2024-01/archive"),
+            Some(("admin.archive", Some("2024-01".to_string())))
+        );
+        assert_eq!(
+            action_for("DELETE", "/api/admin/segments/2024-01"),
+            Some(("admin.purge", Some("2024-01".to_string())))
+        );
+        assert_eq!(
+            action_for("POST", "/api/admin/fsck"),
+            Some(("admin.fsck-repair", None))
+        );
+
+        assert_eq!(action_for("GET", "/api/admin/fsck"), None);
+        assert_eq!(action_for("GET", "/api/devices"), None);
+        assert_eq!(action_for("POST", "/api/messages"), None);
+        assert_eq!(action_for("POST", "/api/messages/m1/pin"), None);
+    }
+
+    #[test]
+    fn parses_queries() {
+        let query = Query::parse(
+            "action=device&actor=d1&outcome=failure&since=2024-01-01T00:00:00Z&limit=5",
+        )
+        .unwrap();
+        assert_eq!(query.action.as_deref(), Some("device"));
+        assert_eq!(query.actor.as_deref(), Some("d1"));
+        assert_eq!(query.success, Some(false));
+        assert_eq!(
+            query.since.unwrap().to_rfc3339(),
+            "2024-01-01T00:00:00+00:00"
+        );
+        assert_eq!(query.until, None);
+        assert_eq!(query.limit, Some(5));
+
+        let empty = Query::parse("action=&outcome=&limit=").unwrap();
+        assert!(empty.action.is_none() && empty.success.is_none() && empty.limit.is_none());
+        assert!(Query::parse("outcome=maybe").is_err());
+        assert!(Query::parse("limit=0").is_err());
+        assert!(Query::parse("limit=-1").is_err());
+        assert!(Query::parse("since=yesterday").is_err());
+    }
+
+    #[test]
+    fn matches_actions_by_prefix_outcome_and_time() {
+        let revoke = entry("device.revoke", true, "2024-01-02T00:00:00Z");
+        let matches = |query: &str| Query::parse(query).unwrap().matches(&revoke);
+
+        assert!(matches(""));
+        assert!(matches("action=device"));
+        assert!(matches("action=device.revoke"));
+        assert!(!matches("action=device.rev"));
+        assert!(!matches("action=dev"));
+        assert!(!matches("action=login"));
+        assert!(matches("outcome=success"));
+        assert!(!matches("outcome=failure"));
+        assert!(!matches("actor=d1"));
+
+        // `since` is exclusive, `until` inclusive, compared as times.
+        assert!(matches("since=2024-01-01T23:59:59Z"));
+        assert!(!matches("since=2024-01-02T00:00:00Z"));
+        assert!(matches("since=2024-01-02T00:59:59%2B01:00"));
+        assert!(matches("until=2024-01-02T00:00:00Z"));
+        assert!(!matches("until=2024-01-01T23:59:59Z"));
+        let unparseable = entry("login", true, "garbage");
+        assert!(
+            !Query::parse("since=2024-01-01T00:00:00Z")
+                .unwrap()
+                .matches(&unparseable)
+        );
+    }
+
+    #[test]
+    fn queries_return_the_newest_first_up_to_the_cap() {
+        let log = MemoryAuditLog::default();
+        for n in 0..MAX_LIMIT + 5 {
+            log.append(&entry("login", n % 2 == 0, &format!("t{}", n)))
+                .unwrap();
+        }
+
+        let newest = query(&log, &Query::parse("limit=3").unwrap()).unwrap();
+        let at: Vec<&str> = newest.iter().map(|e| e.at.as_str()).collect();
+        assert_eq!(at, ["t1004", "t1003", "t1002"]);
+        assert_eq!(query(&log, &Query::default()).unwrap().len(), DEFAULT_LIMIT);
+        let capped = query(&log, &Query::parse("limit=5000").unwrap()).unwrap();
+        assert_eq!(capped.len(), MAX_LIMIT);
+        let failures = query(&log, &Query::parse("outcome=failure&limit=2").unwrap()).unwrap();
+        let at: Vec<&str> = failures.iter().map(|e| e.at.as_str()).collect();
+        assert_eq!(at, ["t1003", "t1001"]);
+    }
+
+    #[test]
+    fn kv_entries_read_back_in_order() {
+        let log = KvAuditLog::new(MemoryBucket::default());
+        // More than a page of keys, which the bucket lists in any order.
+        for n in 0..150 {
+            log.append(&entry("login", true, &format!("t{:03}", n)))
+                .unwrap();
+        }
+        let entries = log.read().unwrap();
+        let at: Vec<String> = entries.iter().map(|e| e.at.clone()).collect();
+        let expected: Vec<String> = (0..150).map(|n| format!("t{:03}", n)).collect();
+        assert_eq!(at, expected);
+
+        let newest = query(&log, &Query::parse("limit=1").unwrap()).unwrap();
+        assert_eq!(newest[0].at, "t149");
+    }
+}
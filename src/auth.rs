@@ -0,0 +1,464 @@
+//! Optional passphrase or OpenID Connect login.
+//!
+//! Login is required once `SPORE_PASSPHRASE_HASH` is set to the output of
+//! `spore-admin hash-passphrase`, or OpenID Connect is configured (see
+//! `oidc`). Sessions are stateless: a session token is its expiry time signed
+//! with a key derived from the passphrase hash and the OpenID Connect client
+//! secret, so changing either signs every session out.
+//!
+//! A token is accepted from the session cookie or an `Authorization: Bearer`
+//! header. Scripts can also send the passphrase itself with HTTP Basic (any
+//! user name), at the cost of a key derivation per request. A session may name
+//! the registered device it was issued to (see `device`).
+
+use crate::oidc::OidcConfig;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+pub const PASSPHRASE_HASH_VAR: &str = "SPORE_PASSPHRASE_HASH";
+pub const SESSION_COOKIE: &str = "spore_session";
+
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const PBKDF2_ROUNDS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const TOKEN_VERSION: &str = "s1";
+
+/// Routes under `/api/` that work without a session.
+const PUBLIC_API_ROUTES: &[&str] = &[
+    "/api/login",
+    "/api/logout",
+    "/api/session",
+    "/api/pairing/redeem",
+];
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn session_lifetime() -> Duration {
+    Duration::days(30)
+}
+
+/// Whether `path` needs a session when login is enabled.
+pub fn is_protected(path: &str) -> bool {
+    path.starts_with("/api/") && !PUBLIC_API_ROUTES.contains(&path)
+}
+
+/// `pbkdf2-sha256$<rounds>$<salt>$<hash>` for a new random salt.
+pub fn hash_passphrase(passphrase: &str) -> Result<String, getrandom::Error> {
+    let mut salt = [0u8; SALT_LEN];
+    getrandom::fill(&mut salt)?;
+    let hash = derive(passphrase, &salt, PBKDF2_ROUNDS);
+    Ok(format!(
+        "{}${}${}${}",
+        HASH_SCHEME,
+        PBKDF2_ROUNDS,
+        STANDARD_NO_PAD.encode(salt),
+        STANDARD_NO_PAD.encode(hash)
+    ))
+}
+
+/// Checks `passphrase` against the output of `hash_passphrase`, or says
+/// what is wrong with the hash.
+pub fn verify_passphrase(passphrase_hash: &str, passphrase: &str) -> Result<bool, String> {
+    let parts: Vec<&str> = passphrase_hash.split('$').collect();
+    let [scheme, rounds, salt, hash] = parts[..] else {
+        return Err("not a passphrase hash".to_string());
+    };
+    let (Ok(rounds), Ok(salt), Ok(hash)) = (
+        rounds.parse::<u32>(),
+        STANDARD_NO_PAD.decode(salt),
+        STANDARD_NO_PAD.decode(hash),
+    ) else {
+        return Err("not a passphrase hash".to_string());
+    };
+    if scheme != HASH_SCHEME {
+        return Err(format!("unsupported passphrase hash scheme {}", scheme));
+    }
+    Ok(constant_time_eq(&derive(passphrase, &salt, rounds), &hash))
+}
+
+fn derive(passphrase: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// What a request proved it knows.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Credential {
+    Passphrase,
+    Session { device_id: Option<String> },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub token: String,
+    pub expires_at: String,
+}
+
+pub struct AuthConfig {
+    passphrase_hash: Option<String>,
+    oidc: Option<OidcConfig>,
+    session_key: [u8; 32],
+}
+
+impl AuthConfig {
+    /// `None` when neither a passphrase nor OpenID Connect is configured,
+    /// i.e. login is disabled.
+    pub fn from_env() -> Option<AuthConfig> {
+        AuthConfig::from_settings(
+            std::env::var(PASSPHRASE_HASH_VAR).ok(),
+            OidcConfig::from_env(),
+        )
+    }
+
+    fn from_settings(
+        passphrase_hash: Option<String>,
+        oidc: Result<Option<OidcConfig>, String>,
+    ) -> Option<AuthConfig> {
+        let passphrase_hash = passphrase_hash
+            .map(|hash| hash.trim().to_string())
+            .filter(|hash| !hash.is_empty());
+        let oidc = match oidc {
+            Ok(oidc) => oidc,
+            Err(e) => {
+                // Someone meant to require a login; keep the box closed
+                // rather than falling back to anonymous use.
+                eprintln!("OpenID Connect login is misconfigured: {}", e);
+                return Some(AuthConfig::locked());
+            }
+        };
+        match (passphrase_hash, oidc) {
+            (None, None) => None,
+            (hash, oidc) => Some(AuthConfig::build(hash, oidc)),
+        }
+    }
+
+    pub fn new(passphrase_hash: &str) -> AuthConfig {
+        AuthConfig::build(Some(passphrase_hash.to_string()), None)
+    }
+
+    /// OpenID Connect login, with or without a passphrase besides.
+    pub fn with_oidc(passphrase_hash: Option<&str>, oidc: OidcConfig) -> AuthConfig {
+        AuthConfig::build(passphrase_hash.map(str::to_string), Some(oidc))
+    }
+
+    fn build(passphrase_hash: Option<String>, oidc: Option<OidcConfig>) -> AuthConfig {
+        let mut mac =
+            HmacSha256::new_from_slice(passphrase_hash.as_deref().unwrap_or("").as_bytes())
+                .expect("HMAC accepts keys of any length");
+        mac.update(b"spore-box session key");
+        if let Some(oidc) = &oidc {
+            mac.update(b"\0");
+            mac.update(oidc.client_secret().as_bytes());
+        }
+        AuthConfig {
+            passphrase_hash,
+            oidc,
+            session_key: mac.finalize().into_bytes().into(),
+        }
+    }
+
+    /// Requires a login that nothing can provide: no passphrase, and a
+    /// random session key that no other request shares. Device tokens still
+    /// work.
+    fn locked() -> AuthConfig {
+        let mut session_key = [0u8; 32];
+        getrandom::fill(&mut session_key).expect("no random numbers for a session key");
+        AuthConfig {
+            passphrase_hash: None,
+            oidc: None,
+            session_key,
+        }
+    }
+
+    pub fn passphrase_enabled(&self) -> bool {
+        self.passphrase_hash.is_some()
+    }
+
+    pub fn oidc(&self) -> Option<&OidcConfig> {
+        self.oidc.as_ref()
+    }
+
+    pub fn check_passphrase(&self, passphrase: &str) -> bool {
+        let Some(passphrase_hash) = &self.passphrase_hash else {
+            return false;
+        };
+        match verify_passphrase(passphrase_hash, passphrase) {
+            Ok(matches) => matches,
+            Err(e) => {
+                eprintln!("{}: {}", PASSPHRASE_HASH_VAR, e);
+                false
+            }
+        }
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.session_key).unwrap();
+        mac.update(payload.as_bytes());
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    pub fn issue_session(
+        &self,
+        now: DateTime<Utc>,
+        device_id: Option<&str>,
+    ) -> Result<Session, getrandom::Error> {
+        let mut nonce = [0u8; 16];
+        getrandom::fill(&mut nonce)?;
+        let expires_at = now + session_lifetime();
+        let payload = format!(
+            "{}.{}.{}.{}",
+            TOKEN_VERSION,
+            expires_at.timestamp(),
+            device_id.unwrap_or_default(),
+            URL_SAFE_NO_PAD.encode(nonce)
+        );
+        Ok(Session {
+            token: format!("{}.{}", payload, self.sign(&payload)),
+            expires_at: expires_at.to_rfc3339(),
+        })
+    }
+
+    /// The device a valid, unexpired session token was issued to.
+    pub fn verify_session(&self, token: &str, now: DateTime<Utc>) -> Option<Credential> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        if !constant_time_eq(self.sign(payload).as_bytes(), signature.as_bytes()) {
+            return None;
+        }
+        let mut parts = payload.split('.');
+        if parts.next() != Some(TOKEN_VERSION) {
+            return None;
+        }
+        let expiry = parts.next()?.parse::<i64>().ok()?;
+        let device_id = parts.next()?;
+        (now.timestamp() < expiry).then(|| Credential::Session {
+            device_id: (!device_id.is_empty()).then(|| device_id.to_string()),
+        })
+    }
+
+    /// Checks the `Authorization` and `Cookie` request headers.
+    pub fn authenticate(
+        &self,
+        authorization: Option<&str>,
+        cookie: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Option<Credential> {
+        if let Some(token) = cookie.and_then(|c| cookie_value(c, SESSION_COOKIE))
+            && let Some(credential) = self.verify_session(token, now)
+        {
+            return Some(credential);
+        }
+
+        let (scheme, credentials) = authorization?.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+        match scheme.to_ascii_lowercase().as_str() {
+            "bearer" => self.verify_session(credentials, now),
+            "basic" => {
+                let decoded = String::from_utf8(STANDARD.decode(credentials).ok()?).ok()?;
+                let (_user, passphrase) = decoded.split_once(':')?;
+                self.check_passphrase(passphrase)
+                    .then_some(Credential::Passphrase)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then_some(token.trim())
+}
+
+/// The value of cookie `name` in a `Cookie` header.
+pub fn cookie_value<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+/// `Set-Cookie` value that stores `token` in the browser for `max_age`.
+pub fn session_cookie(token: &str, max_age: Duration) -> String {
+    format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        SESSION_COOKIE,
+        token,
+        max_age.num_seconds()
+    )
+}
+
+/// `Set-Cookie` value that removes the session cookie.
+pub fn clear_session_cookie() -> String {
+    format!(
+        "{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0",
+        SESSION_COOKIE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // Few rounds, so the tests do not spend a second per derivation.
+    fn quick_hash(passphrase: &str) -> String {
+        let salt = b"0123456789abcdef";
+        format!(
+            "{}$10${}${}",
+            HASH_SCHEME,
+            STANDARD_NO_PAD.encode(salt),
+            STANDARD_NO_PAD.encode(derive(passphrase, salt, 10))
+        )
+    }
+
+    fn noon() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn passphrase_hashes_verify() {
+        let hash = hash_passphrase("correct horse").unwrap();
+        assert!(hash.starts_with("pbkdf2-sha256$100000$"));
+        assert_ne!(hash, hash_passphrase("correct horse").unwrap());
+        assert_eq!(verify_passphrase(&hash, "correct horse"), Ok(true));
+        assert_eq!(verify_passphrase(&hash, "wrong horse"), Ok(false));
+
+        assert!(verify_passphrase("plain text", "x").is_err());
+        assert!(verify_passphrase("md5$1$c2FsdA$aGFzaA", "x").is_err());
+        assert!(!AuthConfig::new("garbage").check_passphrase("x"));
+    }
+
+    #[test]
+    fn sessions_verify_until_they_expire() {
+        let auth = AuthConfig::new(&quick_hash("secret"));
+        let session = auth.issue_session(noon(), Some("dev1")).unwrap();
+        assert_eq!(
+            auth.verify_session(&session.token, noon()),
+            Some(Credential::Session {
+                device_id: Some("dev1".to_string())
+            })
+        );
+        let last = noon() + session_lifetime() - Duration::seconds(1);
+        assert!(auth.verify_session(&session.token, last).is_some());
+        let expired = noon() + session_lifetime();
+        assert_eq!(auth.verify_session(&session.token, expired), None);
+
+        let anonymous = auth.issue_session(noon(), None).unwrap();
+        assert_eq!(
+            auth.verify_session(&anonymous.token, noon()),
+            Some(Credential::Session { device_id: None })
+        );
+    }
+
+    #[test]
+    fn tampered_sessions_are_refused() {
+        let auth = AuthConfig::new(&quick_hash("secret"));
+        let token = auth.issue_session(noon(), Some("dev1")).unwrap().token;
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+
+        let mut flipped = signature.to_string();
+        let last = if flipped.ends_with('A') { "B" } else { "A" };
+        flipped.replace_range(flipped.len() - 1.., last);
+        assert_eq!(
+            auth.verify_session(&format!("{}.{}", payload, flipped), noon()),
+            None
+        );
+
+        let other_device = payload.replace(".dev1.", ".dev2.");
+        assert_eq!(
+            auth.verify_session(&format!("{}.{}", other_device, signature), noon()),
+            None
+        );
+
+        // A new passphrase signs every session out.
+        let rotated = AuthConfig::new(&quick_hash("new secret"));
+        assert_eq!(rotated.verify_session(&token, noon()), None);
+        assert_eq!(auth.verify_session("no-dots", noon()), None);
+    }
+
+    #[test]
+    fn authenticates_cookies_bearers_and_basic() {
+        let auth = AuthConfig::new(&quick_hash("secret"));
+        let token = auth.issue_session(noon(), None).unwrap().token;
+        let session = Some(Credential::Session { device_id: None });
+
+        let cookie = format!("theme=dark; {}={}; other=1", SESSION_COOKIE, token);
+        assert_eq!(auth.authenticate(None, Some(&cookie), noon()), session);
+        let bearer = format!("Bearer {}", token);
+        assert_eq!(auth.authenticate(Some(&bearer), None, noon()), session);
+        let basic = format!("Basic {}", STANDARD.encode("anyone:secret"));
+        assert_eq!(
+            auth.authenticate(Some(&basic), None, noon()),
+            Some(Credential::Passphrase)
+        );
+
+        let wrong = format!("Basic {}", STANDARD.encode("anyone:guess"));
+        assert_eq!(auth.authenticate(Some(&wrong), None, noon()), None);
+        assert_eq!(auth.authenticate(None, Some("theme=dark"), noon()), None);
+        assert_eq!(auth.authenticate(None, None, noon()), None);
+    }
+
+    #[test]
+    fn parses_cookie_and_bearer_headers() {
+        assert_eq!(cookie_value("a=1; b=2", "b"), Some("2"));
+        assert_eq!(cookie_value("a=1;b=2 ", "b"), Some("2"));
+        assert_eq!(cookie_value("ab=1", "a"), None);
+        assert_eq!(cookie_value("a", "a"), None);
+        assert_eq!(cookie_value("a=x=y", "a"), Some("x=y"));
+
+        assert_eq!(bearer_token("Bearer abc"), Some("abc"));
+        assert_eq!(bearer_token(" bearer  abc "), Some("abc"));
+        assert_eq!(bearer_token("Basic abc"), None);
+        assert_eq!(bearer_token("Bearer"), None);
+    }
+
+    #[test]
+    fn classifies_protected_paths() {
+        assert!(is_protected("/api/messages"));
+        assert!(is_protected("/api/admin/fsck"));
+        assert!(is_protected("/api/pairing"));
+        assert!(is_protected("/api/login/extra"));
+        for public in PUBLIC_API_ROUTES {
+            assert!(!is_protected(public));
+        }
+        assert!(!is_protected("/"));
+        assert!(!is_protected("/static/app.js"));
+        assert!(!is_protected("/s/share-token"));
+    }
+
+    #[test]
+    fn misconfigured_oidc_locks_the_box() {
+        assert!(AuthConfig::from_settings(None, Ok(None)).is_none());
+        assert!(AuthConfig::from_settings(Some("  ".to_string()), Ok(None)).is_none());
+
+        let hash = quick_hash("secret");
+        let locked = AuthConfig::from_settings(
+            Some(hash.clone()),
+            Err("SPORE_OIDC_CLIENT_ID is not set".to_string()),
+        )
+        .unwrap();
+        assert!(!locked.passphrase_enabled());
+        assert!(locked.oidc().is_none());
+        assert!(!locked.check_passphrase("secret"));
+        let basic = format!("Basic {}", STANDARD.encode("anyone:secret"));
+        assert_eq!(locked.authenticate(Some(&basic), None, noon()), None);
+
+        // Not even a session signed the way a working setup would sign it.
+        let working = AuthConfig::from_settings(Some(hash), Ok(None)).unwrap();
+        let token = working.issue_session(noon(), None).unwrap().token;
+        assert_eq!(locked.verify_session(&token, noon()), None);
+        assert!(working.verify_session(&token, noon()).is_some());
+    }
+}
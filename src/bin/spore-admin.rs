@@ -0,0 +1,463 @@
+//! Offline maintenance for a spore-box data directory.
+//!
+//! Stop the server (or make sure nothing is writing) before repairing.
+
+use spore_box::audit;
+use spore_box::auth;
+use spore_box::crypt;
+use spore_box::device::{self, DeviceChanges, DeviceRegistry};
+use spore_box::fsck;
+use spore_box::rooms::{self, DEFAULT_ROOM};
+use spore_box::segment;
+use spore_box::store::{DATA_DIR, Storage};
+use spore_box::totp;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: spore-admin [--data DIR] [--room ID] <command>
+
+Commands:
+  fsck [--repair]   Check messages against uploads, optionally fixing issues
+  migrate           Rewrite all messages in the current schema version
+  segments          List log segments
+  archive NAME      Move a segment out of the live log
+  purge NAME        Delete an archived segment and its uploads
+  retention         Archive and purge segments past each room's retention
+                    period
+  rooms             List rooms
+  hash-passphrase   Read a passphrase from stdin and print the value for
+                    SPORE_PASSPHRASE_HASH
+  devices           List registered devices
+  add-device NAME   Register a device and print its token
+  revoke-device ID  Revoke a device's token and sessions
+  reset-totp        Turn off two-factor login, e.g. after losing the
+                    authenticator and recovery codes
+  gen-key           Print a new secret for SPORE_ENCRYPTION_KEY
+  encrypt           Seal messages and uploads stored in the clear with
+                    SPORE_ENCRYPTION_KEY, in every room
+
+fsck, migrate, segments, archive and purge work on the default room unless
+--room names another.";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let mut data_dir = DATA_DIR.to_string();
+    let mut room = DEFAULT_ROOM.to_string();
+    let mut command = None;
+    let mut repair = false;
+    // Segment name, device name or device id, depending on the command.
+    let mut argument = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--data" => match args.next() {
+                Some(dir) => data_dir = dir,
+                None => return usage_error("--data needs a directory"),
+            },
+            "--room" => match args.next() {
+                Some(id) => room = id,
+                None => return usage_error("--room needs a room id"),
+            },
+            "--repair" => repair = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if command.is_none() => command = Some(arg),
+            _ if argument.is_none() => argument = Some(arg),
+            _ => return usage_error(&format!("Unexpected argument {}", arg)),
+        }
+    }
+
+    if command.as_deref() == Some("gen-key") {
+        return run_gen_key();
+    }
+    let storage = match Storage::open_room(&data_dir, &room) {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", data_dir, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if room != DEFAULT_ROOM && !matches!(rooms::get(&*storage.state, &room), Ok(Some(_))) {
+        eprintln!("No room {}", room);
+        return ExitCode::FAILURE;
+    }
+    let audited = audited_action(command.as_deref(), argument.as_deref(), repair);
+    let code = match command.as_deref() {
+        Some("fsck") => run_fsck(&storage, repair),
+        Some("migrate") => run_migrate(&storage),
+        Some("segments") => run_segments(&storage),
+        Some(command @ ("archive" | "purge")) => match argument.as_deref() {
+            Some(name) => run_segment_command(&storage, command, name),
+            None => usage_error(&format!("{} needs a segment name", command)),
+        },
+        Some("retention") => run_retention(&storage, &data_dir),
+        Some("rooms") => run_rooms(&storage),
+        Some("hash-passphrase") => run_hash_passphrase(),
+        Some("devices") => run_devices(&storage),
+        Some(command @ ("add-device" | "revoke-device")) => match argument.as_deref() {
+            Some(arg) => run_device_command(&storage, command, arg),
+            None => usage_error(&format!("{} needs an argument", command)),
+        },
+        Some("reset-totp") => run_reset_totp(&storage),
+        Some("encrypt") => run_encrypt(&storage, &data_dir),
+        Some(other) => usage_error(&format!("Unknown command {}", other)),
+        None => usage_error("Missing command"),
+    };
+    if let Some((action, target)) = audited {
+        record(&storage, action, target, code == ExitCode::SUCCESS);
+    }
+    code
+}
+
+/// The audit action of a command that changes something, and its target.
+fn audited_action<'a>(
+    command: Option<&str>,
+    argument: Option<&'a str>,
+    repair: bool,
+) -> Option<(&'static str, Option<&'a str>)> {
+    let action = match (command?, argument) {
+        ("fsck", _) if repair => "admin.fsck-repair",
+        ("migrate", _) => "admin.migrate",
+        ("archive", Some(_)) => "admin.archive",
+        ("purge", Some(_)) => "admin.purge",
+        ("retention", _) => "admin.retention",
+        ("add-device", Some(_)) => return Some(("device.create", None)),
+        ("revoke-device", Some(_)) => "device.revoke",
+        ("reset-totp", _) => "totp.reset",
+        ("encrypt", _) => "admin.encrypt",
+        _ => return None,
+    };
+    Some((action, argument))
+}
+
+fn record(storage: &Storage, action: &str, target: Option<&str>, success: bool) {
+    let outcome = audit::Outcome {
+        success,
+        status: None,
+    };
+    let mut entry = audit::Entry::new(action, outcome, chrono::Utc::now());
+    entry.target = target.map(str::to_string);
+    if let Err(e) = storage.audit.append(&entry) {
+        eprintln!("Failed to write audit entry: {}", e);
+    }
+}
+
+fn usage_error(msg: &str) -> ExitCode {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    ExitCode::from(2)
+}
+
+fn run_fsck(storage: &Storage, repair: bool) -> ExitCode {
+    let report = match fsck::fsck(storage, repair) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("fsck failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for issue in &report.issues {
+        let subject = issue
+            .message_id
+            .as_deref()
+            .or(issue.blob.as_deref())
+            .unwrap_or("-");
+        let status = if issue.repaired { "repaired" } else { "found" };
+        println!(
+            "{:<8} {:<18} {} {}",
+            status,
+            issue.kind.as_str(),
+            subject,
+            issue.detail
+        );
+    }
+    println!(
+        "{} messages, {} uploads, {} issues, {} unresolved",
+        report.messages,
+        report.blobs,
+        report.issues.len(),
+        report.unresolved()
+    );
+
+    if report.unresolved() == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn run_migrate(storage: &Storage) -> ExitCode {
+    match storage.messages.migrate() {
+        Ok(report) => {
+            println!(
+                "{} messages at version {}",
+                report.total, report.current_version
+            );
+            for (version, count) in &report.upgraded {
+                println!("  upgraded {} from version {}", count, version);
+            }
+            if report.sealed > 0 {
+                println!("  sealed {}", report.sealed);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("migrate failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_segments(storage: &Storage) -> ExitCode {
+    let segments = match storage.messages.segments() {
+        Ok(segments) => segments,
+        Err(e) => {
+            eprintln!("segments failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for segment in &segments {
+        let state = match (&segment.archived_at, segment.head) {
+            (Some(_), _) => "archived",
+            (None, true) => "head",
+            (None, false) => "live",
+        };
+        println!(
+            "{:<12} {:<8} {:>8} messages {:>12} bytes  newest {}",
+            segment.name,
+            state,
+            segment.messages,
+            segment.bytes,
+            segment.newest.as_deref().unwrap_or("-")
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+fn run_segment_command(storage: &Storage, command: &str, name: &str) -> ExitCode {
+    let result = match command {
+        "archive" => segment::archive(storage, name)
+            .map(|kept| format!("archived {}, keeping {} pinned messages", name, kept)),
+        _ => segment::purge(storage, name)
+            .map(|deleted| format!("purged {} and {} uploads", name, deleted)),
+    };
+    match result {
+        Ok(summary) => {
+            println!("{}", summary);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{} failed: {}", command, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_retention(storage: &Storage, data_dir: &str) -> ExitCode {
+    let open = |room: &str| Storage::open_room(data_dir, room);
+    match rooms::apply_retention(&*storage.state, open, chrono::Utc::now()) {
+        Ok(reports) => {
+            for (room, report) in &reports {
+                for name in &report.archived {
+                    println!("{}: archived {}", room, name);
+                }
+                for name in &report.purged {
+                    println!("{}: purged {}", room, name);
+                }
+                println!("{}: {} uploads deleted", room, report.deleted_uploads);
+                if report.kept_pinned > 0 {
+                    println!("{}: {} pinned messages kept", room, report.kept_pinned);
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("retention failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_rooms(storage: &Storage) -> ExitCode {
+    let rooms = match rooms::list(&*storage.state) {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            eprintln!("rooms failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    for room in &rooms {
+        let members = match room.members.len() {
+            0 => "everyone".to_string(),
+            n => format!("{} members", n),
+        };
+        println!("{:<32} {:<12} {}", room.id, members, room.name);
+    }
+    ExitCode::SUCCESS
+}
+
+fn run_hash_passphrase() -> ExitCode {
+    let mut passphrase = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut passphrase) {
+        eprintln!("Failed to read passphrase: {}", e);
+        return ExitCode::FAILURE;
+    }
+    let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+    if passphrase.is_empty() {
+        return usage_error("The passphrase must not be empty");
+    }
+
+    match auth::hash_passphrase(passphrase) {
+        Ok(hash) => {
+            println!("{}", hash);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("hash-passphrase failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_gen_key() -> ExitCode {
+    match crypt::generate_secret() {
+        Ok(secret) => {
+            println!("{}", secret);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("gen-key failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_encrypt(storage: &Storage, data_dir: &str) -> ExitCode {
+    if !matches!(crypt::configured(), Ok(Some(_))) {
+        return usage_error(&format!("encrypt needs {} to be set", crypt::KEY_VAR));
+    }
+    let rooms = match rooms::list(&*storage.state) {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            eprintln!("encrypt failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut code = ExitCode::SUCCESS;
+    for room in &rooms {
+        let result = Storage::open_room(data_dir, &room.id).map(|storage| {
+            println!("Room {}:", room.id);
+            encrypt_room(&storage)
+        });
+        match result {
+            Ok(ExitCode::SUCCESS) => {}
+            Ok(failure) => code = failure,
+            Err(e) => {
+                eprintln!("Failed to open room {}: {}", room.id, e);
+                code = ExitCode::FAILURE;
+            }
+        }
+    }
+    code
+}
+
+fn encrypt_room(storage: &Storage) -> ExitCode {
+    let report = match storage.messages.migrate() {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("encrypt failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("{} of {} messages sealed", report.sealed, report.total);
+
+    let blobs = match storage.blobs.list() {
+        Ok(blobs) => blobs,
+        Err(e) => {
+            eprintln!("encrypt failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut sealed = 0;
+    let mut failed = 0;
+    for blob in &blobs {
+        match storage.blobs.seal(blob) {
+            Ok(true) => sealed += 1,
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("Failed to seal {}: {}", blob, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} of {} uploads sealed", sealed, blobs.len());
+
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn run_devices(storage: &Storage) -> ExitCode {
+    let registry = match DeviceRegistry::load(&*storage.state) {
+        Ok(registry) => registry,
+        Err(e) => {
+            eprintln!("devices failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    for device in &registry.devices {
+        let state = if device.is_active() {
+            "active"
+        } else {
+            "revoked"
+        };
+        println!(
+            "{:<36} {:<8} {} {}",
+            device.id, state, device.color, device.name
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+fn run_reset_totp(storage: &Storage) -> ExitCode {
+    match totp::reset(&*storage.state) {
+        Ok(()) => {
+            println!("Two-factor login is off; passphrase logins no longer ask for a code");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("reset-totp failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_device_command(storage: &Storage, command: &str, arg: &str) -> ExitCode {
+    let now = chrono::Utc::now();
+    let result = match command {
+        "add-device" => {
+            let changes = DeviceChanges {
+                name: Some(arg.to_string()),
+                ..Default::default()
+            };
+            device::register(&*storage.state, changes, now)
+                .map(|(device, token)| format!("{}\n{}", device.id, token))
+        }
+        _ => device::revoke(&*storage.state, arg, now)
+            .map(|device| format!("revoked {} ({})", device.id, device.name)),
+    };
+    match result {
+        Ok(summary) => {
+            println!("{}", summary);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{} failed: {}", command, e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,599 @@
+//! Optional encryption at rest with XChaCha20-Poly1305.
+//!
+//! Setting `SPORE_ENCRYPTION_KEY` to a base64 secret of at least 32 random
+//! bytes (see `spore-admin gen-key`) turns it on. Separate keys for records
+//! and uploads are derived from the secret with HMAC-SHA256.
+//!
+//! A message record is sealed into `{"sealed": <nonce and ciphertext>, "key":
+//! <key id>}`, still one JSON value per line or key, so the log, its index
+//! and the key-value layout keep working. Each record gets a random nonce.
+//!
+//! An upload is a header of `SBXE`, a version byte and a random 19-byte
+//! nonce prefix, followed by the contents in 64 KiB chunks, each sealed on
+//! its own with the prefix, the chunk number and a last-chunk flag as nonce
+//! and the upload's name as associated data. Chunks can be reordered,
+//! dropped or moved between files only by failing authentication, and any
+//! byte range can be read by opening just the chunks it covers.
+//!
+//! Records and uploads written without a key are still read, so turning
+//! encryption on needs no downtime; `spore-admin encrypt` seals the rest.
+
+use crate::store::BlobStore;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+pub const KEY_VAR: &str = "SPORE_ENCRYPTION_KEY";
+
+pub const SEALED_FIELD: &str = "sealed";
+pub const KEY_FIELD: &str = "key";
+
+const MIN_SECRET_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: u64 = 16;
+const RECORD_AAD: &[u8] = b"spore-box record v1";
+
+const BLOB_MAGIC: &[u8] = b"SBXE\x01";
+const NONCE_PREFIX_LEN: usize = NONCE_LEN - 5;
+const HEADER_LEN: u64 = (BLOB_MAGIC.len() + NONCE_PREFIX_LEN) as u64;
+/// Plaintext bytes per sealed chunk of an upload.
+pub const CHUNK_LEN: u64 = 64 * 1024;
+const SEALED_CHUNK_LEN: u64 = CHUNK_LEN + TAG_LEN;
+
+pub struct Keys {
+    records: XChaCha20Poly1305,
+    blobs: XChaCha20Poly1305,
+    id: String,
+}
+
+impl std::fmt::Debug for Keys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keys").field("id", &self.id).finish()
+    }
+}
+
+fn derive(secret: &[u8], purpose: &str) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(purpose.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+impl Keys {
+    pub fn from_secret(secret: &str) -> Result<Keys, String> {
+        let secret = secret.trim();
+        let bytes = URL_SAFE_NO_PAD
+            .decode(secret)
+            .or_else(|_| STANDARD.decode(secret))
+            .map_err(|_| format!("{} is not valid base64", KEY_VAR))?;
+        if bytes.len() < MIN_SECRET_LEN {
+            return Err(format!(
+                "{} must hold at least {} bytes",
+                KEY_VAR, MIN_SECRET_LEN
+            ));
+        }
+        let cipher = |purpose| XChaCha20Poly1305::new(&derive(&bytes, purpose).into());
+        Ok(Keys {
+            records: cipher("spore-box records"),
+            blobs: cipher("spore-box uploads"),
+            id: URL_SAFE_NO_PAD.encode(&derive(&bytes, "spore-box key id")[..6]),
+        })
+    }
+
+    /// `None` unless `SPORE_ENCRYPTION_KEY` is set.
+    pub fn from_env() -> Result<Option<Keys>, String> {
+        match std::env::var(KEY_VAR) {
+            Ok(secret) if !secret.trim().is_empty() => Keys::from_secret(&secret).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Names the secret in sealed records without giving anything away, so
+    /// a record under another key is told apart from a damaged one.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// The keys from the environment, read once per instance. A malformed key is
+/// an error every time it is asked for, so nothing gets written in the clear
+/// by mistake.
+pub fn configured() -> Result<Option<&'static Keys>, Error> {
+    static KEYS: OnceLock<Result<Option<Keys>, String>> = OnceLock::new();
+    match KEYS.get_or_init(Keys::from_env) {
+        Ok(keys) => Ok(keys.as_ref()),
+        Err(msg) => Err(Error::new(ErrorKind::InvalidInput, msg.clone())),
+    }
+}
+
+/// A fresh secret for `SPORE_ENCRYPTION_KEY`.
+pub fn generate_secret() -> Result<String, Error> {
+    Ok(URL_SAFE_NO_PAD.encode(random_bytes::<32>()?))
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], Error> {
+    let mut bytes = [0u8; N];
+    getrandom::fill(&mut bytes).map_err(|e| Error::other(e.to_string()))?;
+    Ok(bytes)
+}
+
+/// Why a sealed record could not be opened.
+#[derive(Debug)]
+pub enum OpenError {
+    /// No key is configured, or a different one sealed the record.
+    Locked(String),
+    Invalid(String),
+}
+
+pub fn is_sealed(record: &Map<String, Value>) -> bool {
+    record.contains_key(SEALED_FIELD)
+}
+
+/// Seals a serialized record.
+pub fn seal_record(keys: &Keys, plaintext: &[u8]) -> Result<Value, Error> {
+    let nonce = random_bytes::<NONCE_LEN>()?;
+    let ciphertext = keys
+        .records
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: RECORD_AAD,
+            },
+        )
+        .map_err(|_| Error::other("Failed to seal record"))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+
+    let mut record = Map::new();
+    record.insert(
+        SEALED_FIELD.to_string(),
+        Value::String(URL_SAFE_NO_PAD.encode(sealed)),
+    );
+    record.insert(KEY_FIELD.to_string(), Value::String(keys.id.clone()));
+    Ok(Value::Object(record))
+}
+
+/// The serialized record inside a sealed one.
+pub fn open_record(keys: Option<&Keys>, record: &Map<String, Value>) -> Result<Vec<u8>, OpenError> {
+    let key_id = record.get(KEY_FIELD).and_then(Value::as_str).unwrap_or("");
+    let keys = match keys {
+        Some(keys) if keys.id == key_id => keys,
+        Some(_) => {
+            return Err(OpenError::Locked(format!(
+                "Record is sealed with key {:?}, not the configured one",
+                key_id
+            )));
+        }
+        None => {
+            return Err(OpenError::Locked(format!(
+                "Record is sealed and {} is not set",
+                KEY_VAR
+            )));
+        }
+    };
+    let sealed = record
+        .get(SEALED_FIELD)
+        .and_then(Value::as_str)
+        .and_then(|s| URL_SAFE_NO_PAD.decode(s).ok())
+        .filter(|s| s.len() >= NONCE_LEN)
+        .ok_or_else(|| OpenError::Invalid("Malformed sealed record".to_string()))?;
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    keys.records
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: RECORD_AAD,
+            },
+        )
+        .map_err(|_| OpenError::Invalid("Sealed record failed authentication".to_string()))
+}
+
+fn chunk_nonce(prefix: &[u8], index: u64, last: bool) -> Result<XNonce, Error> {
+    let index = u32::try_from(index)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Upload too large to seal"))?;
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Ok(*XNonce::from_slice(&nonce))
+}
+
+/// Number of chunks a plaintext of `len` bytes is sealed into. Even an empty
+/// upload has one, so truncating a file to its header is detected.
+fn chunk_count(len: u64) -> u64 {
+    len.div_ceil(CHUNK_LEN).max(1)
+}
+
+/// Plaintext size of a sealed upload of `sealed_len` bytes.
+fn plaintext_len(sealed_len: u64) -> Result<u64, Error> {
+    let body = sealed_len
+        .checked_sub(HEADER_LEN)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Sealed upload is truncated"))?;
+    let full = body / SEALED_CHUNK_LEN;
+    match body % SEALED_CHUNK_LEN {
+        0 if full > 0 => Ok(full * CHUNK_LEN),
+        rest if rest >= TAG_LEN => Ok(full * CHUNK_LEN + rest - TAG_LEN),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Sealed upload is truncated",
+        )),
+    }
+}
+
+fn seal_blob(cipher: &XChaCha20Poly1305, name: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let prefix = random_bytes::<NONCE_PREFIX_LEN>()?;
+    let count = chunk_count(data.len() as u64);
+    let mut sealed =
+        Vec::with_capacity(HEADER_LEN as usize + data.len() + (count * TAG_LEN) as usize);
+    sealed.extend_from_slice(BLOB_MAGIC);
+    sealed.extend_from_slice(&prefix);
+    for index in 0..count {
+        let start = (index * CHUNK_LEN) as usize;
+        let end = (start + CHUNK_LEN as usize).min(data.len());
+        let nonce = chunk_nonce(&prefix, index, index + 1 == count)?;
+        let chunk = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &data[start..end],
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::other("Failed to seal upload"))?;
+        sealed.extend_from_slice(&chunk);
+    }
+    Ok(sealed)
+}
+
+/// Uploads sealed in chunks on top of another store. Uploads stored before
+/// encryption was turned on are passed through as they are. Without keys
+/// nothing new is sealed, and sealed uploads are refused rather than served
+/// as ciphertext.
+pub struct SealedBlobStore {
+    inner: Box<dyn BlobStore>,
+    keys: Option<&'static Keys>,
+}
+
+impl SealedBlobStore {
+    pub fn new(inner: Box<dyn BlobStore>, keys: Option<&'static Keys>) -> SealedBlobStore {
+        SealedBlobStore { inner, keys }
+    }
+
+    /// The nonce prefix and sealed size, or `None` for a plaintext upload.
+    /// An upload is taken for sealed only if it starts with the magic and
+    /// its size fits the chunk layout, so that a plaintext file which
+    /// happens to start the same way is not refused.
+    fn header(&self, name: &str) -> Result<Option<([u8; NONCE_PREFIX_LEN], u64)>, Error> {
+        let header = self.inner.get_range(name, 0..HEADER_LEN)?;
+        if header.len() as u64 != HEADER_LEN || !header.starts_with(BLOB_MAGIC) {
+            return Ok(None);
+        }
+        let sealed_len = self.inner.size(name)?;
+        if plaintext_len(sealed_len).is_err() {
+            return Ok(None);
+        }
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        prefix.copy_from_slice(&header[BLOB_MAGIC.len()..]);
+        Ok(Some((prefix, sealed_len)))
+    }
+
+    fn open_chunks(
+        &self,
+        name: &str,
+        prefix: &[u8],
+        sealed_len: u64,
+        range: Range<u64>,
+    ) -> Result<Vec<u8>, Error> {
+        let keys = self.keys.ok_or_else(|| {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} is sealed and {} is not set", name, KEY_VAR),
+            )
+        })?;
+        let len = plaintext_len(sealed_len)?;
+        let (start, end) = (range.start.min(len), range.end.min(len));
+        if start >= end && len > 0 {
+            return Ok(Vec::new());
+        }
+        let count = chunk_count(len);
+        let first = start / CHUNK_LEN;
+        let last = (end.max(1) - 1) / CHUNK_LEN;
+        let sealed = self.inner.get_range(
+            name,
+            HEADER_LEN + first * SEALED_CHUNK_LEN..HEADER_LEN + (last + 1) * SEALED_CHUNK_LEN,
+        )?;
+
+        let mut plaintext = Vec::with_capacity((end - start) as usize);
+        for (index, chunk) in (first..=last).zip(sealed.chunks(SEALED_CHUNK_LEN as usize)) {
+            let nonce = chunk_nonce(prefix, index, index + 1 == count)?;
+            let opened = keys
+                .blobs
+                .decrypt(
+                    &nonce,
+                    Payload {
+                        msg: chunk,
+                        aad: name.as_bytes(),
+                    },
+                )
+                .map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Chunk {} of {} failed authentication", index, name),
+                    )
+                })?;
+            let chunk_start = index * CHUNK_LEN;
+            let from = start.saturating_sub(chunk_start) as usize;
+            let to = ((end - chunk_start) as usize).min(opened.len());
+            plaintext.extend_from_slice(&opened[from.min(to)..to]);
+        }
+        Ok(plaintext)
+    }
+}
+
+impl BlobStore for SealedBlobStore {
+    fn put(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        match self.keys {
+            Some(keys) => self.inner.put(name, &seal_blob(&keys.blobs, name, data)?),
+            None => self.inner.put(name, data),
+        }
+    }
+
+    fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+        match self.header(name)? {
+            Some((prefix, sealed_len)) => self.open_chunks(name, &prefix, sealed_len, 0..u64::MAX),
+            None => self.inner.get(name),
+        }
+    }
+
+    fn get_range(&self, name: &str, range: Range<u64>) -> Result<Vec<u8>, Error> {
+        match self.header(name)? {
+            Some((prefix, sealed_len)) => self.open_chunks(name, &prefix, sealed_len, range),
+            None => self.inner.get_range(name, range),
+        }
+    }
+
+    fn delete(&self, name: &str) -> Result<(), Error> {
+        self.inner.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        self.inner.list()
+    }
+
+    fn modified(&self, name: &str) -> Result<Option<SystemTime>, Error> {
+        self.inner.modified(name)
+    }
+
+    fn size(&self, name: &str) -> Result<u64, Error> {
+        match self.header(name)? {
+            Some((_, sealed_len)) => plaintext_len(sealed_len),
+            None => self.inner.size(name),
+        }
+    }
+
+    fn seal(&self, name: &str) -> Result<bool, Error> {
+        if self.keys.is_none() || self.header(name)?.is_some() {
+            return Ok(false);
+        }
+        let data = self.inner.get(name)?;
+        self.put(name, &data)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryBlobStore;
+    use std::rc::Rc;
+
+    /// Lets a test look at what the sealed store wrote underneath it.
+    impl BlobStore for Rc<MemoryBlobStore> {
+        fn put(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+            (**self).put(name, data)
+        }
+
+        fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+            (**self).get(name)
+        }
+
+        fn delete(&self, name: &str) -> Result<(), Error> {
+            (**self).delete(name)
+        }
+
+        fn list(&self) -> Result<Vec<String>, Error> {
+            (**self).list()
+        }
+    }
+
+    fn keys(secret: u8) -> &'static Keys {
+        let secret = URL_SAFE_NO_PAD.encode([secret; MIN_SECRET_LEN]);
+        Box::leak(Box::new(Keys::from_secret(&secret).unwrap()))
+    }
+
+    fn sealed_store(keys: Option<&'static Keys>) -> (Rc<MemoryBlobStore>, SealedBlobStore) {
+        let inner = Rc::new(MemoryBlobStore::default());
+        let store = SealedBlobStore::new(Box::new(inner.clone()), keys);
+        (inner, store)
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let keys = keys(1);
+        let plaintext = br#"{"id":"1","text":"hello"}"#;
+        let Value::Object(record) = seal_record(keys, plaintext).unwrap() else {
+            panic!("sealed record is not an object");
+        };
+        assert!(is_sealed(&record));
+        assert_eq!(record[KEY_FIELD], keys.id());
+        assert_eq!(open_record(Some(keys), &record).unwrap(), plaintext);
+
+        assert!(matches!(
+            open_record(None, &record),
+            Err(OpenError::Locked(_))
+        ));
+        assert!(matches!(
+            open_record(Some(self::keys(2)), &record),
+            Err(OpenError::Locked(_))
+        ));
+    }
+
+    #[test]
+    fn tampered_records_fail() {
+        let keys = keys(1);
+        let Value::Object(mut record) = seal_record(keys, b"{}").unwrap() else {
+            panic!("sealed record is not an object");
+        };
+        let mut sealed = URL_SAFE_NO_PAD
+            .decode(record[SEALED_FIELD].as_str().unwrap())
+            .unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        record.insert(
+            SEALED_FIELD.to_string(),
+            Value::String(URL_SAFE_NO_PAD.encode(sealed)),
+        );
+        assert!(matches!(
+            open_record(Some(keys), &record),
+            Err(OpenError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn plaintext_len_follows_chunks() {
+        let sealed = |len: u64| HEADER_LEN + len + chunk_count(len) * TAG_LEN;
+        for len in [
+            0,
+            1,
+            CHUNK_LEN - 1,
+            CHUNK_LEN,
+            CHUNK_LEN + 1,
+            3 * CHUNK_LEN + 100,
+        ] {
+            assert_eq!(plaintext_len(sealed(len)).unwrap(), len, "len {len}");
+        }
+        assert!(plaintext_len(HEADER_LEN - 1).is_err());
+        assert!(plaintext_len(HEADER_LEN).is_err());
+        assert!(plaintext_len(HEADER_LEN + TAG_LEN - 1).is_err());
+    }
+
+    #[test]
+    fn uploads_round_trip_in_chunks() {
+        let (inner, store) = sealed_store(Some(keys(1)));
+        let data = contents(3 * CHUNK_LEN as usize + 100);
+        store.put("upload.bin", &data).unwrap();
+
+        let raw = inner.get("upload.bin").unwrap();
+        assert!(raw.starts_with(BLOB_MAGIC));
+        assert_eq!(
+            raw.len() as u64,
+            HEADER_LEN + data.len() as u64 + 4 * TAG_LEN
+        );
+        assert_eq!(store.size("upload.bin").unwrap(), data.len() as u64);
+        assert_eq!(store.get("upload.bin").unwrap(), data);
+
+        let empty = Vec::new();
+        store.put("empty.bin", &empty).unwrap();
+        assert_eq!(store.size("empty.bin").unwrap(), 0);
+        assert_eq!(store.get("empty.bin").unwrap(), empty);
+    }
+
+    #[test]
+    fn ranges_cross_chunk_boundaries() {
+        let (_, store) = sealed_store(Some(keys(1)));
+        let data = contents(3 * CHUNK_LEN as usize + 100);
+        store.put("upload.bin", &data).unwrap();
+
+        for range in [
+            CHUNK_LEN - 10..CHUNK_LEN + 10,
+            CHUNK_LEN..CHUNK_LEN + 1,
+            5..2 * CHUNK_LEN + 5,
+            3 * CHUNK_LEN - 1..u64::MAX,
+        ] {
+            let expected = &data[range.start as usize..(range.end as usize).min(data.len())];
+            assert_eq!(
+                store.get_range("upload.bin", range.clone()).unwrap(),
+                expected,
+                "{range:?}"
+            );
+        }
+        assert!(
+            store
+                .get_range("upload.bin", data.len() as u64..u64::MAX)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn uploads_are_bound_to_their_name() {
+        let (inner, store) = sealed_store(Some(keys(1)));
+        store.put("a.bin", &contents(100)).unwrap();
+        inner.put("b.bin", &inner.get("a.bin").unwrap()).unwrap();
+        let err = store.get("b.bin").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sealed_uploads_need_the_key() {
+        let (inner, store) = sealed_store(Some(keys(1)));
+        store.put("a.bin", &contents(100)).unwrap();
+        let locked = SealedBlobStore::new(Box::new(inner.clone()), None);
+        assert_eq!(
+            locked.get("a.bin").unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        let other = SealedBlobStore::new(Box::new(inner), Some(keys(2)));
+        assert_eq!(
+            other.get("a.bin").unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn plaintext_uploads_are_read_and_sealed() {
+        let (inner, store) = sealed_store(Some(keys(1)));
+        let data = contents(CHUNK_LEN as usize + 1);
+        inner.put("old.bin", &data).unwrap();
+        assert_eq!(store.get("old.bin").unwrap(), data);
+
+        assert!(store.seal("old.bin").unwrap());
+        assert!(inner.get("old.bin").unwrap().starts_with(BLOB_MAGIC));
+        assert_eq!(store.get("old.bin").unwrap(), data);
+        assert!(!store.seal("old.bin").unwrap());
+    }
+
+    #[test]
+    fn plaintext_that_looks_sealed_is_read_as_plaintext() {
+        let (inner, store) = sealed_store(None);
+        // The magic and a header, then fewer bytes than a chunk's tag.
+        let mut data = BLOB_MAGIC.to_vec();
+        data.extend_from_slice(&[7; NONCE_PREFIX_LEN + TAG_LEN as usize - 1]);
+        inner.put("magic.bin", &data).unwrap();
+        assert_eq!(store.get("magic.bin").unwrap(), data);
+        assert_eq!(store.size("magic.bin").unwrap(), data.len() as u64);
+        assert_eq!(store.get_range("magic.bin", 0..4).unwrap(), b"SBXE");
+
+        // The bare header is no sealed upload either.
+        inner
+            .put("header.bin", &data[..HEADER_LEN as usize])
+            .unwrap();
+        assert_eq!(
+            store.get("header.bin").unwrap(),
+            &data[..HEADER_LEN as usize]
+        );
+    }
+}
@@ -0,0 +1,444 @@
+//! Registry of the devices that use the box.
+//!
+//! Each device has an id, a display name, a colour, an optional icon and its
+//! own revocable token. Only a hash of the token is stored. Messages remember
+//! the id of the device that sent them, so renaming a device renames its old
+//! messages too.
+
+use crate::auth::{self, AuthConfig, Credential, SESSION_COOKIE};
+use crate::message::Message;
+use crate::state::{self, StateStore};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind};
+use uuid::Uuid;
+
+pub const REGISTRY: &str = "devices";
+pub const TOKEN_PREFIX: &str = "sbd_";
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_ICON_LEN: usize = 32;
+const PALETTE: &[&str] = &[
+    "#ef4444", "#f97316", "#eab308", "#22c55e", "#14b8a6", "#3b82f6", "#8b5cf6", "#ec4899",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub color: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    pub created_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<String>,
+    token_hash: String,
+}
+
+/// A device as shown over the API, without its token hash.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    pub color: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<&'a str>,
+    pub created_at: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<&'a str>,
+}
+
+impl Device {
+    pub fn info(&self) -> DeviceInfo<'_> {
+        DeviceInfo {
+            id: &self.id,
+            name: &self.name,
+            color: &self.color,
+            icon: self.icon.as_deref(),
+            created_at: &self.created_at,
+            revoked_at: self.revoked_at.as_deref(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none()
+    }
+}
+
+/// Fields a client may set when registering or editing a device.
+#[derive(Deserialize, Debug, Default)]
+pub struct DeviceChanges {
+    pub name: Option<String>,
+    pub color: Option<String>,
+    /// An empty string removes the icon.
+    pub icon: Option<String>,
+}
+
+impl DeviceChanges {
+    fn apply(self, device: &mut Device) -> Result<(), Error> {
+        if let Some(name) = self.name {
+            let name = name.trim();
+            if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+                return Err(invalid(format!(
+                    "Device names must be 1 to {} characters",
+                    MAX_NAME_LEN
+                )));
+            }
+            device.name = name.to_string();
+        }
+        if let Some(color) = self.color {
+            let hex = color.strip_prefix('#').unwrap_or_default();
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid(format!("Colour {:?} is not #rrggbb", color)));
+            }
+            device.color = color.to_ascii_lowercase();
+        }
+        if let Some(icon) = self.icon {
+            if icon.chars().count() > MAX_ICON_LEN {
+                return Err(invalid(format!(
+                    "Icons must be at most {} characters",
+                    MAX_ICON_LEN
+                )));
+            }
+            device.icon = (!icon.is_empty()).then_some(icon);
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Device tokens do not expire; browsers cap cookie lifetimes at 400 days.
+pub fn cookie_lifetime() -> chrono::Duration {
+    chrono::Duration::days(400)
+}
+
+pub fn is_device_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DeviceRegistry {
+    pub devices: Vec<Device>,
+}
+
+impl DeviceRegistry {
+    pub fn load(state: &dyn StateStore) -> Result<DeviceRegistry, Error> {
+        state::load(state, REGISTRY)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Device> {
+        self.devices.iter().find(|d| d.id == id)
+    }
+
+    pub fn active(&self, id: &str) -> Option<&Device> {
+        self.get(id).filter(|d| d.is_active())
+    }
+
+    pub fn by_token(&self, token: &str) -> Option<&Device> {
+        let hash = hash_token(token);
+        self.devices
+            .iter()
+            .find(|d| d.is_active() && d.token_hash == hash)
+    }
+
+    /// Shows each message under the current name of the device that sent it.
+    pub fn present(&self, messages: &mut [Message]) {
+        for message in messages {
+            if let Some(device) = message.device_id.as_deref().and_then(|id| self.get(id)) {
+                message.sender = device.name.clone();
+            }
+        }
+    }
+}
+
+/// Adds a device and returns it with its token, which is not stored and
+/// cannot be shown again.
+pub fn register(
+    state: &dyn StateStore,
+    changes: DeviceChanges,
+    now: DateTime<Utc>,
+) -> Result<(Device, String), Error> {
+    let mut secret = [0u8; 32];
+    getrandom::fill(&mut secret).map_err(|e| Error::other(e.to_string()))?;
+    let token = format!("{}{}", TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(secret));
+
+    let id = Uuid::new_v4().to_string();
+    let color = PALETTE[id.as_bytes()[0] as usize % PALETTE.len()].to_string();
+    let mut device = Device {
+        id,
+        name: String::new(),
+        color,
+        icon: None,
+        created_at: now.to_rfc3339(),
+        revoked_at: None,
+        token_hash: hash_token(&token),
+    };
+    if changes.name.is_none() {
+        return Err(invalid("A device needs a name".to_string()));
+    }
+    changes.apply(&mut device)?;
+
+    state::update(state, REGISTRY, |registry: &mut DeviceRegistry| {
+        registry.devices.push(device.clone());
+        Ok(())
+    })?;
+    Ok((device, token))
+}
+
+pub fn edit(state: &dyn StateStore, id: &str, changes: DeviceChanges) -> Result<Device, Error> {
+    state::update(state, REGISTRY, |registry: &mut DeviceRegistry| {
+        let device = registry
+            .devices
+            .iter_mut()
+            .find(|d| d.id == id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No device {}", id)))?;
+        changes.apply(device)?;
+        Ok(device.clone())
+    })
+}
+
+/// Invalidates a device's token and sessions. Its messages keep its name.
+pub fn revoke(state: &dyn StateStore, id: &str, now: DateTime<Utc>) -> Result<Device, Error> {
+    state::update(state, REGISTRY, |registry: &mut DeviceRegistry| {
+        let device = registry
+            .devices
+            .iter_mut()
+            .find(|d| d.id == id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No device {}", id)))?;
+        device.revoked_at.get_or_insert_with(|| now.to_rfc3339());
+        Ok(device.clone())
+    })
+}
+
+/// Who is making a request.
+#[derive(Debug, Default)]
+pub struct Identity {
+    /// `None` for anonymous use (login disabled) and for passphrase logins
+    /// that did not register a device.
+    pub device: Option<Device>,
+    /// Whether the requester proved the passphrase, directly or through a
+    /// login session, rather than only holding a device token. Always true
+    /// with login disabled. Administration needs it.
+    pub owner: bool,
+}
+
+impl Identity {
+    fn device(device: &Device, owner: bool) -> Identity {
+        Identity {
+            device: Some(device.clone()),
+            owner,
+        }
+    }
+
+    fn owner() -> Identity {
+        Identity {
+            device: None,
+            owner: true,
+        }
+    }
+
+    /// The name messages from this requester are stored under, given the
+    /// name the client claimed.
+    pub fn sender(&self, claimed: String) -> String {
+        match &self.device {
+            Some(device) => device.name.clone(),
+            None => claimed,
+        }
+    }
+
+    pub fn device_id(&self) -> Option<String> {
+        self.device.as_ref().map(|d| d.id.clone())
+    }
+
+    /// Whether this requester may edit or revoke device `id`: the owner may
+    /// change any device, a device only itself.
+    pub fn may_manage(&self, id: &str) -> bool {
+        self.owner || self.device.as_ref().is_some_and(|d| d.id == id)
+    }
+}
+
+/// Identifies a request from its `Authorization` and `Cookie` headers, or
+/// returns `None` if it presents credentials that are not valid (or none when
+/// login is required). Device tokens are accepted whether or not a passphrase
+/// is configured. `basic_allowed` is false while a second factor is required,
+/// since HTTP Basic sends the passphrase alone.
+pub fn identify(
+    auth: Option<&AuthConfig>,
+    basic_allowed: bool,
+    registry: &DeviceRegistry,
+    authorization: Option<&str>,
+    cookie: Option<&str>,
+    now: DateTime<Utc>,
+) -> Option<Identity> {
+    if let Some(token) = authorization.and_then(auth::bearer_token)
+        && is_device_token(token)
+    {
+        return registry
+            .by_token(token)
+            .map(|d| Identity::device(d, auth.is_none()));
+    }
+    if let Some(token) = cookie.and_then(|c| auth::cookie_value(c, SESSION_COOKIE))
+        && is_device_token(token)
+        && let Some(device) = registry.by_token(token)
+    {
+        return Some(Identity::device(device, auth.is_none()));
+    }
+
+    let Some(auth) = auth else {
+        return Some(Identity::owner());
+    };
+    match auth.authenticate(authorization, cookie, now)? {
+        Credential::Session {
+            device_id: Some(id),
+        } => registry.active(&id).map(|d| Identity::device(d, true)),
+        Credential::Session { device_id: None } => Some(Identity::owner()),
+        Credential::Passphrase => basic_allowed.then(Identity::owner),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+    use crate::testutil::message;
+
+    fn named(name: &str) -> DeviceChanges {
+        DeviceChanges {
+            name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn identify_at(
+        auth: Option<&AuthConfig>,
+        state: &MemoryStateStore,
+        authorization: Option<&str>,
+        cookie: Option<&str>,
+    ) -> Option<Identity> {
+        let registry = DeviceRegistry::load(state).unwrap();
+        identify(auth, true, &registry, authorization, cookie, Utc::now())
+    }
+
+    #[test]
+    fn stores_only_a_hash_of_the_token() {
+        let state = MemoryStateStore::default();
+        let (device, token) = register(&state, named("Laptop"), Utc::now()).unwrap();
+        assert!(is_device_token(&token));
+        assert_eq!(device.token_hash, hash_token(&token));
+        assert_ne!(hash_token(&token), hash_token(&format!("{}x", token)));
+
+        let stored = String::from_utf8(state.get(REGISTRY).unwrap().unwrap()).unwrap();
+        assert!(!stored.contains(&token));
+        assert!(!stored.contains(token.trim_start_matches(TOKEN_PREFIX)));
+        let registry = DeviceRegistry::load(&state).unwrap();
+        assert_eq!(registry.by_token(&token).unwrap().id, device.id);
+        assert!(registry.by_token("sbd_guess").is_none());
+    }
+
+    #[test]
+    fn identifies_device_tokens_by_bearer_and_cookie() {
+        let state = MemoryStateStore::default();
+        let (device, token) = register(&state, named("Phone"), Utc::now()).unwrap();
+        let auth = AuthConfig::new("pbkdf2-sha256$1$c2FsdA$aGFzaA");
+        let bearer = format!("Bearer {}", token);
+        let cookie = format!("{}={}", SESSION_COOKIE, token);
+
+        for auth in [None, Some(&auth)] {
+            let by_bearer = identify_at(auth, &state, Some(&bearer), None).unwrap();
+            assert_eq!(by_bearer.device_id(), Some(device.id.clone()));
+            let by_cookie = identify_at(auth, &state, None, Some(&cookie)).unwrap();
+            assert_eq!(by_cookie.device_id(), Some(device.id.clone()));
+            // With login disabled anyone is the owner anyway.
+            assert_eq!(by_bearer.owner, auth.is_none());
+        }
+
+        assert!(identify_at(Some(&auth), &state, Some("Bearer sbd_guess"), None).is_none());
+        assert!(identify_at(Some(&auth), &state, None, None).is_none());
+        let anonymous = identify_at(None, &state, None, None).unwrap();
+        assert!(anonymous.device.is_none());
+        assert!(anonymous.owner);
+    }
+
+    #[test]
+    fn sessions_make_the_owner_and_keep_their_device() {
+        let state = MemoryStateStore::default();
+        let (device, _) = register(&state, named("Desktop"), Utc::now()).unwrap();
+        let auth = AuthConfig::new("pbkdf2-sha256$1$c2FsdA$aGFzaA");
+        let session = auth.issue_session(Utc::now(), Some(&device.id)).unwrap();
+        let bearer = format!("Bearer {}", session.token);
+
+        let identity = identify_at(Some(&auth), &state, Some(&bearer), None).unwrap();
+        assert!(identity.owner);
+        assert_eq!(identity.device_id(), Some(device.id.clone()));
+
+        revoke(&state, &device.id, Utc::now()).unwrap();
+        assert!(identify_at(Some(&auth), &state, Some(&bearer), None).is_none());
+    }
+
+    #[test]
+    fn revoked_tokens_are_refused() {
+        let state = MemoryStateStore::default();
+        let (device, token) = register(&state, named("Old phone"), Utc::now()).unwrap();
+        let revoked = revoke(&state, &device.id, Utc::now()).unwrap();
+        assert!(!revoked.is_active());
+
+        // Even with login disabled, rather than falling back to anonymous.
+        let bearer = format!("Bearer {}", token);
+        assert!(identify_at(None, &state, Some(&bearer), None).is_none());
+        let auth = AuthConfig::new("pbkdf2-sha256$1$c2FsdA$aGFzaA");
+        assert!(identify_at(Some(&auth), &state, Some(&bearer), None).is_none());
+        assert!(revoke(&state, "no-such-device", Utc::now()).is_err());
+    }
+
+    #[test]
+    fn renames_show_up_in_old_messages() {
+        let state = MemoryStateStore::default();
+        let (device, _) = register(&state, named("Laptop"), Utc::now()).unwrap();
+        let mut messages = vec![
+            Message {
+                sender: "Laptop".to_string(),
+                device_id: Some(device.id.clone()),
+                ..message("a", "2024-01-01T00:00:00Z")
+            },
+            Message {
+                sender: "Visitor".to_string(),
+                ..message("b", "2024-01-01T00:01:00Z")
+            },
+        ];
+
+        edit(&state, &device.id, named("  Work laptop ")).unwrap();
+        DeviceRegistry::load(&state).unwrap().present(&mut messages);
+        assert_eq!(messages[0].sender, "Work laptop");
+        assert_eq!(messages[1].sender, "Visitor");
+
+        assert!(edit(&state, &device.id, named(" ")).is_err());
+        let recolour = DeviceChanges {
+            color: Some("red".to_string()),
+            ..Default::default()
+        };
+        assert!(edit(&state, &device.id, recolour).is_err());
+    }
+
+    #[test]
+    fn devices_manage_only_themselves() {
+        let state = MemoryStateStore::default();
+        let (phone, _) = register(&state, named("Phone"), Utc::now()).unwrap();
+        let phone = Identity::device(&phone, false);
+        assert!(phone.may_manage(&phone.device_id().unwrap()));
+        assert!(!phone.may_manage("another-device"));
+        assert!(Identity::owner().may_manage("another-device"));
+        assert!(!Identity::default().may_manage("another-device"));
+    }
+}
@@ -0,0 +1,495 @@
+//! Changes to messages, recorded as records of their own in the message log.
+//!
+//! An event is a `Message` of type `event` whose `event` field says what
+//! happened to which message. Because events are appended like messages,
+//! polls deliver them to every device, which applies them to the messages
+//! it already has. Full listings fold them in instead (see `fold`), so
+//! clients see each message with its current state and no events.
+
+use crate::message::Message;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+pub const EVENT_TYPE: &str = "event";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Event {
+    /// Pins or unpins message `target`. Pinned messages outlive retention.
+    Pin { target: String, pinned: bool },
+    /// Adds or takes back the sender's `emoji` reaction to `target`.
+    React {
+        target: String,
+        emoji: String,
+        added: bool,
+    },
+}
+
+/// Longest emoji accepted, in bytes; enough for flags and skin tones.
+pub const MAX_EMOJI_LEN: usize = 32;
+
+impl Event {
+    pub fn target(&self) -> &str {
+        match self {
+            Event::Pin { target, .. } | Event::React { target, .. } => target,
+        }
+    }
+
+    /// The record that stores the event, sent by `device` about `target`.
+    /// It is only delivered to the devices that can see `target`.
+    pub fn record(self, target: &Message, sender: String, device: Option<String>) -> Message {
+        let mut recipients = target.recipients.clone();
+        if let Some(sender) = &target.device_id
+            && !recipients.is_empty()
+            && !recipients.contains(sender)
+        {
+            recipients.push(sender.clone());
+        }
+        Message {
+            id: uuid::Uuid::new_v4().to_string(),
+            content: String::new(),
+            sender,
+            device_id: device,
+            timestamp: Utc::now().to_rfc3339(),
+            msg_type: EVENT_TYPE.to_string(),
+            filename: None,
+            file_size: None,
+            mime_type: None,
+            broken: None,
+            recipients,
+            reply_to: None,
+            event: Some(self),
+        }
+    }
+}
+
+/// A message as listed to clients, with the state its events gave it.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageView {
+    #[serde(flatten)]
+    pub message: Message,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    /// The message replied to, if it is in the same listing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quote: Option<Quote>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+}
+
+/// Everyone who reacted to a message with one emoji.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    /// Names of those who reacted, first first.
+    pub by: Vec<String>,
+    /// Ids of the devices among them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<String>,
+}
+
+/// Whether `emoji` can be a reaction: short, with no spaces or control
+/// characters. Which emoji a client offers is up to it.
+pub fn valid_emoji(emoji: &str) -> bool {
+    !emoji.is_empty()
+        && emoji.len() <= MAX_EMOJI_LEN
+        && !emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Enough of a message to show it quoted above a reply.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Quote {
+    pub id: String,
+    pub sender: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    /// The start of the text, or the name of the upload.
+    pub excerpt: String,
+}
+
+const EXCERPT_CHARS: usize = 140;
+
+impl Quote {
+    pub fn of(message: &Message) -> Quote {
+        let excerpt = match message.msg_type.as_str() {
+            "image" | "file" => message
+                .filename
+                .clone()
+                .unwrap_or_else(|| message.content.clone()),
+            _ => {
+                let text = message.content.trim();
+                match text.char_indices().nth(EXCERPT_CHARS) {
+                    Some((end, _)) => format!("{}…", &text[..end]),
+                    None => text.to_string(),
+                }
+            }
+        };
+        Quote {
+            id: message.id.clone(),
+            sender: message.sender.clone(),
+            msg_type: message.msg_type.clone(),
+            excerpt,
+        }
+    }
+}
+
+/// The messages that are pinned, each with the id of the event that pinned
+/// it last. Events apply in log order.
+pub fn pins(messages: &[Message]) -> BTreeMap<String, String> {
+    let mut pins = BTreeMap::new();
+    for message in messages {
+        if let Some(Event::Pin { target, pinned }) = &message.event {
+            if *pinned {
+                pins.insert(target.clone(), message.id.clone());
+            } else {
+                pins.remove(target);
+            }
+        }
+    }
+    pins
+}
+
+/// Each message's reactions, in the order they were first given. Someone is
+/// their device, or the name they sent under without one, and counts once
+/// per emoji.
+pub fn reactions(messages: &[Message]) -> HashMap<String, Vec<Reaction>> {
+    let mut by_target: HashMap<&str, Vec<(&str, Vec<Reactor>)>> = HashMap::new();
+    for message in messages {
+        let Some(Event::React {
+            target,
+            emoji,
+            added,
+        }) = &message.event
+        else {
+            continue;
+        };
+        let reactor = Reactor {
+            name: &message.sender,
+            device: message.device_id.as_deref(),
+        };
+        let emojis = by_target.entry(target).or_default();
+        let reactors = match emojis.iter().position(|(e, _)| e == emoji) {
+            Some(position) => &mut emojis[position].1,
+            None => {
+                emojis.push((emoji, Vec::new()));
+                &mut emojis.last_mut().expect("just pushed").1
+            }
+        };
+        reactors.retain(|r| !r.same(&reactor));
+        if *added {
+            reactors.push(reactor);
+        }
+    }
+
+    by_target
+        .into_iter()
+        .map(|(target, emojis)| {
+            let reactions = emojis
+                .into_iter()
+                .filter(|(_, reactors)| !reactors.is_empty())
+                .map(|(emoji, reactors)| Reaction {
+                    emoji: emoji.to_string(),
+                    count: reactors.len(),
+                    by: reactors.iter().map(|r| r.name.to_string()).collect(),
+                    devices: reactors
+                        .iter()
+                        .filter_map(|r| r.device.map(str::to_string))
+                        .collect(),
+                })
+                .collect();
+            (target.to_string(), reactions)
+        })
+        .collect()
+}
+
+struct Reactor<'a> {
+    name: &'a str,
+    device: Option<&'a str>,
+}
+
+impl Reactor<'_> {
+    fn same(&self, other: &Reactor) -> bool {
+        match (self.device, other.device) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => self.name == other.name,
+            _ => false,
+        }
+    }
+}
+
+/// Applies the events in `messages` to the messages they are about, quotes
+/// the parents of replies and leaves the events out. The result is in
+/// timestamp order, since pinned messages kept through archiving are
+/// appended to the log again.
+pub fn fold(messages: Vec<Message>) -> Vec<MessageView> {
+    let pins = pins(&messages);
+    let mut reactions = reactions(&messages);
+    let quotes: HashMap<&str, Quote> = messages
+        .iter()
+        .filter(|m| m.event.is_none())
+        .map(|m| (m.id.as_str(), Quote::of(m)))
+        .collect();
+    let mut views: Vec<MessageView> = messages
+        .iter()
+        .filter(|m| m.event.is_none())
+        .map(|message| MessageView {
+            pinned: pins.contains_key(&message.id),
+            quote: message
+                .reply_to
+                .as_deref()
+                .and_then(|parent| quotes.get(parent).cloned()),
+            reactions: reactions.remove(&message.id).unwrap_or_default(),
+            message: message.clone(),
+        })
+        .collect();
+    views.sort_by_cached_key(|view| timestamp_key(&view.message));
+    views
+}
+
+/// Sorts unparseable timestamps last, the way polls treat them as newest.
+fn timestamp_key(message: &Message) -> i64 {
+    DateTime::parse_from_rfc3339(&message.timestamp).map_or(i64::MAX, |t| t.timestamp_millis())
+}
+
+/// Whether `message` is pinned, judging by the log `messages` it is in.
+pub fn is_pinned(messages: &[Message], id: &str) -> bool {
+    pins(messages).contains_key(id)
+}
+
+/// Message `id` and every reply under it, at any depth, in timestamp
+/// order; `None` if `id` is not among `messages`.
+pub fn thread(messages: Vec<Message>, id: &str) -> Option<(MessageView, Vec<MessageView>)> {
+    let mut views = fold(messages);
+    let root = views.iter().position(|v| v.message.id == id)?;
+    let root = views.remove(root);
+
+    let mut members = HashSet::from([id.to_string()]);
+    let mut replies = Vec::new();
+    // Replies come after their parents unless clocks disagree, so repeat
+    // until a pass finds no more.
+    loop {
+        let (found, rest): (Vec<_>, Vec<_>) = views.into_iter().partition(|v| {
+            v.message
+                .reply_to
+                .as_ref()
+                .is_some_and(|parent| members.contains(parent))
+        });
+        if found.is_empty() {
+            break;
+        }
+        members.extend(found.iter().map(|v| v.message.id.clone()));
+        replies.extend(found);
+        views = rest;
+    }
+    replies.sort_by_cached_key(|view| timestamp_key(&view.message));
+    Some((root, replies))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::message;
+
+    fn event(id: &str, sender: &str, device: Option<&str>, event: Event) -> Message {
+        Message {
+            sender: sender.to_string(),
+            device_id: device.map(str::to_string),
+            msg_type: EVENT_TYPE.to_string(),
+            content: String::new(),
+            event: Some(event),
+            ..message(id, "2024-01-01T12:00:00Z")
+        }
+    }
+
+    fn pin(id: &str, target: &str, pinned: bool) -> Message {
+        let pin = Event::Pin {
+            target: target.to_string(),
+            pinned,
+        };
+        event(id, "alice", Some("d-alice"), pin)
+    }
+
+    fn react(sender: &str, device: Option<&str>, emoji: &str, added: bool) -> Message {
+        let react = Event::React {
+            target: "m".to_string(),
+            emoji: emoji.to_string(),
+            added,
+        };
+        event(&uuid::Uuid::new_v4().to_string(), sender, device, react)
+    }
+
+    fn reply(id: &str, parent: &str, timestamp: &str) -> Message {
+        Message {
+            reply_to: Some(parent.to_string()),
+            ..message(id, timestamp)
+        }
+    }
+
+    fn view_ids(views: &[MessageView]) -> Vec<&str> {
+        views.iter().map(|v| v.message.id.as_str()).collect()
+    }
+
+    #[test]
+    fn pins_follow_the_last_event() {
+        let log = vec![
+            message("a", "2024-01-01T00:00:00Z"),
+            message("b", "2024-01-01T00:01:00Z"),
+            pin("p1", "a", true),
+            pin("p2", "b", true),
+            pin("p3", "a", false),
+            pin("p4", "b", true),
+            pin("p5", "c", false),
+        ];
+        let pins = pins(&log);
+        assert_eq!(pins.len(), 1);
+        assert_eq!(pins["b"], "p4");
+        assert!(!is_pinned(&log, "a"));
+        assert!(is_pinned(&log, "b"));
+
+        let mut log = log;
+        log.push(pin("p6", "a", true));
+        assert_eq!(super::pins(&log)["a"], "p6");
+    }
+
+    #[test]
+    fn reactions_add_remove_and_come_back() {
+        let log = vec![
+            react("alice", Some("d1"), "👍", true),
+            react("bob", Some("d2"), "👍", true),
+            react("alice", Some("d1"), "🎉", true),
+            // Adding twice counts once.
+            react("bob", Some("d2"), "👍", true),
+            react("alice", Some("d1"), "👍", false),
+            react("alice", Some("d1"), "🎉", false),
+            // Back again, now after bob.
+            react("alice", Some("d1"), "👍", true),
+        ];
+        let reactions = &reactions(&log)["m"];
+        assert_eq!(
+            reactions,
+            &[Reaction {
+                emoji: "👍".to_string(),
+                count: 2,
+                by: vec!["bob".to_string(), "alice".to_string()],
+                devices: vec!["d2".to_string(), "d1".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn reactors_are_devices_or_names() {
+        let log = vec![
+            // One person with two devices counts twice.
+            react("alice", Some("phone"), "👍", true),
+            react("alice", Some("laptop"), "👍", true),
+            // Without a device, the name is who reacted.
+            react("guest", None, "👍", true),
+            react("guest", None, "👍", true),
+            react("alice", None, "👍", true),
+            react("guest", None, "❤️", true),
+            // A device taking back its reaction leaves the name's alone.
+            react("alice", Some("laptop"), "👍", false),
+        ];
+        let reactions = &reactions(&log)["m"];
+        assert_eq!(reactions.len(), 2);
+        assert_eq!(reactions[0].emoji, "👍");
+        assert_eq!(reactions[0].count, 3);
+        assert_eq!(reactions[0].by, ["alice", "guest", "alice"]);
+        assert_eq!(reactions[0].devices, ["phone"]);
+        assert_eq!(reactions[1].emoji, "❤️");
+        assert_eq!(reactions[1].by, ["guest"]);
+        assert!(reactions[1].devices.is_empty());
+
+        assert!(super::reactions(&[react("x", None, "👍", false)])["m"].is_empty());
+    }
+
+    #[test]
+    fn folds_events_into_the_messages() {
+        let parent = message("m", "2024-01-01T00:00:00Z");
+        let log = vec![
+            parent,
+            reply("r", "m", "2024-01-01T00:02:00Z"),
+            reply("orphan", "gone", "2024-01-01T00:03:00Z"),
+            message("late", "not a time"),
+            // Re-appended when kept through archiving, so out of order.
+            message("early", "2023-12-31T00:00:00Z"),
+            pin("p", "m", true),
+            react("bob", Some("d2"), "👍", true),
+        ];
+        let views = fold(log);
+        assert_eq!(view_ids(&views), ["early", "m", "r", "orphan", "late"]);
+
+        let m = &views[1];
+        assert!(m.pinned);
+        assert_eq!(m.reactions.len(), 1);
+        assert_eq!(m.quote.as_ref().map(|q| q.id.as_str()), None);
+        let r = &views[2];
+        assert!(!r.pinned);
+        assert_eq!(r.quote.as_ref().unwrap().excerpt, "text of m");
+        assert!(views[3].quote.is_none());
+    }
+
+    #[test]
+    fn threads_gather_nested_replies() {
+        let log = vec![
+            message("root", "2024-01-01T00:00:00Z"),
+            reply("a", "root", "2024-01-01T00:01:00Z"),
+            reply("a1", "a", "2024-01-01T00:02:00Z"),
+            // Logged before its parent, with a clock behind the root's.
+            reply("b1", "b", "2023-12-31T23:59:00Z"),
+            reply("b", "root", "2024-01-01T00:03:00Z"),
+            reply("a1x", "a1", "2024-01-01T00:04:00Z"),
+            message("other", "2024-01-01T00:05:00Z"),
+            reply("elsewhere", "other", "2024-01-01T00:06:00Z"),
+            reply("lost", "missing", "2024-01-01T00:07:00Z"),
+        ];
+
+        let (root, replies) = thread(log.clone(), "root").unwrap();
+        assert_eq!(root.message.id, "root");
+        assert_eq!(view_ids(&replies), ["b1", "a", "a1", "b", "a1x"]);
+        assert_eq!(replies[0].quote.as_ref().unwrap().id, "b");
+
+        let (_, under_a) = thread(log.clone(), "a").unwrap();
+        assert_eq!(view_ids(&under_a), ["a1", "a1x"]);
+        let (lost, none) = thread(log.clone(), "lost").unwrap();
+        assert!(lost.quote.is_none());
+        assert!(none.is_empty());
+        assert!(thread(log, "missing").is_none());
+    }
+
+    #[test]
+    fn events_reach_who_can_see_their_target() {
+        let dm = Message {
+            device_id: Some("alice".to_string()),
+            recipients: vec!["bob".to_string()],
+            ..message("dm", "2024-01-01T00:00:00Z")
+        };
+        let pin = Event::Pin {
+            target: "dm".to_string(),
+            pinned: true,
+        };
+        let record = pin.record(&dm, "bob".to_string(), Some("bob".to_string()));
+        assert_eq!(record.recipients, ["bob", "alice"]);
+        assert_eq!(record.event.as_ref().unwrap().target(), "dm");
+
+        let open = message("open", "2024-01-01T00:00:00Z");
+        let react = Event::React {
+            target: "open".to_string(),
+            emoji: "👍".to_string(),
+            added: true,
+        };
+        assert!(
+            react
+                .record(&open, "bob".to_string(), None)
+                .recipients
+                .is_empty()
+        );
+        assert!(valid_emoji("👍🏽") && valid_emoji("🇳🇱"));
+        assert!(!valid_emoji("") && !valid_emoji("a b") && !valid_emoji(&"x".repeat(33)));
+    }
+}
@@ -0,0 +1,407 @@
+//! Consistency check between the message log and the upload store.
+
+use crate::message::Message;
+use crate::mime::get_mime_type;
+use crate::store::Storage;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, SystemTime};
+
+pub const MISSING_FILE: &str = "missing-file";
+
+/// How old an unreferenced upload must be before a repair deletes it. An
+/// upload is written before its message, so a younger one may still be on
+/// its way into the log.
+pub const ORPHAN_GRACE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IssueKind {
+    CorruptRecord,
+    MissingBlob,
+    OrphanedBlob,
+    WrongFileSize,
+    WrongMimeType,
+    InvalidTimestamp,
+    DuplicateId,
+}
+
+impl IssueKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IssueKind::CorruptRecord => "corrupt-record",
+            IssueKind::MissingBlob => "missing-blob",
+            IssueKind::OrphanedBlob => "orphaned-blob",
+            IssueKind::WrongFileSize => "wrong-file-size",
+            IssueKind::WrongMimeType => "wrong-mime-type",
+            IssueKind::InvalidTimestamp => "invalid-timestamp",
+            IssueKind::DuplicateId => "duplicate-id",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Issue {
+    pub kind: IssueKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+    pub detail: String,
+    pub repaired: bool,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FsckReport {
+    pub messages: usize,
+    pub blobs: usize,
+    pub repair: bool,
+    pub issues: Vec<Issue>,
+}
+
+impl FsckReport {
+    /// Issues that are still present after the run.
+    pub fn unresolved(&self) -> usize {
+        self.issues.iter().filter(|i| !i.repaired).count()
+    }
+
+    fn push(
+        &mut self,
+        kind: IssueKind,
+        message_id: Option<&str>,
+        blob: Option<&str>,
+        detail: String,
+    ) {
+        self.issues.push(Issue {
+            kind,
+            message_id: message_id.map(str::to_string),
+            blob: blob.map(str::to_string),
+            detail,
+            repaired: false,
+        });
+    }
+}
+
+fn references_blob(message: &Message) -> bool {
+    matches!(message.msg_type.as_str(), "image" | "file")
+}
+
+/// Reports every inconsistency and, with `repair`, fixes what can be fixed:
+/// corrupt records are quarantined, orphaned uploads older than
+/// `ORPHAN_GRACE` deleted, messages whose
+/// upload is gone marked broken, and sizes and MIME types recomputed.
+/// Duplicate ids and bad timestamps are only reported.
+pub fn fsck(storage: &Storage, repair: bool) -> Result<FsckReport, Error> {
+    let now = SystemTime::now();
+    let mut report = FsckReport {
+        repair,
+        ..Default::default()
+    };
+
+    // List uploads before reading messages, so only an upload caught between
+    // writing its blob and appending its message can look orphaned.
+    let blobs: BTreeSet<String> = storage.blobs.list()?.into_iter().collect();
+    let (messages, corrupt) = storage.messages.scan()?;
+    let archived = storage.messages.load_archived()?;
+    report.messages = messages.len();
+    report.blobs = blobs.len();
+
+    for line in &corrupt {
+        report.push(
+            IssueKind::CorruptRecord,
+            None,
+            None,
+            format!("Record {}: {}", line.line_number, line.error),
+        );
+    }
+    if repair && !corrupt.is_empty() {
+        let recovered = storage.messages.recover()?;
+        let quarantined = recovered.quarantined.len();
+        for issue in report.issues.iter_mut().take(quarantined) {
+            issue.repaired = true;
+        }
+    }
+
+    let mut seen_ids = BTreeSet::new();
+    // Archived segments are not checked, but their uploads are still in use.
+    let mut referenced: BTreeSet<&str> = archived
+        .iter()
+        .filter(|m| references_blob(m))
+        .map(|m| m.content.as_str())
+        .collect();
+    let mut fixed: BTreeMap<String, Message> = BTreeMap::new();
+
+    for message in &messages {
+        let id = Some(message.id.as_str());
+
+        if !seen_ids.insert(message.id.as_str()) {
+            report.push(
+                IssueKind::DuplicateId,
+                id,
+                None,
+                "Another message has the same id".to_string(),
+            );
+        }
+
+        if chrono::DateTime::parse_from_rfc3339(&message.timestamp).is_err() {
+            report.push(
+                IssueKind::InvalidTimestamp,
+                id,
+                None,
+                format!("Unparseable timestamp {:?}", message.timestamp),
+            );
+        }
+
+        if !references_blob(message) {
+            continue;
+        }
+        let blob = message.content.as_str();
+        referenced.insert(blob);
+
+        if !blobs.contains(blob) {
+            if message.broken.is_none() {
+                report.push(
+                    IssueKind::MissingBlob,
+                    id,
+                    Some(blob),
+                    "Upload is missing".to_string(),
+                );
+                let mut marked = message.clone();
+                marked.broken = Some(MISSING_FILE.to_string());
+                fixed.insert(message.id.clone(), marked);
+            }
+            continue;
+        }
+
+        let size = storage.blobs.size(blob)?;
+        if message.file_size != Some(size) {
+            report.push(
+                IssueKind::WrongFileSize,
+                id,
+                Some(blob),
+                format!("Recorded {:?}, actual {}", message.file_size, size),
+            );
+            fixed
+                .entry(message.id.clone())
+                .or_insert_with(|| message.clone())
+                .file_size = Some(size);
+        }
+
+        if let Some(filename) = &message.filename {
+            let mime_type = get_mime_type(filename);
+            if message.mime_type.as_deref() != Some(mime_type) {
+                report.push(
+                    IssueKind::WrongMimeType,
+                    id,
+                    Some(blob),
+                    format!("Recorded {:?}, expected {}", message.mime_type, mime_type),
+                );
+                fixed
+                    .entry(message.id.clone())
+                    .or_insert_with(|| message.clone())
+                    .mime_type = Some(mime_type.to_string());
+            }
+        }
+    }
+
+    for blob in blobs.iter().filter(|b| !referenced.contains(b.as_str())) {
+        report.push(
+            IssueKind::OrphanedBlob,
+            None,
+            Some(blob),
+            "No message refers to this upload".to_string(),
+        );
+    }
+
+    if !repair {
+        return Ok(report);
+    }
+
+    if !fixed.is_empty() {
+        let updated: Vec<Message> = fixed.into_values().collect();
+        storage.messages.update(&updated)?;
+        for issue in &mut report.issues {
+            if matches!(
+                issue.kind,
+                IssueKind::MissingBlob | IssueKind::WrongFileSize | IssueKind::WrongMimeType
+            ) {
+                issue.repaired = true;
+            }
+        }
+    }
+
+    for issue in &mut report.issues {
+        if issue.kind != IssueKind::OrphanedBlob {
+            continue;
+        }
+        let blob = issue.blob.as_deref().unwrap_or_default();
+        // Stores that cannot tell an upload's age never lose one to a repair.
+        let old_enough = match storage.blobs.modified(blob) {
+            Ok(Some(modified)) => now
+                .duration_since(modified)
+                .is_ok_and(|age| age >= ORPHAN_GRACE),
+            Ok(None) => false,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                issue.repaired = true;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if !old_enough {
+            issue.detail = format!("{}; kept until it is an hour old", issue.detail);
+            continue;
+        }
+        match storage.blobs.delete(blob) {
+            Ok(()) => issue.repaired = true,
+            Err(e) if e.kind() == ErrorKind::NotFound => issue.repaired = true,
+            Err(e) => issue.detail = format!("{}; delete failed: {}", issue.detail, e),
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{ScratchDir, message};
+    use std::io::Write;
+
+    fn upload(id: &str, name: &str, filename: &str, size: u64, mime_type: &str) -> Message {
+        Message {
+            content: name.to_string(),
+            msg_type: "file".to_string(),
+            filename: Some(filename.to_string()),
+            file_size: Some(size),
+            mime_type: Some(mime_type.to_string()),
+            ..message(id, "2024-01-01T00:00:00Z")
+        }
+    }
+
+    fn set_age(dir: &ScratchDir, blob: &str, age: Duration) {
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join("uploads").join(blob))
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    /// One of every issue fsck knows about, in an on-disk layout.
+    fn damaged(dir: &ScratchDir) -> Storage {
+        let storage = Storage::open(dir.path()).unwrap();
+        let append = |m: &Message| storage.messages.append(m).unwrap();
+        append(&upload("fine", "fine.txt", "fine.txt", 4, "text/plain"));
+        append(&upload("missing", "gone.txt", "gone.txt", 4, "text/plain"));
+        append(&upload("sized", "sized.txt", "sized.txt", 99, "text/plain"));
+        append(&upload("typed", "typed.png", "typed.png", 4, "text/plain"));
+        append(&message("dup", "2024-01-01T00:01:00Z"));
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("log/2024-01.jsonl"))
+            .unwrap()
+            .write_all(b"{not json\n")
+            .unwrap();
+        append(&message("dup", "2024-01-01T00:02:00Z"));
+
+        for blob in ["fine.txt", "sized.txt", "typed.png", "old.bin", "new.bin"] {
+            storage.blobs.put(blob, b"data").unwrap();
+        }
+        set_age(dir, "old.bin", ORPHAN_GRACE * 2);
+        storage
+    }
+
+    fn kinds(report: &FsckReport) -> Vec<(IssueKind, bool)> {
+        report.issues.iter().map(|i| (i.kind, i.repaired)).collect()
+    }
+
+    #[test]
+    fn reports_without_changing_anything() {
+        let dir = ScratchDir::new("fsck-report");
+        let storage = damaged(&dir);
+
+        let report = fsck(&storage, false).unwrap();
+        assert_eq!(report.messages, 6);
+        assert_eq!(report.blobs, 5);
+        assert_eq!(
+            kinds(&report),
+            [
+                (IssueKind::CorruptRecord, false),
+                (IssueKind::MissingBlob, false),
+                (IssueKind::WrongFileSize, false),
+                (IssueKind::WrongMimeType, false),
+                (IssueKind::DuplicateId, false),
+                (IssueKind::OrphanedBlob, false),
+                (IssueKind::OrphanedBlob, false),
+            ]
+        );
+        assert_eq!(report.unresolved(), 7);
+
+        assert_eq!(fsck(&storage, false).unwrap().issues.len(), 7);
+        assert_eq!(storage.blobs.list().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn repairs_what_it_can_and_spares_young_orphans() {
+        let dir = ScratchDir::new("fsck-repair");
+        let storage = damaged(&dir);
+
+        let report = fsck(&storage, true).unwrap();
+        assert_eq!(
+            kinds(&report),
+            [
+                (IssueKind::CorruptRecord, true),
+                (IssueKind::MissingBlob, true),
+                (IssueKind::WrongFileSize, true),
+                (IssueKind::WrongMimeType, true),
+                (IssueKind::DuplicateId, false),
+                (IssueKind::OrphanedBlob, false),
+                (IssueKind::OrphanedBlob, true),
+            ]
+        );
+        assert_eq!(report.issues[5].blob.as_deref(), Some("new.bin"));
+        assert_eq!(report.issues[6].blob.as_deref(), Some("old.bin"));
+        assert_eq!(
+            storage.blobs.list().unwrap(),
+            ["fine.txt", "new.bin", "sized.txt", "typed.png"]
+        );
+
+        let fixed = |id: &str| storage.messages.get(id).unwrap().unwrap();
+        assert_eq!(fixed("missing").broken.as_deref(), Some(MISSING_FILE));
+        assert_eq!(fixed("sized").file_size, Some(4));
+        assert_eq!(fixed("typed").mime_type.as_deref(), Some("image/png"));
+
+        // Only what needs a person, or time, is left.
+        let again = fsck(&storage, true).unwrap();
+        assert_eq!(
+            kinds(&again),
+            [
+                (IssueKind::DuplicateId, false),
+                (IssueKind::OrphanedBlob, false)
+            ]
+        );
+
+        set_age(&dir, "new.bin", ORPHAN_GRACE * 2);
+        fsck(&storage, true).unwrap();
+        assert!(
+            !storage
+                .blobs
+                .list()
+                .unwrap()
+                .contains(&"new.bin".to_string())
+        );
+    }
+
+    #[test]
+    fn keeps_orphans_whose_age_is_unknown() {
+        let storage = Storage::in_memory();
+        storage.blobs.put("stray.bin", b"data").unwrap();
+
+        let report = fsck(&storage, true).unwrap();
+        assert_eq!(kinds(&report), [(IssueKind::OrphanedBlob, false)]);
+        assert_eq!(storage.blobs.list().unwrap(), ["stray.bin"]);
+    }
+}
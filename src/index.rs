@@ -0,0 +1,566 @@
+//! Sidecar index for a JSONL log, so polls read only the new tail.
+//!
+//! `<log>.idx` holds a short header followed by one fixed-size entry per
+//! record: its sequence number, byte offset and length in the log, the
+//! largest timestamp seen up to and including it, and a hash of its id. That
+//! running maximum never decreases even when concurrent appends land slightly
+//! out of order, so it can be bisected to find where the records newer than a
+//! cut-off begin. The id hashes let a single record be found without parsing
+//! the log.
+//!
+//! The header records how far into the log the index has looked. A torn,
+//! unterminated line at the end is not indexed, but the index still counts as
+//! up to date until a complete line follows it.
+//!
+//! The index is only ever appended to under the log's lock. Rewrites of the log
+//! delete it (see `jsonl::write_atomically`), and a reader that finds it
+//! missing or behind the log rebuilds or extends it.
+
+use crate::jsonl::{self, FileLock};
+use crate::schema;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+pub const INDEX_SUFFIX: &str = "idx";
+
+/// Indexes of an older layout fail this check and are rebuilt.
+const MAGIC: &[u8; 8] = b"SPIDX\0\0\x02";
+/// The magic, then the log offset up to which lines have been indexed.
+const HEADER_LEN: u64 = MAGIC.len() as u64 + 8;
+const ENTRY_LEN: u64 = 40;
+
+/// Timestamp used for records whose own timestamp cannot be parsed. Polls
+/// have always returned such records, so they sort as "newest".
+const UNPARSEABLE: i64 = i64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub seq: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub max_timestamp_ms: i64,
+    pub id_hash: u64,
+}
+
+impl IndexEntry {
+    fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.end()
+    }
+
+    fn to_bytes(self) -> [u8; ENTRY_LEN as usize] {
+        let mut bytes = [0u8; ENTRY_LEN as usize];
+        bytes[0..8].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.len.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.max_timestamp_ms.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.id_hash.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; ENTRY_LEN as usize]) -> IndexEntry {
+        let field = |i: usize| bytes[i..i + 8].try_into().unwrap();
+        IndexEntry {
+            seq: u64::from_le_bytes(field(0)),
+            offset: u64::from_le_bytes(field(8)),
+            len: u64::from_le_bytes(field(16)),
+            max_timestamp_ms: i64::from_le_bytes(field(24)),
+            id_hash: u64::from_le_bytes(field(32)),
+        }
+    }
+}
+
+/// Milliseconds since the epoch for an RFC 3339 timestamp.
+pub fn timestamp_ms(timestamp: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|t| t.timestamp_millis())
+}
+
+/// A short, stable hash of a record id. Different ids may collide, so a
+/// match still has to be checked against the record.
+pub fn id_hash(id: &str) -> u64 {
+    u64::from_le_bytes(Sha256::digest(id.as_bytes())[..8].try_into().unwrap())
+}
+
+/// The timestamp and id hash to index a log line under.
+fn line_keys(line: &[u8]) -> (i64, u64) {
+    let record = serde_json::from_slice::<serde_json::Value>(line)
+        .ok()
+        .and_then(|v| schema::unseal(v).ok());
+    let field = |name: &str| {
+        record
+            .as_ref()
+            .and_then(|r| r.get(name)?.as_str().map(str::to_string))
+    };
+    (
+        field("timestamp")
+            .and_then(|t| timestamp_ms(&t))
+            .unwrap_or(UNPARSEABLE),
+        field("id").map_or(0, |id| id_hash(&id)),
+    )
+}
+
+pub struct LogIndex {
+    log_path: PathBuf,
+    path: PathBuf,
+}
+
+impl LogIndex {
+    pub fn for_log(log_path: &Path) -> LogIndex {
+        LogIndex {
+            log_path: log_path.to_path_buf(),
+            path: jsonl::sidecar_path(log_path, INDEX_SUFFIX),
+        }
+    }
+
+    /// Records a line just written at `range` by the lock holder.
+    pub fn push(
+        &self,
+        lock: &FileLock,
+        range: Range<u64>,
+        timestamp: &str,
+        id: &str,
+    ) -> Result<(), Error> {
+        let Some(mut file) = self.open_valid()? else {
+            // Missing or unusable: the next reader rebuilds it from the log.
+            return Ok(());
+        };
+        let last = last_entry(&mut file)?;
+        if scanned_to(&mut file)? != range.start {
+            // Something else (a torn line, an older writer) got in between.
+            return self.catch_up(lock);
+        }
+
+        let ts = timestamp_ms(timestamp).unwrap_or(UNPARSEABLE);
+        let entry = IndexEntry {
+            seq: last.map_or(0, |e| e.seq + 1),
+            offset: range.start,
+            len: range.end - range.start,
+            max_timestamp_ms: last.map_or(ts, |e| e.max_timestamp_ms.max(ts)),
+            id_hash: id_hash(id),
+        };
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&entry.to_bytes())?;
+        set_scanned_to(&mut file, range.end)?;
+        file.sync_data()
+    }
+
+    pub fn invalidate(&self) -> Result<(), Error> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Byte offset in the log of the first record that may be newer than
+    /// `since_ms`; everything before it is known to be older.
+    pub fn start_offset(&self, since_ms: i64) -> Result<u64, Error> {
+        let mut file = self.open_fresh()?;
+        let count = entry_count(&mut file)?;
+
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if read_entry(&mut file, mid)?.max_timestamp_ms > since_ms {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        if low == count {
+            // Nothing indexed is newer; only lines after the last entry, if
+            // any, still need to be looked at.
+            return Ok(match count {
+                0 => 0,
+                _ => read_entry(&mut file, count - 1)?.end(),
+            });
+        }
+        Ok(read_entry(&mut file, low)?.offset)
+    }
+
+    /// Number of indexed records and the newest timestamp among them.
+    pub fn summary(&self) -> Result<(u64, Option<i64>), Error> {
+        let mut file = self.open_fresh()?;
+        let count = entry_count(&mut file)?;
+        let newest = last_entry(&mut file)?
+            .map(|e| e.max_timestamp_ms)
+            .filter(|ts| *ts != UNPARSEABLE);
+        Ok((count, newest))
+    }
+
+    /// Log ranges of the records whose id may be `id`, newest first.
+    pub fn ranges_of(&self, id: &str) -> Result<Vec<Range<u64>>, Error> {
+        let mut file = self.open_fresh()?;
+        let mut entries = Vec::new();
+        file.seek(SeekFrom::Start(HEADER_LEN))?;
+        file.read_to_end(&mut entries)?;
+        let hash = id_hash(id);
+        Ok(entries
+            .chunks_exact(ENTRY_LEN as usize)
+            .rev()
+            .map(|bytes| IndexEntry::from_bytes(bytes.try_into().unwrap()))
+            .filter(|entry| entry.id_hash == hash)
+            .map(|entry| entry.range())
+            .collect())
+    }
+
+    /// Calls `visit` with each entry from the newest back, until it returns
+    /// `false`.
+    pub fn walk_back(
+        &self,
+        mut visit: impl FnMut(IndexEntry) -> Result<bool, Error>,
+    ) -> Result<(), Error> {
+        let mut file = self.open_fresh()?;
+        for index in (0..entry_count(&mut file)?).rev() {
+            if !visit(read_entry(&mut file, index)?)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn open_valid(&self) -> Result<Option<File>, Error> {
+        let mut file = match OpenOptions::new().read(true).write(true).open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let len = file.metadata()?.len();
+        let mut magic = [0u8; MAGIC.len()];
+        if len < HEADER_LEN
+            || !(len - HEADER_LEN).is_multiple_of(ENTRY_LEN)
+            || file.read_exact(&mut magic).is_err()
+            || &magic != MAGIC
+        {
+            return Ok(None);
+        }
+        Ok(Some(file))
+    }
+
+    /// Opens the index, first bringing it up to date with the log if needed.
+    /// Only an index missing a complete line needs the log's lock.
+    fn open_fresh(&self) -> Result<File, Error> {
+        let log_len = file_len(&self.log_path)?;
+        if let Some(mut file) = self.open_valid()? {
+            let scanned = scanned_to(&mut file)?;
+            if scanned == log_len
+                || (scanned < log_len && !has_complete_line(&self.log_path, scanned)?)
+            {
+                return Ok(file);
+            }
+        }
+
+        let lock = FileLock::acquire(&self.log_path)?;
+        self.catch_up(&lock)?;
+        self.open_valid()?
+            .ok_or_else(|| Error::other("Index vanished after rebuild"))
+    }
+
+    /// Indexes the complete lines the log holds beyond what was indexed
+    /// before, rebuilding from scratch if the index is missing or describes a
+    /// different log.
+    fn catch_up(&self, lock: &FileLock) -> Result<(), Error> {
+        let log_len = file_len(&self.log_path)?;
+        let existing = match self.open_valid()? {
+            Some(mut file) => {
+                let last = last_entry(&mut file)?;
+                let end = scanned_to(&mut file)?;
+                if end <= log_len && ends_line(&self.log_path, end)? {
+                    Some((file, last))
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+
+        let (mut file, mut last) = match existing {
+            Some(found) => found,
+            None => {
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&self.path)?;
+                file.write_all(MAGIC)?;
+                file.write_all(&0u64.to_le_bytes())?;
+                (file, None)
+            }
+        };
+
+        let start = scanned_to(&mut file)?;
+        let mut log = match File::open(&self.log_path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == ErrorKind::NotFound => return file.sync_data(),
+            Err(e) => return Err(e),
+        };
+        log.seek(SeekFrom::Start(start))?;
+
+        let mut offset = start;
+        let mut entries = Vec::new();
+        for line in BufReader::new(log).split(b'\n') {
+            let line = line?;
+            let len = line.len() as u64 + 1;
+            if offset + len > log_len {
+                // Unterminated tail: a torn write, left for recovery.
+                break;
+            }
+            lock.keep_alive()?;
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                let (ts, id_hash) = line_keys(&line);
+                let entry = IndexEntry {
+                    seq: last.map_or(0, |e| e.seq + 1),
+                    offset,
+                    len,
+                    max_timestamp_ms: last.map_or(ts, |e| e.max_timestamp_ms.max(ts)),
+                    id_hash,
+                };
+                entries.extend_from_slice(&entry.to_bytes());
+                last = Some(entry);
+            }
+            offset += len;
+        }
+
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&entries)?;
+        set_scanned_to(&mut file, offset)?;
+        file.sync_data()
+    }
+}
+
+fn file_len(path: &Path) -> Result<u64, Error> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `offset` sits right after a newline (or at the start) of the log.
+fn ends_line(log_path: &Path, offset: u64) -> Result<bool, Error> {
+    if offset == 0 {
+        return Ok(true);
+    }
+    let mut log = File::open(log_path)?;
+    log.seek(SeekFrom::Start(offset - 1))?;
+    let mut byte = [0u8; 1];
+    log.read_exact(&mut byte)?;
+    Ok(byte[0] == b'\n')
+}
+
+/// Whether a newline follows `offset` in the log, i.e. there is a complete
+/// line there that the index does not have yet.
+fn has_complete_line(log_path: &Path, offset: u64) -> Result<bool, Error> {
+    let mut log = match File::open(log_path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    log.seek(SeekFrom::Start(offset))?;
+    let mut line = Vec::new();
+    BufReader::new(log).read_until(b'\n', &mut line)?;
+    Ok(line.last() == Some(&b'\n'))
+}
+
+fn scanned_to(file: &mut File) -> Result<u64, Error> {
+    let mut bytes = [0u8; 8];
+    file.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+    file.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn set_scanned_to(file: &mut File, offset: u64) -> Result<(), Error> {
+    file.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+    file.write_all(&offset.to_le_bytes())
+}
+
+fn entry_count(file: &mut File) -> Result<u64, Error> {
+    Ok((file.metadata()?.len() - HEADER_LEN) / ENTRY_LEN)
+}
+
+fn read_entry(file: &mut File, index: u64) -> Result<IndexEntry, Error> {
+    let mut bytes = [0u8; ENTRY_LEN as usize];
+    file.seek(SeekFrom::Start(HEADER_LEN + index * ENTRY_LEN))?;
+    file.read_exact(&mut bytes)?;
+    Ok(IndexEntry::from_bytes(&bytes))
+}
+
+fn last_entry(file: &mut File) -> Result<Option<IndexEntry>, Error> {
+    match entry_count(file)? {
+        0 => Ok(None),
+        count => read_entry(file, count - 1).map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+    use crate::store::{JsonlMessageStore, MessageStore};
+    use crate::testutil::{ScratchDir, ids, message};
+    use std::time::{Duration, Instant};
+
+    fn ms(timestamp: &str) -> i64 {
+        timestamp_ms(timestamp).unwrap()
+    }
+
+    /// Appends records behind the index's back, as an older release would.
+    fn append_unindexed(log: &Path, records: &[Message]) -> Vec<u64> {
+        records
+            .iter()
+            .map(|record| {
+                let offset = file_len(log).unwrap();
+                jsonl::append(log, record).unwrap();
+                offset
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bisects_the_running_maximum() {
+        let dir = ScratchDir::new("index");
+        let log = dir.join("log.jsonl");
+        // The third record landed late, behind a newer one.
+        let offsets = append_unindexed(
+            &log,
+            &[
+                message("a", "2024-01-01T10:00:00Z"),
+                message("b", "2024-01-01T10:03:00Z"),
+                message("c", "2024-01-01T10:01:00Z"),
+                message("d", "2024-01-01T10:04:00Z"),
+            ],
+        );
+        let index = LogIndex::for_log(&log);
+
+        assert_eq!(index.start_offset(ms("2024-01-01T09:00:00Z")).unwrap(), 0);
+        // From b on the running maximum is 10:03, so c is read with it.
+        assert_eq!(
+            index.start_offset(ms("2024-01-01T10:00:00Z")).unwrap(),
+            offsets[1]
+        );
+        assert_eq!(
+            index.start_offset(ms("2024-01-01T10:02:00Z")).unwrap(),
+            offsets[1]
+        );
+        assert_eq!(
+            index.start_offset(ms("2024-01-01T10:03:00Z")).unwrap(),
+            offsets[3]
+        );
+        assert_eq!(
+            index.start_offset(ms("2024-01-01T10:04:00Z")).unwrap(),
+            file_len(&log).unwrap()
+        );
+        assert_eq!(
+            index.summary().unwrap(),
+            (4, Some(ms("2024-01-01T10:04:00Z")))
+        );
+    }
+
+    #[test]
+    fn catches_up_with_unindexed_appends() {
+        let dir = ScratchDir::new("index");
+        let log = dir.join("log.jsonl");
+        let store = JsonlMessageStore::new(&log);
+        store.append(&message("a", "2024-01-01T10:00:00Z")).unwrap();
+        assert_eq!(ids(&store.since("2024-01-01T09:00:00Z").unwrap()), ["a"]);
+
+        append_unindexed(&log, &[message("b", "2024-01-01T11:00:00Z")]);
+        store.append(&message("c", "2024-01-01T12:00:00Z")).unwrap();
+        assert_eq!(
+            ids(&store.since("2024-01-01T10:00:00Z").unwrap()),
+            ["b", "c"]
+        );
+        assert_eq!(LogIndex::for_log(&log).summary().unwrap().0, 3);
+        assert_eq!(store.get("b").unwrap().unwrap().id, "b");
+    }
+
+    #[test]
+    fn rewrites_invalidate_the_index() {
+        let dir = ScratchDir::new("index");
+        let log = dir.join("log.jsonl");
+        let store = JsonlMessageStore::new(&log);
+        for (id, timestamp) in [("a", "2024-01-01T10:00:00Z"), ("b", "2024-01-01T11:00:00Z")] {
+            store.append(&message(id, timestamp)).unwrap();
+        }
+        let index = LogIndex::for_log(&log);
+        assert_eq!(index.summary().unwrap().0, 2);
+
+        store.remove(&["a"]).unwrap();
+        assert!(!jsonl::sidecar_path(&log, INDEX_SUFFIX).exists());
+        assert_eq!(index.summary().unwrap().0, 1);
+        assert_eq!(index.start_offset(ms("2024-01-01T10:30:00Z")).unwrap(), 0);
+        assert!(store.get("a").unwrap().is_none());
+    }
+
+    #[test]
+    fn a_torn_tail_does_not_keep_the_index_behind() {
+        let dir = ScratchDir::new("index");
+        let log = dir.join("log.jsonl");
+        append_unindexed(&log, &[message("a", "2024-01-01T10:00:00Z")]);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&log)
+            .unwrap()
+            .write_all(b"{\"id\":\"torn")
+            .unwrap();
+        let index = LogIndex::for_log(&log);
+        assert_eq!(index.summary().unwrap().0, 1);
+
+        // Up to date, so reading needs no lock even though one is held.
+        let lock = FileLock::acquire(&log).unwrap();
+        let started = Instant::now();
+        assert_eq!(index.summary().unwrap().0, 1);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(lock);
+
+        // A complete line after the torn one is picked up, torn line and all.
+        append_unindexed(&log, &[message("b", "2024-01-01T11:00:00Z")]);
+        assert_eq!(index.summary().unwrap().0, 3);
+    }
+
+    #[test]
+    fn indexes_of_an_older_layout_are_rebuilt() {
+        let dir = ScratchDir::new("index");
+        let log = dir.join("log.jsonl");
+        append_unindexed(&log, &[message("a", "2024-01-01T10:00:00Z")]);
+        let mut old = b"SPIDX\0\0\x01".to_vec();
+        old.extend_from_slice(&[0u8; 32]);
+        std::fs::write(jsonl::sidecar_path(&log, INDEX_SUFFIX), old).unwrap();
+
+        let store = JsonlMessageStore::new(&log);
+        assert_eq!(store.get("a").unwrap().unwrap().id, "a");
+        assert_eq!(LogIndex::for_log(&log).summary().unwrap().0, 1);
+    }
+
+    #[test]
+    fn latest_stops_at_the_running_maximum() {
+        let dir = ScratchDir::new("index");
+        let log = dir.join("log.jsonl");
+        let store = JsonlMessageStore::new(&log);
+        let mut late = message("late", "2024-01-01T12:00:00Z");
+        late.msg_type = "image".to_string();
+        for message in [
+            message("a", "2024-01-01T10:00:00Z"),
+            late,
+            message("b", "2024-01-01T11:00:00Z"),
+            message("bad", "not a time"),
+        ] {
+            store.append(&message).unwrap();
+        }
+
+        assert_eq!(store.latest(&|_| true).unwrap().unwrap().id, "late");
+        let text = store.latest(&|m| m.msg_type == "text").unwrap().unwrap();
+        assert_eq!(text.id, "b");
+        assert!(store.latest(&|m| m.msg_type == "file").unwrap().is_none());
+        assert!(store.get("missing").unwrap().is_none());
+    }
+}
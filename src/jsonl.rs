@@ -0,0 +1,526 @@
+use crate::index::LogIndex;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// wasmtime serve runs every request in its own instance, so appends from
+// concurrent requests are serialized through a lock file next to the log.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+// A lock older than this was left behind by an instance that got killed.
+const LOCK_STALE_AFTER: Duration = Duration::from_secs(30);
+const LOCK_REFRESH_EVERY: Duration = Duration::from_secs(5);
+
+pub struct FileLock {
+    path: PathBuf,
+    /// Written into the lock file, so a holder can tell its lock from one
+    /// that replaced it after it was broken.
+    token: String,
+    refreshed: Cell<SystemTime>,
+}
+
+impl FileLock {
+    pub fn acquire(target: &Path) -> std::io::Result<FileLock> {
+        let path = sidecar_path(target, "lock");
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let started = SystemTime::now();
+
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let _ = writeln!(file, "{} {}", token, chrono::Utc::now().to_rfc3339());
+                    return Ok(FileLock {
+                        path,
+                        token,
+                        refreshed: Cell::new(SystemTime::now()),
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    if break_if_stale(&path)? {
+                        continue;
+                    }
+                    if started.elapsed().unwrap_or_default() > LOCK_TIMEOUT {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            format!("Timed out waiting for {}", path.display()),
+                        ));
+                    }
+                    std::thread::sleep(LOCK_RETRY_INTERVAL);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn is_held(&self) -> bool {
+        std::fs::read_to_string(&self.path).is_ok_and(|content| content.starts_with(&self.token))
+    }
+
+    /// Touches the lock so waiters do not take it for stale. Long rewrites
+    /// call this as they go; it only writes every few seconds, and fails if
+    /// the lock was broken in the meantime.
+    pub fn keep_alive(&self) -> std::io::Result<()> {
+        if self.refreshed.get().elapsed().unwrap_or_default() < LOCK_REFRESH_EVERY {
+            return Ok(());
+        }
+        if !self.is_held() {
+            return Err(std::io::Error::other(format!(
+                "Lost lock {}",
+                self.path.display()
+            )));
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        writeln!(file, "{} {}", self.token, chrono::Utc::now().to_rfc3339())?;
+        self.refreshed.set(SystemTime::now());
+        Ok(())
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if self.is_held() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn lock_is_stale(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age > LOCK_STALE_AFTER)
+}
+
+/// Removes the lock at `path` if it is stale. Returns whether the caller
+/// should try to take the lock again.
+///
+/// Several waiters may see the same stale lock, and by the time one of them
+/// acts another may have broken it and taken a fresh one. So the lock is
+/// first renamed to a name only this waiter uses, and its age checked again
+/// there; a fresh lock caught that way is put back.
+fn break_if_stale(path: &Path) -> std::io::Result<bool> {
+    if !lock_is_stale(path) {
+        return Ok(false);
+    }
+    let aside = sidecar_path(path, &format!("broken-{}", uuid::Uuid::new_v4().simple()));
+    match std::fs::rename(path, &aside) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    }
+    if lock_is_stale(&aside) {
+        eprintln!("Breaking stale lock {}", path.display());
+        std::fs::remove_file(&aside)?;
+        return Ok(true);
+    }
+
+    // Linking fails rather than replace a lock taken since the rename.
+    if let Err(e) = std::fs::hard_link(&aside, path) {
+        eprintln!("Failed to put back lock {}: {}", path.display(), e);
+    }
+    std::fs::remove_file(&aside)?;
+    Ok(false)
+}
+
+/// `<target>.<suffix>` in the same directory, e.g. `messages.jsonl.lock`.
+pub fn sidecar_path(target: &Path, suffix: &str) -> PathBuf {
+    let mut name = target.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    target.with_file_name(name)
+}
+
+/// A line that could not be parsed, kept verbatim so it can be quarantined.
+#[derive(Serialize, Debug, Clone)]
+pub struct CorruptLine {
+    pub line_number: usize,
+    pub content: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct RecoveryReport {
+    pub kept: usize,
+    pub quarantined: Vec<CorruptLine>,
+    pub quarantine_file: Option<String>,
+}
+
+/// Appends one record as a single line and flushes it to stable storage.
+pub fn append<T: Serialize>(path: &Path, record: &T) -> std::io::Result<()> {
+    let lock = FileLock::acquire(path)?;
+    append_locked(&lock, path, record).map(|_| ())
+}
+
+/// Like `append`, for callers that already hold the log's lock. Returns the
+/// byte range the new line occupies.
+pub fn append_locked<T: Serialize>(
+    _lock: &FileLock,
+    path: &Path,
+    record: &T,
+) -> std::io::Result<Range<u64>> {
+    let json = serde_json::to_string(record)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+
+    // A previous writer may have been killed halfway through a line. Terminate
+    // the torn fragment so it stays a separate (corrupt) line instead of
+    // swallowing the record we are about to write.
+    let mut start = file.seek(SeekFrom::End(0))?;
+    if ends_without_newline(&mut file)? {
+        file.write_all(b"\n")?;
+        start += 1;
+    }
+
+    let line = format!("{}\n", json);
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+    Ok(start..start + line.len() as u64)
+}
+
+fn ends_without_newline(file: &mut File) -> std::io::Result<bool> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len == 0 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(len - 1))?;
+    let mut last = [0u8; 1];
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// Reads every record, returning the lines that failed to parse alongside.
+pub fn read<T: DeserializeOwned>(path: &Path) -> std::io::Result<(Vec<T>, Vec<CorruptLine>)> {
+    read_from(path, 0)
+}
+
+/// Reads the records that start at or after byte `offset`, which must be the
+/// start of a line. Line numbers in the result count from `offset`.
+pub fn read_from<T: DeserializeOwned>(
+    path: &Path,
+    offset: u64,
+) -> std::io::Result<(Vec<T>, Vec<CorruptLine>)> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        Err(e) => return Err(e),
+    };
+
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut records = Vec::new();
+    let mut corrupt = Vec::new();
+
+    for (index, line) in BufReader::new(file).split(b'\n').enumerate() {
+        let line = line?;
+        let text = String::from_utf8_lossy(&line);
+        if text.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<T>(&text) {
+            Ok(record) => records.push(record),
+            Err(e) => corrupt.push(CorruptLine {
+                line_number: index + 1,
+                content: text.into_owned(),
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    Ok((records, corrupt))
+}
+
+/// Moves unparseable lines into `<file>.quarantine` and atomically rewrites
+/// the log with only the good lines.
+pub fn recover<T: DeserializeOwned>(path: &Path) -> std::io::Result<RecoveryReport> {
+    let lock = FileLock::acquire(path)?;
+
+    // Re-read under the lock; another instance may have recovered already.
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(RecoveryReport::default());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut good = Vec::new();
+    let mut report = RecoveryReport::default();
+
+    for (index, line) in content.split(|b| *b == b'\n').enumerate() {
+        lock.keep_alive()?;
+        let text = String::from_utf8_lossy(line);
+        if text.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<T>(&text) {
+            Ok(_) => {
+                good.extend_from_slice(line);
+                good.push(b'\n');
+                report.kept += 1;
+            }
+            Err(e) => report.quarantined.push(CorruptLine {
+                line_number: index + 1,
+                content: text.into_owned(),
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    if report.quarantined.is_empty() {
+        return Ok(report);
+    }
+
+    let quarantine_path = sidecar_path(path, "quarantine");
+    let mut quarantine = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&quarantine_path)?;
+    for corrupt in &report.quarantined {
+        let entry = serde_json::json!({
+            "quarantinedAt": chrono::Utc::now().to_rfc3339(),
+            "lineNumber": corrupt.line_number,
+            "error": corrupt.error,
+            "content": corrupt.content,
+        });
+        writeln!(quarantine, "{}", entry)?;
+    }
+    quarantine.sync_data()?;
+
+    write_atomically(path, &good)?;
+    report.quarantine_file = Some(quarantine_path.display().to_string());
+    Ok(report)
+}
+
+/// Replaces `path` with `data` via a synced temporary file and a rename,
+/// then syncs the directory so the rename itself survives a crash. Callers
+/// must hold the lock for `path`.
+pub fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp_path = sidecar_path(path, "tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(data)?;
+    tmp.sync_all()?;
+    drop(tmp);
+
+    // The offsets in an index no longer match once the content changes.
+    LogIndex::for_log(path).invalidate()?;
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent(path)
+}
+
+#[cfg(not(windows))]
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Windows cannot open a directory as a file; NTFS journals renames anyway.
+#[cfg(windows)]
+fn sync_parent(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ScratchDir;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Record {
+        n: u32,
+    }
+
+    fn records(path: &Path) -> (Vec<u32>, Vec<CorruptLine>) {
+        let (records, corrupt) = read::<Record>(path).unwrap();
+        (records.into_iter().map(|r| r.n).collect(), corrupt)
+    }
+
+    fn age(path: &Path, by: Duration) {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - by).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_quarantined() {
+        let dir = ScratchDir::new("jsonl");
+        let log = dir.join("log.jsonl");
+        std::fs::write(&log, "{\"n\":1}\n{\"n\":2}\n{\"n\":").unwrap();
+
+        let (good, corrupt) = records(&log);
+        assert_eq!(good, [1, 2]);
+        assert_eq!(corrupt.len(), 1);
+        assert_eq!(corrupt[0].line_number, 3);
+
+        let report = recover::<Record>(&log).unwrap();
+        assert_eq!(report.kept, 2);
+        assert_eq!(report.quarantined.len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "{\"n\":1}\n{\"n\":2}\n"
+        );
+        assert!(!sidecar_path(&log, "tmp").exists());
+
+        let quarantine = std::fs::read_to_string(sidecar_path(&log, "quarantine")).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(quarantine.trim()).unwrap();
+        assert_eq!(entry["content"], "{\"n\":");
+        assert_eq!(entry["lineNumber"], 3);
+
+        // Nothing left to do the second time.
+        assert!(recover::<Record>(&log).unwrap().quarantined.is_empty());
+    }
+
+    #[test]
+    fn appends_start_a_new_line_after_a_torn_one() {
+        let dir = ScratchDir::new("jsonl");
+        let log = dir.join("log.jsonl");
+        std::fs::write(&log, "{\"n\":1}\n{\"n\"").unwrap();
+        append(&log, &Record { n: 2 }).unwrap();
+
+        let (good, corrupt) = records(&log);
+        assert_eq!(good, [1, 2]);
+        assert_eq!(corrupt[0].content, "{\"n\"");
+    }
+
+    #[test]
+    fn read_from_reports_corrupt_lines() {
+        let dir = ScratchDir::new("jsonl");
+        let log = dir.join("log.jsonl");
+        std::fs::write(&log, "{\"n\":1}\n{\"n\":2}\nnot json\n\n{\"n\":3}\n").unwrap();
+
+        let offset = "{\"n\":1}\n".len() as u64;
+        let (records, corrupt) = read_from::<Record>(&log, offset).unwrap();
+        assert_eq!(records, [Record { n: 2 }, Record { n: 3 }]);
+        assert_eq!(corrupt.len(), 1);
+        assert_eq!(corrupt[0].line_number, 2);
+        assert_eq!(corrupt[0].content, "not json");
+
+        let (records, corrupt) = read_from::<Record>(&dir.join("missing.jsonl"), 0).unwrap();
+        assert!(records.is_empty() && corrupt.is_empty());
+    }
+
+    #[test]
+    fn concurrent_appends_are_serialized() {
+        let dir = ScratchDir::new("jsonl");
+        let log = dir.join("log.jsonl");
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let log = log.clone();
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        append(
+                            &log,
+                            &Record {
+                                n: writer * 100 + i,
+                            },
+                        )
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let (mut good, corrupt) = records(&log);
+        assert!(corrupt.is_empty());
+        good.sort();
+        let expected: Vec<u32> = (0..4)
+            .flat_map(|w| (0..25).map(move |i| w * 100 + i))
+            .collect();
+        assert_eq!(good, expected);
+        assert!(!sidecar_path(&log, "lock").exists());
+    }
+
+    #[test]
+    fn appends_wait_for_the_lock() {
+        let dir = ScratchDir::new("jsonl");
+        let log = dir.join("log.jsonl");
+        let lock = FileLock::acquire(&log).unwrap();
+        let writer = {
+            let log = log.clone();
+            std::thread::spawn(move || append(&log, &Record { n: 1 }).unwrap())
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(records(&log).0.is_empty());
+
+        drop(lock);
+        writer.join().unwrap();
+        assert_eq!(records(&log).0, [1]);
+    }
+
+    #[test]
+    fn stale_locks_are_broken() {
+        let dir = ScratchDir::new("jsonl");
+        let log = dir.join("log.jsonl");
+        let lock_path = sidecar_path(&log, "lock");
+        std::fs::write(&lock_path, "killed 2020-01-01T00:00:00Z\n").unwrap();
+        age(&lock_path, LOCK_STALE_AFTER * 2);
+
+        let lock = FileLock::acquire(&log).unwrap();
+        assert!(lock.is_held());
+        drop(lock);
+        assert!(!lock_path.exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn fresh_locks_are_left_alone() {
+        let dir = ScratchDir::new("jsonl");
+        let lock_path = sidecar_path(&dir.join("log.jsonl"), "lock");
+        std::fs::write(&lock_path, "holder\n").unwrap();
+        assert!(!break_if_stale(&lock_path).unwrap());
+        assert_eq!(std::fs::read_to_string(&lock_path).unwrap(), "holder\n");
+    }
+
+    #[test]
+    fn a_broken_lock_is_not_kept_alive_or_removed() {
+        let dir = ScratchDir::new("jsonl");
+        let log = dir.join("log.jsonl");
+        let lock = FileLock::acquire(&log).unwrap();
+        lock.keep_alive().unwrap();
+
+        // Another instance broke the lock and took it.
+        let lock_path = sidecar_path(&log, "lock");
+        std::fs::write(&lock_path, "other\n").unwrap();
+        lock.refreshed.set(SystemTime::UNIX_EPOCH);
+        assert!(lock.keep_alive().is_err());
+        drop(lock);
+        assert_eq!(std::fs::read_to_string(&lock_path).unwrap(), "other\n");
+    }
+
+    #[test]
+    fn keep_alive_refreshes_the_lock() {
+        let dir = ScratchDir::new("jsonl");
+        let log = dir.join("log.jsonl");
+        let lock = FileLock::acquire(&log).unwrap();
+        let lock_path = sidecar_path(&log, "lock");
+        age(&lock_path, LOCK_STALE_AFTER * 2);
+        assert!(lock_is_stale(&lock_path));
+
+        lock.refreshed.set(SystemTime::UNIX_EPOCH);
+        lock.keep_alive().unwrap();
+        assert!(!lock_is_stale(&lock_path));
+        assert!(lock.is_held());
+    }
+}
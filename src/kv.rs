@@ -0,0 +1,767 @@
+//! Message and upload storage on key-value buckets and blob containers, as
+//! offered by `wasi:keyvalue` and `wasi:blobstore` on hosts without
+//! filesystem preopens.
+//!
+//! Messages are stored under `message/{seq}` where `seq` is a zero-padded
+//! number taken from an atomic counter, so sorting the keys gives append
+//! order. Rooms other than the default one put the same keys under
+//! `room/{id}/`. Uploads map one-to-one onto objects in a container.
+
+use crate::jsonl::{self, CorruptLine, FileLock, RecoveryReport};
+use crate::message::Message;
+use crate::rooms::DEFAULT_ROOM;
+use crate::schema::{self, MigrationReport, SchemaError, StoredMessage};
+use crate::store::MessageStore;
+use crate::url::{percent_decode, percent_encode};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+const MESSAGE_PREFIX: &str = "message/";
+const NEXT_SEQ_KEY: &str = "meta/next-seq";
+const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// The subset of `wasi:keyvalue/store` and `wasi:keyvalue/atomics` we use.
+pub trait Bucket {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    fn set(&self, key: &str, value: &[u8]) -> Result<(), Error>;
+
+    fn delete(&self, key: &str) -> Result<(), Error>;
+
+    /// One page of keys, in no particular order, and the cursor for the next.
+    fn list_keys(&self, cursor: Option<u64>) -> Result<(Vec<String>, Option<u64>), Error>;
+
+    /// Atomically adds `delta` to the counter at `key` and returns the result.
+    fn increment(&self, key: &str, delta: u64) -> Result<u64, Error>;
+}
+
+/// A stored value with its key and the result of upgrading it.
+type RawRecord = (String, Vec<u8>, Result<Message, SchemaError>);
+
+pub struct KvMessageStore<B: Bucket> {
+    bucket: B,
+    /// Empty for the default room, `room/{id}/` for the others.
+    prefix: String,
+}
+
+impl<B: Bucket> KvMessageStore<B> {
+    pub fn new(bucket: B) -> KvMessageStore<B> {
+        KvMessageStore {
+            bucket,
+            prefix: String::new(),
+        }
+    }
+
+    pub fn for_room(bucket: B, room: &str) -> KvMessageStore<B> {
+        let prefix = match room {
+            DEFAULT_ROOM => String::new(),
+            _ => format!("room/{}/", room),
+        };
+        KvMessageStore { bucket, prefix }
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    /// All message keys in append order.
+    fn message_keys(&self) -> Result<Vec<String>, Error> {
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) = self.bucket.list_keys(cursor)?;
+            let prefix = self.key(MESSAGE_PREFIX);
+            keys.extend(page.into_iter().filter(|k| k.starts_with(&prefix)));
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Every message record with its key and parse result, in append order.
+    fn read_all(&self) -> Result<Vec<RawRecord>, Error> {
+        let mut records = Vec::new();
+        for key in self.message_keys()? {
+            if let Some(value) = self.bucket.get(&key)? {
+                let parsed = schema::upgrade_bytes(&value).map(|(message, _)| message);
+                records.push((key, value, parsed));
+            }
+        }
+        Ok(records)
+    }
+
+    fn read_message(&self, key: &str) -> Result<Option<Message>, Error> {
+        let Some(value) = self.bucket.get(key)? else {
+            return Ok(None);
+        };
+        match serde_json::from_slice::<StoredMessage>(&value) {
+            Ok(stored) => Ok(Some(stored.0)),
+            Err(e) => {
+                eprintln!("Skipping unreadable record {}: {}", key, e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<B: Bucket> MessageStore for KvMessageStore<B> {
+    fn load(&self) -> Result<Vec<Message>, Error> {
+        let mut messages = Vec::new();
+        for key in self.message_keys()? {
+            messages.extend(self.read_message(&key)?);
+        }
+        Ok(messages)
+    }
+
+    fn append(&self, message: &Message) -> Result<(), Error> {
+        let seq = self.bucket.increment(&self.key(NEXT_SEQ_KEY), 1)?;
+        let value = serde_json::to_vec(&schema::seal(message)?)?;
+        self.bucket
+            .set(&format!("{}{:020}", self.key(MESSAGE_PREFIX), seq), &value)
+    }
+
+    fn migrate(&self) -> Result<MigrationReport, Error> {
+        let mut report = MigrationReport::new();
+        for key in self.message_keys()? {
+            let Some(value) = self.bucket.get(&key)? else {
+                continue;
+            };
+            let (message, original) = schema::upgrade_bytes(&value)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", key, e)))?;
+            report.record(original);
+            let unsealed = schema::needs_sealing(&value);
+            if unsealed {
+                report.sealed += 1;
+            }
+            if original != schema::CURRENT_VERSION || unsealed {
+                self.bucket
+                    .set(&key, &serde_json::to_vec(&schema::seal(&message)?)?)?;
+            }
+        }
+        Ok(report)
+    }
+
+    fn update(&self, updated: &[Message]) -> Result<usize, Error> {
+        let mut replaced = 0;
+        for (key, _, parsed) in self.read_all()? {
+            let Ok(current) = parsed else {
+                continue;
+            };
+            if let Some(new) = updated.iter().find(|m| m.id == current.id) {
+                self.bucket
+                    .set(&key, &serde_json::to_vec(&schema::seal(new)?)?)?;
+                replaced += 1;
+            }
+        }
+        Ok(replaced)
+    }
+
+    fn remove(&self, ids: &[&str]) -> Result<usize, Error> {
+        let mut removed = 0;
+        for (key, _, parsed) in self.read_all()? {
+            if parsed.is_ok_and(|current| ids.contains(&current.id.as_str())) {
+                self.bucket.delete(&key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn scan(&self) -> Result<(Vec<Message>, Vec<CorruptLine>), Error> {
+        let mut messages = Vec::new();
+        let mut corrupt = Vec::new();
+        for (index, (key, value, parsed)) in self.read_all()?.into_iter().enumerate() {
+            match parsed {
+                Ok(message) => messages.push(message),
+                Err(e) => corrupt.push(CorruptLine {
+                    line_number: index + 1,
+                    content: String::from_utf8_lossy(&value).into_owned(),
+                    error: format!("{}: {}", key, e),
+                }),
+            }
+        }
+        Ok((messages, corrupt))
+    }
+
+    /// Moves unreadable records under `quarantine/`, keeping their values.
+    fn recover(&self) -> Result<RecoveryReport, Error> {
+        let records = self.read_all()?;
+        for (key, _, parsed) in &records {
+            if let Err(e) = parsed
+                && e.is_foreign()
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: {}", key, e),
+                ));
+            }
+        }
+
+        let mut report = RecoveryReport::default();
+        for (index, (key, value, parsed)) in records.into_iter().enumerate() {
+            let Err(e) = parsed else {
+                report.kept += 1;
+                continue;
+            };
+            self.bucket
+                .set(&format!("{}{}", QUARANTINE_PREFIX, key), &value)?;
+            self.bucket.delete(&key)?;
+            report.quarantined.push(CorruptLine {
+                line_number: index + 1,
+                content: String::from_utf8_lossy(&value).into_owned(),
+                error: format!("{}: {}", key, e),
+            });
+        }
+        if !report.quarantined.is_empty() {
+            report.quarantine_file = Some(QUARANTINE_PREFIX.to_string());
+        }
+        Ok(report)
+    }
+
+    /// Messages are appended in timestamp order, so the newest match is the
+    /// first one found reading back from the last key.
+    fn latest(&self, keep: &dyn Fn(&Message) -> bool) -> Result<Option<Message>, Error> {
+        for key in self.message_keys()?.iter().rev() {
+            if let Some(message) = self.read_message(key)?
+                && keep(&message)
+            {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    fn since(&self, since: &str) -> Result<Vec<Message>, Error> {
+        let Ok(since_time) = chrono::DateTime::parse_from_rfc3339(since) else {
+            return self.load();
+        };
+        let keys = self.message_keys()?;
+
+        // Messages are appended in timestamp order, so only the values past
+        // the cut-off need to be fetched once it has been bisected.
+        let is_newer = |key: &str| -> Result<bool, Error> {
+            Ok(match self.read_message(key)? {
+                Some(msg) => chrono::DateTime::parse_from_rfc3339(&msg.timestamp)
+                    .map(|t| t > since_time)
+                    .unwrap_or(true),
+                None => false,
+            })
+        };
+        let (mut low, mut high) = (0, keys.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if is_newer(&keys[mid])? {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        let mut messages = Vec::new();
+        for key in &keys[low..] {
+            messages.extend(self.read_message(key)?);
+        }
+        Ok(messages)
+    }
+}
+
+/// In-memory stand-in for a `wasi:keyvalue` host, for tests. It pages
+/// `list_keys` and hands keys back unordered like a real host would.
+#[derive(Default)]
+pub struct MemoryBucket {
+    entries: RefCell<BTreeMap<String, Vec<u8>>>,
+}
+
+const MEMORY_PAGE_SIZE: usize = 64;
+
+impl Bucket for MemoryBucket {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.entries.borrow().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> Result<(), Error> {
+        self.entries
+            .borrow_mut()
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        self.entries.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    fn list_keys(&self, cursor: Option<u64>) -> Result<(Vec<String>, Option<u64>), Error> {
+        let entries = self.entries.borrow();
+        let start = cursor.unwrap_or(0) as usize;
+        let mut page: Vec<String> = entries
+            .keys()
+            .skip(start)
+            .take(MEMORY_PAGE_SIZE)
+            .cloned()
+            .collect();
+        let next = start + page.len();
+        page.reverse();
+        Ok((page, (next < entries.len()).then_some(next as u64)))
+    }
+
+    fn increment(&self, key: &str, delta: u64) -> Result<u64, Error> {
+        let mut entries = self.entries.borrow_mut();
+        let current = match entries.get(key) {
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Counter is not a number"))?,
+            None => 0,
+        };
+        let updated = current + delta;
+        entries.insert(key.to_string(), updated.to_string().into_bytes());
+        Ok(updated)
+    }
+}
+
+/// A bucket kept as one file per key, standing in for a `wasi:keyvalue`
+/// host where only a directory is available. Unlike `MemoryBucket` it
+/// outlives the instance, so the layout can be run under `wasmtime serve`.
+///
+/// File names are the percent-encoded keys with `.` escaped too, which
+/// leaves names with a dot free for the lock and temporary files.
+pub struct DirBucket {
+    dir: PathBuf,
+}
+
+const DIR_PAGE_SIZE: usize = 256;
+
+impl DirBucket {
+    pub fn new(dir: impl Into<PathBuf>) -> DirBucket {
+        DirBucket { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(percent_encode(key).replace('.', "%2E"))
+    }
+
+    fn read(path: &Path) -> Result<Option<Vec<u8>>, Error> {
+        match std::fs::read(path) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Bucket for DirBucket {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        DirBucket::read(&self.path(key))
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> Result<(), Error> {
+        let path = self.path(key);
+        let _lock = FileLock::acquire(&path)?;
+        jsonl::write_atomically(&path, value)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        match std::fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn list_keys(&self, cursor: Option<u64>) -> Result<(Vec<String>, Option<u64>), Error> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok((Vec::new(), None)),
+            Err(e) => return Err(e),
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            if let Some(name) = name.to_str().filter(|n| !n.contains('.')) {
+                keys.push(percent_decode(name));
+            }
+        }
+        keys.sort();
+        let start = cursor.unwrap_or(0) as usize;
+        let page: Vec<String> = keys
+            .iter()
+            .skip(start)
+            .take(DIR_PAGE_SIZE)
+            .cloned()
+            .collect();
+        let next = start + page.len();
+        Ok((page, (next < keys.len()).then_some(next as u64)))
+    }
+
+    fn increment(&self, key: &str, delta: u64) -> Result<u64, Error> {
+        let path = self.path(key);
+        let _lock = FileLock::acquire(&path)?;
+        let current = match DirBucket::read(&path)? {
+            Some(value) => std::str::from_utf8(&value)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Counter is not a number"))?,
+            None => 0,
+        };
+        let updated = current + delta;
+        jsonl::write_atomically(&path, updated.to_string().as_bytes())?;
+        Ok(updated)
+    }
+}
+
+#[cfg(feature = "wasi-storage")]
+pub use wasi::{WasiBucket, WasiContainer};
+
+#[cfg(feature = "wasi-storage")]
+mod wasi {
+    use super::Bucket;
+    use crate::store::{BlobStore, validate_blob_name};
+    use std::io::{Error, ErrorKind};
+    use std::ops::Range;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    wit_bindgen::generate!({
+        path: "wit",
+        world: "storage",
+        generate_all,
+    });
+
+    use self::wasi::blobstore::blobstore;
+    use self::wasi::blobstore::container::Container;
+    use self::wasi::blobstore::types::{IncomingValue, OutgoingValue};
+    use self::wasi::keyvalue::{atomics, store};
+
+    // blocking-write-and-flush accepts at most 4096 bytes per call.
+    const WRITE_CHUNK: usize = 4096;
+    const LIST_BATCH: u64 = 256;
+
+    fn kv_error(e: store::Error) -> Error {
+        match e {
+            store::Error::NoSuchStore => Error::new(ErrorKind::NotFound, "No such key-value store"),
+            store::Error::AccessDenied => {
+                Error::new(ErrorKind::PermissionDenied, "Key-value store access denied")
+            }
+            store::Error::Other(msg) => Error::other(msg),
+        }
+    }
+
+    fn blob_error(e: String) -> Error {
+        Error::other(format!("Blobstore error: {}", e))
+    }
+
+    pub struct WasiBucket(store::Bucket);
+
+    impl WasiBucket {
+        pub fn open(identifier: &str) -> Result<WasiBucket, Error> {
+            store::open(identifier).map(WasiBucket).map_err(kv_error)
+        }
+    }
+
+    impl Bucket for WasiBucket {
+        fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+            self.0.get(key).map_err(kv_error)
+        }
+
+        fn set(&self, key: &str, value: &[u8]) -> Result<(), Error> {
+            self.0.set(key, value).map_err(kv_error)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), Error> {
+            self.0.delete(key).map_err(kv_error)
+        }
+
+        fn list_keys(&self, cursor: Option<u64>) -> Result<(Vec<String>, Option<u64>), Error> {
+            let response = self.0.list_keys(cursor).map_err(kv_error)?;
+            Ok((response.keys, response.cursor))
+        }
+
+        fn increment(&self, key: &str, delta: u64) -> Result<u64, Error> {
+            atomics::increment(&self.0, key, delta).map_err(kv_error)
+        }
+    }
+
+    pub struct WasiContainer(Container);
+
+    impl WasiContainer {
+        /// Opens the named container, creating it on first use.
+        pub fn open(name: &str) -> Result<WasiContainer, Error> {
+            let container = if blobstore::container_exists(name).map_err(blob_error)? {
+                blobstore::get_container(name)
+            } else {
+                blobstore::create_container(name)
+            };
+            container.map(WasiContainer).map_err(blob_error)
+        }
+
+        fn require(&self, name: &str) -> Result<(), Error> {
+            validate_blob_name(name)?;
+            if self.0.has_object(name).map_err(blob_error)? {
+                Ok(())
+            } else {
+                Err(Error::new(ErrorKind::NotFound, format!("No blob {}", name)))
+            }
+        }
+    }
+
+    impl BlobStore for WasiContainer {
+        fn put(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+            validate_blob_name(name)?;
+            let value = OutgoingValue::new_outgoing_value();
+            let body = value
+                .outgoing_value_write_body()
+                .map_err(|_| Error::other("Blob body already taken"))?;
+            self.0.write_data(name, &value).map_err(blob_error)?;
+            for chunk in data.chunks(WRITE_CHUNK) {
+                body.blocking_write_and_flush(chunk)
+                    .map_err(|e| Error::other(format!("Blob write failed: {:?}", e)))?;
+            }
+            drop(body);
+            OutgoingValue::finish(value).map_err(blob_error)
+        }
+
+        fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+            self.require(name)?;
+            let size = self.0.object_info(name).map_err(blob_error)?.size;
+            if size == 0 {
+                return Ok(Vec::new());
+            }
+            // The end offset is inclusive.
+            let value = self.0.get_data(name, 0, size - 1).map_err(blob_error)?;
+            IncomingValue::incoming_value_consume_sync(value).map_err(blob_error)
+        }
+
+        fn size(&self, name: &str) -> Result<u64, Error> {
+            self.require(name)?;
+            Ok(self.0.object_info(name).map_err(blob_error)?.size)
+        }
+
+        fn modified(&self, name: &str) -> Result<Option<SystemTime>, Error> {
+            self.require(name)?;
+            // Objects are written once, so creation is their last change.
+            let created_at = self.0.object_info(name).map_err(blob_error)?.created_at;
+            Ok(Some(UNIX_EPOCH + Duration::from_secs(created_at)))
+        }
+
+        fn get_range(&self, name: &str, range: Range<u64>) -> Result<Vec<u8>, Error> {
+            let end = range.end.min(self.size(name)?);
+            if range.start >= end {
+                return Ok(Vec::new());
+            }
+            let value = self
+                .0
+                .get_data(name, range.start, end - 1)
+                .map_err(blob_error)?;
+            IncomingValue::incoming_value_consume_sync(value).map_err(blob_error)
+        }
+
+        fn delete(&self, name: &str) -> Result<(), Error> {
+            self.require(name)?;
+            self.0.delete_object(name).map_err(blob_error)
+        }
+
+        fn list(&self) -> Result<Vec<String>, Error> {
+            let stream = self.0.list_objects().map_err(blob_error)?;
+            let mut names = Vec::new();
+            loop {
+                let (batch, done) = stream
+                    .read_stream_object_names(LIST_BATCH)
+                    .map_err(blob_error)?;
+                names.extend(batch);
+                if done {
+                    break;
+                }
+            }
+            names.sort();
+            Ok(names)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(n: usize, timestamp: &str) -> Message {
+        Message {
+            id: format!("m{}", n),
+            content: format!("message {}", n),
+            sender: "tester".to_string(),
+            device_id: None,
+            timestamp: timestamp.to_string(),
+            msg_type: "text".to_string(),
+            filename: None,
+            file_size: None,
+            mime_type: None,
+            broken: None,
+            recipients: Vec::new(),
+            reply_to: None,
+            event: None,
+        }
+    }
+
+    fn minute(n: usize) -> String {
+        let start = chrono::DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        (start + chrono::Duration::minutes(n as i64)).to_rfc3339()
+    }
+
+    /// More messages than fit on one `list_keys` page, so listing has to
+    /// follow the cursor and sort what the pages return.
+    fn filled(count: usize) -> KvMessageStore<MemoryBucket> {
+        let store = KvMessageStore::new(MemoryBucket::default());
+        for n in 0..count {
+            store.append(&message(n, &minute(n))).unwrap();
+        }
+        store
+    }
+
+    fn ids(messages: &[Message]) -> Vec<String> {
+        messages.iter().map(|m| m.id.clone()).collect()
+    }
+
+    /// Lets stores for different rooms share one bucket, as they do on a
+    /// real host.
+    impl Bucket for &MemoryBucket {
+        fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+            (*self).get(key)
+        }
+
+        fn set(&self, key: &str, value: &[u8]) -> Result<(), Error> {
+            (*self).set(key, value)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), Error> {
+            (*self).delete(key)
+        }
+
+        fn list_keys(&self, cursor: Option<u64>) -> Result<(Vec<String>, Option<u64>), Error> {
+            (*self).list_keys(cursor)
+        }
+
+        fn increment(&self, key: &str, delta: u64) -> Result<u64, Error> {
+            (*self).increment(key, delta)
+        }
+    }
+
+    #[test]
+    fn listing_is_in_append_order_across_pages() {
+        let count = MEMORY_PAGE_SIZE * 2 + 5;
+        let store = filled(count);
+        let expected: Vec<String> = (0..count).map(|n| format!("m{}", n)).collect();
+        assert_eq!(ids(&store.load().unwrap()), expected);
+    }
+
+    #[test]
+    fn since_bisects_to_the_first_newer_message() {
+        let count = MEMORY_PAGE_SIZE + 10;
+        let store = filled(count);
+        for cut in [0, 1, MEMORY_PAGE_SIZE - 1, MEMORY_PAGE_SIZE, count - 1] {
+            let expected: Vec<String> = (cut + 1..count).map(|n| format!("m{}", n)).collect();
+            assert_eq!(
+                ids(&store.since(&minute(cut)).unwrap()),
+                expected,
+                "cut {}",
+                cut
+            );
+        }
+        assert_eq!(store.since("2023-12-31T23:59:59Z").unwrap().len(), count);
+        assert!(store.since(&minute(count)).unwrap().is_empty());
+        assert_eq!(store.since("not a time").unwrap().len(), count);
+    }
+
+    #[test]
+    fn since_between_timestamps() {
+        let store = filled(10);
+        let between = "2024-01-01T00:04:30Z";
+        assert_eq!(
+            ids(&store.since(between).unwrap()),
+            ["m5", "m6", "m7", "m8", "m9"]
+        );
+    }
+
+    #[test]
+    fn since_on_an_empty_bucket() {
+        let store = KvMessageStore::new(MemoryBucket::default());
+        assert!(store.since(&minute(0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rooms_keep_their_own_messages() {
+        let bucket = MemoryBucket::default();
+        KvMessageStore::new(&bucket)
+            .append(&message(0, &minute(0)))
+            .unwrap();
+        KvMessageStore::for_room(&bucket, "other")
+            .append(&message(1, &minute(1)))
+            .unwrap();
+
+        assert_eq!(ids(&KvMessageStore::new(&bucket).load().unwrap()), ["m0"]);
+        assert_eq!(
+            ids(&KvMessageStore::for_room(&bucket, "other").load().unwrap()),
+            ["m1"]
+        );
+    }
+
+    #[test]
+    fn dir_bucket_keeps_keys_in_files() {
+        let dir = crate::testutil::ScratchDir::new("dir-bucket");
+        let bucket = DirBucket::new(dir.path());
+        assert_eq!(bucket.get("missing").unwrap(), None);
+        bucket.set("msg/1.lock", b"one").unwrap();
+        bucket.set("a b", b"two").unwrap();
+        assert_eq!(bucket.increment("count", 2).unwrap(), 2);
+
+        let reopened = DirBucket::new(dir.path());
+        assert_eq!(reopened.get("msg/1.lock").unwrap().unwrap(), b"one");
+        assert_eq!(reopened.increment("count", 3).unwrap(), 5);
+        assert_eq!(
+            reopened.list_keys(None).unwrap(),
+            (
+                vec![
+                    "a b".to_string(),
+                    "count".to_string(),
+                    "msg/1.lock".to_string()
+                ],
+                None
+            )
+        );
+
+        reopened.delete("a b").unwrap();
+        reopened.delete("a b").unwrap();
+        assert_eq!(reopened.get("a b").unwrap(), None);
+    }
+
+    #[test]
+    fn dir_bucket_pages_its_listing() {
+        let dir = crate::testutil::ScratchDir::new("dir-bucket-pages");
+        let store = KvMessageStore::new(DirBucket::new(dir.path()));
+        for n in 0..DIR_PAGE_SIZE + 10 {
+            store.append(&message(n, &minute(n))).unwrap();
+        }
+        let bucket = DirBucket::new(dir.path());
+        let (first, cursor) = bucket.list_keys(None).unwrap();
+        assert_eq!(first.len(), DIR_PAGE_SIZE);
+        let (rest, end) = bucket.list_keys(cursor).unwrap();
+        assert!(end.is_none());
+        assert_eq!(first.len() + rest.len(), bucket_key_count(&bucket));
+        assert_eq!(store.load().unwrap().len(), DIR_PAGE_SIZE + 10);
+    }
+
+    fn bucket_key_count(bucket: &DirBucket) -> usize {
+        std::fs::read_dir(&bucket.dir)
+            .unwrap()
+            .filter(|e| {
+                !e.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_str()
+                    .unwrap()
+                    .contains('.')
+            })
+            .count()
+    }
+}
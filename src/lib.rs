@@ -0,0 +1,27 @@
+pub mod audit;
+pub mod auth;
+pub mod crypt;
+pub mod device;
+pub mod event;
+pub mod fsck;
+pub mod index;
+pub mod jsonl;
+pub mod kv;
+pub mod message;
+pub mod mime;
+pub mod oidc;
+pub mod pairing;
+pub mod presence;
+pub mod ratelimit;
+pub mod reads;
+pub mod rooms;
+pub mod schema;
+pub mod security;
+pub mod segment;
+pub mod share;
+pub mod state;
+pub mod store;
+#[cfg(test)]
+mod testutil;
+pub mod totp;
+pub mod url;
@@ -1,63 +1,584 @@
 use rust_embed::Embed;
-use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
-use std::io::{BufRead, BufReader, Write};
+use serde::Deserialize;
+use spore_box::audit::{self, AuditLog, RequestInfo};
+use spore_box::auth::{self, AuthConfig};
+use spore_box::crypt;
+use spore_box::device::{self, Device, DeviceChanges, DeviceRegistry, Identity};
+use spore_box::event::{self, Event};
+use spore_box::fsck;
+use spore_box::message::Message;
+use spore_box::mime::{get_mime_type, is_image_file};
+use spore_box::oidc::{self, OidcError};
+use spore_box::pairing;
+use spore_box::presence;
+use spore_box::ratelimit::{self, Decision, RateLimits};
+use spore_box::reads;
+use spore_box::rooms::{self, DEFAULT_ROOM, Room, RoomChanges, Transfer};
+use spore_box::security::{self, RequestOrigin};
+use spore_box::segment;
+use spore_box::share::{self, Access, AccessOutcome, ShareOptions, Visitor};
+use spore_box::store::Storage;
+use spore_box::totp::{self, TotpError};
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::rc::Rc;
 use uuid::Uuid;
-use wstd::http::body::IncomingBody;
-use wstd::http::server::{Finished, Responder};
-use wstd::http::{IntoBody, Request, Response, StatusCode};
-use wstd::io::{copy, empty};
+use wstd::http::body::{BodyForthcoming, IncomingBody, OutgoingBody};
+use wstd::http::server::{self, Finished};
+use wstd::http::{Body, HeaderValue, IntoBody, Request, Response, StatusCode};
+use wstd::io::{AsyncRead, AsyncWrite, copy, empty};
 
 #[derive(Embed)]
 #[folder = "frontend/build"]
 struct Assets;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Message {
-    id: String,
+#[derive(Deserialize)]
+struct SendMessageRequest {
     content: String,
     sender: String,
-    timestamp: String,
     #[serde(rename = "type")]
     msg_type: String,
     filename: Option<String>,
-    #[serde(rename = "fileSize")]
-    file_size: Option<u64>,
-    #[serde(rename = "mimeType")]
-    mime_type: Option<String>,
+    /// Device ids to send the message to; everyone if empty.
+    #[serde(default)]
+    recipients: Vec<String>,
+    /// The message this one answers.
+    #[serde(rename = "replyTo")]
+    reply_to: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct SendMessageRequest {
-    content: String,
+struct LoginRequest {
+    passphrase: String,
+    /// Device to sign in as; a new one is registered if absent or revoked.
+    #[serde(rename = "deviceId")]
+    device_id: Option<String>,
+    #[serde(rename = "deviceName")]
+    device_name: Option<String>,
+    /// Authenticator or recovery code, once two-factor login is enabled.
+    otp: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TotpCodeRequest {
+    code: String,
+}
+
+#[derive(Deserialize, Default)]
+struct PairingRequest {
+    #[serde(rename = "deviceName")]
+    device_name: Option<String>,
+}
+
+/// Body of `POST /api/messages/{id}/pin`, which toggles without one.
+#[derive(Deserialize, Default)]
+struct PinRequest {
+    pinned: Option<bool>,
+    #[serde(default)]
     sender: String,
-    #[serde(rename = "type")]
-    msg_type: String,
-    filename: Option<String>,
+}
+
+/// Body of `POST /api/messages/read`; without a message, everything is.
+#[derive(Deserialize, Default)]
+struct ReadRequest {
+    #[serde(rename = "messageId")]
+    message_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReactRequest {
+    emoji: String,
+    /// False takes the reaction back.
+    #[serde(default = "default_true")]
+    added: bool,
+    #[serde(default)]
+    sender: String,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct TransferRequest {
+    /// The room to copy or move the message to.
+    room: String,
+}
+
+#[derive(Deserialize)]
+struct RedeemRequest {
+    code: String,
+    #[serde(rename = "deviceName")]
+    device_name: Option<String>,
+}
+
+/// wstd's responder with the security headers added to every response, so
+/// no handler can send one without them.
+struct Responder {
+    inner: server::Responder,
+    path: String,
+    audit: Option<PendingAudit>,
+}
+
+/// An audit entry waiting for the response status.
+struct PendingAudit {
+    log: Rc<dyn AuditLog>,
+    action: &'static str,
+    actor: Option<String>,
+    target: Option<String>,
+    request: RequestInfo,
+}
+
+impl Responder {
+    /// Records `device` as the one acting, for actions like logging in that
+    /// only learn it while running.
+    fn audit_actor(&mut self, device: &Device) {
+        if let Some(audit) = &mut self.audit {
+            audit.actor = Some(device.id.clone());
+        }
+    }
+
+    fn audit_target(&mut self, target: &str) {
+        if let Some(audit) = &mut self.audit {
+            audit.target = Some(target.to_string());
+        }
+    }
+
+    fn record(&mut self, status: StatusCode) {
+        let Some(audit) = self.audit.take() else {
+            return;
+        };
+        let outcome = audit::Outcome::from_status(status.as_u16());
+        let mut entry = audit::Entry::new(audit.action, outcome, chrono::Utc::now());
+        entry.actor = audit.actor;
+        entry.target = audit.target;
+        entry.request = Some(audit.request);
+        if let Err(e) = audit.log.append(&entry) {
+            eprintln!("Failed to write audit entry for {}: {}", entry.action, e);
+        }
+    }
+
+    fn secure<B>(&self, response: &mut Response<B>) {
+        let headers = response.headers_mut();
+        for (name, value) in security::headers(&self.path) {
+            headers
+                .entry(name)
+                .or_insert(HeaderValue::from_static(value));
+        }
+    }
+
+    async fn respond<B: Body>(mut self, mut response: Response<B>) -> Finished {
+        self.secure(&mut response);
+        self.record(response.status());
+        self.inner.respond(response).await
+    }
+
+    fn start_response(mut self, mut response: Response<BodyForthcoming>) -> OutgoingBody {
+        self.secure(&mut response);
+        self.record(response.status());
+        self.inner.start_response(response)
+    }
 }
 
 #[wstd::http_server]
-async fn main(request: Request<IncomingBody>, responder: Responder) -> Finished {
+async fn main(request: Request<IncomingBody>, responder: server::Responder) -> Finished {
     let uri = request.uri();
     let path = uri.path();
     let method = request.method().as_str();
+    let mut responder = Responder {
+        inner: responder,
+        path: path.to_string(),
+        audit: None,
+    };
+
+    let origin = RequestOrigin {
+        method,
+        path,
+        host: header_str(&request, "host").or(uri.authority().map(|a| a.as_str())),
+        forwarded_host: header_str(&request, "x-forwarded-host"),
+        origin: header_str(&request, "origin"),
+        sec_fetch_site: header_str(&request, "sec-fetch-site"),
+        cookie: header_str(&request, "cookie"),
+        csrf_token: header_str(&request, security::TOKEN_HEADER),
+    };
+    if let Err(refusal) = security::check(&origin) {
+        eprintln!("Refused {} {}: {}", method, path, refusal);
+        return json_error(
+            StatusCode::FORBIDDEN,
+            refusal.code(),
+            &refusal.to_string(),
+            responder,
+        )
+        .await;
+    }
+    // `/api/rooms/{id}/messages` and the like are the default room's routes
+    // for room `id`.
+    let (room, route) = match rooms::scoped_route(path) {
+        Some((room, route)) => (room.to_string(), route),
+        None => (DEFAULT_ROOM.to_string(), path.to_string()),
+    };
+    let path = route.as_str();
+    let storage = match Storage::open_default_room(&room) {
+        Ok(storage) => storage,
+        Err(e) if e.kind() == ErrorKind::InvalidInput => return no_such_room(responder).await,
+        Err(e) => {
+            eprintln!("Failed to open storage: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Storage unavailable".into_body())
+                .unwrap();
+            return responder.respond(response).await;
+        }
+    };
+
+    let auth = AuthConfig::from_env();
+    let devices = DeviceRegistry::load(&*storage.state).unwrap_or_else(|e| {
+        eprintln!("Failed to load device registry: {}", e);
+        DeviceRegistry::default()
+    });
+    // HTTP Basic would bypass the second factor, so it is refused once one is
+    // enabled.
+    let totp_enabled = auth.as_ref().is_some_and(AuthConfig::passphrase_enabled)
+        && totp::is_enabled(&*storage.state).unwrap_or_else(|e| {
+            eprintln!("Failed to read two-factor state: {}", e);
+            true
+        });
+    let identity = device::identify(
+        auth.as_ref(),
+        !totp_enabled,
+        &devices,
+        header_str(&request, "authorization"),
+        header_str(&request, "cookie"),
+        chrono::Utc::now(),
+    );
+    let authenticated = identity.is_some();
+    if let Some((action, target)) = audit::action_for(method, path) {
+        responder.audit = Some(PendingAudit {
+            log: storage.audit.clone(),
+            action,
+            actor: identity
+                .as_ref()
+                .and_then(|i| i.device.as_ref())
+                .map(|d| d.id.clone()),
+            target,
+            request: RequestInfo {
+                method: method.to_string(),
+                path: request.uri().path().to_string(),
+                client: header_str(&request, "x-forwarded-for").map(str::to_string),
+                user_agent: header_str(&request, "user-agent").map(str::to_string),
+            },
+        });
+    }
+    // Only a credential that worked picks the bucket, so made-up ones fall
+    // back to the client's address. Checked before refusing bad credentials,
+    // so guessing them uses up tokens too.
+    let credential = authenticated
+        .then(|| {
+            header_str(&request, "authorization").or(header_str(&request, "cookie")
+                .and_then(|c| auth::cookie_value(c, auth::SESSION_COOKIE)))
+        })
+        .flatten()
+        .filter(|_| auth.is_some());
+    let client = ratelimit::client_key(
+        identity
+            .as_ref()
+            .and_then(|i| i.device.as_ref())
+            .map(|d| d.id.as_str()),
+        credential,
+        header_str(&request, "x-forwarded-for"),
+    );
+    let (limit_method, limit_path) =
+        if !authenticated && header_str(&request, "authorization").is_some() {
+            ("POST", "/api/login")
+        } else {
+            (method, path)
+        };
+    match RateLimits::from_env().check(
+        &*storage.state,
+        limit_method,
+        limit_path,
+        &client,
+        chrono::Utc::now(),
+    ) {
+        Ok(Decision::Limited { retry_after, .. }) => {
+            return too_many_requests(retry_after, responder).await;
+        }
+        Ok(_) => {}
+        // Limits guard against runaway clients, not attackers; keep serving.
+        Err(e) => eprintln!("Rate limiting unavailable: {}", e),
+    }
+
+    let identity = match identity {
+        Some(identity) => identity,
+        None if auth::is_protected(path) => {
+            let response = Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body("Login required".into_body())
+                .unwrap();
+            return responder.respond(response).await;
+        }
+        None => Identity::default(),
+    };
+
+    if rooms::is_room_route(path) {
+        match rooms::get(&*storage.state, &storage.room) {
+            Ok(Some(room)) if room.admits(identity.device_id().as_deref()) => {}
+            Ok(_) => return no_such_room(responder).await,
+            Err(e) => {
+                eprintln!("Failed to load rooms: {}", e);
+                let response = Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body("Failed to load rooms".into_body())
+                    .unwrap();
+                return responder.respond(response).await;
+            }
+        }
+    }
+
+    // Device tokens are handed to phones and scripts; repairs and other
+    // administration stay with whoever knows the passphrase.
+    if path.starts_with("/api/admin/") && !identity.owner {
+        return owner_required(responder).await;
+    }
 
     match path {
+        "/api/login" => match method {
+            "POST" => api_login(&storage, auth.as_ref(), &devices, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        oidc::LOGIN_PATH => match method {
+            "GET" => auth_oidc_login(&storage, auth.as_ref(), request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        oidc::CALLBACK_PATH => match method {
+            "GET" => {
+                auth_oidc_callback(&storage, auth.as_ref(), &devices, request, responder).await
+            }
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/logout" => match method {
+            "POST" => api_logout(responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/session" => match method {
+            "GET" => {
+                api_session(
+                    auth.as_ref(),
+                    totp_enabled,
+                    authenticated,
+                    &identity,
+                    header_str(&request, "cookie"),
+                    responder,
+                )
+                .await
+            }
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/totp" => match method {
+            "GET" => api_totp_status(&storage, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/totp/enroll" => match method {
+            "POST" => api_totp_enroll(&storage, auth.as_ref(), &identity, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/totp/confirm" => match method {
+            "POST" => api_totp_confirm(&storage, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/totp/disable" => match method {
+            "POST" => api_totp_disable(&storage, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/totp/recovery-codes" => match method {
+            "POST" => api_totp_recovery_codes(&storage, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
         "/api/messages" => match method {
-            "GET" => api_get_messages(request, responder).await,
-            "POST" => api_send_message(request, responder).await,
+            "GET" => api_get_messages(&storage, &identity, &devices, responder).await,
+            "POST" => api_send_message(&storage, &identity, &devices, request, responder).await,
             _ => method_not_allowed(responder).await,
         },
         "/api/messages/poll" => match method {
-            "GET" => api_poll_messages(request, responder).await,
+            "GET" => api_poll_messages(&storage, &identity, &devices, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/messages/pinned" => match method {
+            "GET" => api_pinned_messages(&storage, &identity, &devices, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/messages/unread" => match method {
+            "GET" => api_unread(&storage, &identity, &devices, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/messages/read" => match method {
+            "POST" => api_mark_read(&storage, &identity, &devices, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/messages/inbox" => match method {
+            "GET" => api_inbox(&storage, &identity, &devices, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        _ if path.starts_with("/api/messages/") => {
+            let rest = path.trim_start_matches("/api/messages/");
+            let transfer = match rest.rsplit_once('/') {
+                Some((id, "copy")) => Some((id, Transfer::Copy)),
+                Some((id, "move")) => Some((id, Transfer::Move)),
+                _ => None,
+            };
+            match (method, transfer) {
+                ("POST", Some((id, mode))) => {
+                    let id = id.to_string();
+                    api_transfer_message(&storage, &identity, &id, mode, request, responder).await
+                }
+                (_, Some(_)) => method_not_allowed(responder).await,
+                _ => match rest.rsplit_once('/') {
+                    Some((id, "pin")) => match method {
+                        "POST" => {
+                            let id = id.to_string();
+                            api_pin_message(&storage, &identity, &devices, &id, request, responder)
+                                .await
+                        }
+                        _ => method_not_allowed(responder).await,
+                    },
+                    Some((id, "reactions")) => match method {
+                        "POST" => {
+                            let id = id.to_string();
+                            api_react_to_message(
+                                &storage, &identity, &devices, &id, request, responder,
+                            )
+                            .await
+                        }
+                        _ => method_not_allowed(responder).await,
+                    },
+                    Some((id, "reads")) => match method {
+                        "GET" => {
+                            api_read_receipts(&storage, &identity, &devices, id, responder).await
+                        }
+                        _ => method_not_allowed(responder).await,
+                    },
+                    Some((id, "thread")) => match method {
+                        "GET" => api_thread(&storage, &identity, &devices, id, responder).await,
+                        _ => method_not_allowed(responder).await,
+                    },
+                    _ => http_not_found(request, responder).await,
+                },
+            }
+        }
+        "/api/rooms" => match method {
+            "GET" => api_list_rooms(&storage, &identity, responder).await,
+            "POST" => api_create_room(&storage, &identity, &devices, request, responder).await,
             _ => method_not_allowed(responder).await,
         },
+        _ if path.starts_with(rooms::ROOMS_PATH) => {
+            let id = path.trim_start_matches(rooms::ROOMS_PATH).to_string();
+            match method {
+                "GET" => api_get_room(&storage, &identity, &id, responder).await,
+                "PATCH" => {
+                    api_edit_room(&storage, &identity, &devices, &id, request, responder).await
+                }
+                _ => method_not_allowed(responder).await,
+            }
+        }
         "/api/upload" => match method {
-            "POST" => api_upload_file(request, responder).await,
+            "POST" => api_upload_file(&storage, &identity, &devices, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/clipboard" => match method {
+            "PUT" => api_put_clipboard(&storage, &identity, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/clipboard/latest" => match method {
+            "GET" => api_latest_clipboard(&storage, &identity, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/devices" => match method {
+            "GET" => api_list_devices(&storage, &devices, responder).await,
+            "POST" if !identity.owner => owner_required(responder).await,
+            "POST" => api_register_device(&storage, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/devices/me" => match method {
+            "GET" => api_current_device(&identity, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/pairing" => match method {
+            "POST" => api_create_pairing(&storage, &identity, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/pairing/redeem" => match method {
+            "POST" => api_redeem_pairing(&storage, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        _ if path.starts_with("/api/devices/") => {
+            let id = path.trim_start_matches("/api/devices/").to_string();
+            match method {
+                "PATCH" | "DELETE" if !identity.may_manage(&id) => owner_required(responder).await,
+                "PATCH" => api_edit_device(&storage, &id, request, responder).await,
+                "DELETE" => api_revoke_device(&storage, &id, responder).await,
+                _ => method_not_allowed(responder).await,
+            }
+        }
+        _ if path.starts_with("/api/files/") && path.ends_with("/share") => {
+            let file = path
+                .trim_start_matches("/api/files/")
+                .trim_end_matches("/share")
+                .to_string();
+            match method {
+                "POST" => api_share_file(&storage, &identity, &file, request, responder).await,
+                _ => method_not_allowed(responder).await,
+            }
+        }
+        "/api/shares" => match method {
+            "GET" => api_list_shares(&storage, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        _ if path.starts_with("/api/shares/") => {
+            let id = path.trim_start_matches("/api/shares/").to_string();
+            match method {
+                "DELETE" => api_revoke_share(&storage, &id, responder).await,
+                _ => method_not_allowed(responder).await,
+            }
+        }
+        _ if path.starts_with(share::SHARE_PATH) => match method {
+            "GET" | "POST" => serve_shared_file(&storage, request, responder).await,
             _ => method_not_allowed(responder).await,
         },
         _ if path.starts_with("/api/files/") => match method {
-            "GET" => serve_uploaded_file(path, responder).await,
+            "GET" => {
+                let range = header_str(&request, "range");
+                serve_uploaded_file(&storage, &identity, path, range, responder).await
+            }
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/audit" => match method {
+            "GET" if !identity.owner => owner_required(responder).await,
+            "GET" => api_audit(&storage, request, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/admin/fsck" => match method {
+            "GET" => api_fsck(&storage, false, responder).await,
+            "POST" => api_fsck(&storage, true, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/admin/migrate" => match method {
+            "POST" => api_migrate(&storage, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        "/api/admin/segments" => match method {
+            "GET" => api_list_segments(&storage, responder).await,
+            _ => method_not_allowed(responder).await,
+        },
+        _ if path.starts_with("/api/admin/segments/") => {
+            let rest = path.trim_start_matches("/api/admin/segments/");
+            match (method, rest.strip_suffix("/archive")) {
+                ("POST", Some(name)) => api_archive_segment(&storage, name, responder).await,
+                ("DELETE", None) => api_purge_segment(&storage, rest, responder).await,
+                _ => method_not_allowed(responder).await,
+            }
+        }
+        "/api/admin/retention" => match method {
+            "POST" => api_apply_retention(&storage, responder).await,
             _ => method_not_allowed(responder).await,
         },
         "/" => http_home(request, responder).await,
@@ -71,190 +592,1110 @@ async fn main(request: Request<IncomingBody>, responder: Responder) -> Finished
     }
 }
 
-async fn method_not_allowed(responder: Responder) -> Finished {
+fn header_str<'a>(request: &'a Request<IncomingBody>, name: &str) -> Option<&'a str> {
+    request.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+async fn api_login(
+    storage: &Storage,
+    auth: Option<&AuthConfig>,
+    devices: &DeviceRegistry,
+    mut request: Request<IncomingBody>,
+    mut responder: Responder,
+) -> Finished {
+    let Some(auth) = auth.filter(|a| a.passphrase_enabled()) else {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("Passphrase login is not enabled".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    };
+
+    let mut body_data = Vec::new();
+    let login = match copy(
+        request.body_mut(),
+        &mut wstd::io::Cursor::new(&mut body_data),
+    )
+    .await
+    {
+        Ok(()) => serde_json::from_slice::<LoginRequest>(&body_data).ok(),
+        Err(_) => None,
+    };
+    let Some(login) = login else {
+        let response = Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("Expected {\"passphrase\": ...}".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    };
+
+    if !auth.check_passphrase(&login.passphrase) {
+        eprintln!("Rejected login with a wrong passphrase");
+        let response = Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body("Wrong passphrase".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    }
+
+    let now = chrono::Utc::now();
+    match totp::is_enabled(&*storage.state) {
+        Ok(false) => {}
+        Ok(true) => {
+            let Some(otp) = login.otp.as_deref().filter(|c| !c.trim().is_empty()) else {
+                return json_error(
+                    StatusCode::UNAUTHORIZED,
+                    "totp-required",
+                    "Enter the code from your authenticator app or a recovery code",
+                    responder,
+                )
+                .await;
+            };
+            if let Err(e) = totp::verify(&*storage.state, otp, now) {
+                eprintln!("Rejected login with a wrong second factor: {}", e);
+                return respond_totp_error(e, responder).await;
+            }
+        }
+        Err(e) => return respond_totp_error(e.into(), responder).await,
+    }
+
+    let device_name = login.device_name.unwrap_or_else(|| "Browser".to_string());
+    let device = match login_device(storage, devices, login.device_id, device_name) {
+        Ok(device) => device,
+        Err(e) => return respond_device_error(e, responder).await,
+    };
+    responder.audit_actor(&device);
+
+    let session = match auth.issue_session(now, Some(&device.id)) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Failed to issue session: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to issue session".into_body())
+                .unwrap();
+            return responder.respond(response).await;
+        }
+    };
+
+    let json = serde_json::json!({
+        "token": session.token,
+        "expiresAt": session.expires_at,
+        "device": device.info(),
+    });
     let response = Response::builder()
-        .status(StatusCode::METHOD_NOT_ALLOWED)
-        .body(empty())
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header(
+            "Set-Cookie",
+            auth::session_cookie(&session.token, auth::session_lifetime()),
+        )
+        .body(json.to_string().into_body())
         .unwrap();
     responder.respond(response).await
 }
 
-async fn api_get_messages(_request: Request<IncomingBody>, responder: Responder) -> Finished {
-    let messages = load_messages().unwrap_or_default();
-    let json = serde_json::to_string(&messages).unwrap_or_else(|_| "[]".to_string());
+/// The device a login signs in as: the one the client names if it is still
+/// active, otherwise a new one called `device_name`.
+fn login_device(
+    storage: &Storage,
+    devices: &DeviceRegistry,
+    device_id: Option<String>,
+    device_name: String,
+) -> Result<Device, std::io::Error> {
+    if let Some(device) = device_id.as_deref().and_then(|id| devices.active(id)) {
+        return Ok(device.clone());
+    }
+    let changes = DeviceChanges {
+        name: Some(device_name),
+        ..Default::default()
+    };
+    device::register(&*storage.state, changes, chrono::Utc::now()).map(|(device, _token)| device)
+}
+
+/// Where the identity provider sends the browser back to.
+fn oidc_redirect_uri(config: &oidc::OidcConfig, request: &Request<IncomingBody>) -> String {
+    config
+        .redirect_uri
+        .clone()
+        .unwrap_or_else(|| format!("{}{}", request_origin(request), oidc::CALLBACK_PATH))
+}
+
+async fn auth_oidc_login(
+    storage: &Storage,
+    auth: Option<&AuthConfig>,
+    request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let Some(config) = auth.and_then(AuthConfig::oidc) else {
+        return http_not_found(request, responder).await;
+    };
+    let (device_id, device_name) = oidc::requested_device(request.uri().query().unwrap_or(""));
+    let redirect_uri = oidc_redirect_uri(config, &request);
+
+    match oidc::start(
+        config,
+        &*storage.state,
+        &redirect_uri,
+        device_id,
+        device_name,
+        chrono::Utc::now(),
+    )
+    .await
+    {
+        Ok(url) => {
+            let response = Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header("Location", url)
+                .body(empty())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => respond_oidc_error(e, responder).await,
+    }
+}
+
+async fn auth_oidc_callback(
+    storage: &Storage,
+    auth: Option<&AuthConfig>,
+    devices: &DeviceRegistry,
+    request: Request<IncomingBody>,
+    mut responder: Responder,
+) -> Finished {
+    let Some(auth) = auth.filter(|a| a.oidc().is_some()) else {
+        return http_not_found(request, responder).await;
+    };
+    let config = auth.oidc().unwrap();
+    let query = request.uri().query().unwrap_or("");
+    let now = chrono::Utc::now();
+
+    let user = match oidc::finish(config, &*storage.state, query, now).await {
+        Ok(user) => user,
+        Err(e) => return respond_oidc_error(e, responder).await,
+    };
+    eprintln!("OpenID Connect login by {} ({})", user.name, user.subject);
+    responder.audit_target(&user.subject);
+
+    let device_name = user
+        .device_name
+        .unwrap_or_else(|| user.name.chars().take(64).collect());
+    let device = match login_device(storage, devices, user.device_id, device_name) {
+        Ok(device) => device,
+        Err(e) => return respond_device_error(e, responder).await,
+    };
+    responder.audit_actor(&device);
+    let session = match auth.issue_session(now, Some(&device.id)) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Failed to issue session: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to issue session".into_body())
+                .unwrap();
+            return responder.respond(response).await;
+        }
+    };
 
     let response = Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "application/json")
-        .body(json.into_body())
+        .status(StatusCode::SEE_OTHER)
+        .header("Location", "/")
+        .header(
+            "Set-Cookie",
+            auth::session_cookie(&session.token, auth::session_lifetime()),
+        )
+        .body(empty())
         .unwrap();
     responder.respond(response).await
 }
 
-async fn api_poll_messages(request: Request<IncomingBody>, responder: Responder) -> Finished {
-    let uri = request.uri();
-    let query = uri.query().unwrap_or("");
+async fn respond_oidc_error(e: OidcError, responder: Responder) -> Finished {
+    eprintln!("OpenID Connect login failed: {}", e);
+    let status = match &e {
+        OidcError::UnknownLogin => StatusCode::BAD_REQUEST,
+        OidcError::Provider(_) | OidcError::NotAllowed(_) => StatusCode::FORBIDDEN,
+        OidcError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+        OidcError::Http(_) => StatusCode::BAD_GATEWAY,
+        OidcError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let response = Response::builder()
+        .status(status)
+        .body(e.to_string().into_body())
+        .unwrap();
+    responder.respond(response).await
+}
 
-    let since_timestamp = parse_since_parameter(query);
-    let new_messages = get_messages_since(&since_timestamp).unwrap_or_default();
+async fn api_logout(responder: Responder) -> Finished {
+    let response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("Set-Cookie", auth::clear_session_cookie())
+        .body(empty())
+        .unwrap();
+    responder.respond(response).await
+}
 
-    let response_data = serde_json::json!({
-        "messages": new_messages,
-        "timestamp": chrono::Utc::now().to_rfc3339()
+async fn api_session(
+    auth: Option<&AuthConfig>,
+    totp_enabled: bool,
+    authenticated: bool,
+    identity: &Identity,
+    cookie: Option<&str>,
+    responder: Responder,
+) -> Finished {
+    let json = serde_json::json!({
+        "authRequired": auth.is_some(),
+        "passphraseLogin": auth.is_some_and(AuthConfig::passphrase_enabled),
+        "oidcLogin": auth.is_some_and(|a| a.oidc().is_some()),
+        "totpRequired": totp_enabled,
+        "authenticated": authenticated,
+        "device": identity.device.as_ref().map(Device::info),
+        // Sent back as `X-CSRF-Token` with requests that change something.
+        "csrfToken": security::token_for(cookie),
     });
 
-    let json = serde_json::to_string(&response_data).unwrap_or_else(|_| "{}".to_string());
-
     let response = Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "application/json")
-        .body(json.into_body())
+        .body(json.to_string().into_body())
         .unwrap();
     responder.respond(response).await
 }
 
-async fn api_send_message(mut request: Request<IncomingBody>, responder: Responder) -> Finished {
-    let mut body_data = Vec::new();
+async fn too_many_requests(retry_after: u64, responder: Responder) -> Finished {
+    let json = serde_json::json!({
+        "error": "rate-limited",
+        "message": format!("Too many requests, try again in {} seconds", retry_after),
+    });
+    let response = Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("Content-Type", "application/json")
+        .header("Retry-After", retry_after)
+        .body(json.to_string().into_body())
+        .unwrap();
+    responder.respond(response).await
+}
 
-    let copy_result = copy(
-        request.body_mut(),
-        &mut wstd::io::Cursor::new(&mut body_data),
-    )
-    .await;
+async fn json_error(
+    status: StatusCode,
+    code: &str,
+    message: &str,
+    responder: Responder,
+) -> Finished {
+    let json = serde_json::json!({ "error": code, "message": message });
+    let response = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(json.to_string().into_body())
+        .unwrap();
+    responder.respond(response).await
+}
 
-    let send_request = if copy_result.is_ok() {
-        let body_str = String::from_utf8_lossy(&body_data);
-        serde_json::from_str::<SendMessageRequest>(&body_str).unwrap_or_else(|_| {
-            SendMessageRequest {
-                content: "Failed to parse request".to_string(),
-                sender: "Unknown".to_string(),
-                msg_type: "text".to_string(),
-                filename: None,
-            }
-        })
-    } else {
-        SendMessageRequest {
-            content: "Body read failed".to_string(),
-            sender: "Unknown".to_string(),
-            msg_type: "text".to_string(),
-            filename: None,
+async fn respond_totp_error(e: TotpError, responder: Responder) -> Finished {
+    let status = match &e {
+        TotpError::InvalidCode => StatusCode::UNAUTHORIZED,
+        TotpError::NotEnabled | TotpError::AlreadyEnabled | TotpError::NotEnrolling => {
+            StatusCode::CONFLICT
+        }
+        TotpError::Storage(e) => {
+            eprintln!("Two-factor state update failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
         }
     };
+    json_error(status, e.code(), &e.to_string(), responder).await
+}
 
-    let message = Message {
-        id: Uuid::new_v4().to_string(),
-        content: send_request.content,
-        sender: send_request.sender,
-        timestamp: chrono::Utc::now().to_rfc3339(),
-        msg_type: send_request.msg_type,
-        filename: send_request.filename,
-        file_size: None,
-        mime_type: None,
+async fn respond_recovery_codes(codes: Vec<String>, responder: Responder) -> Finished {
+    let json = serde_json::json!({ "recoveryCodes": codes });
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(json.to_string().into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn read_totp_code(request: &mut Request<IncomingBody>) -> Result<String, String> {
+    read_json_body::<TotpCodeRequest>(request)
+        .await
+        .map(|body| body.code)
+}
+
+async fn api_totp_status(storage: &Storage, responder: Responder) -> Finished {
+    match totp::status(&*storage.state) {
+        Ok(status) => {
+            let json = serde_json::to_string(&status).unwrap_or_else(|_| "{}".to_string());
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => respond_totp_error(e.into(), responder).await,
+    }
+}
+
+async fn api_totp_enroll(
+    storage: &Storage,
+    auth: Option<&AuthConfig>,
+    identity: &Identity,
+    responder: Responder,
+) -> Finished {
+    if !auth.is_some_and(AuthConfig::passphrase_enabled) {
+        return json_error(
+            StatusCode::CONFLICT,
+            "login-not-enabled",
+            "Two-factor login needs a passphrase to be configured first",
+            responder,
+        )
+        .await;
+    }
+    let account = identity
+        .device
+        .as_ref()
+        .map_or("spore-box", |d| d.name.as_str());
+    match totp::enroll(&*storage.state, account) {
+        Ok(enrolment) => {
+            let json = serde_json::to_string(&enrolment).unwrap_or_else(|_| "{}".to_string());
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => respond_totp_error(e, responder).await,
+    }
+}
+
+async fn api_totp_confirm(
+    storage: &Storage,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let code = match read_totp_code(&mut request).await {
+        Ok(code) => code,
+        Err(msg) => {
+            return json_error(StatusCode::BAD_REQUEST, "invalid-request", &msg, responder).await;
+        }
     };
+    match totp::confirm(&*storage.state, &code, chrono::Utc::now()) {
+        Ok(codes) => respond_recovery_codes(codes, responder).await,
+        Err(e) => respond_totp_error(e, responder).await,
+    }
+}
 
-    // Save the message
-    let _ = save_message(&message);
+async fn api_totp_disable(
+    storage: &Storage,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let code = match read_totp_code(&mut request).await {
+        Ok(code) => code,
+        Err(msg) => {
+            return json_error(StatusCode::BAD_REQUEST, "invalid-request", &msg, responder).await;
+        }
+    };
+    match totp::disable(&*storage.state, &code, chrono::Utc::now()) {
+        Ok(()) => {
+            let response = Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(empty())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => respond_totp_error(e, responder).await,
+    }
+}
 
-    let json = serde_json::to_string(&message).unwrap_or_else(|_| "{}".to_string());
+async fn api_totp_recovery_codes(
+    storage: &Storage,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let code = match read_totp_code(&mut request).await {
+        Ok(code) => code,
+        Err(msg) => {
+            return json_error(StatusCode::BAD_REQUEST, "invalid-request", &msg, responder).await;
+        }
+    };
+    match totp::regenerate_recovery_codes(&*storage.state, &code, chrono::Utc::now()) {
+        Ok(codes) => respond_recovery_codes(codes, responder).await,
+        Err(e) => respond_totp_error(e, responder).await,
+    }
+}
+
+async fn method_not_allowed(responder: Responder) -> Finished {
     let response = Response::builder()
-        .status(StatusCode::CREATED)
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .body(empty())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn api_get_messages(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    responder: Responder,
+) -> Finished {
+    let device = identity.device_id();
+    let mut messages = storage.messages.load().unwrap_or_default();
+    messages.retain(|m| m.visible_to(device.as_deref()));
+    devices.present(&mut messages);
+    let json = serde_json::to_string(&event::fold(messages)).unwrap_or_else(|_| "[]".to_string());
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
         .header("Content-Type", "application/json")
         .body(json.into_body())
         .unwrap();
     responder.respond(response).await
 }
 
-async fn api_upload_file(mut request: Request<IncomingBody>, responder: Responder) -> Finished {
-    let mut body_data = Vec::new();
+/// Messages addressed to the requesting device or to everyone, leaving out
+/// those it sent to other devices.
+async fn api_inbox(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    responder: Responder,
+) -> Finished {
+    let device = identity.device_id();
+    let mut messages = storage.messages.load().unwrap_or_default();
+    messages.retain(|m| m.visible_to(device.as_deref()));
+    devices.present(&mut messages);
+    // Folded before filtering so replies still quote what this device sent.
+    let mut inbox = event::fold(messages);
+    inbox.retain(|view| view.message.is_for(device.as_deref()));
+    let json = serde_json::to_string(&inbox).unwrap_or_else(|_| "[]".to_string());
 
-    let copy_result = copy(
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn api_pinned_messages(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    responder: Responder,
+) -> Finished {
+    let device = identity.device_id();
+    let mut messages = storage.messages.load().unwrap_or_default();
+    messages.retain(|m| m.visible_to(device.as_deref()));
+    devices.present(&mut messages);
+    let mut pinned = event::fold(messages);
+    pinned.retain(|m| m.pinned);
+    let json = serde_json::to_string(&pinned).unwrap_or_else(|_| "[]".to_string());
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn no_device(responder: Responder) -> Finished {
+    json_error(
+        StatusCode::BAD_REQUEST,
+        "no-device",
+        "Read positions are kept per device; sign in as one",
+        responder,
+    )
+    .await
+}
+
+async fn respond_unread(
+    storage: &Storage,
+    device: &str,
+    devices: &DeviceRegistry,
+    responder: Responder,
+) -> Finished {
+    let result = reads::position(&*storage.state, &storage.room, device).and_then(|position| {
+        let mut messages = storage.messages.load()?;
+        messages.retain(|m| m.visible_to(Some(device)));
+        devices.present(&mut messages);
+        Ok(reads::unread(messages, device, position))
+    });
+    match result {
+        Ok(unread) => {
+            let json = serde_json::to_string(&unread).unwrap_or_else(|_| "{}".to_string());
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => {
+            eprintln!("Failed to count unread messages: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to count unread messages".into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
+
+/// How many messages the device has not read, and the first of them.
+async fn api_unread(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    responder: Responder,
+) -> Finished {
+    match identity.device_id() {
+        Some(device) => respond_unread(storage, &device, devices, responder).await,
+        None => no_device(responder).await,
+    }
+}
+
+/// Moves the device's read position up to a message, or to the newest one.
+async fn api_mark_read(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let Some(device) = identity.device_id() else {
+        return no_device(responder).await;
+    };
+    let mut body_data = Vec::new();
+    if let Err(e) = copy(
         request.body_mut(),
         &mut wstd::io::Cursor::new(&mut body_data),
     )
-    .await;
+    .await
+    {
+        return bad_request(format!("Body read failed: {}", e), responder).await;
+    }
+    let read_request = if body_data.iter().all(u8::is_ascii_whitespace) {
+        ReadRequest::default()
+    } else {
+        match serde_json::from_slice::<ReadRequest>(&body_data) {
+            Ok(read_request) => read_request,
+            Err(e) => return bad_request(format!("Invalid request: {}", e), responder).await,
+        }
+    };
 
-    if copy_result.is_err() {
-        let response = Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("Failed to read request body".into_body())
-            .unwrap();
-        return responder.respond(response).await;
+    let readable = |m: &Message| m.event.is_none() && m.visible_to(Some(&device));
+    let message = match &read_request.message_id {
+        Some(id) => storage.messages.get(id).map(|m| m.filter(readable)),
+        None => storage.messages.latest(&readable),
     }
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to load messages: {}", e);
+        None
+    });
+    let Some(message) = message else {
+        return match read_request.message_id {
+            Some(id) => {
+                let response = Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(format!("No message {}", id).into_body())
+                    .unwrap();
+                responder.respond(response).await
+            }
+            None => respond_unread(storage, &device, devices, responder).await,
+        };
+    };
 
-    // Parse multipart form data manually
-    let content_type = request
-        .headers()
-        .get("content-type")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
+    let now = chrono::Utc::now();
+    match reads::mark_read(&*storage.state, &storage.room, &device, &message, now) {
+        Ok(_) => respond_unread(storage, &device, devices, responder).await,
+        Err(e) if e.kind() == ErrorKind::InvalidInput => {
+            bad_request(e.to_string(), responder).await
+        }
+        Err(e) => {
+            eprintln!("Failed to save read position: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to save read position".into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
 
-    if !content_type.starts_with("multipart/form-data") {
+/// Which of the devices that can see a message have read it.
+async fn api_read_receipts(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    id: &str,
+    responder: Responder,
+) -> Finished {
+    let requester = identity.device_id();
+    let message = storage
+        .messages
+        .get(id)
+        .unwrap_or_default()
+        .filter(|m| m.event.is_none() && m.visible_to(requester.as_deref()));
+    let Some(message) = message else {
         let response = Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("Expected multipart/form-data".into_body())
+            .status(StatusCode::NOT_FOUND)
+            .body(format!("No message {}", id).into_body())
             .unwrap();
         return responder.respond(response).await;
+    };
+
+    let result = reads::positions(&*storage.state, &storage.room).and_then(|positions| {
+        let room = rooms::get(&*storage.state, &storage.room)?;
+        // Everyone who can see the message, except whoever sent it.
+        let readers = devices.devices.iter().filter(|d| {
+            d.is_active()
+                && message.device_id.as_deref() != Some(d.id.as_str())
+                && message.visible_to(Some(&d.id))
+                && room.as_ref().is_none_or(|room| room.admits(Some(&d.id)))
+        });
+        Ok(reads::receipts(&message, &positions, readers))
+    });
+    match result {
+        Ok(receipts) => {
+            let json = serde_json::to_string(&receipts).unwrap_or_else(|_| "{}".to_string());
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => {
+            eprintln!("Failed to load read positions: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to load read positions".into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
     }
+}
 
-    // Extract boundary
-    let boundary = if let Some(boundary_part) = content_type.split("boundary=").nth(1) {
-        boundary_part.trim_matches('"')
-    } else {
+/// A message with every reply under it.
+async fn api_thread(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    id: &str,
+    responder: Responder,
+) -> Finished {
+    let device = identity.device_id();
+    let mut messages = storage.messages.load().unwrap_or_default();
+    messages.retain(|m| m.visible_to(device.as_deref()));
+    devices.present(&mut messages);
+    let Some((message, replies)) = event::thread(messages, id) else {
         let response = Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("Missing boundary in multipart data".into_body())
+            .status(StatusCode::NOT_FOUND)
+            .body(format!("No message {}", id).into_body())
             .unwrap();
         return responder.respond(response).await;
     };
 
-    // Parse multipart data
-    let (file_data, filename, sender) = match parse_multipart_data(&body_data, boundary) {
-        Ok(data) => data,
-        Err(err) => {
-            eprintln!("Multipart parsing error: {}", err);
+    let json = serde_json::json!({ "message": message, "replies": replies }).to_string();
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+/// Pins or unpins a message by appending an event to the log, so polling
+/// devices learn of it. Without a body it toggles.
+async fn api_pin_message(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    id: &str,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let mut body_data = Vec::new();
+    if let Err(e) = copy(
+        request.body_mut(),
+        &mut wstd::io::Cursor::new(&mut body_data),
+    )
+    .await
+    {
+        return bad_request(format!("Body read failed: {}", e), responder).await;
+    }
+    let pin_request = if body_data.iter().all(u8::is_ascii_whitespace) {
+        PinRequest::default()
+    } else {
+        match serde_json::from_slice::<PinRequest>(&body_data) {
+            Ok(pin_request) => pin_request,
+            Err(e) => return bad_request(format!("Invalid request: {}", e), responder).await,
+        }
+    };
+
+    let pin = |messages: &[Message]| Event::Pin {
+        target: id.to_string(),
+        pinned: pin_request
+            .pinned
+            .unwrap_or_else(|| !event::is_pinned(messages, id)),
+    };
+    let sender = identity.sender(pin_request.sender.clone());
+    record_event(storage, identity, devices, id, pin, sender, responder).await
+}
+
+async fn api_react_to_message(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    id: &str,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let react_request = match read_json_body::<ReactRequest>(&mut request).await {
+        Ok(react_request) => react_request,
+        Err(msg) => return bad_request(msg, responder).await,
+    };
+    if !event::valid_emoji(&react_request.emoji) {
+        return bad_request(
+            format!(
+                "Reactions must be up to {} bytes without spaces",
+                event::MAX_EMOJI_LEN
+            ),
+            responder,
+        )
+        .await;
+    }
+
+    let react = |_: &[Message]| Event::React {
+        target: id.to_string(),
+        emoji: react_request.emoji.clone(),
+        added: react_request.added,
+    };
+    let sender = identity.sender(react_request.sender.clone());
+    record_event(storage, identity, devices, id, react, sender, responder).await
+}
+
+/// Appends the event `make` returns about message `id`, given the log, and
+/// responds with the message as listings now show it. Polling devices get
+/// the event itself.
+async fn record_event(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    id: &str,
+    make: impl FnOnce(&[Message]) -> Event,
+    sender: String,
+    responder: Responder,
+) -> Finished {
+    let mut messages = match storage.messages.load() {
+        Ok(messages) => messages,
+        Err(e) => {
+            eprintln!("Failed to load messages: {}", e);
             let response = Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(format!("Multipart parsing error: {}", err).into_body())
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to load messages".into_body())
                 .unwrap();
             return responder.respond(response).await;
         }
     };
-
-    // Create uploads directory inside data folder
-    if let Err(e) = std::fs::create_dir_all("data/uploads") {
-        eprintln!("Failed to create upload directory: {}", e);
+    let device = identity.device_id();
+    messages.retain(|m| m.visible_to(device.as_deref()));
+    let Some(target) = messages.iter().find(|m| m.id == id && m.event.is_none()) else {
         let response = Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body("Failed to create upload directory".into_body())
+            .status(StatusCode::NOT_FOUND)
+            .body(format!("No message {}", id).into_body())
             .unwrap();
         return responder.respond(response).await;
-    }
-
-    // Generate unique filename
-    let file_id = Uuid::new_v4().to_string();
-    let extension = filename
-        .split('.')
-        .last()
-        .map(|ext| format!(".{}", ext))
-        .unwrap_or_default();
-    let stored_filename = format!("{}{}", file_id, extension);
-    let file_path = format!("data/uploads/{}", stored_filename);
+    };
 
-    // Save file
-    if let Err(e) = std::fs::write(&file_path, &file_data) {
-        eprintln!("Failed to save file: {}", e);
+    let record = make(&messages).record(target, sender, device);
+    if let Err(e) = storage.messages.append(&record) {
+        eprintln!("Failed to save event: {}", e);
         let response = Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body("Failed to save file".into_body())
+            .body("Failed to save event".into_body())
             .unwrap();
         return responder.respond(response).await;
     }
 
-    // Determine message type based on file extension
-    let msg_type = if is_image_file(&filename) {
-        "image"
-    } else {
-        "file"
+    messages.push(record);
+    devices.present(&mut messages);
+    let view = event::fold(messages)
+        .into_iter()
+        .find(|view| view.message.id == id);
+    let json = serde_json::to_string(&view).unwrap_or_else(|_| "{}".to_string());
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+/// Refuses replies to messages that are not in the log or not visible to
+/// the requester.
+fn check_reply_to(
+    storage: &Storage,
+    identity: &Identity,
+    reply_to: Option<&str>,
+) -> Result<(), String> {
+    let Some(parent) = reply_to else {
+        return Ok(());
+    };
+    let device = identity.device_id();
+    let found = storage
+        .messages
+        .get(parent)
+        .map_err(|e| format!("Failed to load messages: {}", e))?
+        .is_some_and(|m| m.event.is_none() && m.visible_to(device.as_deref()));
+    if found {
+        Ok(())
+    } else {
+        Err(format!("No message {} to reply to", parent))
+    }
+}
+
+/// Refuses recipients that are not registered, active devices.
+fn check_recipients(recipients: &[String], devices: &DeviceRegistry) -> Result<(), String> {
+    match recipients.iter().find(|id| devices.active(id).is_none()) {
+        Some(id) => Err(format!("No device {}", id)),
+        None => Ok(()),
+    }
+}
+
+/// Whether `file` is the upload of a message the requester may not see.
+fn upload_hidden(storage: &Storage, identity: &Identity, file: &str) -> bool {
+    let device = identity.device_id();
+    storage
+        .messages
+        .load()
+        .unwrap_or_default()
+        .iter()
+        .any(|m| m.content == file && !m.visible_to(device.as_deref()))
+}
+
+async fn api_poll_messages(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let uri = request.uri();
+    let query = uri.query().unwrap_or("");
+
+    let since_timestamp = parse_since_parameter(query);
+    let device = identity.device_id();
+    let mut new_messages = storage.messages.since(&since_timestamp).unwrap_or_default();
+    new_messages.retain(|m| m.visible_to(device.as_deref()));
+    devices.present(&mut new_messages);
+
+    let now = chrono::Utc::now();
+    if let Some(device) = &device
+        && let Err(e) = presence::record(&*storage.state, device, now)
+    {
+        eprintln!("Failed to record presence of {}: {}", device, e);
+    }
+
+    let mut response_data = serde_json::json!({
+        "messages": new_messages,
+        "timestamp": now.to_rfc3339()
+    });
+    if spore_box::url::query_param(query, "presence").is_some_and(|v| v == "1" || v == "true") {
+        let last_seen = presence::last_seen(&*storage.state).unwrap_or_default();
+        response_data["presence"] =
+            serde_json::json!(presence::changes(&last_seen, &since_timestamp, now));
+    }
+
+    let json = serde_json::to_string(&response_data).unwrap_or_else(|_| "{}".to_string());
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn api_send_message(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let mut body_data = Vec::new();
+
+    let copy_result = copy(
+        request.body_mut(),
+        &mut wstd::io::Cursor::new(&mut body_data),
+    )
+    .await;
+
+    let send_request = if copy_result.is_ok() {
+        let body_str = String::from_utf8_lossy(&body_data);
+        serde_json::from_str::<SendMessageRequest>(&body_str).unwrap_or_else(|_| {
+            SendMessageRequest {
+                content: "Failed to parse request".to_string(),
+                sender: "Unknown".to_string(),
+                msg_type: "text".to_string(),
+                filename: None,
+                recipients: Vec::new(),
+                reply_to: None,
+            }
+        })
+    } else {
+        SendMessageRequest {
+            content: "Body read failed".to_string(),
+            sender: "Unknown".to_string(),
+            msg_type: "text".to_string(),
+            filename: None,
+            recipients: Vec::new(),
+            reply_to: None,
+        }
+    };
+    if let Err(msg) = check_recipients(&send_request.recipients, devices) {
+        return bad_request(msg, responder).await;
+    }
+    if send_request.msg_type == event::EVENT_TYPE {
+        return bad_request("Events cannot be sent as messages".to_string(), responder).await;
+    }
+    if let Err(msg) = check_reply_to(storage, identity, send_request.reply_to.as_deref()) {
+        return bad_request(msg, responder).await;
+    }
+
+    let message = Message {
+        id: Uuid::new_v4().to_string(),
+        content: send_request.content,
+        sender: identity.sender(send_request.sender),
+        device_id: identity.device_id(),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        msg_type: send_request.msg_type,
+        filename: send_request.filename,
+        file_size: None,
+        mime_type: None,
+        broken: None,
+        recipients: send_request.recipients,
+        reply_to: send_request.reply_to,
+        event: None,
+    };
+
+    // Save the message
+    if let Err(e) = storage.messages.append(&message) {
+        eprintln!("Failed to save message: {}", e);
+        let response = Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body("Failed to save message".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    }
+
+    let json = serde_json::to_string(&message).unwrap_or_else(|_| "{}".to_string());
+    let response = Response::builder()
+        .status(StatusCode::CREATED)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn api_upload_file(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let body_data = match read_upload_body(&mut request).await {
+        Ok(Some(body_data)) => body_data,
+        Ok(None) => return upload_too_large(responder).await,
+        Err(_) => {
+            let response = Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("Failed to read request body".into_body())
+                .unwrap();
+            return responder.respond(response).await;
+        }
+    };
+
+    // Parse multipart form data manually
+    let content_type = request
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+
+    if !content_type.starts_with("multipart/form-data") {
+        let response = Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("Expected multipart/form-data".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    }
+
+    // Extract boundary
+    let boundary = if let Some(boundary_part) = content_type.split("boundary=").nth(1) {
+        boundary_part.trim_matches('"')
+    } else {
+        let response = Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("Missing boundary in multipart data".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    };
+
+    // Parse multipart data
+    let upload = match parse_multipart_data(&body_data, boundary) {
+        Ok(upload) => upload,
+        Err(err) => {
+            eprintln!("Multipart parsing error: {}", err);
+            let response = Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(format!("Multipart parsing error: {}", err).into_body())
+                .unwrap();
+            return responder.respond(response).await;
+        }
+    };
+
+    let MultipartUpload {
+        data: file_data,
+        filename,
+        sender,
+        recipients,
+        reply_to,
+    } = upload;
+    if let Err(msg) = check_recipients(&recipients, devices) {
+        return bad_request(msg, responder).await;
+    }
+    if let Err(msg) = check_reply_to(storage, identity, reply_to.as_deref()) {
+        return bad_request(msg, responder).await;
+    }
+
+    // Generate unique filename
+    let file_id = Uuid::new_v4().to_string();
+    let extension = filename
+        .split('.')
+        .next_back()
+        .map(|ext| format!(".{}", ext))
+        .unwrap_or_default();
+    let stored_filename = format!("{}{}", file_id, extension);
+
+    // Save file
+    if let Err(e) = storage.blobs.put(&stored_filename, &file_data) {
+        eprintln!("Failed to save file: {}", e);
+        let response = Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body("Failed to save file".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    }
+
+    // Determine message type based on file extension
+    let msg_type = if is_image_file(&filename) {
+        "image"
+    } else {
+        "file"
     };
 
     // Determine MIME type
@@ -264,16 +1705,120 @@ async fn api_upload_file(mut request: Request<IncomingBody>, responder: Responde
     let message = Message {
         id: Uuid::new_v4().to_string(),
         content: stored_filename, // Store the file ID/name
-        sender,
+        sender: identity.sender(sender),
+        device_id: identity.device_id(),
         timestamp: chrono::Utc::now().to_rfc3339(),
         msg_type: msg_type.to_string(),
         filename: Some(filename),
         file_size: Some(file_data.len() as u64),
         mime_type: Some(mime_type.to_string()),
+        broken: None,
+        recipients,
+        reply_to,
+        event: None,
     };
 
     // Save message
-    if let Err(e) = save_message(&message) {
+    if let Err(e) = storage.messages.append(&message) {
+        eprintln!("Failed to save message: {}", e);
+        let response = Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body("Failed to save message".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    }
+
+    let json = serde_json::to_string(&message).unwrap_or_else(|_| "{}".to_string());
+    let response = Response::builder()
+        .status(StatusCode::CREATED)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+/// Largest body `POST /api/upload` and `PUT /api/clipboard` accept.
+const MAX_UPLOAD_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Reads an upload's body, or `None` once it is longer than
+/// `MAX_UPLOAD_BYTES`, without holding more than that in memory.
+async fn read_upload_body(request: &mut Request<IncomingBody>) -> std::io::Result<Option<Vec<u8>>> {
+    let declared = request
+        .headers()
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    if declared.is_some_and(|len| len > MAX_UPLOAD_BYTES) {
+        return Ok(None);
+    }
+
+    let mut body_data = Vec::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = request.body_mut().read(&mut buf).await?;
+        if n == 0 {
+            return Ok(Some(body_data));
+        }
+        if (body_data.len() + n) as u64 > MAX_UPLOAD_BYTES {
+            return Ok(None);
+        }
+        body_data.extend_from_slice(&buf[..n]);
+    }
+}
+
+async fn upload_too_large(responder: Responder) -> Finished {
+    let response = Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(
+            format!(
+                "Uploads are limited to {} MiB",
+                MAX_UPLOAD_BYTES / (1024 * 1024)
+            )
+            .into_body(),
+        )
+        .unwrap();
+    responder.respond(response).await
+}
+
+/// Records a raw request body as a message: an image when it is one, by its
+/// `Content-Type` or its first bytes, and text otherwise.
+async fn api_put_clipboard(
+    storage: &Storage,
+    identity: &Identity,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let body_data = match read_upload_body(&mut request).await {
+        Ok(Some(body_data)) => body_data,
+        Ok(None) => return upload_too_large(responder).await,
+        Err(_) => {
+            return bad_request("Failed to read request body".to_string(), responder).await;
+        }
+    };
+
+    let content_type = request
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let query = request.uri().query().unwrap_or("");
+    let (message, image) =
+        match clipboard_message(identity, body_data, content_type, query, chrono::Utc::now()) {
+            Ok(clip) => clip,
+            Err(msg) => return bad_request(msg, responder).await,
+        };
+    if let Some(image) = image
+        && let Err(e) = storage.blobs.put(&message.content, &image)
+    {
+        eprintln!("Failed to save file: {}", e);
+        let response = Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body("Failed to save file".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    }
+
+    if let Err(e) = storage.messages.append(&message) {
         eprintln!("Failed to save message: {}", e);
         let response = Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -282,81 +1827,754 @@ async fn api_upload_file(mut request: Request<IncomingBody>, responder: Responde
         return responder.respond(response).await;
     }
 
-    let json = serde_json::to_string(&message).unwrap_or_else(|_| "{}".to_string());
+    let json = serde_json::to_string(&message).unwrap_or_else(|_| "{}".to_string());
+    let response = Response::builder()
+        .status(StatusCode::CREATED)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+/// The message a clipboard body becomes, with the bytes to store under its
+/// content when it is an image.
+fn clipboard_message(
+    identity: &Identity,
+    body_data: Vec<u8>,
+    content_type: &str,
+    query: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<(Message, Option<Vec<u8>>), String> {
+    if body_data.is_empty() {
+        return Err("Clipboard is empty".to_string());
+    }
+    let image = if content_type.starts_with("image/svg+xml") {
+        Some("svg")
+    } else {
+        spore_box::mime::sniff_image(&body_data)
+    };
+    let sender = identity.sender(
+        spore_box::url::query_param(query, "sender").unwrap_or_else(|| "clipboard".to_string()),
+    );
+
+    let mut message = Message {
+        id: Uuid::new_v4().to_string(),
+        content: String::new(),
+        sender,
+        device_id: identity.device_id(),
+        timestamp: now.to_rfc3339(),
+        msg_type: "text".to_string(),
+        filename: None,
+        file_size: None,
+        mime_type: None,
+        broken: None,
+        recipients: Vec::new(),
+        reply_to: None,
+        event: None,
+    };
+    match image {
+        Some(extension) => {
+            let filename = format!("clipboard-{}.{}", now.format("%Y%m%d-%H%M%S"), extension);
+            message.msg_type = "image".to_string();
+            message.mime_type = Some(get_mime_type(&filename).to_string());
+            message.filename = Some(filename);
+            message.file_size = Some(body_data.len() as u64);
+            message.content = format!("{}.{}", Uuid::new_v4(), extension);
+            Ok((message, Some(body_data)))
+        }
+        None => match String::from_utf8(body_data) {
+            Ok(text) => {
+                message.content = text;
+                Ok((message, None))
+            }
+            Err(_) => {
+                Err("Clipboard takes UTF-8 text or PNG, JPEG, GIF, WebP or SVG images".to_string())
+            }
+        },
+    }
+}
+
+/// Sends the newest message the requester can see, raw: text as
+/// `text/plain`, uploads as they are stored. `type=` limits it to one
+/// message type and `from=` to one sender name or device id.
+async fn api_latest_clipboard(
+    storage: &Storage,
+    identity: &Identity,
+    request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let query = request.uri().query().unwrap_or("");
+    let Some(latest) = latest_clipboard(storage, identity, query) else {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("Nothing on the clipboard".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    };
+
+    match latest.msg_type.as_str() {
+        "image" | "file" => {
+            let content_type = latest
+                .mime_type
+                .as_deref()
+                .or_else(|| get_content_type(&latest.content))
+                .unwrap_or("application/octet-stream");
+            let response = Response::builder()
+                .header("Content-Type", content_type)
+                .header("X-Message-Id", &latest.id);
+            send_blob(storage, &latest.content, None, response, responder).await
+        }
+        _ => {
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .header("X-Message-Id", &latest.id)
+                .body(latest.content.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
+
+/// The newest message `GET /api/clipboard/latest` sends for `query`.
+fn latest_clipboard(storage: &Storage, identity: &Identity, query: &str) -> Option<Message> {
+    let msg_type = spore_box::url::query_param(query, "type");
+    let from = spore_box::url::query_param(query, "from");
+    let device = identity.device_id();
+
+    let wanted = |m: &Message| {
+        m.event.is_none()
+            && m.broken.is_none()
+            && m.visible_to(device.as_deref())
+            && msg_type.as_ref().is_none_or(|t| &m.msg_type == t)
+            && from
+                .as_ref()
+                .is_none_or(|f| &m.sender == f || m.device_id.as_ref() == Some(f))
+    };
+    storage.messages.latest(&wanted).unwrap_or_else(|e| {
+        eprintln!("Failed to load messages: {}", e);
+        None
+    })
+}
+
+async fn api_audit(
+    storage: &Storage,
+    request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let query = match audit::Query::parse(request.uri().query().unwrap_or("")) {
+        Ok(query) => query,
+        Err(msg) => return bad_request(msg, responder).await,
+    };
+    match audit::query(&*storage.audit, &query) {
+        Ok(entries) => {
+            let json = serde_json::to_string(&entries).unwrap_or_else(|_| "[]".to_string());
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => {
+            eprintln!("Failed to read audit log: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to read audit log".into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
+
+async fn api_fsck(storage: &Storage, repair: bool, responder: Responder) -> Finished {
+    match fsck::fsck(storage, repair) {
+        Ok(report) => {
+            let json = serde_json::to_string(&report).unwrap_or_else(|_| "{}".to_string());
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => {
+            eprintln!("Fsck failed: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(format!("Fsck failed: {}", e).into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
+
+async fn api_migrate(storage: &Storage, responder: Responder) -> Finished {
+    match storage.messages.migrate() {
+        Ok(report) => {
+            let json = serde_json::to_string(&report).unwrap_or_else(|_| "{}".to_string());
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => {
+            eprintln!("Migration failed: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(format!("Migration failed: {}", e).into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
+
+async fn api_list_devices(
+    storage: &Storage,
+    devices: &DeviceRegistry,
+    responder: Responder,
+) -> Finished {
+    let now = chrono::Utc::now();
+    let last_seen = presence::last_seen(&*storage.state).unwrap_or_else(|e| {
+        eprintln!("Failed to load presence: {}", e);
+        Default::default()
+    });
+    let list: Vec<_> = devices
+        .devices
+        .iter()
+        .map(|device| {
+            let seen = last_seen.get(&device.id).copied();
+            let status = match device.revoked_at {
+                Some(_) => presence::Status::Offline,
+                None => presence::Status::at(seen, now),
+            };
+            presence::DeviceWithPresence {
+                device: device.info(),
+                status,
+                last_seen: seen.map(|t| t.to_rfc3339()),
+            }
+        })
+        .collect();
+    let json = serde_json::to_string(&list).unwrap_or_else(|_| "[]".to_string());
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn api_current_device(identity: &Identity, responder: Responder) -> Finished {
+    let Some(device) = &identity.device else {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("Not signed in as a registered device".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    };
+    let json = serde_json::to_string(&device.info()).unwrap_or_else(|_| "{}".to_string());
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+/// Reads and parses a JSON request body, or describes why it could not.
+async fn read_json_body<T: serde::de::DeserializeOwned>(
+    request: &mut Request<IncomingBody>,
+) -> Result<T, String> {
+    let mut body_data = Vec::new();
+    copy(
+        request.body_mut(),
+        &mut wstd::io::Cursor::new(&mut body_data),
+    )
+    .await
+    .map_err(|e| format!("Body read failed: {}", e))?;
+    serde_json::from_slice(&body_data).map_err(|e| format!("Invalid request: {}", e))
+}
+
+async fn api_register_device(
+    storage: &Storage,
+    mut request: Request<IncomingBody>,
+    mut responder: Responder,
+) -> Finished {
+    let changes = match read_json_body::<DeviceChanges>(&mut request).await {
+        Ok(changes) => changes,
+        Err(msg) => return bad_request(msg, responder).await,
+    };
+    match device::register(&*storage.state, changes, chrono::Utc::now()) {
+        Ok((device, token)) => {
+            responder.audit_target(&device.id);
+            let json = serde_json::json!({ "device": device.info(), "token": token });
+            let response = Response::builder()
+                .status(StatusCode::CREATED)
+                .header("Content-Type", "application/json")
+                .body(json.to_string().into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => respond_device_error(e, responder).await,
+    }
+}
+
+async fn api_edit_device(
+    storage: &Storage,
+    id: &str,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let changes = match read_json_body::<DeviceChanges>(&mut request).await {
+        Ok(changes) => changes,
+        Err(msg) => return bad_request(msg, responder).await,
+    };
+    match device::edit(&*storage.state, id, changes) {
+        Ok(device) => respond_device(&device, responder).await,
+        Err(e) => respond_device_error(e, responder).await,
+    }
+}
+
+async fn api_revoke_device(storage: &Storage, id: &str, responder: Responder) -> Finished {
+    match device::revoke(&*storage.state, id, chrono::Utc::now()) {
+        Ok(device) => respond_device(&device, responder).await,
+        Err(e) => respond_device_error(e, responder).await,
+    }
+}
+
+async fn respond_device(device: &Device, responder: Responder) -> Finished {
+    let json = serde_json::to_string(&device.info()).unwrap_or_else(|_| "{}".to_string());
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn respond_device_error(e: std::io::Error, responder: Responder) -> Finished {
+    let status = match e.kind() {
+        ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        _ => {
+            eprintln!("Device registry update failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    let response = Response::builder()
+        .status(status)
+        .body(e.to_string().into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn bad_request(msg: String, responder: Responder) -> Finished {
+    let response = Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(msg.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+/// Scheme and host the client reached us on, for links it can open.
+fn request_origin(request: &Request<IncomingBody>) -> String {
+    if let Some(origin) = header_str(request, "origin") {
+        return origin.to_string();
+    }
+    let scheme = header_str(request, "x-forwarded-proto").unwrap_or("http");
+    let host = header_str(request, "host").unwrap_or("localhost");
+    format!("{}://{}", scheme, host)
+}
+
+async fn api_create_pairing(
+    storage: &Storage,
+    identity: &Identity,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let origin = request_origin(&request);
+    // The body is optional; an empty one suggests no device name.
+    let pairing_request = read_json_body::<PairingRequest>(&mut request)
+        .await
+        .unwrap_or_default();
+
+    match pairing::create(
+        &*storage.state,
+        identity.device.as_ref(),
+        pairing_request.device_name,
+        &origin,
+        chrono::Utc::now(),
+    ) {
+        Ok(code) => {
+            let json = serde_json::to_string(&code).unwrap_or_else(|_| "{}".to_string());
+            let response = Response::builder()
+                .status(StatusCode::CREATED)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => {
+            eprintln!("Failed to create pairing code: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to create pairing code".into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
+
+async fn api_redeem_pairing(
+    storage: &Storage,
+    mut request: Request<IncomingBody>,
+    mut responder: Responder,
+) -> Finished {
+    let redeem = match read_json_body::<RedeemRequest>(&mut request).await {
+        Ok(redeem) => redeem,
+        Err(msg) => return bad_request(msg, responder).await,
+    };
+
+    match pairing::redeem(
+        &*storage.state,
+        &redeem.code,
+        redeem.device_name,
+        chrono::Utc::now(),
+    ) {
+        Ok((device, token)) => {
+            responder.audit_actor(&device);
+            let json = serde_json::json!({ "device": device.info(), "token": token });
+            let response = Response::builder()
+                .status(StatusCode::CREATED)
+                .header("Content-Type", "application/json")
+                .header(
+                    "Set-Cookie",
+                    auth::session_cookie(&token, device::cookie_lifetime()),
+                )
+                .body(json.to_string().into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let response = Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(e.to_string().into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => respond_device_error(e, responder).await,
+    }
+}
+
+async fn api_list_segments(storage: &Storage, responder: Responder) -> Finished {
+    respond_admin_result(storage.messages.segments(), "List segments", responder).await
+}
+
+async fn api_archive_segment(storage: &Storage, name: &str, responder: Responder) -> Finished {
+    let result = segment::archive(storage, name)
+        .map(|kept| serde_json::json!({ "archived": name, "keptPinned": kept }));
+    respond_admin_result(result, "Archive", responder).await
+}
+
+async fn api_purge_segment(storage: &Storage, name: &str, responder: Responder) -> Finished {
+    let result = segment::purge(storage, name).map(
+        |deleted_uploads| serde_json::json!({ "purged": name, "deletedUploads": deleted_uploads }),
+    );
+    respond_admin_result(result, "Purge", responder).await
+}
+
+/// Applies each room's retention. The result has a report per room.
+async fn api_apply_retention(storage: &Storage, responder: Responder) -> Finished {
+    let result = rooms::apply_retention(
+        &*storage.state,
+        Storage::open_default_room,
+        chrono::Utc::now(),
+    );
+    respond_admin_result(result, "Retention", responder).await
+}
+
+async fn owner_required(responder: Responder) -> Finished {
+    json_error(
+        StatusCode::FORBIDDEN,
+        "owner-required",
+        "Only a passphrase login can do this",
+        responder,
+    )
+    .await
+}
+
+async fn no_such_room(responder: Responder) -> Finished {
+    json_error(
+        StatusCode::NOT_FOUND,
+        "no-such-room",
+        "No such room",
+        responder,
+    )
+    .await
+}
+
+/// The room `id` if it exists and the requester may use it.
+fn visible_room(
+    storage: &Storage,
+    identity: &Identity,
+    id: &str,
+) -> Result<Option<Room>, std::io::Error> {
+    Ok(
+        rooms::get(&*storage.state, id)?
+            .filter(|room| room.admits(identity.device_id().as_deref())),
+    )
+}
+
+/// Refuses member lists that name devices that are not registered.
+fn check_members(changes: &RoomChanges, devices: &DeviceRegistry) -> Result<(), String> {
+    match changes
+        .members
+        .iter()
+        .flatten()
+        .find(|id| devices.active(id).is_none())
+    {
+        Some(id) => Err(format!("No device {}", id)),
+        None => Ok(()),
+    }
+}
+
+async fn respond_room(room: &Room, status: StatusCode, responder: Responder) -> Finished {
+    let json = serde_json::to_string(room).unwrap_or_else(|_| "{}".to_string());
+    let response = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn respond_room_error(e: std::io::Error, responder: Responder) -> Finished {
+    match e.kind() {
+        ErrorKind::InvalidInput => bad_request(e.to_string(), responder).await,
+        ErrorKind::NotFound => no_such_room(responder).await,
+        ErrorKind::AlreadyExists => {
+            json_error(
+                StatusCode::CONFLICT,
+                "room-exists",
+                &e.to_string(),
+                responder,
+            )
+            .await
+        }
+        _ => {
+            eprintln!("Room update failed: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to update rooms".into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
+
+async fn api_list_rooms(storage: &Storage, identity: &Identity, responder: Responder) -> Finished {
+    let device = identity.device_id();
+    let rooms: Vec<Room> = rooms::list(&*storage.state)
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to load rooms: {}", e);
+            Vec::new()
+        })
+        .into_iter()
+        .filter(|room| room.admits(device.as_deref()))
+        .collect();
+    let json = serde_json::to_string(&rooms).unwrap_or_else(|_| "[]".to_string());
     let response = Response::builder()
-        .status(StatusCode::CREATED)
+        .status(StatusCode::OK)
         .header("Content-Type", "application/json")
         .body(json.into_body())
         .unwrap();
     responder.respond(response).await
 }
 
-fn load_messages() -> Result<Vec<Message>, std::io::Error> {
-    let file = std::fs::File::open("data/messages.jsonl");
-    match file {
-        Ok(file) => {
-            let reader = BufReader::new(file);
-            let mut messages = Vec::new();
-            for line in reader.lines() {
-                if let Ok(line) = line {
-                    if let Ok(message) = serde_json::from_str::<Message>(&line) {
-                        messages.push(message);
-                    }
-                }
-            }
-            Ok(messages)
-        }
-        Err(_) => Ok(Vec::new()), // Return empty vec if file doesn't exist
+async fn api_create_room(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let changes = match read_json_body::<RoomChanges>(&mut request).await {
+        Ok(changes) => changes,
+        Err(msg) => return bad_request(msg, responder).await,
+    };
+    if let Err(msg) = check_members(&changes, devices) {
+        return bad_request(msg, responder).await;
+    }
+    match rooms::create(
+        &*storage.state,
+        changes,
+        identity.device_id(),
+        chrono::Utc::now(),
+    ) {
+        Ok(room) => respond_room(&room, StatusCode::CREATED, responder).await,
+        Err(e) => respond_room_error(e, responder).await,
     }
 }
 
-fn save_message(message: &Message) -> Result<(), std::io::Error> {
-    // Ensure directory exists
-    std::fs::create_dir_all("data")?;
-
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("data/messages.jsonl")?;
+async fn api_get_room(
+    storage: &Storage,
+    identity: &Identity,
+    id: &str,
+    responder: Responder,
+) -> Finished {
+    match visible_room(storage, identity, id) {
+        Ok(Some(room)) => respond_room(&room, StatusCode::OK, responder).await,
+        Ok(None) => no_such_room(responder).await,
+        Err(e) => respond_room_error(e, responder).await,
+    }
+}
 
-    let json = serde_json::to_string(message)?;
-    writeln!(file, "{}", json)?;
-    Ok(())
+async fn api_edit_room(
+    storage: &Storage,
+    identity: &Identity,
+    devices: &DeviceRegistry,
+    id: &str,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let room = match visible_room(storage, identity, id) {
+        Ok(Some(room)) => room,
+        Ok(None) => return no_such_room(responder).await,
+        Err(e) => return respond_room_error(e, responder).await,
+    };
+    let changes = match read_json_body::<RoomChanges>(&mut request).await {
+        Ok(changes) => changes,
+        Err(msg) => return bad_request(msg, responder).await,
+    };
+    if changes.changes_access()
+        && !identity.owner
+        && !room.created_by_device(identity.device_id().as_deref())
+    {
+        return json_error(
+            StatusCode::FORBIDDEN,
+            "room-creator-required",
+            "Only the room's creator or a passphrase login can change its members or retention",
+            responder,
+        )
+        .await;
+    }
+    if let Err(msg) = check_members(&changes, devices) {
+        return bad_request(msg, responder).await;
+    }
+    match rooms::edit(&*storage.state, id, changes) {
+        Ok(room) => respond_room(&room, StatusCode::OK, responder).await,
+        Err(e) => respond_room_error(e, responder).await,
+    }
 }
 
-fn parse_since_parameter(query: &str) -> String {
-    for param in query.split('&') {
-        if let Some((key, value)) = param.split_once('=') {
-            if key == "since" {
-                return value.replace("%20", " ").replace("%3A", ":");
-            }
+async fn api_transfer_message(
+    storage: &Storage,
+    identity: &Identity,
+    id: &str,
+    mode: Transfer,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let target = match read_json_body::<TransferRequest>(&mut request).await {
+        Ok(transfer) => transfer.room,
+        Err(msg) => return bad_request(msg, responder).await,
+    };
+    match visible_room(storage, identity, &target) {
+        Ok(Some(_)) => {}
+        Ok(None) => return no_such_room(responder).await,
+        Err(e) => return respond_room_error(e, responder).await,
+    }
+    let device = identity.device_id();
+    let hidden = storage
+        .messages
+        .get(id)
+        .unwrap_or_default()
+        .is_some_and(|m| !m.visible_to(device.as_deref()));
+    if hidden {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(format!("No message {}", id).into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    }
+    let result = Storage::open_default_room(&target)
+        .and_then(|to| rooms::transfer(storage, &to, id, mode, chrono::Utc::now()));
+    match result {
+        Ok(message) => {
+            let json = serde_json::to_string(&message).unwrap_or_else(|_| "{}".to_string());
+            let response = Response::builder()
+                .status(StatusCode::CREATED)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let response = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(e.to_string().into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) if e.kind() == ErrorKind::InvalidInput => {
+            bad_request(e.to_string(), responder).await
+        }
+        Err(e) => {
+            eprintln!("Failed to transfer message {}: {}", id, e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to transfer message".into_body())
+                .unwrap();
+            responder.respond(response).await
         }
     }
-    "1970-01-01T00:00:00Z".to_string()
 }
 
-fn get_messages_since(since: &str) -> Result<Vec<Message>, std::io::Error> {
-    let all_messages = load_messages()?;
-
-    let since_time = match chrono::DateTime::parse_from_rfc3339(since) {
-        Ok(time) => time,
-        Err(_) => {
-            return Ok(all_messages);
+/// Serializes a segment operation's result, mapping refusals to 4xx.
+async fn respond_admin_result<T: serde::Serialize>(
+    result: Result<T, std::io::Error>,
+    operation: &str,
+    responder: Responder,
+) -> Finished {
+    let e = match result {
+        Ok(value) => {
+            let json = serde_json::to_string(&value).unwrap_or_else(|_| "{}".to_string());
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            return responder.respond(response).await;
         }
+        Err(e) => e,
     };
 
-    let filtered_messages: Vec<Message> = all_messages
-        .into_iter()
-        .filter(|msg| {
-            if let Ok(msg_time) = chrono::DateTime::parse_from_rfc3339(&msg.timestamp) {
-                msg_time > since_time
-            } else {
-                true
-            }
-        })
-        .collect();
+    let status = match e.kind() {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::InvalidInput | ErrorKind::Unsupported => StatusCode::CONFLICT,
+        _ => {
+            eprintln!("{} failed: {}", operation, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    let response = Response::builder()
+        .status(status)
+        .body(format!("{} failed: {}", operation, e).into_body())
+        .unwrap();
+    responder.respond(response).await
+}
 
-    Ok(filtered_messages)
+fn parse_since_parameter(query: &str) -> String {
+    for param in query.split('&') {
+        if let Some((key, value)) = param.split_once('=')
+            && key == "since"
+        {
+            return value.replace("%20", " ").replace("%3A", ":");
+        }
+    }
+    "1970-01-01T00:00:00Z".to_string()
 }
 
 async fn http_home(_request: Request<IncomingBody>, responder: Responder) -> Finished {
@@ -406,7 +2624,7 @@ async fn serve_asset(
 }
 
 fn get_content_type(filename: &str) -> Option<&'static str> {
-    let extension = filename.split('.').last()?;
+    let extension = filename.split('.').next_back()?;
     match extension.to_lowercase().as_str() {
         "html" => Some("text/html; charset=utf-8"),
         "css" => Some("text/css"),
@@ -423,7 +2641,18 @@ fn get_content_type(filename: &str) -> Option<&'static str> {
     }
 }
 
-fn parse_multipart_data(data: &[u8], boundary: &str) -> Result<(Vec<u8>, String, String), String> {
+struct MultipartUpload {
+    data: Vec<u8>,
+    filename: String,
+    sender: String,
+    /// From `recipients` fields, each holding one or more comma-separated
+    /// device ids.
+    recipients: Vec<String>,
+    /// From a `replyTo` field.
+    reply_to: Option<String>,
+}
+
+fn parse_multipart_data(data: &[u8], boundary: &str) -> Result<MultipartUpload, String> {
     let boundary_start = format!("--{}", boundary);
     let boundary_end = format!("--{}--", boundary);
 
@@ -432,6 +2661,8 @@ fn parse_multipart_data(data: &[u8], boundary: &str) -> Result<(Vec<u8>, String,
     let mut file_data = Vec::new();
     let mut filename = String::new();
     let mut sender = String::from("Unknown");
+    let mut recipients = Vec::new();
+    let mut reply_to = None;
 
     // Split by boundary markers
     let parts: Vec<&str> = data_str.split(&boundary_start).collect();
@@ -471,12 +2702,10 @@ fn parse_multipart_data(data: &[u8], boundary: &str) -> Result<(Vec<u8>, String,
 
             // Extract file data - need to work with bytes, not string
             // Find the start position in the original byte array
-            if let Some(body_start) = find_body_start_in_bytes(data, part) {
-                if let Some(body_end) =
-                    find_body_end_in_bytes(data, body_start, &boundary_start, &boundary_end)
-                {
-                    file_data = data[body_start..body_end].to_vec();
-                }
+            if let Some(body_start) = find_body_start_in_bytes(data, part)
+                && let Some(body_end) = find_body_end_in_bytes(data, body_start, &boundary_start)
+            {
+                file_data = data[body_start..body_end].to_vec();
             }
         } else if headers.contains("name=\"sender\"") {
             // Extract sender value
@@ -485,6 +2714,18 @@ fn parse_multipart_data(data: &[u8], boundary: &str) -> Result<(Vec<u8>, String,
                 .trim_end_matches(&boundary_end)
                 .trim()
                 .to_string();
+        } else if headers.contains("name=\"recipients\"") {
+            let value = body.trim().trim_end_matches(&boundary_end).trim();
+            recipients.extend(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|id| !id.is_empty())
+                    .map(str::to_string),
+            );
+        } else if headers.contains("name=\"replyTo\"") {
+            let value = body.trim().trim_end_matches(&boundary_end).trim();
+            reply_to = Some(value.to_string()).filter(|id| !id.is_empty());
         }
     }
 
@@ -496,7 +2737,13 @@ fn parse_multipart_data(data: &[u8], boundary: &str) -> Result<(Vec<u8>, String,
         return Err("No filename found".to_string());
     }
 
-    Ok((file_data, filename, sender))
+    Ok(MultipartUpload {
+        data: file_data,
+        filename,
+        sender,
+        recipients,
+        reply_to,
+    })
 }
 
 fn find_body_start_in_bytes(data: &[u8], part_str: &str) -> Option<usize> {
@@ -553,25 +2800,12 @@ fn find_body_start_in_bytes(data: &[u8], part_str: &str) -> Option<usize> {
     None
 }
 
-fn find_body_end_in_bytes(
-    data: &[u8],
-    start: usize,
-    boundary_start: &str,
-    _boundary_end: &str,
-) -> Option<usize> {
+/// Where the body starting at `start` ends: before the line break that
+/// precedes the next boundary, which the closing `--boundary--` also starts
+/// with.
+fn find_body_end_in_bytes(data: &[u8], start: usize, boundary_start: &str) -> Option<usize> {
     let search_data = &data[start..];
 
-    // Look for the next boundary
-    let end_boundary_bytes = format!("--{}--", boundary_start.trim_start_matches("--"))
-        .as_bytes()
-        .to_vec();
-
-    // First check for end boundary
-    if let Some(pos) = search_in_bytes(search_data, &end_boundary_bytes) {
-        return Some(start + pos);
-    }
-
-    // Then check for next part boundary (with leading CRLF or LF)
     let crlf_boundary = format!("\r\n{}", boundary_start);
     let lf_boundary = format!("\n{}", boundary_start);
 
@@ -601,68 +2835,683 @@ fn search_in_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     None
 }
 
-fn is_image_file(filename: &str) -> bool {
-    let extension = filename.split('.').last().unwrap_or("").to_lowercase();
-    matches!(
-        extension.as_str(),
-        "jpg" | "jpeg" | "png" | "gif" | "svg" | "webp" | "bmp" | "ico"
+async fn api_share_file(
+    storage: &Storage,
+    identity: &Identity,
+    file: &str,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    if storage.blobs.size(file).is_err() || upload_hidden(storage, identity, file) {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("File not found".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    }
+    let origin = request_origin(&request);
+    let mut body_data = Vec::new();
+    if let Err(e) = copy(
+        request.body_mut(),
+        &mut wstd::io::Cursor::new(&mut body_data),
+    )
+    .await
+    {
+        return bad_request(format!("Body read failed: {}", e), responder).await;
+    }
+    // The body is optional; without one the link lasts a week.
+    let options = if body_data.iter().all(u8::is_ascii_whitespace) {
+        ShareOptions::default()
+    } else {
+        match serde_json::from_slice::<ShareOptions>(&body_data) {
+            Ok(options) => options,
+            Err(e) => return bad_request(format!("Invalid request: {}", e), responder).await,
+        }
+    };
+
+    // Recipients get the name the file was uploaded under.
+    let filename = storage
+        .messages
+        .load()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|m| m.content == file)
+        .and_then(|m| m.filename)
+        .unwrap_or_else(|| file.to_string());
+
+    match share::create(
+        &*storage.state,
+        &storage.room,
+        file,
+        &filename,
+        identity.device_id(),
+        options,
+        chrono::Utc::now(),
+    ) {
+        Ok((link, token)) => {
+            let mut json = serde_json::to_value(link.info()).unwrap_or_default();
+            json["url"] = format!("{}{}{}", origin, share::SHARE_PATH, token).into();
+            let response = Response::builder()
+                .status(StatusCode::CREATED)
+                .header("Content-Type", "application/json")
+                .body(json.to_string().into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) if e.kind() == ErrorKind::InvalidInput => {
+            bad_request(e.to_string(), responder).await
+        }
+        Err(e) => {
+            eprintln!("Failed to create share link: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to create share link".into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
+
+async fn api_list_shares(storage: &Storage, responder: Responder) -> Finished {
+    let links = share::list(&*storage.state).unwrap_or_else(|e| {
+        eprintln!("Failed to load share links: {}", e);
+        Vec::new()
+    });
+    let infos: Vec<_> = links.iter().map(|l| l.info()).collect();
+    let json = serde_json::to_string(&infos).unwrap_or_else(|_| "[]".to_string());
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(json.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn api_revoke_share(storage: &Storage, id: &str, responder: Responder) -> Finished {
+    match share::revoke(&*storage.state, id, chrono::Utc::now()) {
+        Ok(link) => {
+            let json = serde_json::to_string(&link.info()).unwrap_or_else(|_| "{}".to_string());
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(json.into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let response = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(e.to_string().into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+        Err(e) => {
+            eprintln!("Failed to revoke share link: {}", e);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to revoke share link".into_body())
+                .unwrap();
+            responder.respond(response).await
+        }
+    }
+}
+
+/// The page a password-protected link shows instead of the file.
+fn share_password_form(wrong: bool) -> String {
+    format!(
+        "<!doctype html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width\"><title>Spore Box</title></head>\
+<body style=\"font-family:sans-serif;max-width:20rem;margin:4rem auto\"><form method=\"post\">\
+<p>This file is protected by a password.</p>{}\
+<input type=\"password\" name=\"password\" autofocus required> <button>Download</button></form></body></html>",
+        if wrong {
+            "<p style=\"color:#dc2626\">Wrong password.</p>"
+        } else {
+            ""
+        }
+    )
+}
+
+/// `Content-Disposition` that downloads as `filename`, whatever it contains.
+fn attachment_disposition(filename: &str) -> String {
+    let ascii: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii,
+        spore_box::url::percent_encode(filename)
     )
 }
 
-fn get_mime_type(filename: &str) -> &'static str {
-    let extension = filename.split('.').last().unwrap_or("").to_lowercase();
-    match extension.as_str() {
-        "png" => "image/png",
-        "jpg" | "jpeg" => "image/jpeg",
-        "gif" => "image/gif",
-        "svg" => "image/svg+xml",
-        "webp" => "image/webp",
-        "bmp" => "image/bmp",
-        "ico" => "image/x-icon",
-        "pdf" => "application/pdf",
-        "txt" => "text/plain",
-        "html" => "text/html",
-        "css" => "text/css",
-        "js" => "application/javascript",
-        "json" => "application/json",
-        "xml" => "application/xml",
-        "zip" => "application/zip",
-        "doc" => "application/msword",
-        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
-        "xls" => "application/vnd.ms-excel",
-        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
-        _ => "application/octet-stream",
-    }
-}
-
-async fn serve_uploaded_file(path: &str, responder: Responder) -> Finished {
+async fn serve_shared_file(
+    storage: &Storage,
+    mut request: Request<IncomingBody>,
+    responder: Responder,
+) -> Finished {
+    let token = request
+        .uri()
+        .path()
+        .trim_start_matches(share::SHARE_PATH)
+        .to_string();
+    let visitor = Visitor {
+        client: header_str(&request, "x-forwarded-for").map(str::to_string),
+        user_agent: header_str(&request, "user-agent").map(str::to_string),
+        download_key: header_str(&request, "cookie")
+            .and_then(|c| auth::cookie_value(c, share::DOWNLOAD_COOKIE))
+            .map(str::to_string),
+    };
+    let password = if request.method() == "POST" {
+        let mut body_data = Vec::new();
+        let _ = copy(
+            request.body_mut(),
+            &mut wstd::io::Cursor::new(&mut body_data),
+        )
+        .await;
+        spore_box::url::query_param(&String::from_utf8_lossy(&body_data), "password")
+    } else {
+        None
+    };
+
+    let range = header_str(&request, "range").map(str::to_string);
+    let access = share::open(
+        &*storage.state,
+        &token,
+        password.as_deref(),
+        visitor,
+        range_resumes(range.as_deref()),
+        chrono::Utc::now(),
+    );
+    let (status, body) = match access {
+        Ok(Access::Granted {
+            room,
+            file,
+            filename,
+            download_key,
+        }) => {
+            let mut response = Response::builder()
+                .header("Accept-Ranges", "bytes")
+                .header(
+                    "Content-Type",
+                    get_content_type(&file).unwrap_or("application/octet-stream"),
+                )
+                .header("Content-Disposition", attachment_disposition(&filename))
+                .header("Cache-Control", "no-store")
+                .header("X-Content-Type-Options", "nosniff");
+            if let Some(key) = download_key {
+                let cookie = share::download_cookie(&token, &key, chrono::Utc::now());
+                response = response.header("Set-Cookie", cookie);
+            }
+            let room_storage;
+            let storage = if room == storage.room {
+                storage
+            } else {
+                room_storage = match Storage::open_default_room(&room) {
+                    Ok(room_storage) => room_storage,
+                    Err(e) => {
+                        eprintln!("Failed to open room {}: {}", room, e);
+                        let response = Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body("Storage unavailable".into_body())
+                            .unwrap();
+                        return responder.respond(response).await;
+                    }
+                };
+                &room_storage
+            };
+            return send_blob(storage, &file, range.as_deref(), response, responder).await;
+        }
+        Ok(Access::Refused(
+            outcome @ (AccessOutcome::PasswordRequired | AccessOutcome::WrongPassword),
+        )) => {
+            let response = Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .header("Cache-Control", "no-store")
+                .body(share_password_form(outcome == AccessOutcome::WrongPassword).into_body())
+                .unwrap();
+            return responder.respond(response).await;
+        }
+        Ok(Access::Refused(AccessOutcome::Expired)) => (StatusCode::GONE, "This link has expired"),
+        Ok(Access::Refused(AccessOutcome::Exhausted)) => {
+            (StatusCode::GONE, "This link has been used up")
+        }
+        Ok(Access::Refused(_)) => (StatusCode::GONE, "This link has been revoked"),
+        Ok(Access::Unknown) => (StatusCode::NOT_FOUND, "Unknown link"),
+        Err(e) => {
+            eprintln!("Failed to open share link: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Storage unavailable")
+        }
+    };
+    let response = Response::builder()
+        .status(status)
+        .body(body.into_body())
+        .unwrap();
+    responder.respond(response).await
+}
+
+async fn serve_uploaded_file(
+    storage: &Storage,
+    identity: &Identity,
+    path: &str,
+    range: Option<&str>,
+    responder: Responder,
+) -> Finished {
     // Extract filename from path like "/api/files/filename.ext"
     let stored_filename = path.strip_prefix("/api/files/").unwrap_or("");
-    let file_path = format!("data/uploads/{}", stored_filename);
+    if upload_hidden(storage, identity, stored_filename) {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("File not found".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    }
 
-    match std::fs::read(&file_path) {
-        Ok(file_data) => {
-            let mut response = Response::builder().status(StatusCode::OK);
+    let mut response = Response::builder().header("Accept-Ranges", "bytes");
 
-            // Set content type based on file extension
-            if let Some(content_type) = get_content_type(stored_filename) {
-                response = response.header("Content-Type", content_type);
-            }
+    // Set content type based on file extension
+    if let Some(content_type) = get_content_type(stored_filename) {
+        response = response.header("Content-Type", content_type);
+    }
 
-            // For non-image files, add download header
-            if !is_image_file(stored_filename) {
-                response = response.header("Content-Disposition", "attachment");
-            }
+    // For non-image files, add download header
+    if !is_image_file(stored_filename) {
+        response = response.header("Content-Disposition", "attachment");
+    }
 
-            let response = response.body(file_data.into_body()).unwrap();
-            responder.respond(response).await
+    send_blob(storage, stored_filename, range, response, responder).await
+}
+
+/// Bytes of an upload read and written per step, a whole number of sealed
+/// chunks so large files never sit in memory at once.
+const STREAM_WINDOW: u64 = 16 * crypt::CHUNK_LEN;
+
+/// Whether a `Range` header asks for a part from a given offset past byte 0,
+/// which continues a download rather than starting one. A suffix such as
+/// `bytes=-100` is the end of the file for whoever asks, so it never does.
+/// Headers `byte_range` ignores ask for the whole body.
+fn range_resumes(header: Option<&str>) -> bool {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return false;
+    };
+    if spec.contains(',') {
+        return false;
+    }
+    match spec.split_once('-') {
+        Some((first, _)) => first.parse::<u64>().is_ok_and(|first| first > 0),
+        None => false,
+    }
+}
+
+/// Parses a `Range` header against a body of `size` bytes. `None` means the
+/// whole body is sent: no header, a unit other than bytes, several ranges
+/// or a malformed one. `Some(Err(()))` cannot be satisfied.
+fn byte_range(header: Option<&str>, size: u64) -> Option<Result<Range<u64>, ()>> {
+    let spec = header?.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let range = if first.is_empty() {
+        let suffix: u64 = last.parse().ok()?;
+        size.saturating_sub(suffix)..size
+    } else {
+        let first: u64 = first.parse().ok()?;
+        let end = match last {
+            "" => size,
+            last => last.parse::<u64>().ok()?.checked_add(1)?.min(size),
+        };
+        if end <= first && first < size {
+            return None;
         }
-        Err(_) => {
+        first..end
+    };
+    Some(if range.start < range.end {
+        Ok(range)
+    } else {
+        Err(())
+    })
+}
+
+/// Streams blob `name`, or the part `range` asks for, with the headers in
+/// `response`.
+async fn send_blob(
+    storage: &Storage,
+    name: &str,
+    range: Option<&str>,
+    response: wstd::http::response::Builder,
+    responder: Responder,
+) -> Finished {
+    let Ok(size) = storage.blobs.size(name) else {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("File not found".into_body())
+            .unwrap();
+        return responder.respond(response).await;
+    };
+    let (response, range) = match byte_range(range, size) {
+        None => (response.status(StatusCode::OK), 0..size),
+        Some(Ok(range)) => (
+            response.status(StatusCode::PARTIAL_CONTENT).header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+            ),
+            range,
+        ),
+        Some(Err(())) => {
             let response = Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body("File not found".into_body())
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("Content-Range", format!("bytes */{}", size))
+                .body(empty())
                 .unwrap();
-            responder.respond(response).await
+            return responder.respond(response).await;
+        }
+    };
+
+    // Read the first window before committing to a status, so an unreadable
+    // upload still gets a proper error.
+    let mut offset = range.start;
+    let window = |offset: u64| {
+        let end = ((offset / STREAM_WINDOW + 1) * STREAM_WINDOW).min(range.end);
+        match storage.blobs.get_range(name, offset..end) {
+            Ok(data) if data.len() as u64 == end - offset => Ok(data),
+            Ok(_) => Err(format!("{} changed while being read", name)),
+            Err(e) => Err(format!("Failed to read {}: {}", name, e)),
+        }
+    };
+    let first = match window(offset) {
+        Ok(data) => data,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            let response = Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to read file".into_body())
+                .unwrap();
+            return responder.respond(response).await;
+        }
+    };
+
+    let response = response
+        .header("Content-Length", range.end - range.start)
+        .body(BodyForthcoming)
+        .unwrap();
+    let mut body = responder.start_response(response);
+    let mut data = first;
+    loop {
+        if let Err(e) = body.write_all(&data).await {
+            eprintln!("Failed to send {}: {}", name, e);
+            return Finished::fail(body);
+        }
+        offset += data.len() as u64;
+        if offset >= range.end {
+            return Finished::finish(body, Ok(()), None);
         }
+        data = match window(offset) {
+            Ok(data) => data,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                return Finished::fail(body);
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, device: Option<&str>, recipients: &[&str]) -> Message {
+        Message {
+            id: id.to_string(),
+            content: format!("{}.txt", id),
+            sender: "tester".to_string(),
+            device_id: device.map(str::to_string),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            msg_type: "file".to_string(),
+            filename: Some(format!("{}.txt", id)),
+            file_size: None,
+            mime_type: None,
+            broken: None,
+            recipients: recipients.iter().map(|r| r.to_string()).collect(),
+            reply_to: None,
+            event: None,
+        }
+    }
+
+    fn register(storage: &Storage, name: &str) -> Identity {
+        let changes = DeviceChanges {
+            name: Some(name.to_string()),
+            ..Default::default()
+        };
+        let (device, _token) =
+            device::register(&*storage.state, changes, chrono::Utc::now()).unwrap();
+        Identity {
+            device: Some(device),
+            owner: false,
+        }
+    }
+
+    #[test]
+    fn replies_need_a_visible_message() {
+        let storage = Storage::in_memory();
+        let alice = register(&storage, "alice");
+        let bob = register(&storage, "bob");
+        let alice_id = alice.device_id().unwrap();
+        storage
+            .messages
+            .append(&message("open", Some(&alice_id), &[]))
+            .unwrap();
+        storage
+            .messages
+            .append(&message("note-to-self", Some(&alice_id), &[&alice_id]))
+            .unwrap();
+
+        assert!(check_reply_to(&storage, &bob, None).is_ok());
+        assert!(check_reply_to(&storage, &bob, Some("open")).is_ok());
+        assert!(check_reply_to(&storage, &alice, Some("note-to-self")).is_ok());
+        assert!(check_reply_to(&storage, &bob, Some("note-to-self")).is_err());
+        assert!(check_reply_to(&storage, &bob, Some("missing")).is_err());
+    }
+
+    #[test]
+    fn direct_uploads_are_hidden_from_other_devices() {
+        let storage = Storage::in_memory();
+        let alice = register(&storage, "alice");
+        let bob = register(&storage, "bob");
+        let carol = register(&storage, "carol");
+        let bob_id = bob.device_id().unwrap();
+        storage
+            .messages
+            .append(&message("dm", alice.device_id().as_deref(), &[&bob_id]))
+            .unwrap();
+
+        assert!(!upload_hidden(&storage, &alice, "dm.txt"));
+        assert!(!upload_hidden(&storage, &bob, "dm.txt"));
+        assert!(upload_hidden(&storage, &carol, "dm.txt"));
+        assert!(upload_hidden(&storage, &Identity::default(), "dm.txt"));
+        assert!(!upload_hidden(&storage, &carol, "other.txt"));
+        assert!(!upload_hidden(&storage, &Identity::default(), "other.txt"));
+    }
+
+    #[test]
+    fn recipients_must_be_active_devices() {
+        let storage = Storage::in_memory();
+        let bob = register(&storage, "bob");
+        let devices = DeviceRegistry::load(&*storage.state).unwrap();
+
+        assert!(check_recipients(&[bob.device_id().unwrap()], &devices).is_ok());
+        assert_eq!(
+            check_recipients(&["nobody".to_string()], &devices),
+            Err("No device nobody".to_string())
+        );
+    }
+
+    #[test]
+    fn multipart_uploads_keep_binary_data() {
+        let body = b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a.bin\"\r\n\
+Content-Type: application/octet-stream\r\n\r\n\
+\x00\x01\r\n\xff\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"sender\"\r\n\r\n\
+tester\r\n\
+--XyZ--\r\n";
+        let upload = parse_multipart_data(body, "XyZ").unwrap();
+        assert_eq!(upload.filename, "a.bin");
+        assert_eq!(upload.sender, "tester");
+        assert_eq!(upload.data, b"\x00\x01\r\n\xff");
+
+        let last = b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"b.txt\"\r\n\r\n\
+hello\r\n\
+--XyZ--\r\n";
+        assert_eq!(parse_multipart_data(last, "XyZ").unwrap().data, b"hello");
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(byte_range(None, 10), None);
+        assert_eq!(byte_range(Some("bytes=2-4"), 10), Some(Ok(2..5)));
+        assert_eq!(byte_range(Some("bytes=-3"), 10), Some(Ok(7..10)));
+        assert_eq!(byte_range(Some("bytes=8-"), 10), Some(Ok(8..10)));
+        assert_eq!(byte_range(Some("bytes=20-"), 10), Some(Err(())));
+        assert_eq!(byte_range(Some("bytes=0-1,4-5"), 10), None);
+
+        assert!(!range_resumes(None));
+        assert!(!range_resumes(Some("bytes=0-")));
+        assert!(!range_resumes(Some("bytes=0-1,4-5")));
+        assert!(range_resumes(Some("bytes=100-")));
+        assert!(range_resumes(Some("bytes=100-199")));
+        assert!(!range_resumes(Some("bytes=-100")));
+    }
+
+    #[test]
+    fn exhausted_links_refuse_suffix_ranges_from_the_same_visitor() {
+        let storage = Storage::in_memory();
+        let now = chrono::Utc::now();
+        let options = ShareOptions {
+            max_downloads: Some(1),
+            ..Default::default()
+        };
+        let (_, token) = share::create(
+            &*storage.state,
+            DEFAULT_ROOM,
+            "f.mp4",
+            "f.mp4",
+            None,
+            options,
+            now,
+        )
+        .unwrap();
+        let visitor = || Visitor {
+            client: Some("10.0.0.1".to_string()),
+            user_agent: Some("player".to_string()),
+            download_key: None,
+        };
+        let open = |range: &str| {
+            let resuming = range_resumes(Some(range));
+            share::open(&*storage.state, &token, None, visitor(), resuming, now).unwrap()
+        };
+
+        assert!(matches!(open("bytes=0-"), Access::Granted { .. }));
+        for range in ["bytes=-100", "bytes=-0", "bytes=100-"] {
+            assert!(
+                matches!(open(range), Access::Refused(AccessOutcome::Exhausted)),
+                "{range}"
+            );
+        }
+    }
+
+    #[test]
+    fn clipboard_bodies_become_text_or_images() {
+        let alice = register(&Storage::in_memory(), "alice");
+        let now = chrono::Utc::now();
+
+        let (text, blob) = clipboard_message(&alice, b"hello".to_vec(), "", "", now).unwrap();
+        assert_eq!(text.msg_type, "text");
+        assert_eq!(text.content, "hello");
+        assert_eq!(text.sender, "alice");
+        assert_eq!(text.device_id, alice.device_id());
+        assert!(blob.is_none());
+
+        // The body decides, whatever the content type claims.
+        let png = b"\x89PNG\r\n\x1a\nrest".to_vec();
+        let (image, blob) = clipboard_message(&alice, png.clone(), "text/plain", "", now).unwrap();
+        assert_eq!(image.msg_type, "image");
+        assert!(image.content.ends_with(".png"));
+        assert_eq!(image.mime_type.as_deref(), Some("image/png"));
+        assert_eq!(image.file_size, Some(png.len() as u64));
+        assert!(image.filename.unwrap().starts_with("clipboard-"));
+        assert_eq!(blob, Some(png));
+
+        // SVG is text, so only its content type marks it as an image.
+        let svg = b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>".to_vec();
+        let (image, blob) =
+            clipboard_message(&alice, svg.clone(), "image/svg+xml; charset=utf-8", "", now)
+                .unwrap();
+        assert_eq!(image.msg_type, "image");
+        assert!(image.content.ends_with(".svg"));
+        assert_eq!(blob, Some(svg.clone()));
+        let (text, _) = clipboard_message(&alice, svg, "text/plain", "", now).unwrap();
+        assert_eq!(text.msg_type, "text");
+
+        assert!(clipboard_message(&alice, vec![0xff, 0xfe, 0x00], "", "", now).is_err());
+        assert!(clipboard_message(&alice, Vec::new(), "", "", now).is_err());
+
+        let (text, _) =
+            clipboard_message(&Identity::default(), b"hi".to_vec(), "", "sender=cli", now).unwrap();
+        assert_eq!(text.sender, "cli");
+        assert_eq!(text.device_id, None);
+    }
+
+    #[test]
+    fn latest_clipboard_is_the_newest_visible_match() {
+        let storage = Storage::in_memory();
+        let alice = register(&storage, "alice");
+        let bob = register(&storage, "bob");
+        let alice_id = alice.device_id().unwrap();
+        let bob_id = bob.device_id().unwrap();
+        let at = |mut message: Message, sender: &str, msg_type: &str, timestamp: &str| {
+            message.sender = sender.to_string();
+            message.msg_type = msg_type.to_string();
+            message.timestamp = timestamp.to_string();
+            storage.messages.append(&message).unwrap();
+        };
+        at(
+            message("image", Some(&alice_id), &[]),
+            "alice",
+            "image",
+            "2024-01-01T12:00:00Z",
+        );
+        // Appended later, but stamped earlier in another zone.
+        at(
+            message("text", Some(&bob_id), &[]),
+            "bob",
+            "text",
+            "2024-01-01T12:30:00+01:00",
+        );
+        at(
+            message("dm", Some(&alice_id), &[&alice_id]),
+            "alice",
+            "text",
+            "2024-01-01T13:00:00Z",
+        );
+        let latest = |identity: &Identity, query: &str| {
+            latest_clipboard(&storage, identity, query).map(|m| m.id)
+        };
+
+        assert_eq!(latest(&bob, "").as_deref(), Some("image"));
+        assert_eq!(latest(&alice, "").as_deref(), Some("dm"));
+        assert_eq!(latest(&bob, "type=text").as_deref(), Some("text"));
+        assert_eq!(latest(&bob, "type=file"), None);
+        assert_eq!(latest(&bob, "from=bob").as_deref(), Some("text"));
+        assert_eq!(
+            latest(&bob, &format!("from={}", alice_id)).as_deref(),
+            Some("image")
+        );
+        assert_eq!(latest(&bob, "from=carol"), None);
     }
 }
@@ -0,0 +1,101 @@
+use crate::event::Event;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub id: String,
+    pub content: String,
+    pub sender: String,
+    /// Registered device that sent this message; its current name is shown
+    /// in place of `sender`.
+    #[serde(rename = "deviceId", default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    pub timestamp: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub filename: Option<String>,
+    #[serde(rename = "fileSize")]
+    pub file_size: Option<u64>,
+    #[serde(rename = "mimeType")]
+    pub mime_type: Option<String>,
+    /// Set by fsck when the upload behind this message is gone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broken: Option<String>,
+    /// Devices the message is addressed to; empty when it is for everyone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+    /// The message this one answers.
+    #[serde(rename = "replyTo", default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Set on records of type `event`, which change another message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+}
+
+impl Message {
+    /// Whether the message is addressed to `device`, directly or by being
+    /// for everyone.
+    pub fn is_for(&self, device: Option<&str>) -> bool {
+        self.recipients.is_empty()
+            || device.is_some_and(|device| self.recipients.iter().any(|r| r == device))
+    }
+
+    /// Whether `device` may see the message: it is addressed to the device
+    /// or was sent by it. Requests without a device, like HTTP Basic with
+    /// the passphrase or any request with login turned off, only see
+    /// messages for everyone.
+    pub fn visible_to(&self, device: Option<&str>) -> bool {
+        match device {
+            Some(device) => self.is_for(Some(device)) || self.device_id.as_deref() == Some(device),
+            None => self.is_for(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(device: Option<&str>, recipients: &[&str]) -> Message {
+        Message {
+            id: "m".to_string(),
+            content: "hi".to_string(),
+            sender: "tester".to_string(),
+            device_id: device.map(str::to_string),
+            timestamp: "2024-01-01T00:00:00Z".to_string(),
+            msg_type: "text".to_string(),
+            filename: None,
+            file_size: None,
+            mime_type: None,
+            broken: None,
+            recipients: recipients.iter().map(|r| r.to_string()).collect(),
+            reply_to: None,
+            event: None,
+        }
+    }
+
+    #[test]
+    fn messages_for_everyone_are_visible_to_everyone() {
+        let message = message(Some("alice"), &[]);
+        assert!(message.is_for(None));
+        assert!(message.visible_to(None));
+        assert!(message.visible_to(Some("bob")));
+    }
+
+    #[test]
+    fn direct_messages_are_visible_to_recipients_and_sender() {
+        let message = message(Some("alice"), &["bob"]);
+        assert!(message.visible_to(Some("alice")));
+        assert!(message.visible_to(Some("bob")));
+        assert!(!message.visible_to(Some("carol")));
+        assert!(message.is_for(Some("bob")));
+        assert!(!message.is_for(Some("alice")));
+    }
+
+    #[test]
+    fn requests_without_a_device_do_not_see_direct_messages() {
+        assert!(!message(Some("alice"), &["bob"]).visible_to(None));
+        assert!(!message(None, &["bob"]).visible_to(None));
+        assert!(!message(None, &["bob"]).is_for(None));
+    }
+}
@@ -0,0 +1,807 @@
+//! Optional OpenID Connect login against a self-hosted identity provider.
+//!
+//! `/auth/login` sends the browser to the provider with an authorization
+//! code request protected by PKCE, a `state` and a `nonce`, which are kept
+//! in the `oidc` state document until `/auth/callback` uses them up. The
+//! callback exchanges the code for an ID token, checks its signature against
+//! the provider's JWKS (RS256 or ES256) and its issuer, audience, expiry and
+//! nonce, and lets the user in if a claim matches `SPORE_OIDC_ALLOW`.
+//!
+//! Discovery, token and JWKS requests go out through `wasi:http`, so they
+//! need no other services; every login fetches them afresh.
+
+use crate::state::{self, StateStore};
+use crate::url::{percent_encode, query_param};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::io::Error;
+use wstd::http::{Client, IntoBody, Request};
+
+pub const ISSUER_VAR: &str = "SPORE_OIDC_ISSUER";
+pub const CLIENT_ID_VAR: &str = "SPORE_OIDC_CLIENT_ID";
+pub const CLIENT_SECRET_VAR: &str = "SPORE_OIDC_CLIENT_SECRET";
+pub const ALLOW_VAR: &str = "SPORE_OIDC_ALLOW";
+pub const SCOPES_VAR: &str = "SPORE_OIDC_SCOPES";
+pub const REDIRECT_URI_VAR: &str = "SPORE_OIDC_REDIRECT_URI";
+
+pub const LOGIN_PATH: &str = "/auth/login";
+pub const CALLBACK_PATH: &str = "/auth/callback";
+
+const PENDING: &str = "oidc";
+const DEFAULT_SCOPES: &str = "openid profile email";
+/// Allowed clock difference between us and the provider.
+const LEEWAY_SECONDS: i64 = 60;
+
+fn login_lifetime() -> Duration {
+    Duration::minutes(10)
+}
+
+/// A `claim=value` entry of `SPORE_OIDC_ALLOW`. It matches a string claim
+/// equal to the value or an array claim containing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClaimRule {
+    pub claim: String,
+    pub value: String,
+}
+
+impl ClaimRule {
+    fn matches(&self, claims: &Map<String, Value>) -> bool {
+        // An address the provider has not verified proves nothing.
+        if self.claim == "email" && claims.get("email_verified") == Some(&Value::Bool(false)) {
+            return false;
+        }
+        match claims.get(&self.claim) {
+            Some(Value::String(s)) => *s == self.value,
+            Some(Value::Array(values)) => values.iter().any(|v| v.as_str() == Some(&self.value)),
+            Some(Value::Bool(b)) => b.to_string() == self.value,
+            _ => false,
+        }
+    }
+}
+
+/// Parses a comma or whitespace separated list of `claim=value` rules,
+/// e.g. `email=alice@example.com, groups=family`.
+pub fn parse_rules(text: &str) -> Result<Vec<ClaimRule>, String> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| match rule.split_once('=') {
+            Some((claim, value)) if !claim.is_empty() && !value.is_empty() => Ok(ClaimRule {
+                claim: claim.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(format!("{:?} is not claim=value", rule)),
+        })
+        .collect()
+}
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    client_secret: String,
+    pub scopes: String,
+    /// Fixed callback URL, for when the box sits behind a proxy that hides
+    /// the host the browser used. Otherwise it is derived per request.
+    pub redirect_uri: Option<String>,
+    pub allow: Vec<ClaimRule>,
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+impl OidcConfig {
+    /// `Ok(None)` when `SPORE_OIDC_ISSUER` is not set; an error when it is
+    /// but the rest of the configuration is missing or malformed.
+    pub fn from_env() -> Result<Option<OidcConfig>, String> {
+        let Some(issuer) = env_var(ISSUER_VAR) else {
+            return Ok(None);
+        };
+        let required = |name: &str| env_var(name).ok_or_else(|| format!("{} is not set", name));
+        let allow = parse_rules(&env_var(ALLOW_VAR).unwrap_or_default())
+            .map_err(|e| format!("{}: {}", ALLOW_VAR, e))?;
+        if allow.is_empty() {
+            eprintln!(
+                "{} is empty, so nobody can sign in with OpenID Connect",
+                ALLOW_VAR
+            );
+        }
+        Ok(Some(OidcConfig {
+            issuer,
+            client_id: required(CLIENT_ID_VAR)?,
+            client_secret: required(CLIENT_SECRET_VAR)?,
+            scopes: env_var(SCOPES_VAR).unwrap_or_else(|| DEFAULT_SCOPES.to_string()),
+            redirect_uri: env_var(REDIRECT_URI_VAR),
+            allow,
+        }))
+    }
+
+    pub fn new(
+        issuer: &str,
+        client_id: &str,
+        client_secret: &str,
+        allow: Vec<ClaimRule>,
+    ) -> OidcConfig {
+        OidcConfig {
+            issuer: issuer.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            scopes: DEFAULT_SCOPES.to_string(),
+            redirect_uri: None,
+            allow,
+        }
+    }
+
+    /// Key material for signing sessions, which must stay secret.
+    pub(crate) fn client_secret(&self) -> &str {
+        &self.client_secret
+    }
+
+    fn discovery_url(&self) -> String {
+        format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer.trim_end_matches('/')
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    /// The callback's `state` is unknown, used or expired.
+    UnknownLogin,
+    /// The provider refused the login, e.g. because the user cancelled.
+    Provider(String),
+    /// The provider could not be reached or answered nonsense.
+    Http(String),
+    InvalidToken(String),
+    /// The user signed in but no `SPORE_OIDC_ALLOW` rule matches.
+    NotAllowed(String),
+    Storage(Error),
+}
+
+impl std::fmt::Display for OidcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OidcError::UnknownLogin => write!(f, "Unknown or expired login, please start again"),
+            OidcError::Provider(msg) => write!(f, "The identity provider refused: {}", msg),
+            OidcError::Http(msg) => write!(f, "Identity provider request failed: {}", msg),
+            OidcError::InvalidToken(msg) => write!(f, "Invalid ID token: {}", msg),
+            OidcError::NotAllowed(user) => write!(f, "{} may not use this box", user),
+            OidcError::Storage(e) => write!(f, "Storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OidcError {}
+
+impl From<Error> for OidcError {
+    fn from(e: Error) -> OidcError {
+        OidcError::Storage(e)
+    }
+}
+
+fn invalid_token(msg: impl Into<String>) -> OidcError {
+    OidcError::InvalidToken(msg.into())
+}
+
+/// The parts of the provider's discovery document we use.
+#[derive(Deserialize, Debug)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    #[serde(default)]
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    #[serde(rename = "use")]
+    pub key_use: Option<String>,
+    pub n: Option<String>,
+    pub e: Option<String>,
+    pub crv: Option<String>,
+    pub x: Option<String>,
+    pub y: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PendingLogins {
+    logins: Vec<PendingLogin>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PendingLogin {
+    state: String,
+    nonce: String,
+    code_verifier: String,
+    redirect_uri: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_name: Option<String>,
+    expires_at: String,
+}
+
+impl PendingLogins {
+    fn drop_expired(&mut self, now: DateTime<Utc>) {
+        self.logins.retain(|l| {
+            DateTime::parse_from_rfc3339(&l.expires_at).is_ok_and(|expiry| expiry > now)
+        });
+    }
+}
+
+/// Someone the provider vouched for and `SPORE_OIDC_ALLOW` lets in.
+#[derive(Debug)]
+pub struct OidcUser {
+    pub subject: String,
+    /// `preferred_username`, `name` or `email`, whichever comes first.
+    pub name: String,
+    /// The device the browser asked to sign in as, from `/auth/login`.
+    pub device_id: Option<String>,
+    pub device_name: Option<String>,
+}
+
+fn random_token() -> Result<String, Error> {
+    let mut bytes = [0u8; 32];
+    getrandom::fill(&mut bytes).map_err(|e| Error::other(e.to_string()))?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// RFC 7636 S256 challenge for `verifier`.
+pub fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, OidcError> {
+    let request = Request::get(url)
+        .header("Accept", "application/json")
+        .body(wstd::io::empty())
+        .map_err(|e| OidcError::Http(format!("{}: {}", url, e)))?;
+    let mut response = Client::new()
+        .send(request)
+        .await
+        .map_err(|e| OidcError::Http(format!("{}: {}", url, e)))?;
+    if !response.status().is_success() {
+        return Err(OidcError::Http(format!(
+            "{} answered {}",
+            url,
+            response.status()
+        )));
+    }
+    response
+        .body_mut()
+        .json()
+        .await
+        .map_err(|e| OidcError::Http(format!("{}: {}", url, e)))
+}
+
+pub async fn discover(config: &OidcConfig) -> Result<Discovery, OidcError> {
+    let discovery: Discovery = get_json(&config.discovery_url()).await?;
+    // OpenID Connect Discovery 1.0, section 4.3.
+    if discovery.issuer != config.issuer {
+        return Err(OidcError::Http(format!(
+            "discovery document is for issuer {}, not {}",
+            discovery.issuer, config.issuer
+        )));
+    }
+    Ok(discovery)
+}
+
+/// Starts a login and returns the provider URL to send the browser to.
+/// `redirect_uri` is where the provider should send it back.
+pub async fn start(
+    config: &OidcConfig,
+    store: &dyn StateStore,
+    redirect_uri: &str,
+    device_id: Option<String>,
+    device_name: Option<String>,
+    now: DateTime<Utc>,
+) -> Result<String, OidcError> {
+    let discovery = discover(config).await?;
+    let pending = PendingLogin {
+        state: random_token()?,
+        nonce: random_token()?,
+        code_verifier: random_token()?,
+        redirect_uri: redirect_uri.to_string(),
+        device_id,
+        device_name,
+        expires_at: (now + login_lifetime()).to_rfc3339(),
+    };
+
+    let separator = if discovery.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let url = format!(
+        "{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+        discovery.authorization_endpoint,
+        separator,
+        percent_encode(&config.client_id),
+        percent_encode(redirect_uri),
+        percent_encode(&config.scopes),
+        pending.state,
+        pending.nonce,
+        code_challenge(&pending.code_verifier),
+    );
+
+    state::update(store, PENDING, |logins: &mut PendingLogins| {
+        logins.drop_expired(now);
+        logins.logins.push(pending);
+        Ok(())
+    })?;
+    Ok(url)
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Exchanges an authorization code for the ID token.
+async fn redeem_code(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    code: &str,
+    pending: &PendingLogin,
+) -> Result<String, OidcError> {
+    let mut form = format!(
+        "grant_type=authorization_code&code={}&redirect_uri={}&code_verifier={}&client_id={}",
+        percent_encode(code),
+        percent_encode(&pending.redirect_uri),
+        pending.code_verifier,
+        percent_encode(&config.client_id),
+    );
+    let methods = &discovery.token_endpoint_auth_methods_supported;
+    // client_secret_basic is the default when the provider lists nothing.
+    let basic = methods.is_empty() || methods.iter().any(|m| m == "client_secret_basic");
+    let mut request = Request::post(&discovery.token_endpoint)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "application/json");
+    if basic {
+        let credentials = format!(
+            "{}:{}",
+            percent_encode(&config.client_id),
+            percent_encode(&config.client_secret)
+        );
+        request = request.header(
+            "Authorization",
+            format!("Basic {}", STANDARD.encode(credentials)),
+        );
+    } else {
+        form.push_str(&format!(
+            "&client_secret={}",
+            percent_encode(&config.client_secret)
+        ));
+    }
+
+    let url = &discovery.token_endpoint;
+    let request = request
+        .body(form.into_body())
+        .map_err(|e| OidcError::Http(format!("{}: {}", url, e)))?;
+    let mut response = Client::new()
+        .send(request)
+        .await
+        .map_err(|e| OidcError::Http(format!("{}: {}", url, e)))?;
+    let status = response.status();
+    let body = response
+        .body_mut()
+        .bytes()
+        .await
+        .map_err(|e| OidcError::Http(format!("{}: {}", url, e)))?;
+    if !status.is_success() {
+        return Err(match serde_json::from_slice::<TokenError>(&body) {
+            Ok(e) => OidcError::Provider(e.error_description.unwrap_or(e.error)),
+            Err(_) => OidcError::Http(format!("{} answered {}", url, status)),
+        });
+    }
+    serde_json::from_slice::<TokenResponse>(&body)
+        .map(|t| t.id_token)
+        .map_err(|e| OidcError::Http(format!("{}: {}", url, e)))
+}
+
+/// Finishes the login that issued `state`, given the callback's query
+/// string, and returns the user if they are allowed in.
+pub async fn finish(
+    config: &OidcConfig,
+    store: &dyn StateStore,
+    query: &str,
+    now: DateTime<Utc>,
+) -> Result<OidcUser, OidcError> {
+    let param = |key: &str| query_param(query, key);
+    let state_param = param("state").ok_or(OidcError::UnknownLogin)?;
+    let pending = state::update(store, PENDING, |logins: &mut PendingLogins| {
+        logins.drop_expired(now);
+        Ok(logins
+            .logins
+            .iter()
+            .position(|l| l.state == state_param)
+            .map(|index| logins.logins.remove(index)))
+    })?
+    .ok_or(OidcError::UnknownLogin)?;
+
+    if let Some(error) = param("error") {
+        return Err(OidcError::Provider(
+            param("error_description").unwrap_or(error),
+        ));
+    }
+    let code = param("code").ok_or_else(|| OidcError::Provider("no code".to_string()))?;
+
+    let discovery = discover(config).await?;
+    let id_token = redeem_code(config, &discovery, &code, &pending).await?;
+    let jwks: Jwks = get_json(&discovery.jwks_uri).await?;
+    let claims = validate_id_token(&id_token, &jwks, config, &pending.nonce, now)?;
+    let user = allowed_user(&claims, &config.allow)?;
+    Ok(OidcUser {
+        device_id: pending.device_id,
+        device_name: pending.device_name,
+        ..user
+    })
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>, OidcError> {
+    URL_SAFE_NO_PAD
+        .decode(segment.trim_end_matches('='))
+        .map_err(|e| invalid_token(e.to_string()))
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+fn verify_signature(
+    alg: &str,
+    key: &Jwk,
+    signing_input: &[u8],
+    signature: &[u8],
+) -> Result<(), OidcError> {
+    let field = |value: &Option<String>, name: &str| {
+        value
+            .as_deref()
+            .ok_or_else(|| invalid_token(format!("key without {}", name)))
+            .and_then(decode_segment)
+    };
+    match alg {
+        "RS256" => {
+            use rsa::pkcs1v15::{Signature, VerifyingKey};
+            use rsa::signature::Verifier;
+            use rsa::{BigUint, RsaPublicKey};
+            let public = RsaPublicKey::new(
+                BigUint::from_bytes_be(&field(&key.n, "n")?),
+                BigUint::from_bytes_be(&field(&key.e, "e")?),
+            )
+            .map_err(|e| invalid_token(e.to_string()))?;
+            let signature =
+                Signature::try_from(signature).map_err(|e| invalid_token(e.to_string()))?;
+            VerifyingKey::<Sha256>::new(public)
+                .verify(signing_input, &signature)
+                .map_err(|_| invalid_token("bad signature"))
+        }
+        "ES256" => {
+            use p256::ecdsa::signature::Verifier;
+            use p256::ecdsa::{Signature, VerifyingKey};
+            if key.crv.as_deref() != Some("P-256") {
+                return Err(invalid_token("ES256 needs a P-256 key"));
+            }
+            let (x, y) = (field(&key.x, "x")?, field(&key.y, "y")?);
+            if x.len() != 32 || y.len() != 32 {
+                return Err(invalid_token("malformed P-256 key"));
+            }
+            let point = p256::EncodedPoint::from_affine_coordinates(
+                x.as_slice().into(),
+                y.as_slice().into(),
+                false,
+            );
+            let public = VerifyingKey::from_encoded_point(&point)
+                .map_err(|e| invalid_token(e.to_string()))?;
+            let signature =
+                Signature::from_slice(signature).map_err(|e| invalid_token(e.to_string()))?;
+            public
+                .verify(signing_input, &signature)
+                .map_err(|_| invalid_token("bad signature"))
+        }
+        other => Err(invalid_token(format!("unsupported algorithm {}", other))),
+    }
+}
+
+fn numeric_claim(claims: &Map<String, Value>, name: &str) -> Result<i64, OidcError> {
+    claims
+        .get(name)
+        .and_then(|v| v.as_i64().or_else(|| v.as_f64().map(|f| f as i64)))
+        .ok_or_else(|| invalid_token(format!("no {} claim", name)))
+}
+
+/// Checks an ID token's signature and claims (OpenID Connect Core 1.0,
+/// section 3.1.3.7) and returns its claims.
+pub fn validate_id_token(
+    token: &str,
+    jwks: &Jwks,
+    config: &OidcConfig,
+    nonce: &str,
+    now: DateTime<Utc>,
+) -> Result<Map<String, Value>, OidcError> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid_token("not a signed JWT"));
+    };
+    let jwt_header: JwtHeader = serde_json::from_slice(&decode_segment(header)?)
+        .map_err(|e| invalid_token(e.to_string()))?;
+    let kty = match jwt_header.alg.as_str() {
+        "RS256" => "RSA",
+        "ES256" => "EC",
+        other => return Err(invalid_token(format!("unsupported algorithm {}", other))),
+    };
+    let key = jwks
+        .keys
+        .iter()
+        .filter(|k| k.kty == kty && k.key_use.as_deref().is_none_or(|u| u == "sig"))
+        .find(|k| jwt_header.kid.is_none() || k.kid == jwt_header.kid)
+        .ok_or_else(|| invalid_token("signing key not in the provider's JWKS"))?;
+    let signing_input = &token[..header.len() + 1 + payload.len()];
+    verify_signature(
+        &jwt_header.alg,
+        key,
+        signing_input.as_bytes(),
+        &decode_segment(signature)?,
+    )?;
+
+    let claims: Map<String, Value> = serde_json::from_slice(&decode_segment(payload)?)
+        .map_err(|e| invalid_token(e.to_string()))?;
+    if claims.get("iss").and_then(Value::as_str) != Some(config.issuer.as_str()) {
+        return Err(invalid_token("wrong issuer"));
+    }
+    let audience_ok = match claims.get("aud") {
+        Some(Value::String(aud)) => *aud == config.client_id,
+        Some(Value::Array(auds)) => {
+            auds.iter().any(|a| a.as_str() == Some(&config.client_id))
+                && (auds.len() == 1
+                    || claims.get("azp").and_then(Value::as_str) == Some(&config.client_id))
+        }
+        _ => false,
+    };
+    if !audience_ok {
+        return Err(invalid_token("issued to another client"));
+    }
+    if numeric_claim(&claims, "exp")? + LEEWAY_SECONDS < now.timestamp() {
+        return Err(invalid_token("expired"));
+    }
+    if numeric_claim(&claims, "iat")? - LEEWAY_SECONDS > now.timestamp() {
+        return Err(invalid_token("issued in the future"));
+    }
+    if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+        return Err(invalid_token("wrong nonce"));
+    }
+    Ok(claims)
+}
+
+/// The user the claims describe, if any rule lets them in.
+pub fn allowed_user(
+    claims: &Map<String, Value>,
+    rules: &[ClaimRule],
+) -> Result<OidcUser, OidcError> {
+    let subject = claims
+        .get("sub")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid_token("no sub claim"))?
+        .to_string();
+    let name = ["preferred_username", "name", "email"]
+        .iter()
+        .find_map(|c| claims.get(*c).and_then(Value::as_str))
+        .unwrap_or(&subject)
+        .to_string();
+    if !rules.iter().any(|rule| rule.matches(claims)) {
+        return Err(OidcError::NotAllowed(name));
+    }
+    Ok(OidcUser {
+        subject,
+        name,
+        device_id: None,
+        device_name: None,
+    })
+}
+
+/// Decodes the query of a `/auth/login` request: the device to sign in as.
+pub fn requested_device(query: &str) -> (Option<String>, Option<String>) {
+    let param = |key: &str| query_param(query, key).filter(|v| !v.trim().is_empty());
+    (param("deviceId"), param("device"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::{Signature, SigningKey};
+    use serde_json::json;
+
+    const ISSUER: &str = "https://id.example.com";
+    const CLIENT_ID: &str = "spore-box";
+    const NONCE: &str = "n-0S6_WzA2Mj";
+    const KID: &str = "test-key";
+    /// 2024-01-01T00:00:00Z.
+    const NOW: i64 = 1704067200;
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_slice(&[7; 32]).unwrap()
+    }
+
+    fn jwks() -> Jwks {
+        let point = signing_key().verifying_key().to_encoded_point(false);
+        Jwks {
+            keys: vec![Jwk {
+                kty: "EC".to_string(),
+                kid: Some(KID.to_string()),
+                key_use: Some("sig".to_string()),
+                n: None,
+                e: None,
+                crv: Some("P-256".to_string()),
+                x: Some(URL_SAFE_NO_PAD.encode(point.x().unwrap())),
+                y: Some(URL_SAFE_NO_PAD.encode(point.y().unwrap())),
+            }],
+        }
+    }
+
+    fn config() -> OidcConfig {
+        OidcConfig::new(ISSUER, CLIENT_ID, "secret", Vec::new())
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": ISSUER,
+            "sub": "alice",
+            "aud": CLIENT_ID,
+            "exp": NOW + 300,
+            "iat": NOW,
+            "nonce": NONCE,
+        })
+    }
+
+    fn sign(header: &Value, claims: &Value) -> String {
+        let input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature: Signature = signing_key().sign(input.as_bytes());
+        format!("{}.{}", input, URL_SAFE_NO_PAD.encode(signature.to_bytes()))
+    }
+
+    fn token(claims: &Value) -> String {
+        sign(&json!({"alg": "ES256", "kid": KID}), claims)
+    }
+
+    fn validate(token: &str) -> Result<Map<String, Value>, OidcError> {
+        let now = DateTime::from_timestamp(NOW, 0).unwrap();
+        validate_id_token(token, &jwks(), &config(), NONCE, now)
+    }
+
+    fn rejection(token: &str) -> String {
+        match validate(token) {
+            Err(OidcError::InvalidToken(msg)) => msg,
+            other => panic!("expected an invalid token, got {:?}", other),
+        }
+    }
+
+    fn with(claim: &str, value: Value) -> Value {
+        let mut claims = claims();
+        claims[claim] = value;
+        claims
+    }
+
+    #[test]
+    fn accepts_a_valid_token() {
+        let claims = validate(&token(&claims())).unwrap();
+        assert_eq!(claims["sub"], "alice");
+        // A header without a kid may use any signing key of the right type.
+        validate(&sign(&json!({"alg": "ES256"}), &self::claims())).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        for alg in ["none", "HS256", "ES384"] {
+            let token = sign(&json!({"alg": alg, "kid": KID}), &claims());
+            assert!(rejection(&token).contains("unsupported algorithm"), "{alg}");
+        }
+        // RS256 needs an RSA key, which the JWKS does not have.
+        let token = sign(&json!({"alg": "RS256", "kid": KID}), &claims());
+        assert!(rejection(&token).contains("JWKS"));
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_signatures() {
+        let token = sign(&json!({"alg": "ES256", "kid": "other"}), &claims());
+        assert!(rejection(&token).contains("JWKS"));
+
+        let good = self::token(&claims());
+        let parts: Vec<&str> = good.split('.').collect();
+        let forged = format!(
+            "{}.{}.{}",
+            parts[0],
+            URL_SAFE_NO_PAD.encode(with("sub", json!("mallory")).to_string()),
+            parts[2]
+        );
+        assert_eq!(rejection(&forged), "bad signature");
+        assert_eq!(rejection("only.two"), "not a signed JWT");
+    }
+
+    #[test]
+    fn rejects_other_issuers() {
+        let token = token(&with("iss", json!("https://evil.example.com")));
+        assert_eq!(rejection(&token), "wrong issuer");
+        let mut claims = claims();
+        claims.as_object_mut().unwrap().remove("iss");
+        assert_eq!(rejection(&self::token(&claims)), "wrong issuer");
+    }
+
+    #[test]
+    fn checks_audience_and_authorized_party() {
+        let other = "issued to another client";
+        assert_eq!(
+            rejection(&token(&with("aud", json!("someone-else")))),
+            other
+        );
+        validate(&token(&with("aud", json!([CLIENT_ID])))).unwrap();
+
+        // With several audiences, azp must name us.
+        let shared = with("aud", json!([CLIENT_ID, "someone-else"]));
+        assert_eq!(rejection(&token(&shared)), other);
+        let mut ours = shared.clone();
+        ours["azp"] = json!(CLIENT_ID);
+        validate(&token(&ours)).unwrap();
+        let mut theirs = shared;
+        theirs["azp"] = json!("someone-else");
+        assert_eq!(rejection(&token(&theirs)), other);
+    }
+
+    #[test]
+    fn checks_expiry_and_issue_time() {
+        validate(&token(&with("exp", json!(NOW - LEEWAY_SECONDS)))).unwrap();
+        let expired = with("exp", json!(NOW - LEEWAY_SECONDS - 1));
+        assert_eq!(rejection(&token(&expired)), "expired");
+
+        validate(&token(&with("iat", json!(NOW + LEEWAY_SECONDS)))).unwrap();
+        let future = with("iat", json!(NOW + LEEWAY_SECONDS + 1));
+        assert_eq!(rejection(&token(&future)), "issued in the future");
+
+        let mut claims = claims();
+        claims.as_object_mut().unwrap().remove("exp");
+        assert_eq!(rejection(&token(&claims)), "no exp claim");
+    }
+
+    #[test]
+    fn checks_the_nonce() {
+        assert_eq!(
+            rejection(&token(&with("nonce", json!("replayed")))),
+            "wrong nonce"
+        );
+        let mut claims = claims();
+        claims.as_object_mut().unwrap().remove("nonce");
+        assert_eq!(rejection(&token(&claims)), "wrong nonce");
+    }
+}
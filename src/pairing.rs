@@ -0,0 +1,272 @@
+//! Pairing new devices with one-time codes.
+//!
+//! A signed-in device asks for a short code, shown as text and as a QR code
+//! of the pairing URL (`/?pair=<code>&device=<name>`). The new device redeems
+//! the code once, before it expires, and is registered with its own token.
+
+use crate::device::{self, Device, DeviceChanges};
+use crate::state::{self, StateStore};
+use crate::url::percent_encode;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Duration, Utc};
+use qrcode::QrCode;
+use qrcode::render::svg;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind};
+
+pub const PAIRING: &str = "pairing";
+
+/// No 0/O, 1/I/L or U, so codes survive being read out loud.
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTVWXYZ";
+const CODE_LEN: usize = 8;
+
+pub fn code_lifetime() -> Duration {
+    Duration::minutes(10)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PendingCodes {
+    codes: Vec<PendingCode>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PendingCode {
+    code_hash: String,
+    created_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_name: Option<String>,
+    expires_at: String,
+}
+
+impl PendingCodes {
+    fn drop_expired(&mut self, now: DateTime<Utc>) {
+        self.codes.retain(|c| {
+            DateTime::parse_from_rfc3339(&c.expires_at).is_ok_and(|expiry| expiry > now)
+        });
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PairingCode {
+    pub code: String,
+    pub expires_at: String,
+    pub url: String,
+    /// The pairing URL as an SVG QR code.
+    pub qr_svg: String,
+}
+
+/// Canonical form of a code as typed: upper case, without separators.
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+fn hash_code(code: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(normalize(code).as_bytes()))
+}
+
+fn random_code() -> Result<String, Error> {
+    let mut bytes = [0u8; CODE_LEN];
+    getrandom::fill(&mut bytes).map_err(|e| Error::other(e.to_string()))?;
+    // 256 is not a multiple of the alphabet size; the bias is negligible for
+    // a code that lives ten minutes.
+    let chars: String = bytes
+        .iter()
+        .map(|b| CODE_ALPHABET[*b as usize % CODE_ALPHABET.len()] as char)
+        .collect();
+    Ok(format!("{}-{}", &chars[..4], &chars[4..]))
+}
+
+/// Issues a code on behalf of `created_by`. `origin` is the scheme and host
+/// the new device should open, e.g. `http://192.168.1.5:8081`.
+pub fn create(
+    state: &dyn StateStore,
+    created_by: Option<&Device>,
+    device_name: Option<String>,
+    origin: &str,
+    now: DateTime<Utc>,
+) -> Result<PairingCode, Error> {
+    let code = random_code()?;
+    let expires_at = (now + code_lifetime()).to_rfc3339();
+
+    let mut url = format!("{}/?pair={}", origin.trim_end_matches('/'), code);
+    if let Some(name) = &device_name {
+        url.push_str(&format!("&device={}", percent_encode(name)));
+    }
+    let qr_svg = QrCode::new(url.as_bytes())
+        .map_err(|e| Error::other(e.to_string()))?
+        .render::<svg::Color>()
+        .min_dimensions(240, 240)
+        .build();
+
+    state::update(state, PAIRING, |pending: &mut PendingCodes| {
+        pending.drop_expired(now);
+        pending.codes.push(PendingCode {
+            code_hash: hash_code(&code),
+            created_by: created_by.map(|d| d.id.clone()),
+            device_name,
+            expires_at: expires_at.clone(),
+        });
+        Ok(())
+    })?;
+
+    Ok(PairingCode {
+        code,
+        expires_at,
+        url,
+        qr_svg,
+    })
+}
+
+/// Uses up `code` and registers the new device, named `device_name` or else
+/// whatever the code was issued for. Returns the device and its token.
+pub fn redeem(
+    state: &dyn StateStore,
+    code: &str,
+    device_name: Option<String>,
+    now: DateTime<Utc>,
+) -> Result<(Device, String), Error> {
+    let hash = hash_code(code);
+    let pending = state::update(state, PAIRING, |pending: &mut PendingCodes| {
+        pending.drop_expired(now);
+        let index = pending
+            .codes
+            .iter()
+            .position(|c| c.code_hash == hash)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::PermissionDenied,
+                    "Unknown or expired pairing code",
+                )
+            })?;
+        Ok(pending.codes.remove(index))
+    })?;
+
+    let name = device_name
+        .filter(|n| !n.trim().is_empty())
+        .or(pending.device_name)
+        .unwrap_or_else(|| "Browser".to_string());
+    eprintln!(
+        "Pairing code issued by {} redeemed for {:?}",
+        pending
+            .created_by
+            .as_deref()
+            .unwrap_or("a session without a device"),
+        name
+    );
+    device::register(
+        state,
+        DeviceChanges {
+            name: Some(name),
+            ..Default::default()
+        },
+        now,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceRegistry;
+    use crate::state::MemoryStateStore;
+    use chrono::TimeZone;
+
+    fn noon() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap()
+    }
+
+    fn issue(state: &MemoryStateStore, device_name: Option<&str>) -> PairingCode {
+        create(
+            state,
+            None,
+            device_name.map(str::to_string),
+            "http://box.local:8081/",
+            noon(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn codes_are_short_and_point_at_the_box() {
+        let state = MemoryStateStore::default();
+        let pairing = issue(&state, Some("Tom's phone"));
+        assert_eq!(pairing.code.len(), CODE_LEN + 1);
+        assert!(
+            normalize(&pairing.code)
+                .bytes()
+                .all(|b| CODE_ALPHABET.contains(&b))
+        );
+        assert_eq!(
+            pairing.url,
+            format!(
+                "http://box.local:8081/?pair={}&device=Tom%27s%20phone",
+                pairing.code
+            )
+        );
+        assert!(pairing.qr_svg.starts_with("<?xml"));
+        assert_eq!(pairing.expires_at, (noon() + code_lifetime()).to_rfc3339());
+    }
+
+    #[test]
+    fn codes_redeem_once() {
+        let state = MemoryStateStore::default();
+        let pairing = issue(&state, None);
+
+        let (device, token) = redeem(&state, &pairing.code, None, noon()).unwrap();
+        let registry = DeviceRegistry::load(&state).unwrap();
+        assert_eq!(registry.by_token(&token).unwrap().id, device.id);
+
+        let again = redeem(&state, &pairing.code, None, noon()).unwrap_err();
+        assert_eq!(again.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(DeviceRegistry::load(&state).unwrap().devices.len(), 1);
+    }
+
+    #[test]
+    fn codes_expire() {
+        let state = MemoryStateStore::default();
+        let pairing = issue(&state, None);
+        let expired = redeem(&state, &pairing.code, None, noon() + code_lifetime());
+        assert_eq!(expired.unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+        let pairing = issue(&state, None);
+        let last_second = noon() + code_lifetime() - Duration::seconds(1);
+        assert!(redeem(&state, &pairing.code, None, last_second).is_ok());
+    }
+
+    #[test]
+    fn codes_are_normalised_as_typed() {
+        assert_eq!(normalize("abcd-efgh"), "ABCDEFGH");
+        assert_eq!(hash_code("abcd-efgh"), hash_code("ABCDEFGH"));
+        assert_eq!(hash_code(" Ab Cd\tEfGh "), hash_code("ABCD-EFGH"));
+        assert_ne!(hash_code("ABCD-EFGJ"), hash_code("ABCD-EFGH"));
+
+        let state = MemoryStateStore::default();
+        let pairing = issue(&state, None);
+        let typed = pairing.code.to_ascii_lowercase().replace('-', " ");
+        assert!(redeem(&state, &typed, None, noon()).is_ok());
+    }
+
+    #[test]
+    fn names_fall_back_to_the_issued_name_then_browser() {
+        let state = MemoryStateStore::default();
+        let name = |code: &str, given: Option<&str>| {
+            redeem(&state, code, given.map(str::to_string), noon())
+                .unwrap()
+                .0
+                .name
+        };
+
+        let named = issue(&state, Some("Kitchen tablet"));
+        assert_eq!(name(&named.code, Some("Tablet")), "Tablet");
+        let named = issue(&state, Some("Kitchen tablet"));
+        assert_eq!(name(&named.code, Some("  ")), "Kitchen tablet");
+        let unnamed = issue(&state, None);
+        assert_eq!(name(&unnamed.code, None), "Browser");
+    }
+}
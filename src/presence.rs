@@ -0,0 +1,235 @@
+//! When each device was last seen, and whether that makes it online.
+//!
+//! Devices poll while the app is open, so each poll records the time in the
+//! `presence` state document. To spare the document a write per poll, a
+//! device's time is only rewritten once it is 15 seconds old.
+
+use crate::device::DeviceInfo;
+use crate::state::{self, StateStore};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Error;
+
+pub const PRESENCE: &str = "presence";
+
+/// Polls back off to 30 seconds when nothing happens, so a device that
+/// polled within a minute still has the app open.
+pub fn online_within() -> Duration {
+    Duration::seconds(60)
+}
+
+pub fn idle_within() -> Duration {
+    Duration::minutes(10)
+}
+
+fn record_every() -> Duration {
+    Duration::seconds(15)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Presence {
+    /// Device id to RFC 3339 time.
+    #[serde(default)]
+    last_seen: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Online,
+    Idle,
+    Offline,
+}
+
+impl Status {
+    /// The status at `now` of a device last seen at `last_seen`.
+    pub fn at(last_seen: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Status {
+        match last_seen.map(|seen| now - seen) {
+            Some(age) if age <= online_within() => Status::Online,
+            Some(age) if age <= idle_within() => Status::Idle,
+            _ => Status::Offline,
+        }
+    }
+}
+
+fn parse(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Every device's last-seen time, by device id.
+pub fn last_seen(store: &dyn StateStore) -> Result<BTreeMap<String, DateTime<Utc>>, Error> {
+    let presence: Presence = state::load(store, PRESENCE)?;
+    Ok(presence
+        .last_seen
+        .into_iter()
+        .filter_map(|(device, seen)| Some((device, parse(&seen)?)))
+        .collect())
+}
+
+/// Notes that `device` was seen at `now`.
+pub fn record(store: &dyn StateStore, device: &str, now: DateTime<Utc>) -> Result<(), Error> {
+    let fresh = |presence: &Presence| {
+        presence
+            .last_seen
+            .get(device)
+            .and_then(|seen| parse(seen))
+            .is_some_and(|seen| now - seen < record_every())
+    };
+    if fresh(&state::load(store, PRESENCE)?) {
+        return Ok(());
+    }
+    state::update(store, PRESENCE, |presence: &mut Presence| {
+        if !fresh(presence) {
+            presence
+                .last_seen
+                .insert(device.to_string(), now.to_rfc3339());
+        }
+        Ok(())
+    })
+}
+
+/// A device's presence, as listed by `GET /api/devices` and polls.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DevicePresence {
+    pub device_id: String,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<String>,
+}
+
+impl DevicePresence {
+    pub fn new(device_id: &str, last_seen: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Self {
+        DevicePresence {
+            device_id: device_id.to_string(),
+            status: Status::at(last_seen, now),
+            last_seen: last_seen.map(|t| t.to_rfc3339()),
+        }
+    }
+}
+
+/// A device with its presence.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceWithPresence<'a> {
+    #[serde(flatten)]
+    pub device: DeviceInfo<'a>,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<String>,
+}
+
+/// The devices whose status is not what it was at `since`: those seen
+/// after it, and those that have since gone idle or offline. Without a
+/// usable `since`, every device, the way polls then send every message.
+pub fn changes(
+    last_seen: &BTreeMap<String, DateTime<Utc>>,
+    since: &str,
+    now: DateTime<Utc>,
+) -> Vec<DevicePresence> {
+    let since = parse(since);
+    last_seen
+        .iter()
+        .filter(|(_, seen)| match since {
+            Some(since) => {
+                **seen > since || Status::at(Some(**seen), since) != Status::at(Some(**seen), now)
+            }
+            None => true,
+        })
+        .map(|(device, seen)| DevicePresence::new(device, Some(*seen), now))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        parse(timestamp).unwrap()
+    }
+
+    fn noon() -> DateTime<Utc> {
+        at("2024-01-01T12:00:00Z")
+    }
+
+    #[test]
+    fn status_boundaries() {
+        let seen = |age: Duration| Status::at(Some(noon() - age), noon());
+        assert_eq!(seen(Duration::zero()), Status::Online);
+        assert_eq!(seen(online_within()), Status::Online);
+        assert_eq!(seen(online_within() + Duration::seconds(1)), Status::Idle);
+        assert_eq!(seen(idle_within()), Status::Idle);
+        assert_eq!(seen(idle_within() + Duration::seconds(1)), Status::Offline);
+        assert_eq!(Status::at(None, noon()), Status::Offline);
+        // A clock ahead of ours still counts as just seen.
+        assert_eq!(seen(Duration::seconds(-5)), Status::Online);
+    }
+
+    #[test]
+    fn records_at_most_every_fifteen_seconds() {
+        let store = MemoryStateStore::default();
+        record(&store, "d1", noon()).unwrap();
+        record(&store, "d1", noon() + Duration::seconds(14)).unwrap();
+        assert_eq!(last_seen(&store).unwrap()["d1"], noon());
+
+        let later = noon() + record_every();
+        record(&store, "d1", later).unwrap();
+        record(&store, "d2", noon() + Duration::seconds(1)).unwrap();
+        let seen = last_seen(&store).unwrap();
+        assert_eq!(seen["d1"], later);
+        assert_eq!(seen["d2"], noon() + Duration::seconds(1));
+    }
+
+    #[test]
+    fn changes_since_a_poll() {
+        let since = noon();
+        let now = noon() + Duration::seconds(30);
+        let last_seen = BTreeMap::from([
+            // Seen after `since`.
+            ("active".to_string(), noon() + Duration::seconds(10)),
+            // Online at `since`, idle now.
+            (
+                "dozing".to_string(),
+                now - online_within() - Duration::seconds(1),
+            ),
+            // Idle at `since`, offline now.
+            (
+                "gone".to_string(),
+                now - idle_within() - Duration::seconds(1),
+            ),
+            // Online then and now, and not seen since.
+            ("steady".to_string(), noon() - Duration::seconds(10)),
+            // Offline then and now.
+            ("away".to_string(), noon() - Duration::days(1)),
+        ]);
+
+        let changes = changes(&last_seen, &since.to_rfc3339(), now);
+        let by_device: Vec<(&str, Status)> = changes
+            .iter()
+            .map(|c| (c.device_id.as_str(), c.status))
+            .collect();
+        assert_eq!(
+            by_device,
+            [
+                ("active", Status::Online),
+                ("dozing", Status::Idle),
+                ("gone", Status::Offline),
+            ]
+        );
+        assert_eq!(
+            changes[0].last_seen.as_deref(),
+            Some((noon() + Duration::seconds(10)).to_rfc3339().as_str())
+        );
+
+        // The same `since` written another way means the same time.
+        let offset = "2024-01-01T13:00:00+01:00";
+        assert_eq!(super::changes(&last_seen, offset, now).len(), 3);
+        assert_eq!(super::changes(&last_seen, "not a time", now).len(), 5);
+        assert_eq!(super::changes(&last_seen, "", now).len(), 5);
+    }
+}
@@ -0,0 +1,323 @@
+//! Token-bucket rate limits per client and route.
+//!
+//! Each rule gives every client a bucket of `limit` tokens that refills
+//! evenly over `period`; a request takes one token and is refused while the
+//! bucket is empty. Buckets live in the `ratelimits` state document, since
+//! every request runs in a fresh instance.
+//!
+//! Rules come from `SPORE_RATE_LIMITS`, a comma-separated list such as
+//! `POST /api/messages=30/1m, POST /api/upload=10/1m, *=60/1m`. A route is a
+//! method (or `*`) and a path, which may end in `*` to match a prefix; a bare
+//! `*` matches every request that changes something. The first matching rule
+//! applies. `off` turns limiting off; unset, the defaults below apply.
+//!
+//! A credential that fails to authenticate is a guess, so it draws on the
+//! `POST /api/login` limit wherever it was sent.
+
+use crate::state::{self, StateStore};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Error;
+
+pub const RATE_LIMITS: &str = "ratelimits";
+pub const RATE_LIMITS_VAR: &str = "SPORE_RATE_LIMITS";
+
+const DEFAULT_RULES: &str = "POST /api/login=10/5m, POST /api/pairing/redeem=10/5m, \
+POST /api/messages=30/1m, POST /api/upload=10/1m, PUT /api/clipboard=10/1m, *=60/1m";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// `METHOD /path`, `METHOD /prefix*` or `*`, as configured.
+    pub route: String,
+    pub limit: u32,
+    pub period: Duration,
+}
+
+impl Rule {
+    fn matches(&self, method: &str, path: &str) -> bool {
+        if self.route == "*" {
+            return !matches!(method, "GET" | "HEAD" | "OPTIONS");
+        }
+        let Some((rule_method, rule_path)) = self.route.split_once(' ') else {
+            return false;
+        };
+        let method_matches = rule_method == "*" || rule_method.eq_ignore_ascii_case(method);
+        let path_matches = match rule_path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == rule_path,
+        };
+        method_matches && path_matches
+    }
+
+    /// Tokens regained per millisecond.
+    fn refill_rate(&self) -> f64 {
+        self.limit as f64 / self.period.num_milliseconds().max(1) as f64
+    }
+}
+
+fn parse_period(period: &str) -> Option<Duration> {
+    let unit_at = period
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(period.len());
+    let (count, unit) = period.split_at(unit_at);
+    let count: i64 = if count.is_empty() {
+        1
+    } else {
+        count.parse().ok()?
+    };
+    let period = match unit {
+        "s" => Duration::seconds(count),
+        "m" | "min" => Duration::minutes(count),
+        "h" => Duration::hours(count),
+        "d" => Duration::days(count),
+        _ => return None,
+    };
+    (count > 0).then_some(period)
+}
+
+fn parse_rule(rule: &str) -> Result<Rule, String> {
+    let (route, rate) = rule
+        .rsplit_once('=')
+        .ok_or_else(|| format!("{:?} is not ROUTE=LIMIT/PERIOD", rule))?;
+    let (limit, period) = rate
+        .trim()
+        .split_once('/')
+        .ok_or_else(|| format!("{:?} is not LIMIT/PERIOD", rate))?;
+    let limit: u32 = limit
+        .trim()
+        .parse()
+        .ok()
+        .filter(|l| *l > 0)
+        .ok_or_else(|| format!("{:?} is not a positive limit", limit))?;
+    let period =
+        parse_period(period.trim()).ok_or_else(|| format!("{:?} is not a period", period))?;
+    let route = route.split_whitespace().collect::<Vec<_>>().join(" ");
+    if route != "*" && route.split(' ').count() != 2 {
+        return Err(format!("{:?} is not METHOD /path or *", route));
+    }
+    Ok(Rule {
+        route,
+        limit,
+        period,
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    rules: Vec<Rule>,
+}
+
+impl RateLimits {
+    pub fn parse(spec: &str) -> Result<RateLimits, String> {
+        if spec.trim().eq_ignore_ascii_case("off") {
+            return Ok(RateLimits::default());
+        }
+        let rules = spec
+            .split(',')
+            .filter(|r| !r.trim().is_empty())
+            .map(parse_rule)
+            .collect::<Result<_, _>>()?;
+        Ok(RateLimits { rules })
+    }
+
+    pub fn defaults() -> RateLimits {
+        RateLimits::parse(DEFAULT_RULES).expect("default rate limits parse")
+    }
+
+    /// The configured limits; a malformed setting falls back to the
+    /// defaults rather than to no limits.
+    pub fn from_env() -> RateLimits {
+        match std::env::var(RATE_LIMITS_VAR) {
+            Ok(spec) if !spec.trim().is_empty() => RateLimits::parse(&spec).unwrap_or_else(|e| {
+                eprintln!("Ignoring {}: {}", RATE_LIMITS_VAR, e);
+                RateLimits::defaults()
+            }),
+            _ => RateLimits::defaults(),
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn rule_for(&self, method: &str, path: &str) -> Option<&Rule> {
+        self.rules.iter().find(|r| r.matches(method, path))
+    }
+
+    /// Takes a token from `client`'s bucket for the rule matching the
+    /// request, if any.
+    pub fn check(
+        &self,
+        store: &dyn StateStore,
+        method: &str,
+        path: &str,
+        client: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Decision, Error> {
+        let Some(rule) = self.rule_for(method, path) else {
+            return Ok(Decision::Unlimited);
+        };
+        let now_ms = now.timestamp_millis();
+        state::update(store, RATE_LIMITS, |buckets: &mut Buckets| {
+            buckets.drop_full(self, now_ms);
+            let bucket = buckets
+                .routes
+                .entry(rule.route.clone())
+                .or_default()
+                .entry(client.to_string())
+                .or_insert(Bucket {
+                    tokens: rule.limit as f64,
+                    updated_at: now_ms,
+                });
+            bucket.refill(rule, now_ms);
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                return Ok(Decision::Allowed {
+                    limit: rule.limit,
+                    remaining: bucket.tokens as u32,
+                });
+            }
+            let wait_ms = (1.0 - bucket.tokens) / rule.refill_rate();
+            eprintln!("Rate limited {} on {}", client, rule.route);
+            Ok(Decision::Limited {
+                limit: rule.limit,
+                retry_after: (wait_ms / 1000.0).ceil().max(1.0) as u64,
+            })
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// No rule covers the request.
+    Unlimited,
+    Allowed {
+        limit: u32,
+        remaining: u32,
+    },
+    /// Refused; a token will be back in `retry_after` seconds.
+    Limited {
+        limit: u32,
+        retry_after: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Buckets {
+    /// Buckets by rule route, then by client.
+    #[serde(default)]
+    routes: BTreeMap<String, BTreeMap<String, Bucket>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+struct Bucket {
+    tokens: f64,
+    /// Milliseconds since the epoch.
+    updated_at: i64,
+}
+
+impl Bucket {
+    fn refill(&mut self, rule: &Rule, now_ms: i64) {
+        let elapsed = (now_ms - self.updated_at).max(0) as f64;
+        self.tokens = (self.tokens + elapsed * rule.refill_rate()).min(rule.limit as f64);
+        self.updated_at = now_ms;
+    }
+}
+
+impl Buckets {
+    /// Forgets buckets that have filled up again, or whose rule is gone; a
+    /// new bucket starts full anyway.
+    fn drop_full(&mut self, limits: &RateLimits, now_ms: i64) {
+        self.routes.retain(|route, clients| {
+            let Some(rule) = limits.rules.iter().find(|r| &r.route == route) else {
+                return false;
+            };
+            clients.retain(|_, bucket| {
+                let mut bucket = *bucket;
+                bucket.refill(rule, now_ms);
+                bucket.tokens < rule.limit as f64
+            });
+            !clients.is_empty()
+        });
+    }
+}
+
+/// Whose bucket a request draws from: its device, else a hash of the
+/// credential it authenticated with, else the address it came from.
+/// Credentials that did not authenticate are ignored, so making them up does
+/// not buy fresh buckets.
+///
+/// The address is the last hop of `X-Forwarded-For`, the one the nearest
+/// proxy added; earlier ones are whatever the client claimed. Requests
+/// without the header share the anonymous bucket.
+pub fn client_key(
+    device_id: Option<&str>,
+    credential: Option<&str>,
+    forwarded_for: Option<&str>,
+) -> String {
+    if let Some(id) = device_id {
+        return format!("device:{}", id);
+    }
+    if let Some(credential) = credential {
+        let digest = Sha256::digest(credential.as_bytes());
+        let hex: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        return format!("token:{}", hex);
+    }
+    match forwarded_for
+        .and_then(|header| header.rsplit(',').next())
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+    {
+        Some(addr) => format!("client:{}", addr),
+        None => "anonymous".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    #[test]
+    fn devices_and_credentials_come_before_addresses() {
+        assert_eq!(
+            client_key(Some("d1"), Some("secret"), Some("10.0.0.1")),
+            "device:d1"
+        );
+        assert!(client_key(None, Some("secret"), Some("10.0.0.1")).starts_with("token:"));
+        assert_eq!(client_key(None, None, Some("10.0.0.1")), "client:10.0.0.1");
+        assert_eq!(client_key(None, None, None), "anonymous");
+        assert_eq!(client_key(None, None, Some(" ")), "anonymous");
+    }
+
+    #[test]
+    fn addresses_are_the_hop_the_proxy_added() {
+        assert_eq!(
+            client_key(None, None, Some("1.2.3.4, 10.0.0.7")),
+            "client:10.0.0.7"
+        );
+    }
+
+    #[test]
+    fn anonymous_clients_do_not_share_a_bucket() {
+        let store = MemoryStateStore::default();
+        let limits = RateLimits::parse("POST /api/login=2/5m").unwrap();
+        let now = Utc::now();
+        let login = |client: &str| {
+            limits
+                .check(&store, "POST", "/api/login", client, now)
+                .unwrap()
+        };
+
+        let attacker = client_key(None, None, Some("10.0.0.1"));
+        assert!(matches!(login(&attacker), Decision::Allowed { .. }));
+        assert!(matches!(login(&attacker), Decision::Allowed { .. }));
+        assert!(matches!(login(&attacker), Decision::Limited { .. }));
+
+        let other = client_key(None, None, Some("10.0.0.2"));
+        assert!(matches!(login(&other), Decision::Allowed { .. }));
+    }
+}
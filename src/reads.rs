@@ -0,0 +1,306 @@
+//! How far each device has read in each room.
+//!
+//! The `reads` state document keeps, per room and device, the last message
+//! the device has read and that message's timestamp. Messages with a later
+//! timestamp are unread, so the position survives the message itself being
+//! archived. Positions only move forward.
+
+use crate::device::Device;
+use crate::event::{self, MessageView};
+use crate::message::Message;
+use crate::state::{self, StateStore};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+pub const READS: &str = "reads";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Reads {
+    /// Room id, then device id.
+    #[serde(default)]
+    rooms: BTreeMap<String, BTreeMap<String, ReadPosition>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadPosition {
+    pub message_id: String,
+    /// Timestamp of that message.
+    pub timestamp: String,
+    /// When the device read it.
+    pub read_at: String,
+}
+
+impl ReadPosition {
+    /// Whether `message` is at or before this position.
+    pub fn covers(&self, message: &Message) -> bool {
+        message.id == self.message_id
+            || match (parse(&message.timestamp), parse(&self.timestamp)) {
+                (Some(message), Some(position)) => message <= position,
+                _ => false,
+            }
+    }
+}
+
+fn parse(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+pub fn position(
+    store: &dyn StateStore,
+    room: &str,
+    device: &str,
+) -> Result<Option<ReadPosition>, Error> {
+    let reads: Reads = state::load(store, READS)?;
+    Ok(reads
+        .rooms
+        .get(room)
+        .and_then(|devices| devices.get(device))
+        .cloned())
+}
+
+/// Every device's position in `room`, by device id.
+pub fn positions(
+    store: &dyn StateStore,
+    room: &str,
+) -> Result<BTreeMap<String, ReadPosition>, Error> {
+    let mut reads: Reads = state::load(store, READS)?;
+    Ok(reads.rooms.remove(room).unwrap_or_default())
+}
+
+/// Moves `device`'s position in `room` up to `message`, unless it is
+/// already further along. Returns the position it ends up at.
+pub fn mark_read(
+    store: &dyn StateStore,
+    room: &str,
+    device: &str,
+    message: &Message,
+    now: DateTime<Utc>,
+) -> Result<ReadPosition, Error> {
+    if parse(&message.timestamp).is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Message {} has no usable timestamp", message.id),
+        ));
+    }
+    state::update(store, READS, |reads: &mut Reads| {
+        let devices = reads.rooms.entry(room.to_string()).or_default();
+        match devices.get(device) {
+            Some(position) if position.covers(message) => Ok(position.clone()),
+            _ => {
+                let position = ReadPosition {
+                    message_id: message.id.clone(),
+                    timestamp: message.timestamp.clone(),
+                    read_at: now.to_rfc3339(),
+                };
+                devices.insert(device.to_string(), position.clone());
+                Ok(position)
+            }
+        }
+    })
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Unread {
+    pub count: usize,
+    /// The oldest unread message.
+    pub first_unread: Option<MessageView>,
+    pub position: Option<ReadPosition>,
+}
+
+/// What `device` has not read among `messages`, which should be the ones it
+/// can see. Its own messages and events do not count.
+pub fn unread(messages: Vec<Message>, device: &str, position: Option<ReadPosition>) -> Unread {
+    let mut unread: Vec<MessageView> = event::fold(messages)
+        .into_iter()
+        .filter(|view| view.message.device_id.as_deref() != Some(device))
+        .filter(|view| !position.as_ref().is_some_and(|p| p.covers(&view.message)))
+        .collect();
+    Unread {
+        count: unread.len(),
+        first_unread: (!unread.is_empty()).then(|| unread.remove(0)),
+        position,
+    }
+}
+
+/// Whether one device has seen a message.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Receipt {
+    pub device_id: String,
+    pub name: String,
+    /// When the device last moved its position, for those that have seen it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_at: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Receipts {
+    pub message_id: String,
+    pub seen_by: Vec<Receipt>,
+    pub not_seen_by: Vec<Receipt>,
+}
+
+/// Sorts `devices` by whether their position covers `message`.
+pub fn receipts<'a>(
+    message: &Message,
+    positions: &BTreeMap<String, ReadPosition>,
+    devices: impl IntoIterator<Item = &'a Device>,
+) -> Receipts {
+    let mut receipts = Receipts {
+        message_id: message.id.clone(),
+        seen_by: Vec::new(),
+        not_seen_by: Vec::new(),
+    };
+    for device in devices {
+        let position = positions.get(&device.id).filter(|p| p.covers(message));
+        let receipt = Receipt {
+            device_id: device.id.clone(),
+            name: device.name.clone(),
+            read_at: position.map(|p| p.read_at.clone()),
+        };
+        if position.is_some() {
+            receipts.seen_by.push(receipt);
+        } else {
+            receipts.not_seen_by.push(receipt);
+        }
+    }
+    receipts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{self, DeviceChanges};
+    use crate::event::Event;
+    use crate::state::MemoryStateStore;
+    use crate::testutil::message;
+
+    fn from(id: &str, device: &str, timestamp: &str) -> Message {
+        Message {
+            device_id: Some(device.to_string()),
+            ..message(id, timestamp)
+        }
+    }
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        parse(timestamp).unwrap()
+    }
+
+    #[test]
+    fn positions_cover_their_message_and_everything_before() {
+        let position = ReadPosition {
+            message_id: "b".to_string(),
+            timestamp: "2024-01-01T01:00:00+01:00".to_string(),
+            read_at: "2024-01-02T00:00:00Z".to_string(),
+        };
+        assert!(position.covers(&message("a", "2023-12-31T23:59:59Z")));
+        assert!(position.covers(&message("same-time", "2024-01-01T00:00:00Z")));
+        assert!(!position.covers(&message("c", "2024-01-01T00:00:01Z")));
+        // Its own message, whatever its timestamp; no others without one.
+        assert!(position.covers(&message("b", "garbage")));
+        assert!(!position.covers(&message("x", "garbage")));
+    }
+
+    #[test]
+    fn positions_only_move_forward() {
+        let store = MemoryStateStore::default();
+        let early = message("early", "2024-01-01T00:00:00Z");
+        let late = message("late", "2024-01-01T00:05:00Z");
+        let now = at("2024-01-02T00:00:00Z");
+
+        let first = mark_read(&store, "default", "d1", &late, now).unwrap();
+        assert_eq!(first.message_id, "late");
+        let back = mark_read(&store, "default", "d1", &early, at("2024-01-03T00:00:00Z"));
+        assert_eq!(back.unwrap(), first);
+        assert_eq!(position(&store, "default", "d1").unwrap(), Some(first));
+
+        // Each room and device keeps its own position.
+        mark_read(&store, "work", "d1", &early, now).unwrap();
+        mark_read(&store, "default", "d2", &early, now).unwrap();
+        assert_eq!(
+            position(&store, "work", "d1").unwrap().unwrap().message_id,
+            "early"
+        );
+        let positions = positions(&store, "default").unwrap();
+        assert_eq!(positions.keys().collect::<Vec<_>>(), ["d1", "d2"]);
+        assert_eq!(position(&store, "default", "d3").unwrap(), None);
+
+        let broken = message("broken", "not a time");
+        let err = mark_read(&store, "default", "d1", &broken, now).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn unread_leaves_out_own_messages_and_events() {
+        let store = MemoryStateStore::default();
+        let c = from("c", "d2", "2024-01-01T00:03:00Z");
+        let pin = Event::Pin {
+            target: "c".to_string(),
+            pinned: true,
+        }
+        .record(&c, "bob".to_string(), Some("d2".to_string()));
+        let messages = vec![
+            from("a", "d2", "2024-01-01T00:00:00Z"),
+            from("b", "d2", "2024-01-01T00:01:00Z"),
+            from("mine", "d1", "2024-01-01T00:02:00Z"),
+            c,
+            pin,
+        ];
+
+        let none = unread(messages.clone(), "d1", None);
+        assert_eq!(none.count, 3);
+        assert_eq!(none.first_unread.unwrap().message.id, "a");
+
+        let position = mark_read(&store, "default", "d1", &messages[0], Utc::now()).unwrap();
+        let some = unread(messages.clone(), "d1", Some(position));
+        assert_eq!(some.count, 2);
+        let first = some.first_unread.unwrap();
+        assert_eq!(first.message.id, "b");
+
+        let position = mark_read(&store, "default", "d1", &messages[3], Utc::now()).unwrap();
+        let caught_up = unread(messages, "d1", Some(position.clone()));
+        assert_eq!(caught_up.count, 0);
+        assert!(caught_up.first_unread.is_none());
+        assert_eq!(caught_up.position, Some(position));
+    }
+
+    #[test]
+    fn receipts_split_devices_by_position() {
+        let store = MemoryStateStore::default();
+        let named = |name: &str| {
+            let changes = DeviceChanges {
+                name: Some(name.to_string()),
+                ..Default::default()
+            };
+            device::register(&store, changes, Utc::now()).unwrap().0
+        };
+        let (alice, bob, carol) = (named("alice"), named("bob"), named("carol"));
+        let first = message("first", "2024-01-01T00:00:00Z");
+        let second = message("second", "2024-01-01T00:01:00Z");
+        let read_at = at("2024-01-02T00:00:00Z");
+        mark_read(&store, "default", &alice.id, &second, read_at).unwrap();
+        mark_read(&store, "default", &bob.id, &first, read_at).unwrap();
+
+        let positions = positions(&store, "default").unwrap();
+        let receipts = receipts(&second, &positions, [&alice, &bob, &carol]);
+        assert_eq!(receipts.message_id, "second");
+        let names = |r: &[Receipt]| r.iter().map(|r| r.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&receipts.seen_by), ["alice"]);
+        assert_eq!(names(&receipts.not_seen_by), ["bob", "carol"]);
+        assert_eq!(
+            receipts.seen_by[0].read_at.as_deref(),
+            Some(read_at.to_rfc3339().as_str())
+        );
+        assert!(receipts.not_seen_by[0].read_at.is_none());
+
+        let earlier = super::receipts(&first, &positions, [&alice, &bob, &carol]);
+        assert_eq!(names(&earlier.seen_by), ["alice", "bob"]);
+    }
+}
@@ -0,0 +1,584 @@
+//! Rooms: separate message logs and upload namespaces within one box.
+//!
+//! The `rooms` state document lists each room's name, members and retention.
+//! The room that existed before there were rooms is `default`; its log and
+//! uploads stay where they always were, so it needs no migration and is
+//! listed even if the document has never been written. Other rooms keep
+//! their data apart (see `Storage::open_room`).
+//!
+//! Room routes are the default room's routes under `/api/rooms/{id}`:
+//! `/api/rooms/work/messages` is to the `work` room what `/api/messages` is
+//! to the default one.
+
+use crate::message::Message;
+use crate::segment::{self, RetentionPolicy, RetentionReport};
+use crate::state::{self, StateStore};
+use crate::store::Storage;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+pub const ROOMS: &str = "rooms";
+pub const DEFAULT_ROOM: &str = "default";
+/// Where rooms other than the default one keep their data.
+pub const ROOMS_DIR: &str = "rooms";
+pub const ROOMS_PATH: &str = "/api/rooms/";
+
+const MAX_ID_LEN: usize = 32;
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Rooms {
+    #[serde(default)]
+    rooms: Vec<Room>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Room {
+    pub id: String,
+    pub name: String,
+    pub created_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// Devices that may use the room. Empty means everyone, including
+    /// requests without a device.
+    #[serde(default)]
+    pub members: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+}
+
+/// A room's own retention; unset fields keep the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Retention {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_after_days: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purge_after_days: Option<u32>,
+}
+
+impl Room {
+    fn default_room() -> Room {
+        Room {
+            id: DEFAULT_ROOM.to_string(),
+            name: "Default".to_string(),
+            created_at: String::new(),
+            created_by: None,
+            members: Vec::new(),
+            retention: None,
+        }
+    }
+
+    /// Whether `device` may use the room. Requests without a device, such as
+    /// HTTP Basic with the passphrase, may only use rooms open to every
+    /// device.
+    pub fn admits(&self, device: Option<&str>) -> bool {
+        self.members.is_empty()
+            || device.is_some_and(|device| self.members.iter().any(|m| m == device))
+    }
+
+    /// Whether `device` created the room, which lets it change who is in the
+    /// room and how long its messages are kept.
+    pub fn created_by_device(&self, device: Option<&str>) -> bool {
+        self.created_by.is_some() && self.created_by.as_deref() == device
+    }
+
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        let defaults = RetentionPolicy::default();
+        let retention = self.retention.clone().unwrap_or_default();
+        RetentionPolicy {
+            archive_after: retention
+                .archive_after_days
+                .map_or(defaults.archive_after, |d| Duration::days(d.into())),
+            purge_after: retention
+                .purge_after_days
+                .map_or(defaults.purge_after, |d| Duration::days(d.into())),
+        }
+    }
+}
+
+/// What `POST /api/rooms` and `PATCH /api/rooms/{id}` may set. The id can
+/// only be chosen at creation; without one it is made from the name.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomChanges {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub members: Option<Vec<String>>,
+    pub retention: Option<Retention>,
+}
+
+impl RoomChanges {
+    /// Whether these changes touch members or retention, which only the
+    /// room's creator or the owner may change, rather than just the name.
+    pub fn changes_access(&self) -> bool {
+        self.members.is_some() || self.retention.is_some()
+    }
+}
+
+/// Lower-case letters, digits and dashes, not starting with a dash.
+pub fn validate_id(id: &str) -> Result<(), Error> {
+    let valid = !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && !id.starts_with('-')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid room id: {:?}", id),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_name(name: &str) -> Result<String, Error> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Room names must have 1 to {} characters", MAX_NAME_LEN),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// `Family photos` becomes `family-photos`.
+fn slug(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(MAX_ID_LEN);
+    slug.trim_end_matches('-').to_string()
+}
+
+fn load(store: &dyn StateStore) -> Result<Rooms, Error> {
+    let mut rooms: Rooms = state::load(store, ROOMS)?;
+    rooms.with_default();
+    Ok(rooms)
+}
+
+impl Rooms {
+    fn with_default(&mut self) {
+        if !self.rooms.iter().any(|r| r.id == DEFAULT_ROOM) {
+            self.rooms.insert(0, Room::default_room());
+        }
+    }
+
+    fn find_mut(&mut self, id: &str) -> Result<&mut Room, Error> {
+        self.rooms
+            .iter_mut()
+            .find(|r| r.id == id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No room {}", id)))
+    }
+}
+
+/// Every room, the default one first.
+pub fn list(store: &dyn StateStore) -> Result<Vec<Room>, Error> {
+    Ok(load(store)?.rooms)
+}
+
+pub fn get(store: &dyn StateStore, id: &str) -> Result<Option<Room>, Error> {
+    Ok(load(store)?.rooms.into_iter().find(|r| r.id == id))
+}
+
+pub fn create(
+    store: &dyn StateStore,
+    changes: RoomChanges,
+    created_by: Option<String>,
+    now: DateTime<Utc>,
+) -> Result<Room, Error> {
+    let name = validate_name(changes.name.as_deref().unwrap_or_default())?;
+    let chosen = changes.id.is_some();
+    let base = changes.id.unwrap_or_else(|| slug(&name));
+    let base = if base.is_empty() {
+        "room".to_string()
+    } else {
+        base
+    };
+    validate_id(&base)?;
+
+    let room = state::update(store, ROOMS, |rooms: &mut Rooms| {
+        rooms.with_default();
+        let taken = |id: &str| rooms.rooms.iter().any(|r| r.id == id);
+        let id = if !taken(&base) {
+            base.clone()
+        } else if chosen {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Room {} already exists", base),
+            ));
+        } else {
+            (2..)
+                .map(|n| {
+                    let suffix = format!("-{}", n);
+                    let stem = &base[..base.len().min(MAX_ID_LEN - suffix.len())];
+                    format!("{}{}", stem.trim_end_matches('-'), suffix)
+                })
+                .find(|id| !taken(id))
+                .expect("some suffix is free")
+        };
+        let room = Room {
+            id,
+            name,
+            created_at: now.to_rfc3339(),
+            created_by,
+            members: changes.members.unwrap_or_default(),
+            retention: changes.retention,
+        };
+        rooms.rooms.push(room.clone());
+        Ok(room)
+    })?;
+    eprintln!("Created room {} ({})", room.id, room.name);
+    Ok(room)
+}
+
+pub fn edit(store: &dyn StateStore, id: &str, changes: RoomChanges) -> Result<Room, Error> {
+    if changes.id.as_deref().is_some_and(|new| new != id) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Rooms cannot be renumbered; change the name instead",
+        ));
+    }
+    let name = changes.name.as_deref().map(validate_name).transpose()?;
+    state::update(store, ROOMS, |rooms: &mut Rooms| {
+        rooms.with_default();
+        let room = rooms.find_mut(id)?;
+        if let Some(name) = name {
+            room.name = name;
+        }
+        if let Some(members) = changes.members {
+            room.members = members;
+        }
+        if let Some(retention) = changes.retention {
+            room.retention = Some(retention);
+        }
+        Ok(room.clone())
+    })
+}
+
+/// Paths that exist once per room. The bare `/api/...` forms address the
+/// default room.
+pub fn is_room_route(path: &str) -> bool {
+    [
+        "/api/messages",
+        "/api/upload",
+        "/api/clipboard",
+        "/api/admin/segments",
+    ]
+    .iter()
+    .any(|route| path == *route || path.starts_with(&format!("{}/", route)))
+        || path.starts_with("/api/files/")
+        || matches!(path, "/api/admin/fsck" | "/api/admin/migrate")
+}
+
+/// Splits `/api/rooms/{id}/messages` into the room and `/api/messages`, the
+/// route it stands for. Other paths, including the room's own
+/// `/api/rooms/{id}`, are left alone.
+pub fn scoped_route(path: &str) -> Option<(&str, String)> {
+    let (id, rest) = path.strip_prefix(ROOMS_PATH)?.split_once('/')?;
+    let route = format!("/api/{}", rest);
+    is_room_route(&route).then_some((id, route))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    Copy,
+    Move,
+}
+
+/// Copies or moves message `id` from `from` into `to`, with its upload, and
+/// returns it as it now is in `to`. It is stamped with the time of the
+/// transfer so that devices polling `to` pick it up, and no longer replies
+/// to a message that stays behind. A copy gets a new id and its own copy of
+/// the upload; a move keeps both and takes them out of `from`, with its
+/// pins and reactions, and replies to it left in `from` stop quoting it.
+pub fn transfer(
+    from: &Storage,
+    to: &Storage,
+    id: &str,
+    mode: Transfer,
+    now: DateTime<Utc>,
+) -> Result<Message, Error> {
+    if from.room == to.room {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The message is already in that room",
+        ));
+    }
+    let messages = from.messages.load()?;
+    let original = messages
+        .iter()
+        .find(|m| m.id == id && m.event.is_none())
+        .cloned()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No message {}", id)))?;
+
+    let mut message = original.clone();
+    message.timestamp = now.to_rfc3339();
+    message.reply_to = None;
+    let has_upload = matches!(message.msg_type.as_str(), "image" | "file");
+    if mode == Transfer::Copy {
+        message.id = uuid::Uuid::new_v4().to_string();
+        if has_upload {
+            let extension = original
+                .content
+                .rsplit_once('.')
+                .map(|(_, ext)| format!(".{}", ext))
+                .unwrap_or_default();
+            message.content = format!("{}{}", uuid::Uuid::new_v4(), extension);
+        }
+    }
+    if has_upload {
+        let data = from.blobs.get(&original.content)?;
+        to.blobs.put(&message.content, &data)?;
+    }
+    to.messages.append(&message)?;
+
+    if mode == Transfer::Move {
+        let events: Vec<&Message> = messages
+            .iter()
+            .filter(|m| m.event.as_ref().is_some_and(|e| e.target() == id))
+            .collect();
+        for event in &events {
+            let mut event = (*event).clone();
+            event.timestamp = now.to_rfc3339();
+            to.messages.append(&event)?;
+        }
+        let replies: Vec<Message> = messages
+            .iter()
+            .filter(|m| m.reply_to.as_deref() == Some(id))
+            .map(|m| Message {
+                reply_to: None,
+                ..m.clone()
+            })
+            .collect();
+        if !replies.is_empty() {
+            from.messages.update(&replies)?;
+        }
+        let mut gone = vec![id];
+        gone.extend(events.iter().map(|e| e.id.as_str()));
+        from.messages.remove(&gone)?;
+        if has_upload {
+            if let Err(e) = from.blobs.delete(&original.content) {
+                eprintln!("Failed to delete moved upload {}: {}", original.content, e);
+            }
+            crate::share::rehome(&*from.state, &from.room, &to.room, &original.content)?;
+        }
+    }
+    eprintln!(
+        "{} message {} from room {} to {}",
+        if mode == Transfer::Move {
+            "Moved"
+        } else {
+            "Copied"
+        },
+        id,
+        from.room,
+        to.room
+    );
+    Ok(message)
+}
+
+/// Applies every room's retention, opening each with `open`.
+pub fn apply_retention(
+    store: &dyn StateStore,
+    open: impl Fn(&str) -> Result<Storage, Error>,
+    now: DateTime<Utc>,
+) -> Result<BTreeMap<String, RetentionReport>, Error> {
+    let mut reports = BTreeMap::new();
+    for room in list(store)? {
+        let report = segment::apply_retention(&open(&room.id)?, &room.retention_policy(), now)?;
+        reports.insert(room.id, report);
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+    use crate::testutil::{ids, message};
+
+    fn room(members: &[&str]) -> Room {
+        Room {
+            id: "r".to_string(),
+            name: "Room".to_string(),
+            created_at: "2024-01-01T00:00:00Z".to_string(),
+            created_by: None,
+            members: members.iter().map(|m| m.to_string()).collect(),
+            retention: None,
+        }
+    }
+
+    #[test]
+    fn open_rooms_admit_everyone() {
+        let room = room(&[]);
+        assert!(room.admits(Some("alice")));
+        assert!(room.admits(None));
+    }
+
+    #[test]
+    fn rooms_with_members_admit_only_them() {
+        let room = room(&["alice"]);
+        assert!(room.admits(Some("alice")));
+        assert!(!room.admits(Some("bob")));
+        assert!(!room.admits(None));
+    }
+
+    #[test]
+    fn only_the_creator_may_change_access() {
+        let mut room = room(&["alice", "bob"]);
+        assert!(!room.created_by_device(None));
+        room.created_by = Some("alice".to_string());
+        assert!(room.created_by_device(Some("alice")));
+        assert!(!room.created_by_device(Some("bob")));
+        assert!(!room.created_by_device(None));
+
+        let rename = RoomChanges {
+            name: Some("Renamed".to_string()),
+            ..Default::default()
+        };
+        assert!(!rename.changes_access());
+        let members = RoomChanges {
+            members: Some(Vec::new()),
+            ..Default::default()
+        };
+        assert!(members.changes_access());
+        let retention = RoomChanges {
+            retention: Some(Retention::default()),
+            ..Default::default()
+        };
+        assert!(retention.changes_access());
+    }
+
+    #[test]
+    fn room_routes_are_scoped() {
+        assert_eq!(
+            scoped_route("/api/rooms/r/messages/poll"),
+            Some(("r", "/api/messages/poll".to_string()))
+        );
+        assert_eq!(
+            scoped_route("/api/rooms/r/files/a.png"),
+            Some(("r", "/api/files/a.png".to_string()))
+        );
+        assert_eq!(scoped_route("/api/rooms/r"), None);
+        assert_eq!(scoped_route("/api/rooms/r/devices"), None);
+        assert!(is_room_route("/api/messages"));
+        assert!(!is_room_route("/api/messagesx"));
+    }
+
+    fn rooms_pair() -> (Storage, Storage) {
+        let mut to = Storage::in_memory();
+        to.room = "work".to_string();
+        (Storage::in_memory(), to)
+    }
+
+    fn upload(id: &str, blob: &str) -> Message {
+        Message {
+            content: blob.to_string(),
+            msg_type: "image".to_string(),
+            filename: Some("cat.png".to_string()),
+            reply_to: Some("question".to_string()),
+            ..message(id, "2024-01-01T00:00:00Z")
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-02-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn copies_keep_the_original_and_its_upload() {
+        let (from, to) = rooms_pair();
+        from.messages
+            .append(&message("question", "2024-01-01T00:00:00Z"))
+            .unwrap();
+        from.messages.append(&upload("cat", "cat.png")).unwrap();
+        from.blobs.put("cat.png", b"meow").unwrap();
+
+        let copy = transfer(&from, &to, "cat", Transfer::Copy, now()).unwrap();
+        assert_ne!(copy.id, "cat");
+        assert_ne!(copy.content, "cat.png");
+        assert!(copy.content.ends_with(".png"));
+        assert_eq!(copy.timestamp, now().to_rfc3339());
+        assert_eq!(copy.reply_to, None);
+        assert_eq!(to.blobs.get(&copy.content).unwrap(), b"meow");
+        assert_eq!(ids(&to.messages.load().unwrap()), [copy.id.as_str()]);
+
+        assert_eq!(ids(&from.messages.load().unwrap()), ["question", "cat"]);
+        assert_eq!(from.blobs.get("cat.png").unwrap(), b"meow");
+        assert!(transfer(&from, &from, "cat", Transfer::Copy, now()).is_err());
+        assert_eq!(
+            transfer(&from, &to, "nothing", Transfer::Copy, now())
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn moves_take_the_upload_pins_and_reactions_along() {
+        let (from, to) = rooms_pair();
+        let cat = upload("cat", "cat.png");
+        from.messages.append(&cat).unwrap();
+        from.blobs.put("cat.png", b"meow").unwrap();
+        let pin = Event::Pin {
+            target: "cat".to_string(),
+            pinned: true,
+        }
+        .record(&cat, "alice".to_string(), None);
+        let react = Event::React {
+            target: "cat".to_string(),
+            emoji: "👍".to_string(),
+            added: true,
+        }
+        .record(&cat, "bob".to_string(), None);
+        let reply = Message {
+            reply_to: Some("cat".to_string()),
+            ..message("reply", "2024-01-01T00:05:00Z")
+        };
+        let other_pin = Event::Pin {
+            target: "reply".to_string(),
+            pinned: true,
+        }
+        .record(&reply, "alice".to_string(), None);
+        for record in [&pin, &react, &reply, &other_pin] {
+            from.messages.append(record).unwrap();
+        }
+
+        let moved = transfer(&from, &to, "cat", Transfer::Move, now()).unwrap();
+        assert_eq!(
+            (moved.id.as_str(), moved.content.as_str()),
+            ("cat", "cat.png")
+        );
+        assert_eq!(to.blobs.get("cat.png").unwrap(), b"meow");
+        assert!(from.blobs.get("cat.png").is_err());
+
+        let views = crate::event::fold(to.messages.load().unwrap());
+        assert_eq!(views.len(), 1);
+        assert!(views[0].pinned);
+        assert_eq!(views[0].reactions[0].by, ["bob"]);
+        assert!(
+            to.messages
+                .load()
+                .unwrap()
+                .iter()
+                .all(|m| m.timestamp == now().to_rfc3339())
+        );
+
+        let left = from.messages.load().unwrap();
+        assert_eq!(ids(&left), ["reply", other_pin.id.as_str()]);
+        assert_eq!(left[0].reply_to, None);
+    }
+}
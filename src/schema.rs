@@ -0,0 +1,292 @@
+//! Versioning for stored message records.
+//!
+//! Every record carries a `v` tag. Reading a record runs it through the
+//! migrations from its version up to `CURRENT_VERSION` before it is
+//! deserialized, so old history keeps loading after `Message` changes. To
+//! change the model, bump `CURRENT_VERSION` and append a migration that turns
+//! the previous layout into the new one.
+//!
+//! With encryption at rest turned on, records are also sealed here on write
+//! and opened on read (see `crypt`), so every message store gets it.
+
+use crate::crypt::{self, OpenError};
+use crate::message::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+pub const VERSION_FIELD: &str = "v";
+
+pub const CURRENT_VERSION: u64 = 2;
+
+type Migration = fn(Map<String, Value>) -> Result<Map<String, Value>, String>;
+
+/// `MIGRATIONS[n]` upgrades a version `n` record to version `n + 1`.
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [v0_to_v1, v1_to_v2];
+
+/// Records written before versioning existed have no tag. Fill in what the
+/// frontend has always assumed so they deserialize like new ones.
+fn v0_to_v1(mut record: Map<String, Value>) -> Result<Map<String, Value>, String> {
+    for field in ["id", "content", "sender", "timestamp"] {
+        if !record.get(field).is_some_and(Value::is_string) {
+            return Err(format!("Missing field `{}`", field));
+        }
+    }
+    record
+        .entry("type")
+        .or_insert_with(|| Value::String("text".to_string()));
+    for field in ["filename", "fileSize", "mimeType"] {
+        record.entry(field).or_insert(Value::Null);
+    }
+    Ok(record)
+}
+
+/// Version 2 added direct messages (`recipients`), event records (`type`
+/// `event` with an `event` field) and replies (`replyTo`). All are optional,
+/// so old records need no change; the bump is there so that releases from
+/// before it refuse logs they would misread, listing events as messages and
+/// direct messages to everyone.
+fn v1_to_v2(record: Map<String, Value>) -> Result<Map<String, Value>, String> {
+    Ok(record)
+}
+
+#[derive(Debug)]
+pub enum SchemaError {
+    /// Written by a newer release; must not be rewritten or quarantined.
+    Newer(u64),
+    /// Sealed under a key we do not have; likewise left alone.
+    Locked(String),
+    Invalid(String),
+}
+
+impl SchemaError {
+    /// Whether the record is intact but unreadable here, as opposed to
+    /// corrupt.
+    pub fn is_foreign(&self) -> bool {
+        matches!(self, SchemaError::Newer(_) | SchemaError::Locked(_))
+    }
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaError::Newer(version) => write!(
+                f,
+                "Record version {} is newer than supported version {}",
+                version, CURRENT_VERSION
+            ),
+            SchemaError::Locked(msg) => write!(f, "{}", msg),
+            SchemaError::Invalid(msg) => write!(f, "Invalid record: {}", msg),
+        }
+    }
+}
+
+pub fn record_version(record: &Map<String, Value>) -> Result<u64, SchemaError> {
+    match record.get(VERSION_FIELD) {
+        None => Ok(0),
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| SchemaError::Invalid(format!("Bad version tag {}", v))),
+    }
+}
+
+/// The record inside a sealed one; any other record as it is.
+pub fn unseal(value: Value) -> Result<Value, SchemaError> {
+    match &value {
+        Value::Object(record) if crypt::is_sealed(record) => {
+            let keys = crypt::configured().ok().flatten();
+            let plaintext = crypt::open_record(keys, record).map_err(|e| match e {
+                OpenError::Locked(msg) => SchemaError::Locked(msg),
+                OpenError::Invalid(msg) => SchemaError::Invalid(msg),
+            })?;
+            match serde_json::from_slice(&plaintext) {
+                Ok(Value::Object(inner)) if !crypt::is_sealed(&inner) => Ok(Value::Object(inner)),
+                _ => Err(SchemaError::Invalid(
+                    "Sealed record holds no record".to_string(),
+                )),
+            }
+        }
+        _ => Ok(value),
+    }
+}
+
+/// Upgrades a raw record to the current version and deserializes it.
+pub fn upgrade(value: Value) -> Result<(Message, u64), SchemaError> {
+    let Value::Object(mut record) = unseal(value)? else {
+        return Err(SchemaError::Invalid("Record is not an object".to_string()));
+    };
+
+    let original = record_version(&record)?;
+    if original > CURRENT_VERSION {
+        return Err(SchemaError::Newer(original));
+    }
+
+    for migration in &MIGRATIONS[original as usize..] {
+        record = migration(record).map_err(SchemaError::Invalid)?;
+    }
+    record.remove(VERSION_FIELD);
+
+    let message = serde_json::from_value(Value::Object(record))
+        .map_err(|e| SchemaError::Invalid(e.to_string()))?;
+    Ok((message, original))
+}
+
+/// Parses and upgrades one serialized record.
+pub fn upgrade_bytes(bytes: &[u8]) -> Result<(Message, u64), SchemaError> {
+    let value = serde_json::from_slice(bytes).map_err(|e| SchemaError::Invalid(e.to_string()))?;
+    upgrade(value)
+}
+
+/// Serializes a message tagged with the current version.
+pub fn tag(message: &Message) -> Value {
+    let mut value = serde_json::to_value(message).unwrap_or(Value::Null);
+    if let Value::Object(record) = &mut value {
+        record.insert(VERSION_FIELD.to_string(), Value::from(CURRENT_VERSION));
+    }
+    value
+}
+
+/// The record to store for a message: tagged, and sealed if encryption is
+/// turned on.
+pub fn seal(message: &Message) -> Result<Value, std::io::Error> {
+    let record = tag(message);
+    match crypt::configured()? {
+        Some(keys) => crypt::seal_record(keys, &serde_json::to_vec(&record)?),
+        None => Ok(record),
+    }
+}
+
+/// Whether a stored record is in the clear although encryption is on.
+pub fn needs_sealing(bytes: &[u8]) -> bool {
+    crypt::configured().is_ok_and(|keys| keys.is_some())
+        && !serde_json::from_slice::<Value>(bytes)
+            .is_ok_and(|v| v.as_object().is_some_and(crypt::is_sealed))
+}
+
+/// A message as it appears on disk: versioned on write, migrated on read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "Value", into = "Value")]
+pub struct StoredMessage(pub Message);
+
+impl TryFrom<Value> for StoredMessage {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        upgrade(value)
+            .map(|(message, _)| StoredMessage(message))
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl From<StoredMessage> for Value {
+    fn from(stored: StoredMessage) -> Value {
+        tag(&stored.0)
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationReport {
+    pub current_version: u64,
+    pub total: usize,
+    /// Records rewritten, keyed by the version they had before.
+    pub upgraded: std::collections::BTreeMap<u64, usize>,
+    /// Records that were stored in the clear and are now sealed.
+    pub sealed: usize,
+}
+
+impl MigrationReport {
+    pub fn new() -> MigrationReport {
+        MigrationReport {
+            current_version: CURRENT_VERSION,
+            ..Default::default()
+        }
+    }
+
+    pub fn merge(&mut self, other: MigrationReport) {
+        self.total += other.total;
+        self.sealed += other.sealed;
+        for (version, count) in other.upgraded {
+            *self.upgraded.entry(version).or_default() += count;
+        }
+    }
+
+    pub fn record(&mut self, original_version: u64) {
+        self.total += 1;
+        if original_version != CURRENT_VERSION {
+            *self.upgraded.entry(original_version).or_default() += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn untagged_records_get_defaults() {
+        let (message, version) = upgrade(json!({
+            "id": "a", "content": "hi", "sender": "s", "timestamp": "2024-01-01T00:00:00Z"
+        }))
+        .unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(message.msg_type, "text");
+        assert!(message.recipients.is_empty());
+    }
+
+    #[test]
+    fn version_1_records_still_load() {
+        let (message, version) = upgrade(json!({
+            "v": 1, "id": "a", "content": "hi", "sender": "s",
+            "timestamp": "2024-01-01T00:00:00Z", "type": "text", "recipients": ["d1"]
+        }))
+        .unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(message.recipients, ["d1"]);
+    }
+
+    #[test]
+    fn new_records_are_tagged_with_the_current_version() {
+        let (message, _) = upgrade(json!({
+            "id": "a", "content": "hi", "sender": "s", "timestamp": "2024-01-01T00:00:00Z"
+        }))
+        .unwrap();
+        assert_eq!(tag(&message)[VERSION_FIELD], json!(2));
+        let (_, version) = upgrade(tag(&message)).unwrap();
+        assert_eq!(version, CURRENT_VERSION);
+    }
+
+    #[test]
+    fn newer_records_are_refused_as_foreign() {
+        let err = upgrade(json!({
+            "v": CURRENT_VERSION + 1, "id": "a", "content": "hi", "sender": "s",
+            "timestamp": "2024-01-01T00:00:00Z"
+        }))
+        .unwrap_err();
+        assert!(matches!(err, SchemaError::Newer(v) if v == CURRENT_VERSION + 1));
+        assert!(err.is_foreign());
+    }
+
+    #[test]
+    fn broken_records_are_invalid() {
+        let err = upgrade(json!({ "id": "a", "sender": "s" })).unwrap_err();
+        assert!(matches!(err, SchemaError::Invalid(_)));
+        assert!(!err.is_foreign());
+        assert!(matches!(
+            upgrade(json!("text")),
+            Err(SchemaError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn reports_count_upgrades_by_original_version() {
+        let mut report = MigrationReport::new();
+        report.record(0);
+        report.record(1);
+        report.record(1);
+        report.record(CURRENT_VERSION);
+        assert_eq!(report.total, 4);
+        assert_eq!(report.upgraded.get(&1), Some(&2));
+        assert_eq!(report.upgraded.get(&CURRENT_VERSION), None);
+    }
+}
@@ -0,0 +1,319 @@
+//! Cross-site request checks and security headers.
+//!
+//! A state-changing API request (anything but GET, HEAD and OPTIONS) is
+//! refused when the browser says it comes from another site: `Sec-Fetch-Site` must be
+//! `same-origin` or `none`, or failing that `Origin` must name the host the
+//! request was sent to (or one listed in `SPORE_ALLOWED_ORIGINS`). Requests
+//! without either header, such as those from scripts, pass.
+//!
+//! Requests that carry the session cookie must also send `X-CSRF-Token`, a
+//! hash of the cookie that `/api/session` hands out. Pages on other sites
+//! cannot read the cookie, so they cannot compute the token. Routes that work
+//! without a session, like logging in, are exempt, so a stale cookie cannot
+//! lock anyone out.
+
+use crate::auth::{self, SESSION_COOKIE, constant_time_eq};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use sha2::{Digest, Sha256};
+
+pub const TOKEN_HEADER: &str = "x-csrf-token";
+pub const ALLOWED_ORIGINS_VAR: &str = "SPORE_ALLOWED_ORIGINS";
+
+/// For the app itself. Styles may be inline because the markdown and syntax
+/// highlighting set them; images may be data URLs for QR codes.
+const APP_CSP: &str = "default-src 'self'; script-src 'self'; style-src 'self' 'unsafe-inline'; \
+img-src 'self' data: blob:; connect-src 'self'; object-src 'none'; base-uri 'self'; \
+form-action 'self'; frame-ancestors 'none'";
+
+/// For API responses, uploads and shared files, which are never meant to run
+/// anything. The share password form still needs its inline styles and to
+/// post itself.
+const API_CSP: &str = "default-src 'none'; style-src 'unsafe-inline'; form-action 'self'; \
+base-uri 'none'; frame-ancestors 'none'";
+
+const COMMON_HEADERS: [(&str, &str); 4] = [
+    ("Referrer-Policy", "no-referrer"),
+    ("X-Frame-Options", "DENY"),
+    ("X-Content-Type-Options", "nosniff"),
+    (
+        "Permissions-Policy",
+        "camera=(), microphone=(), geolocation=(), payment=(), usb=(), \
+clipboard-read=(self), clipboard-write=(self)",
+    ),
+];
+
+/// Headers every response to `path` gets, unless the handler set them.
+pub fn headers(path: &str) -> impl Iterator<Item = (&'static str, &'static str)> {
+    let api = path.starts_with("/api/") || path.starts_with(crate::share::SHARE_PATH);
+    let csp = if api { API_CSP } else { APP_CSP };
+    std::iter::once(("Content-Security-Policy", csp)).chain(COMMON_HEADERS)
+}
+
+/// The token to send with state-changing requests made with `cookie`, the
+/// session cookie's value.
+pub fn token(cookie: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"spore-box csrf\0");
+    hasher.update(cookie.as_bytes());
+    URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+/// The token for the session cookie in a `Cookie` header, if it has one.
+pub fn token_for(cookie_header: Option<&str>) -> Option<String> {
+    cookie_header
+        .and_then(|c| auth::cookie_value(c, SESSION_COOKIE))
+        .filter(|value| !value.is_empty())
+        .map(token)
+}
+
+/// What a request says about where it comes from.
+#[derive(Debug, Default)]
+pub struct RequestOrigin<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub host: Option<&'a str>,
+    pub forwarded_host: Option<&'a str>,
+    pub origin: Option<&'a str>,
+    pub sec_fetch_site: Option<&'a str>,
+    pub cookie: Option<&'a str>,
+    pub csrf_token: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Refusal {
+    CrossSite,
+    MissingToken,
+    InvalidToken,
+}
+
+impl Refusal {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Refusal::CrossSite => "cross-site-request",
+            Refusal::MissingToken => "csrf-token-required",
+            Refusal::InvalidToken => "invalid-csrf-token",
+        }
+    }
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refusal::CrossSite => write!(f, "Cross-site requests are not allowed"),
+            Refusal::MissingToken => write!(f, "Missing {} header", TOKEN_HEADER),
+            Refusal::InvalidToken => write!(f, "Invalid {} header", TOKEN_HEADER),
+        }
+    }
+}
+
+fn is_safe_method(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS")
+}
+
+/// `host[:port]` of an origin such as `https://box.example:8443`.
+fn origin_host(origin: &str) -> Option<&str> {
+    let (_scheme, rest) = origin.split_once("://")?;
+    Some(rest.trim_end_matches('/'))
+}
+
+fn allowed_origins() -> Vec<String> {
+    parse_origins(&std::env::var(ALLOWED_ORIGINS_VAR).unwrap_or_default())
+}
+
+fn parse_origins(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|o| o.trim().trim_end_matches('/').to_string())
+        .filter(|o| !o.is_empty())
+        .collect()
+}
+
+fn same_origin(request: &RequestOrigin, allowed: &[String]) -> bool {
+    if let Some(site) = request.sec_fetch_site {
+        return matches!(site, "same-origin" | "none");
+    }
+    let Some(origin) = request.origin else {
+        return true;
+    };
+    let origin = origin.trim();
+    if allowed.iter().any(|o| o == origin) {
+        return true;
+    }
+    let Some(origin_host) = origin_host(origin) else {
+        // Includes the `null` origin of sandboxed frames and data URLs.
+        return false;
+    };
+    [request.host, request.forwarded_host]
+        .into_iter()
+        .flatten()
+        .any(|host| host.trim().eq_ignore_ascii_case(origin_host))
+}
+
+/// Refuses cross-site state-changing requests and cookie requests without a
+/// matching token.
+pub fn check(request: &RequestOrigin) -> Result<(), Refusal> {
+    check_allowing(request, &allowed_origins())
+}
+
+fn check_allowing(request: &RequestOrigin, allowed: &[String]) -> Result<(), Refusal> {
+    if is_safe_method(request.method) || !request.path.starts_with("/api/") {
+        return Ok(());
+    }
+    if !same_origin(request, allowed) {
+        return Err(Refusal::CrossSite);
+    }
+    if !auth::is_protected(request.path) {
+        return Ok(());
+    }
+    let Some(expected) = token_for(request.cookie) else {
+        return Ok(());
+    };
+    match request.csrf_token {
+        None => Err(Refusal::MissingToken),
+        Some(sent) if constant_time_eq(sent.trim().as_bytes(), expected.as_bytes()) => Ok(()),
+        Some(_) => Err(Refusal::InvalidToken),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(path: &str) -> RequestOrigin<'_> {
+        RequestOrigin {
+            method: "POST",
+            path,
+            host: Some("box.local:8081"),
+            ..Default::default()
+        }
+    }
+
+    fn checked(request: &RequestOrigin) -> Result<(), Refusal> {
+        check_allowing(request, &[])
+    }
+
+    #[test]
+    fn trusts_sec_fetch_site() {
+        for (site, expected) in [
+            ("same-origin", Ok(())),
+            ("none", Ok(())),
+            ("same-site", Err(Refusal::CrossSite)),
+            ("cross-site", Err(Refusal::CrossSite)),
+        ] {
+            let request = RequestOrigin {
+                sec_fetch_site: Some(site),
+                // Sec-Fetch-Site wins over a matching Origin.
+                origin: Some("http://box.local:8081"),
+                ..post("/api/messages")
+            };
+            assert_eq!(checked(&request), expected, "{site}");
+        }
+    }
+
+    #[test]
+    fn compares_origin_with_the_host() {
+        let from = |origin| RequestOrigin {
+            origin: Some(origin),
+            ..post("/api/messages")
+        };
+        assert_eq!(checked(&from("http://box.local:8081")), Ok(()));
+        assert_eq!(checked(&from("https://BOX.local:8081/")), Ok(()));
+        assert_eq!(checked(&from("http://box.local")), Err(Refusal::CrossSite));
+        assert_eq!(
+            checked(&from("https://evil.example")),
+            Err(Refusal::CrossSite)
+        );
+        assert_eq!(checked(&from("null")), Err(Refusal::CrossSite));
+
+        let proxied = RequestOrigin {
+            forwarded_host: Some("box.example.org"),
+            ..from("https://box.example.org")
+        };
+        assert_eq!(checked(&proxied), Ok(()));
+        // Scripts send neither header.
+        assert_eq!(checked(&post("/api/messages")), Ok(()));
+    }
+
+    #[test]
+    fn allows_listed_origins() {
+        let allowed = parse_origins(" https://app.example/ ,, http://10.0.0.2:8081");
+        assert_eq!(allowed, ["https://app.example", "http://10.0.0.2:8081"]);
+        let from = |origin| RequestOrigin {
+            origin: Some(origin),
+            ..post("/api/messages")
+        };
+        assert_eq!(
+            check_allowing(&from("https://app.example"), &allowed),
+            Ok(())
+        );
+        assert_eq!(
+            check_allowing(&from("http://10.0.0.2:8081"), &allowed),
+            Ok(())
+        );
+        assert_eq!(
+            check_allowing(&from("https://other.example"), &allowed),
+            Err(Refusal::CrossSite)
+        );
+        assert_eq!(check_allowing(&from("null"), &["null".to_string()]), Ok(()));
+    }
+
+    #[test]
+    fn cookies_need_a_matching_token() {
+        let cookie = format!("theme=dark; {}=session-value", SESSION_COOKIE);
+        let expected = token("session-value");
+        let with = |csrf_token| RequestOrigin {
+            cookie: Some(&cookie),
+            csrf_token,
+            ..post("/api/messages")
+        };
+        assert_eq!(checked(&with(None)), Err(Refusal::MissingToken));
+        assert_eq!(checked(&with(Some("wrong"))), Err(Refusal::InvalidToken));
+        assert_eq!(checked(&with(Some(&expected))), Ok(()));
+        assert_eq!(checked(&with(Some(&format!(" {} ", expected)))), Ok(()));
+
+        // Without a session cookie there is nothing to forge.
+        let other_cookie = RequestOrigin {
+            cookie: Some("theme=dark"),
+            ..post("/api/messages")
+        };
+        assert_eq!(checked(&other_cookie), Ok(()));
+        let empty = format!("{}=", SESSION_COOKIE);
+        let cleared = RequestOrigin {
+            cookie: Some(&empty),
+            ..post("/api/messages")
+        };
+        assert_eq!(checked(&cleared), Ok(()));
+    }
+
+    #[test]
+    fn exempts_safe_methods_and_routes_without_a_session() {
+        let cookie = format!("{}=stale", SESSION_COOKIE);
+        for path in ["/api/login", "/api/logout", "/api/pairing/redeem"] {
+            let request = RequestOrigin {
+                cookie: Some(&cookie),
+                ..post(path)
+            };
+            assert_eq!(checked(&request), Ok(()), "{path}");
+        }
+        // But they are still refused from other sites.
+        let cross_site = RequestOrigin {
+            sec_fetch_site: Some("cross-site"),
+            ..post("/api/login")
+        };
+        assert_eq!(checked(&cross_site), Err(Refusal::CrossSite));
+
+        let read = RequestOrigin {
+            method: "GET",
+            sec_fetch_site: Some("cross-site"),
+            cookie: Some(&cookie),
+            ..post("/api/messages")
+        };
+        assert_eq!(checked(&read), Ok(()));
+        let share_form = RequestOrigin {
+            sec_fetch_site: Some("cross-site"),
+            ..post("/share/abc.123.sig")
+        };
+        assert_eq!(checked(&share_form), Ok(()));
+    }
+}
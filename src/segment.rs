@@ -0,0 +1,805 @@
+//! The on-disk message log, split into per-month segment files.
+//!
+//! `data/log/manifest.json` lists the segments oldest first. Appends go to the
+//! last (head) segment, which rolls over when a message from a later month
+//! arrives or the file grows past `MAX_SEGMENT_BYTES`. Reads walk the live
+//! segments in manifest order, so callers see one continuous log. Archiving
+//! moves a whole segment file to `data/archive/`, purging deletes it.
+//!
+//! A pre-segment `data/messages.jsonl` is adopted in place as the first
+//! segment the first time the log is opened.
+
+use crate::event;
+use crate::index::{self, INDEX_SUFFIX, LogIndex};
+use crate::jsonl::{self, CorruptLine, FileLock, RecoveryReport};
+use crate::message::Message;
+use crate::schema::MigrationReport;
+use crate::store::{JsonlMessageStore, MessageStore, Storage, timestamp_key};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+pub const MAX_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+const LEGACY_LOG: &str = "messages.jsonl";
+const LOG_DIR: &str = "log";
+const ARCHIVE_DIR: &str = "archive";
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Manifest {
+    segments: Vec<SegmentEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct SegmentEntry {
+    name: String,
+    created_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    archived_at: Option<String>,
+}
+
+impl Manifest {
+    fn live(&self) -> impl Iterator<Item = &SegmentEntry> {
+        self.segments.iter().filter(|s| s.archived_at.is_none())
+    }
+
+    fn find(&self, name: &str) -> Option<&SegmentEntry> {
+        self.segments.iter().find(|s| s.name == name)
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentInfo {
+    pub name: String,
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archived_at: Option<String>,
+    pub head: bool,
+    pub bytes: u64,
+    pub messages: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub newest: Option<String>,
+}
+
+pub struct SegmentedMessageStore {
+    data_dir: PathBuf,
+    log_dir: PathBuf,
+    archive_dir: PathBuf,
+}
+
+impl SegmentedMessageStore {
+    pub fn new(data_dir: impl Into<PathBuf>) -> SegmentedMessageStore {
+        let data_dir = data_dir.into();
+        SegmentedMessageStore {
+            log_dir: data_dir.join(LOG_DIR),
+            archive_dir: data_dir.join(ARCHIVE_DIR),
+            data_dir,
+        }
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.log_dir.join(MANIFEST)
+    }
+
+    fn segment_path(&self, entry: &SegmentEntry) -> PathBuf {
+        let dir = match entry.archived_at {
+            Some(_) => &self.archive_dir,
+            None => &self.log_dir,
+        };
+        dir.join(format!("{}.jsonl", entry.name))
+    }
+
+    fn segment(&self, entry: &SegmentEntry) -> JsonlMessageStore {
+        JsonlMessageStore::new(self.segment_path(entry))
+    }
+
+    fn live_segments(&self) -> Result<Vec<JsonlMessageStore>, Error> {
+        Ok(self.manifest()?.live().map(|s| self.segment(s)).collect())
+    }
+
+    fn manifest(&self) -> Result<Manifest, Error> {
+        match std::fs::read(self.manifest_path()) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: {}", self.manifest_path().display(), e),
+                )
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let lock = FileLock::acquire(&self.manifest_path())?;
+                self.adopt_legacy_log(&lock)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Re-reads the manifest under its lock, applies `f` and saves it.
+    fn edit_manifest<T>(
+        &self,
+        f: impl FnOnce(&mut Manifest, &FileLock) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        // Make sure a legacy log has been adopted before taking the lock.
+        self.manifest()?;
+        let lock = FileLock::acquire(&self.manifest_path())?;
+        let mut manifest = self.manifest()?;
+        let result = f(&mut manifest, &lock)?;
+        jsonl::write_atomically(
+            &self.manifest_path(),
+            &serde_json::to_vec_pretty(&manifest)?,
+        )?;
+        Ok(result)
+    }
+
+    /// Creates the manifest, turning `data/messages.jsonl` into the first
+    /// segment if it exists. The caller holds the manifest lock.
+    fn adopt_legacy_log(&self, _lock: &FileLock) -> Result<Manifest, Error> {
+        // Another instance may have finished while we waited for the lock.
+        if let Ok(bytes) = std::fs::read(self.manifest_path()) {
+            return serde_json::from_slice(&bytes)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()));
+        }
+
+        let mut manifest = Manifest::default();
+        let legacy = self.data_dir.join(LEGACY_LOG);
+        if legacy.exists() {
+            let legacy_store = JsonlMessageStore::new(&legacy);
+            let first_month = legacy_store
+                .load()?
+                .first()
+                .map(|m| month_of(&m.timestamp))
+                .unwrap_or_else(|| month_of(""));
+            let entry = SegmentEntry {
+                name: first_month,
+                created_at: Utc::now().to_rfc3339(),
+                archived_at: None,
+            };
+
+            let legacy_lock = FileLock::acquire(&legacy)?;
+            LogIndex::for_log(&legacy).invalidate()?;
+            std::fs::rename(&legacy, self.segment_path(&entry))?;
+            drop(legacy_lock);
+
+            eprintln!("Adopted {} as log segment {}", legacy.display(), entry.name);
+            manifest.segments.push(entry);
+        }
+
+        jsonl::write_atomically(
+            &self.manifest_path(),
+            &serde_json::to_vec_pretty(&manifest)?,
+        )?;
+        Ok(manifest)
+    }
+
+    /// The segment a message with `timestamp` should be appended to, creating
+    /// a new head if the current one is from an earlier month or full.
+    fn head_for(&self, timestamp: &str) -> Result<JsonlMessageStore, Error> {
+        let month = month_of(timestamp);
+        let manifest = self.manifest()?;
+        if let Some(head) = manifest.live().last()
+            && !self.needs_rollover(head, &month)
+        {
+            return Ok(self.segment(head));
+        }
+
+        self.edit_manifest(|manifest, _| {
+            if let Some(head) = manifest.live().last()
+                && !self.needs_rollover(head, &month)
+            {
+                return Ok(self.segment(head));
+            }
+
+            let base = match manifest.live().last() {
+                Some(head) if segment_month(&head.name) > month.as_str() => {
+                    segment_month(&head.name).to_string()
+                }
+                _ => month.clone(),
+            };
+            let taken = manifest
+                .segments
+                .iter()
+                .filter(|s| segment_month(&s.name) == base)
+                .count();
+            let name = match taken {
+                0 => base,
+                n => format!("{}.{:03}", base, n),
+            };
+
+            let entry = SegmentEntry {
+                name,
+                created_at: Utc::now().to_rfc3339(),
+                archived_at: None,
+            };
+            let segment = self.segment(&entry);
+            manifest.segments.push(entry);
+            Ok(segment)
+        })
+    }
+
+    fn needs_rollover(&self, head: &SegmentEntry, month: &str) -> bool {
+        if month > segment_month(&head.name) {
+            return true;
+        }
+        std::fs::metadata(self.segment_path(head)).is_ok_and(|m| m.len() >= MAX_SEGMENT_BYTES)
+    }
+
+    fn info(&self, entry: &SegmentEntry, head: bool) -> Result<SegmentInfo, Error> {
+        let path = self.segment_path(entry);
+        let bytes = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        let (messages, newest) = LogIndex::for_log(&path).summary()?;
+        Ok(SegmentInfo {
+            name: entry.name.clone(),
+            created_at: entry.created_at.clone(),
+            archived_at: entry.archived_at.clone(),
+            head,
+            bytes,
+            messages,
+            newest: newest
+                .and_then(DateTime::from_timestamp_millis)
+                .map(|t| t.to_rfc3339()),
+        })
+    }
+}
+
+/// `YYYY-MM` of an RFC 3339 timestamp in UTC, or of now if it is unparseable.
+fn month_of(timestamp: &str) -> String {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_else(|_| Utc::now())
+        .format("%Y-%m")
+        .to_string()
+}
+
+/// The month part of a segment name such as `2026-10` or `2026-10.001`.
+fn segment_month(name: &str) -> &str {
+    name.split('.').next().unwrap_or(name)
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<(), Error> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn remove_file(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+impl MessageStore for SegmentedMessageStore {
+    fn load(&self) -> Result<Vec<Message>, Error> {
+        let mut messages = Vec::new();
+        for segment in self.live_segments()? {
+            messages.extend(segment.load()?);
+        }
+        Ok(messages)
+    }
+
+    fn append(&self, message: &Message) -> Result<(), Error> {
+        self.head_for(&message.timestamp)?.append(message)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Message>, Error> {
+        for segment in self.live_segments()?.iter().rev() {
+            if let Some(message) = segment.get(id)? {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    fn latest(&self, keep: &dyn Fn(&Message) -> bool) -> Result<Option<Message>, Error> {
+        // Later segments hold later months, but a message can arrive late, so
+        // an older segment is only skipped once everything in it is older
+        // than the best match so far.
+        let mut best: Option<Message> = None;
+        for entry in self
+            .manifest()?
+            .live()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+        {
+            if let Some(best_ms) = best
+                .as_ref()
+                .and_then(|b| index::timestamp_ms(&b.timestamp))
+            {
+                let (_, newest) = LogIndex::for_log(&self.segment_path(entry)).summary()?;
+                if newest.is_some_and(|newest| newest < best_ms) {
+                    continue;
+                }
+            }
+            if let Some(found) = self.segment(entry).latest(keep)?
+                && best
+                    .as_ref()
+                    .is_none_or(|b| timestamp_key(&found.timestamp) > timestamp_key(&b.timestamp))
+            {
+                best = Some(found);
+            }
+        }
+        Ok(best)
+    }
+
+    fn since(&self, since: &str) -> Result<Vec<Message>, Error> {
+        // Each segment bisects its own index, so old segments cost a lookup.
+        let mut messages = Vec::new();
+        for segment in self.live_segments()? {
+            messages.extend(segment.since(since)?);
+        }
+        Ok(messages)
+    }
+
+    fn migrate(&self) -> Result<MigrationReport, Error> {
+        let mut report = MigrationReport::new();
+        for entry in &self.manifest()?.segments {
+            report.merge(self.segment(entry).migrate()?);
+        }
+        Ok(report)
+    }
+
+    fn update(&self, updated: &[Message]) -> Result<usize, Error> {
+        let mut replaced = 0;
+        for entry in &self.manifest()?.segments {
+            replaced += self.segment(entry).update(updated)?;
+        }
+        Ok(replaced)
+    }
+
+    fn remove(&self, ids: &[&str]) -> Result<usize, Error> {
+        let mut removed = 0;
+        for entry in &self.manifest()?.segments {
+            removed += self.segment(entry).remove(ids)?;
+        }
+        Ok(removed)
+    }
+
+    fn scan(&self) -> Result<(Vec<Message>, Vec<CorruptLine>), Error> {
+        let mut messages = Vec::new();
+        let mut corrupt = Vec::new();
+        for entry in self.manifest()?.live() {
+            let (segment_messages, segment_corrupt) = self.segment(entry).scan()?;
+            messages.extend(segment_messages);
+            corrupt.extend(segment_corrupt.into_iter().map(|mut line| {
+                line.error = format!("segment {}: {}", entry.name, line.error);
+                line
+            }));
+        }
+        Ok((messages, corrupt))
+    }
+
+    fn recover(&self) -> Result<RecoveryReport, Error> {
+        let mut report = RecoveryReport::default();
+        for segment in self.live_segments()? {
+            let segment_report = segment.recover()?;
+            report.kept += segment_report.kept;
+            report.quarantined.extend(segment_report.quarantined);
+            report.quarantine_file = segment_report.quarantine_file.or(report.quarantine_file);
+        }
+        Ok(report)
+    }
+
+    fn segments(&self) -> Result<Vec<SegmentInfo>, Error> {
+        let manifest = self.manifest()?;
+        let head = manifest.live().last().map(|s| s.name.clone());
+        manifest
+            .segments
+            .iter()
+            .map(|s| self.info(s, Some(&s.name) == head.as_ref()))
+            .collect()
+    }
+
+    fn archive_segment(&self, name: &str) -> Result<(), Error> {
+        self.edit_manifest(|manifest, _| {
+            let head = manifest.live().last().map(|s| s.name.clone());
+            if head.as_deref() == Some(name) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "The head segment is still being written",
+                ));
+            }
+            let entry = manifest
+                .segments
+                .iter_mut()
+                .find(|s| s.name == name && s.archived_at.is_none())
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::NotFound, format!("No live segment {}", name))
+                })?;
+
+            let from = self.segment_path(entry);
+            entry.archived_at = Some(Utc::now().to_rfc3339());
+            let to = self.segment_path(entry);
+
+            std::fs::create_dir_all(&self.archive_dir)?;
+            let _segment_lock = FileLock::acquire(&from)?;
+            move_file(&from, &to)?;
+            move_file(
+                &jsonl::sidecar_path(&from, INDEX_SUFFIX),
+                &jsonl::sidecar_path(&to, INDEX_SUFFIX),
+            )?;
+            Ok(())
+        })
+    }
+
+    fn purge_segment(&self, name: &str) -> Result<Vec<Message>, Error> {
+        self.edit_manifest(|manifest, _| {
+            let entry = manifest
+                .find(name)
+                .cloned()
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No segment {}", name)))?;
+            if entry.archived_at.is_none() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Only archived segments can be purged",
+                ));
+            }
+
+            let path = self.segment_path(&entry);
+            let purged = self.segment(&entry).load()?;
+            let _segment_lock = FileLock::acquire(&path)?;
+            remove_file(&path)?;
+            remove_file(&jsonl::sidecar_path(&path, INDEX_SUFFIX))?;
+            manifest.segments.retain(|s| s.name != name);
+            Ok(purged)
+        })
+    }
+
+    fn load_archived(&self) -> Result<Vec<Message>, Error> {
+        let mut messages = Vec::new();
+        for entry in self
+            .manifest()?
+            .segments
+            .iter()
+            .filter(|s| s.archived_at.is_some())
+        {
+            messages.extend(self.segment(entry).load()?);
+        }
+        Ok(messages)
+    }
+}
+
+/// Archives a live segment, keeping its pinned messages live: they and the
+/// events that pinned them are appended to the log again. Returns how many
+/// messages were kept.
+pub fn archive(storage: &Storage, name: &str) -> Result<usize, Error> {
+    let pins = event::pins(&storage.messages.load()?);
+    storage.messages.archive_segment(name)?;
+    if pins.is_empty() {
+        return Ok(0);
+    }
+
+    let mut live: HashSet<String> = storage.messages.load()?.into_iter().map(|m| m.id).collect();
+    let mut kept = 0;
+    for message in storage.messages.load_archived()? {
+        let pinned = pins.contains_key(&message.id);
+        if (pinned || pins.values().any(|id| *id == message.id)) && live.insert(message.id.clone())
+        {
+            storage.messages.append(&message)?;
+            kept += usize::from(pinned);
+        }
+    }
+    if kept > 0 {
+        eprintln!("Kept {} pinned messages from segment {} live", kept, name);
+    }
+    Ok(kept)
+}
+
+/// Purges an archived segment along with the uploads its messages refer to,
+/// returning how many uploads were deleted. Uploads of messages that are
+/// still live, because they were pinned, stay.
+pub fn purge(storage: &Storage, name: &str) -> Result<usize, Error> {
+    let live: HashSet<String> = storage
+        .messages
+        .load()?
+        .into_iter()
+        .filter(has_upload)
+        .map(|m| m.content)
+        .collect();
+    let mut deleted = 0;
+    for message in storage.messages.purge_segment(name)? {
+        if has_upload(&message)
+            && !live.contains(&message.content)
+            && storage.blobs.delete(&message.content).is_ok()
+        {
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+fn has_upload(message: &Message) -> bool {
+    matches!(message.msg_type.as_str(), "image" | "file")
+}
+
+/// How long messages stay live, and then archived, before they go.
+pub struct RetentionPolicy {
+    pub archive_after: chrono::Duration,
+    pub purge_after: chrono::Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> RetentionPolicy {
+        RetentionPolicy {
+            archive_after: chrono::Duration::days(30),
+            purge_after: chrono::Duration::days(30),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionReport {
+    pub archived: Vec<String>,
+    pub purged: Vec<String>,
+    pub deleted_uploads: usize,
+    /// Pinned messages kept live out of the segments that were archived.
+    pub kept_pinned: usize,
+}
+
+/// Archives live segments whose newest message is older than
+/// `archive_after`, and purges archived segments (and their uploads) once
+/// they have been archived for `purge_after`. The head is never archived,
+/// and pinned messages stay live (see `archive`).
+pub fn apply_retention(
+    storage: &Storage,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> Result<RetentionReport, Error> {
+    let mut report = RetentionReport::default();
+    let archive_before = now - policy.archive_after;
+    let purge_before = now - policy.purge_after;
+
+    for segment in storage.messages.segments()? {
+        match &segment.archived_at {
+            None if !segment.head
+                && segment.newest.as_deref().is_some_and(|newest| {
+                    DateTime::parse_from_rfc3339(newest).is_ok_and(|t| t < archive_before)
+                }) =>
+            {
+                report.kept_pinned += archive(storage, &segment.name)?;
+                report.archived.push(segment.name);
+            }
+            Some(archived_at)
+                if DateTime::parse_from_rfc3339(archived_at).is_ok_and(|t| t < purge_before) =>
+            {
+                report.deleted_uploads += purge(storage, &segment.name)?;
+                report.purged.push(segment.name);
+            }
+            _ => {}
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+    use crate::testutil::{ScratchDir, ids, message};
+
+    fn upload(id: &str, timestamp: &str) -> Message {
+        Message {
+            msg_type: "file".to_string(),
+            content: format!("{}.bin", id),
+            ..message(id, timestamp)
+        }
+    }
+
+    fn pin(target: &Message, timestamp: &str) -> Message {
+        let mut event = Event::Pin {
+            target: target.id.clone(),
+            pinned: true,
+        }
+        .record(target, "tester".to_string(), None);
+        event.id = format!("pin-{}", target.id);
+        event.timestamp = timestamp.to_string();
+        event
+    }
+
+    fn names(storage: &Storage) -> Vec<(String, bool)> {
+        storage
+            .messages
+            .segments()
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.name, s.archived_at.is_some()))
+            .collect()
+    }
+
+    fn live(names: &[&str]) -> Vec<(String, bool)> {
+        names.iter().map(|n| (n.to_string(), false)).collect()
+    }
+
+    fn time(timestamp: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(timestamp)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn adopts_a_legacy_log() {
+        let dir = ScratchDir::new("segment");
+        let legacy = dir.join(LEGACY_LOG);
+        for (id, timestamp) in [("a", "2024-01-05T00:00:00Z"), ("b", "2024-02-05T00:00:00Z")] {
+            jsonl::append(&legacy, &message(id, timestamp)).unwrap();
+        }
+
+        let storage = Storage::open(dir.path()).unwrap();
+        assert_eq!(ids(&storage.messages.load().unwrap()), ["a", "b"]);
+        assert_eq!(names(&storage), live(&["2024-01"]));
+        assert!(!legacy.exists());
+        assert!(dir.join("log/2024-01.jsonl").exists());
+
+        // The adopted segment is the head until a later month arrives.
+        storage
+            .messages
+            .append(&message("c", "2024-02-06T00:00:00Z"))
+            .unwrap();
+        assert_eq!(names(&storage), live(&["2024-01", "2024-02"]));
+    }
+
+    #[test]
+    fn rolls_over_by_month_and_reads_across_segments() {
+        let dir = ScratchDir::new("segment");
+        let storage = Storage::open(dir.path()).unwrap();
+        for (id, timestamp) in [
+            ("a", "2024-01-31T23:00:00Z"),
+            ("b", "2024-02-01T00:30:00+01:00"),
+            ("c", "2024-02-10T00:00:00Z"),
+            // Late, from a month that already rolled over: it joins the head.
+            ("d", "2024-01-15T00:00:00Z"),
+            ("e", "2024-03-01T00:00:00Z"),
+        ] {
+            storage.messages.append(&message(id, timestamp)).unwrap();
+        }
+
+        // b is still January in UTC.
+        assert_eq!(names(&storage), live(&["2024-01", "2024-02", "2024-03"]));
+        assert_eq!(
+            ids(&storage.messages.load().unwrap()),
+            ["a", "b", "c", "d", "e"]
+        );
+        assert_eq!(
+            ids(&storage.messages.since("2024-01-31T23:00:00Z").unwrap()),
+            ["b", "c", "e"]
+        );
+        assert_eq!(storage.messages.get("a").unwrap().unwrap().id, "a");
+        assert_eq!(storage.messages.latest(&|_| true).unwrap().unwrap().id, "e");
+    }
+
+    #[test]
+    fn rolls_over_a_full_segment() {
+        let dir = ScratchDir::new("segment");
+        let storage = Storage::open(dir.path()).unwrap();
+        storage
+            .messages
+            .append(&message("a", "2024-01-01T00:00:00Z"))
+            .unwrap();
+
+        // Pad the head to the limit, sparsely, and take the padding off again
+        // once the next append has rolled over.
+        let head = dir.join("log/2024-01.jsonl");
+        let len = std::fs::metadata(&head).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&head).unwrap();
+        file.set_len(MAX_SEGMENT_BYTES).unwrap();
+        storage
+            .messages
+            .append(&message("b", "2024-01-02T00:00:00Z"))
+            .unwrap();
+        file.set_len(len).unwrap();
+
+        assert_eq!(names(&storage), live(&["2024-01", "2024-01.001"]));
+        assert_eq!(ids(&storage.messages.load().unwrap()), ["a", "b"]);
+    }
+
+    #[test]
+    fn archiving_keeps_pinned_messages_live() {
+        let dir = ScratchDir::new("segment");
+        let storage = Storage::open(dir.path()).unwrap();
+        let kept = message("kept", "2024-01-02T00:00:00Z");
+        for record in [
+            message("gone", "2024-01-01T00:00:00Z"),
+            kept.clone(),
+            pin(&kept, "2024-01-03T00:00:00Z"),
+            message("head", "2024-02-01T00:00:00Z"),
+        ] {
+            storage.messages.append(&record).unwrap();
+        }
+
+        let err = storage.messages.archive_segment("2024-02").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        assert_eq!(archive(&storage, "2024-01").unwrap(), 1);
+        assert_eq!(
+            names(&storage),
+            [
+                ("2024-01".to_string(), true),
+                ("2024-02".to_string(), false)
+            ]
+        );
+        assert!(dir.join("archive/2024-01.jsonl").exists());
+        let live = storage.messages.load().unwrap();
+        assert_eq!(ids(&live), ["head", "kept", "pin-kept"]);
+        assert!(event::is_pinned(&live, "kept"));
+        assert_eq!(
+            ids(&storage.messages.load_archived().unwrap()),
+            ["gone", "kept", "pin-kept"]
+        );
+    }
+
+    #[test]
+    fn purging_keeps_uploads_of_live_messages() {
+        let dir = ScratchDir::new("segment");
+        let storage = Storage::open(dir.path()).unwrap();
+        let kept = upload("kept", "2024-01-02T00:00:00Z");
+        for record in [
+            upload("gone", "2024-01-01T00:00:00Z"),
+            kept.clone(),
+            pin(&kept, "2024-01-03T00:00:00Z"),
+            message("head", "2024-02-01T00:00:00Z"),
+        ] {
+            storage.messages.append(&record).unwrap();
+        }
+        for name in ["gone.bin", "kept.bin"] {
+            storage.blobs.put(name, b"data").unwrap();
+        }
+
+        let err = storage.messages.purge_segment("2024-01").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        archive(&storage, "2024-01").unwrap();
+        assert_eq!(purge(&storage, "2024-01").unwrap(), 1);
+
+        assert_eq!(storage.blobs.list().unwrap(), ["kept.bin"]);
+        assert_eq!(names(&storage), live(&["2024-02"]));
+        assert!(!dir.join("archive/2024-01.jsonl").exists());
+        assert!(storage.messages.load_archived().unwrap().is_empty());
+    }
+
+    #[test]
+    fn retention_archives_then_purges() {
+        let dir = ScratchDir::new("segment");
+        let storage = Storage::open(dir.path()).unwrap();
+        for (id, timestamp) in [
+            ("old", "2024-01-10T12:00:00+02:00"),
+            ("recent", "2024-02-20T00:00:00Z"),
+            ("head", "2024-03-01T00:00:00Z"),
+        ] {
+            storage.messages.append(&upload(id, timestamp)).unwrap();
+            storage.blobs.put(&format!("{}.bin", id), b"data").unwrap();
+        }
+        let policy = RetentionPolicy::default();
+
+        // The newest message of 2024-01 is 30 days old only a moment later.
+        let report = apply_retention(&storage, &policy, time("2024-02-09T10:00:00Z")).unwrap();
+        assert!(report.archived.is_empty());
+
+        let report = apply_retention(&storage, &policy, time("2024-02-09T10:00:01Z")).unwrap();
+        assert_eq!(report.archived, ["2024-01"]);
+        assert!(report.purged.is_empty());
+
+        let archived_at = storage.messages.segments().unwrap()[0]
+            .archived_at
+            .clone()
+            .unwrap();
+        let purge_at = time(&archived_at) + policy.purge_after + chrono::Duration::seconds(1);
+        let report = apply_retention(&storage, &policy, purge_at).unwrap();
+        assert_eq!(report.purged, ["2024-01"]);
+        assert_eq!(report.deleted_uploads, 1);
+        // 2024-02 is archived now too, but not purged yet; the head stays.
+        assert_eq!(report.archived, ["2024-02"]);
+        assert_eq!(
+            names(&storage),
+            [
+                ("2024-02".to_string(), true),
+                ("2024-03".to_string(), false)
+            ]
+        );
+        assert_eq!(storage.blobs.list().unwrap(), ["head.bin", "recent.bin"]);
+    }
+}
@@ -0,0 +1,557 @@
+//! Public links to single uploaded files.
+//!
+//! A share link is `/share/<id>.<expiry>.<signature>`: the link id and its
+//! expiry time, signed with HMAC-SHA256 under a random key kept in the
+//! `shares` state document. The document also holds each link's file,
+//! optional download limit and password hash, revocation and a log of recent
+//! accesses, so a link can be cut off before it expires.
+
+use crate::auth::{self, constant_time_eq};
+use crate::rooms::DEFAULT_ROOM;
+use crate::state::{self, StateStore};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::io::{Error, ErrorKind};
+
+pub const SHARES: &str = "shares";
+pub const SHARE_PATH: &str = "/share/";
+/// Holds the key of the download a visitor was last served from a link.
+pub const DOWNLOAD_COOKIE: &str = "spore_download";
+
+/// Accesses kept per link; older ones are dropped.
+const ACCESS_LOG_LEN: usize = 100;
+
+pub fn default_lifetime() -> Duration {
+    Duration::days(7)
+}
+
+pub fn max_lifetime() -> Duration {
+    Duration::days(90)
+}
+
+/// How long expired links and their logs stay listed.
+fn kept_after_expiry() -> Duration {
+    Duration::days(30)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Shares {
+    /// HMAC key for link signatures, created with the first link.
+    #[serde(default)]
+    key: String,
+    #[serde(default)]
+    links: Vec<ShareLink>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLink {
+    pub id: String,
+    /// The room the upload is in; links from before rooms have none and
+    /// point into the default room.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Stored name of the shared upload.
+    pub file: String,
+    /// Name the recipient downloads it under.
+    pub filename: String,
+    pub created_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    pub expires_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_downloads: Option<u32>,
+    #[serde(default)]
+    pub downloads: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<String>,
+    #[serde(default)]
+    pub accesses: Vec<ShareAccess>,
+    /// Keys handed out with the most recent counted downloads. A range
+    /// request that brings one back continues that download.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    download_keys: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareAccess {
+    pub at: String,
+    pub outcome: AccessOutcome,
+    /// `X-Forwarded-For` and `User-Agent`, when the request had them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessOutcome {
+    Served,
+    PasswordRequired,
+    WrongPassword,
+    Expired,
+    Exhausted,
+    Revoked,
+}
+
+/// A link as shown over the API, without its password hash.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareInfo<'a> {
+    pub id: &'a str,
+    pub room: &'a str,
+    pub file: &'a str,
+    pub filename: &'a str,
+    pub created_at: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<&'a str>,
+    pub expires_at: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+    pub has_password: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<&'a str>,
+    pub accesses: &'a [ShareAccess],
+}
+
+impl Shares {
+    fn drop_old(&mut self, now: DateTime<Utc>) {
+        self.links.retain(|l| {
+            DateTime::parse_from_rfc3339(&l.expires_at)
+                .is_ok_and(|expiry| expiry + kept_after_expiry() > now)
+        });
+    }
+}
+
+impl ShareLink {
+    pub fn room(&self) -> &str {
+        self.room.as_deref().unwrap_or(DEFAULT_ROOM)
+    }
+
+    pub fn info(&self) -> ShareInfo<'_> {
+        ShareInfo {
+            id: &self.id,
+            room: self.room(),
+            file: &self.file,
+            filename: &self.filename,
+            created_at: &self.created_at,
+            created_by: self.created_by.as_deref(),
+            expires_at: &self.expires_at,
+            max_downloads: self.max_downloads,
+            downloads: self.downloads,
+            has_password: self.password_hash.is_some(),
+            revoked_at: self.revoked_at.as_deref(),
+            accesses: &self.accesses,
+        }
+    }
+
+    /// Why the link no longer works, if it does not.
+    fn refusal(&self, now: DateTime<Utc>) -> Option<AccessOutcome> {
+        if self.revoked_at.is_some() {
+            return Some(AccessOutcome::Revoked);
+        }
+        let expired = DateTime::parse_from_rfc3339(&self.expires_at).map_or(true, |e| e <= now);
+        if expired {
+            return Some(AccessOutcome::Expired);
+        }
+        if self.max_downloads.is_some_and(|max| self.downloads >= max) {
+            return Some(AccessOutcome::Exhausted);
+        }
+        None
+    }
+
+    /// Whether `visitor` brought back the key of a download served from
+    /// this link, so that a later range request continues that download.
+    /// Request headers say nothing here; anyone can send the same ones.
+    fn served_to(&self, visitor: &Visitor) -> bool {
+        visitor.download_key.as_ref().is_some_and(|key| {
+            self.download_keys
+                .iter()
+                .any(|k| constant_time_eq(k.as_bytes(), key.as_bytes()))
+        })
+    }
+}
+
+/// What a client may ask for when sharing a file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareOptions {
+    /// Lifetime in seconds; a week if absent, at most 90 days.
+    pub expires_in: Option<i64>,
+    pub max_downloads: Option<u32>,
+    pub password: Option<String>,
+}
+
+/// Who is opening a link: their address and user agent for the access
+/// log, and the download key they were given, if any.
+#[derive(Debug, Default)]
+pub struct Visitor {
+    pub client: Option<String>,
+    pub user_agent: Option<String>,
+    pub download_key: Option<String>,
+}
+
+fn sign(key: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+fn random_id(len: usize) -> Result<String, Error> {
+    let mut bytes = vec![0u8; len];
+    getrandom::fill(&mut bytes).map_err(|e| Error::other(e.to_string()))?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// Creates a link to upload `file` and returns it with its token, the part
+/// of the URL after `/share/`.
+pub fn create(
+    store: &dyn StateStore,
+    room: &str,
+    file: &str,
+    filename: &str,
+    created_by: Option<String>,
+    options: ShareOptions,
+    now: DateTime<Utc>,
+) -> Result<(ShareLink, String), Error> {
+    let lifetime = match options.expires_in {
+        None => default_lifetime(),
+        Some(seconds) if seconds > 0 && seconds <= max_lifetime().num_seconds() => {
+            Duration::seconds(seconds)
+        }
+        Some(_) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "expiresIn must be between 1 and {} seconds",
+                    max_lifetime().num_seconds()
+                ),
+            ));
+        }
+    };
+    if options.max_downloads == Some(0) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "maxDownloads must be at least 1",
+        ));
+    }
+    let password_hash = match options.password.filter(|p| !p.is_empty()) {
+        Some(password) => {
+            Some(auth::hash_passphrase(&password).map_err(|e| Error::other(e.to_string()))?)
+        }
+        None => None,
+    };
+
+    let expires_at = now + lifetime;
+    let link = ShareLink {
+        id: random_id(12)?,
+        room: (room != DEFAULT_ROOM).then(|| room.to_string()),
+        file: file.to_string(),
+        filename: filename.to_string(),
+        created_at: now.to_rfc3339(),
+        created_by,
+        expires_at: expires_at.to_rfc3339(),
+        max_downloads: options.max_downloads,
+        downloads: 0,
+        password_hash,
+        revoked_at: None,
+        accesses: Vec::new(),
+        download_keys: Vec::new(),
+    };
+    let new_key = random_id(32)?;
+    let token = state::update(store, SHARES, |shares: &mut Shares| {
+        if shares.key.is_empty() {
+            shares.key = new_key;
+        }
+        shares.drop_old(now);
+        shares.links.push(link.clone());
+        let payload = format!("{}.{}", link.id, expires_at.timestamp());
+        Ok(format!("{}.{}", payload, sign(&shares.key, &payload)))
+    })?;
+    eprintln!("Shared {} as link {}", file, link.id);
+    Ok((link, token))
+}
+
+pub fn list(store: &dyn StateStore) -> Result<Vec<ShareLink>, Error> {
+    Ok(state::load::<Shares>(store, SHARES)?.links)
+}
+
+pub fn revoke(store: &dyn StateStore, id: &str, now: DateTime<Utc>) -> Result<ShareLink, Error> {
+    state::update(store, SHARES, |shares: &mut Shares| {
+        let link = shares
+            .links
+            .iter_mut()
+            .find(|l| l.id == id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No share link {}", id)))?;
+        link.revoked_at.get_or_insert_with(|| now.to_rfc3339());
+        Ok(link.clone())
+    })
+}
+
+/// Points the links to `file` in room `from` at room `to`, where it has
+/// been moved.
+pub fn rehome(store: &dyn StateStore, from: &str, to: &str, file: &str) -> Result<(), Error> {
+    if !list(store)?
+        .iter()
+        .any(|l| l.room() == from && l.file == file)
+    {
+        return Ok(());
+    }
+    state::update(store, SHARES, |shares: &mut Shares| {
+        for link in &mut shares.links {
+            if link.room() == from && link.file == file {
+                link.room = (to != DEFAULT_ROOM).then(|| to.to_string());
+            }
+        }
+        Ok(())
+    })
+}
+
+/// `Set-Cookie` value that gives a visitor `key` for the link at `token`,
+/// kept until the link expires.
+pub fn download_cookie(token: &str, key: &str, now: DateTime<Utc>) -> String {
+    let max_age = token
+        .split('.')
+        .nth(1)
+        .and_then(|expiry| expiry.parse::<i64>().ok())
+        .map_or(0, |expiry| (expiry - now.timestamp()).max(0));
+    format!(
+        "{}={}; Path={}{}; HttpOnly; SameSite=Lax; Max-Age={}",
+        DOWNLOAD_COOKIE, key, SHARE_PATH, token, max_age
+    )
+}
+
+/// The outcome of opening a link.
+#[derive(Debug)]
+pub enum Access {
+    /// Serve this upload under this name. A counted download comes with a
+    /// new key to hand the visitor; a continued one has none.
+    Granted {
+        room: String,
+        file: String,
+        filename: String,
+        download_key: Option<String>,
+    },
+    Refused(AccessOutcome),
+    /// The token is malformed, forged or names no link.
+    Unknown,
+}
+
+/// Checks `token` and, if the link may be used, counts a download. Every
+/// attempt on a real link is logged with it.
+///
+/// `resuming` is for range requests that do not start at byte 0, as players
+/// send when seeking. When the visitor brings the key of a download served
+/// before, they continue that download: they are not counted, and are let
+/// through once the link is used up. Otherwise they count like any other
+/// request.
+pub fn open(
+    store: &dyn StateStore,
+    token: &str,
+    password: Option<&str>,
+    visitor: Visitor,
+    resuming: bool,
+    now: DateTime<Utc>,
+) -> Result<Access, Error> {
+    let Some((payload, signature)) = token.rsplit_once('.') else {
+        return Ok(Access::Unknown);
+    };
+    let Some((id, expiry)) = payload.split_once('.') else {
+        return Ok(Access::Unknown);
+    };
+    let shares: Shares = state::load(store, SHARES)?;
+    if shares.key.is_empty()
+        || !constant_time_eq(sign(&shares.key, payload).as_bytes(), signature.as_bytes())
+    {
+        return Ok(Access::Unknown);
+    }
+    let Ok(expiry) = expiry.parse::<i64>() else {
+        return Ok(Access::Unknown);
+    };
+    let Some(link) = shares.links.iter().find(|l| l.id == id) else {
+        return Ok(Access::Unknown);
+    };
+
+    let refusal = |link: &ShareLink, resumes: bool| match link.refusal(now) {
+        Some(AccessOutcome::Exhausted) if resumes => None,
+        refusal => refusal,
+    };
+
+    // Checking the password is slow, so it happens outside the lock.
+    let outcome = if now.timestamp() >= expiry {
+        AccessOutcome::Expired
+    } else if let Some(refusal) = refusal(link, resuming && link.served_to(&visitor)) {
+        refusal
+    } else {
+        match (&link.password_hash, password) {
+            (None, _) => AccessOutcome::Served,
+            (Some(_), None) => AccessOutcome::PasswordRequired,
+            (Some(hash), Some(password)) => match auth::verify_passphrase(hash, password) {
+                Ok(true) => AccessOutcome::Served,
+                _ => AccessOutcome::WrongPassword,
+            },
+        }
+    };
+
+    let new_key = random_id(16)?;
+    state::update(store, SHARES, |shares: &mut Shares| {
+        let link = shares
+            .links
+            .iter_mut()
+            .find(|l| l.id == id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No share link {}", id)))?;
+        // Another download may have used up the link meanwhile.
+        let resumes = resuming && link.served_to(&visitor);
+        let outcome = match outcome {
+            AccessOutcome::Served => refusal(link, resumes).unwrap_or(AccessOutcome::Served),
+            other => other,
+        };
+        let counted = outcome == AccessOutcome::Served && !resumes;
+        if counted {
+            link.downloads += 1;
+            link.download_keys.push(new_key.clone());
+            let excess = link.download_keys.len().saturating_sub(ACCESS_LOG_LEN);
+            link.download_keys.drain(..excess);
+        }
+        link.accesses.push(ShareAccess {
+            at: now.to_rfc3339(),
+            outcome,
+            client: visitor.client.clone(),
+            user_agent: visitor.user_agent.clone(),
+        });
+        let excess = link.accesses.len().saturating_sub(ACCESS_LOG_LEN);
+        link.accesses.drain(..excess);
+        eprintln!("Share link {}: {:?}", link.id, outcome);
+
+        Ok(match outcome {
+            AccessOutcome::Served => Access::Granted {
+                room: link.room().to_string(),
+                file: link.file.clone(),
+                filename: link.filename.clone(),
+                download_key: counted.then(|| new_key.clone()),
+            },
+            refused => Access::Refused(refused),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    fn visitor(key: Option<&str>) -> Visitor {
+        Visitor {
+            client: Some("10.0.0.1".to_string()),
+            user_agent: Some("player".to_string()),
+            download_key: key.map(str::to_string),
+        }
+    }
+
+    fn shared(store: &MemoryStateStore, max_downloads: u32, now: DateTime<Utc>) -> String {
+        let options = ShareOptions {
+            max_downloads: Some(max_downloads),
+            ..Default::default()
+        };
+        let (_, token) = create(store, DEFAULT_ROOM, "f.mp4", "f.mp4", None, options, now)
+            .expect("link created");
+        token
+    }
+
+    fn downloads(store: &MemoryStateStore) -> u32 {
+        let shares: Shares = state::load(store, SHARES).unwrap();
+        shares.links[0].downloads
+    }
+
+    /// The download key a granted access came with; panics on a refusal.
+    fn granted(access: Access) -> Option<String> {
+        match access {
+            Access::Granted { download_key, .. } => download_key,
+            refused => panic!("not granted: {:?}", refused),
+        }
+    }
+
+    #[test]
+    fn seeking_continues_a_download_without_counting_it() {
+        let store = MemoryStateStore::default();
+        let now = Utc::now();
+        let token = shared(&store, 1, now);
+
+        let first = open(&store, &token, None, visitor(None), false, now).unwrap();
+        let key = granted(first).expect("a counted download has a key");
+        for _ in 0..3 {
+            let seek = open(&store, &token, None, visitor(Some(&key)), true, now).unwrap();
+            assert_eq!(granted(seek), None);
+        }
+        assert_eq!(downloads(&store), 1);
+
+        // The key continues a download; it does not start another.
+        let again = open(&store, &token, None, visitor(Some(&key)), false, now).unwrap();
+        assert!(matches!(again, Access::Refused(AccessOutcome::Exhausted)));
+    }
+
+    #[test]
+    fn ranges_without_a_key_count() {
+        let store = MemoryStateStore::default();
+        let now = Utc::now();
+        let token = shared(&store, 2, now);
+
+        let seek = open(&store, &token, None, visitor(None), true, now).unwrap();
+        assert!(granted(seek).is_some());
+        assert_eq!(downloads(&store), 1);
+
+        let guessed = open(&store, &token, None, visitor(Some("guess")), true, now).unwrap();
+        assert!(granted(guessed).is_some());
+        assert_eq!(downloads(&store), 2);
+        let other = open(&store, &token, None, visitor(None), true, now).unwrap();
+        assert!(matches!(other, Access::Refused(AccessOutcome::Exhausted)));
+    }
+
+    #[test]
+    fn matching_headers_do_not_resume() {
+        let store = MemoryStateStore::default();
+        let now = Utc::now();
+        let token = shared(&store, 1, now);
+
+        open(&store, &token, None, visitor(None), false, now).unwrap();
+        let seek = open(&store, &token, None, visitor(None), true, now).unwrap();
+        assert!(matches!(seek, Access::Refused(AccessOutcome::Exhausted)));
+    }
+
+    #[test]
+    fn keys_belong_to_their_link() {
+        let store = MemoryStateStore::default();
+        let now = Utc::now();
+        let first = shared(&store, 1, now);
+        let second = shared(&store, 1, now);
+
+        let key = granted(open(&store, &first, None, visitor(None), false, now).unwrap());
+        open(&store, &second, None, visitor(None), false, now).unwrap();
+        let elsewhere = open(&store, &second, None, visitor(key.as_deref()), true, now).unwrap();
+        assert!(matches!(
+            elsewhere,
+            Access::Refused(AccessOutcome::Exhausted)
+        ));
+    }
+
+    #[test]
+    fn download_cookies_last_as_long_as_the_link() {
+        let now = DateTime::from_timestamp(1_000_000, 0).unwrap();
+        assert_eq!(
+            download_cookie("abc.1003600.sig", "k3y", now),
+            "spore_download=k3y; Path=/share/abc.1003600.sig; HttpOnly; SameSite=Lax; Max-Age=3600"
+        );
+        assert!(download_cookie("abc.999.sig", "k3y", now).ends_with("Max-Age=0"));
+    }
+}
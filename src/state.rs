@@ -0,0 +1,157 @@
+//! Small JSON documents kept beside the message log, such as the device
+//! registry. Each document is read and rewritten as a whole.
+
+use crate::jsonl::{self, FileLock};
+use crate::kv::Bucket;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+pub trait StateStore {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Replaces document `name` with what `f` makes of its current content.
+    /// Backends that can serialize this against other instances do.
+    fn modify(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Result<Vec<u8>, Error>,
+    ) -> Result<(), Error>;
+}
+
+fn parse<T: DeserializeOwned + Default>(name: &str, bytes: Option<Vec<u8>>) -> Result<T, Error> {
+    match bytes {
+        Some(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("State {}: {}", name, e))),
+        None => Ok(T::default()),
+    }
+}
+
+/// Reads document `name`, or its default if it has never been written.
+pub fn load<T: DeserializeOwned + Default>(store: &dyn StateStore, name: &str) -> Result<T, Error> {
+    parse(name, store.get(name)?)
+}
+
+/// Read-modify-write of document `name`. Nothing is written if `f` fails.
+pub fn update<T, R>(
+    store: &dyn StateStore,
+    name: &str,
+    f: impl FnOnce(&mut T) -> Result<R, Error>,
+) -> Result<R, Error>
+where
+    T: Serialize + DeserializeOwned + Default,
+{
+    let mut f = Some(f);
+    let mut result = None;
+    store.modify(name, &mut |current| {
+        let mut document: T = parse(name, current)?;
+        let f = f.take().expect("modify calls its closure once");
+        result = Some(f(&mut document)?);
+        Ok(serde_json::to_vec_pretty(&document)?)
+    })?;
+    Ok(result.expect("modify succeeded without calling its closure"))
+}
+
+fn validate_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid state name: {:?}", name),
+        ));
+    }
+    Ok(())
+}
+
+/// `<dir>/<name>.json`, rewritten under a lock file.
+pub struct DirStateStore {
+    dir: PathBuf,
+}
+
+impl DirStateStore {
+    pub fn new(dir: impl Into<PathBuf>) -> DirStateStore {
+        DirStateStore { dir: dir.into() }
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        validate_name(name)?;
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+}
+
+impl StateStore for DirStateStore {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        match std::fs::read(self.path(name)?) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn modify(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Result<Vec<u8>, Error>,
+    ) -> Result<(), Error> {
+        let path = self.path(name)?;
+        let _lock = FileLock::acquire(&path)?;
+        let updated = f(self.get(name)?)?;
+        jsonl::write_atomically(&path, &updated)
+    }
+}
+
+/// `state/<name>` keys in a key-value bucket. `wasi:keyvalue` has no
+/// compare-and-swap, so concurrent updates of one document can race.
+pub struct KvStateStore<B: Bucket> {
+    bucket: B,
+}
+
+const STATE_PREFIX: &str = "state/";
+
+impl<B: Bucket> KvStateStore<B> {
+    pub fn new(bucket: B) -> KvStateStore<B> {
+        KvStateStore { bucket }
+    }
+}
+
+impl<B: Bucket> StateStore for KvStateStore<B> {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        validate_name(name)?;
+        self.bucket.get(&format!("{}{}", STATE_PREFIX, name))
+    }
+
+    fn modify(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Result<Vec<u8>, Error>,
+    ) -> Result<(), Error> {
+        let updated = f(self.get(name)?)?;
+        self.bucket
+            .set(&format!("{}{}", STATE_PREFIX, name), &updated)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStateStore {
+    documents: RefCell<BTreeMap<String, Vec<u8>>>,
+}
+
+impl StateStore for MemoryStateStore {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.documents.borrow().get(name).cloned())
+    }
+
+    fn modify(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(Option<Vec<u8>>) -> Result<Vec<u8>, Error>,
+    ) -> Result<(), Error> {
+        let updated = f(self.get(name)?)?;
+        self.documents
+            .borrow_mut()
+            .insert(name.to_string(), updated);
+        Ok(())
+    }
+}
@@ -0,0 +1,822 @@
+use crate::audit::{AuditLog, JsonlAuditLog, KvAuditLog, MemoryAuditLog};
+use crate::crypt::{self, SealedBlobStore};
+use crate::index::{self, LogIndex};
+use crate::jsonl::{self, CorruptLine, RecoveryReport};
+use crate::kv::{DirBucket, KvMessageStore};
+#[cfg(feature = "wasi-storage")]
+use crate::kv::{WasiBucket, WasiContainer};
+use crate::message::Message;
+use crate::rooms::{self, DEFAULT_ROOM};
+use crate::schema::{self, MigrationReport, StoredMessage};
+use crate::segment::{SegmentInfo, SegmentedMessageStore};
+use crate::state::{DirStateStore, KvStateStore, MemoryStateStore, StateStore};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+pub const DATA_DIR: &str = "data";
+
+/// Overrides the compiled-in backend; only `kv-dir` is recognised.
+pub const STORAGE_VAR: &str = "SPORE_STORAGE";
+const KV_DIR: &str = "kv-dir";
+
+/// Ordered, append-only log of chat messages.
+pub trait MessageStore {
+    fn load(&self) -> Result<Vec<Message>, Error>;
+
+    fn append(&self, message: &Message) -> Result<(), Error>;
+
+    /// Rewrites every record in the current schema version.
+    fn migrate(&self) -> Result<MigrationReport, Error>;
+
+    /// Replaces the stored records that share an id with one of `updated`
+    /// and returns how many were replaced.
+    fn update(&self, updated: &[Message]) -> Result<usize, Error>;
+
+    /// Deletes the records with one of `ids` and returns how many went.
+    fn remove(&self, ids: &[&str]) -> Result<usize, Error>;
+
+    /// Reads every record without repairing anything, returning the
+    /// unreadable ones separately.
+    fn scan(&self) -> Result<(Vec<Message>, Vec<CorruptLine>), Error> {
+        Ok((self.load()?, Vec::new()))
+    }
+
+    /// Moves unreadable records out of the log.
+    fn recover(&self) -> Result<RecoveryReport, Error> {
+        Ok(RecoveryReport::default())
+    }
+
+    /// The record with `id`, if the log has one.
+    fn get(&self, id: &str) -> Result<Option<Message>, Error> {
+        Ok(self.load()?.into_iter().find(|m| m.id == id))
+    }
+
+    /// The newest record by timestamp that `keep` accepts. Records whose
+    /// timestamp cannot be parsed count as the oldest.
+    fn latest(&self, keep: &dyn Fn(&Message) -> bool) -> Result<Option<Message>, Error> {
+        Ok(self
+            .load()?
+            .into_iter()
+            .filter(|m| keep(m))
+            .max_by_key(|m| timestamp_key(&m.timestamp)))
+    }
+
+    /// Messages newer than the RFC 3339 `since` timestamp. An unparseable
+    /// timestamp returns the whole history.
+    fn since(&self, since: &str) -> Result<Vec<Message>, Error> {
+        Ok(filter_since(self.load()?, since))
+    }
+
+    /// Segments of a sharded log, oldest first. Unsharded logs have none.
+    fn segments(&self) -> Result<Vec<SegmentInfo>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Takes a whole segment out of the live log without deleting it.
+    fn archive_segment(&self, _name: &str) -> Result<(), Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "This log has no segments",
+        ))
+    }
+
+    /// Deletes an archived segment, returning the messages it held so their
+    /// uploads can be deleted too.
+    fn purge_segment(&self, _name: &str) -> Result<Vec<Message>, Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "This log has no segments",
+        ))
+    }
+
+    /// Messages in archived segments, which `load` no longer returns.
+    fn load_archived(&self) -> Result<Vec<Message>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+/// What `latest` orders records by.
+pub fn timestamp_key(timestamp: &str) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    chrono::DateTime::parse_from_rfc3339(timestamp).ok()
+}
+
+/// Keeps the messages newer than `since`, plus any whose own timestamp cannot
+/// be parsed. An unparseable `since` keeps everything.
+pub fn filter_since(messages: Vec<Message>, since: &str) -> Vec<Message> {
+    let since_time = match chrono::DateTime::parse_from_rfc3339(since) {
+        Ok(time) => time,
+        Err(_) => return messages,
+    };
+
+    messages
+        .into_iter()
+        .filter(
+            |msg| match chrono::DateTime::parse_from_rfc3339(&msg.timestamp) {
+                Ok(msg_time) => msg_time > since_time,
+                Err(_) => true,
+            },
+        )
+        .collect()
+}
+
+/// Flat namespace of uploaded files, addressed by their stored name.
+pub trait BlobStore {
+    fn put(&self, name: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Fails with `ErrorKind::NotFound` if there is no blob called `name`.
+    fn get(&self, name: &str) -> Result<Vec<u8>, Error>;
+
+    fn delete(&self, name: &str) -> Result<(), Error>;
+
+    fn list(&self) -> Result<Vec<String>, Error>;
+
+    fn size(&self, name: &str) -> Result<u64, Error> {
+        Ok(self.get(name)?.len() as u64)
+    }
+
+    /// When `name` was last written, if the store keeps track.
+    fn modified(&self, _name: &str) -> Result<Option<SystemTime>, Error> {
+        Ok(None)
+    }
+
+    /// The bytes of `name` within `range`, cut short at its end.
+    fn get_range(&self, name: &str, range: Range<u64>) -> Result<Vec<u8>, Error> {
+        let data = self.get(name)?;
+        let len = data.len() as u64;
+        Ok(data[range.start.min(len) as usize..range.end.min(len) as usize].to_vec())
+    }
+
+    /// Encrypts `name` in place if this store encrypts uploads and it was
+    /// stored in the clear. Returns whether it did.
+    fn seal(&self, _name: &str) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+/// One room's messages and uploads, with the state and audit log that all
+/// rooms share.
+pub struct Storage {
+    /// The room `messages` and `blobs` belong to.
+    pub room: String,
+    pub messages: Box<dyn MessageStore>,
+    pub blobs: Box<dyn BlobStore>,
+    pub state: Box<dyn StateStore>,
+    /// Shared so responses can record entries after their handler is done.
+    pub audit: Rc<dyn AuditLog>,
+}
+
+impl Storage {
+    /// The on-disk layout: monthly segments under `log`, an `uploads`
+    /// directory, server state under `state` and the audit log in
+    /// `audit.jsonl`. Fails if
+    /// `SPORE_ENCRYPTION_KEY` is set but unusable.
+    pub fn open(data_dir: impl AsRef<Path>) -> Result<Storage, Error> {
+        Storage::open_room(data_dir, DEFAULT_ROOM)
+    }
+
+    /// The default room keeps the top-level `log` and `uploads`; other rooms
+    /// have their own under `rooms/<id>`.
+    pub fn open_room(data_dir: impl AsRef<Path>, room: &str) -> Result<Storage, Error> {
+        rooms::validate_id(room)?;
+        let data_dir = data_dir.as_ref();
+        let room_dir = match room {
+            DEFAULT_ROOM => data_dir.to_path_buf(),
+            _ => data_dir.join(rooms::ROOMS_DIR).join(room),
+        };
+        Ok(Storage {
+            room: room.to_string(),
+            messages: Box::new(SegmentedMessageStore::new(&room_dir)),
+            blobs: sealed(Box::new(DirBlobStore::new(room_dir.join("uploads"))))?,
+            state: Box::new(DirStateStore::new(data_dir.join("state"))),
+            audit: Rc::new(JsonlAuditLog::new(data_dir.join("audit.jsonl"))),
+        })
+    }
+
+    pub fn in_memory() -> Storage {
+        Storage {
+            room: DEFAULT_ROOM.to_string(),
+            messages: Box::new(MemoryMessageStore::default()),
+            blobs: Box::new(MemoryBlobStore::default()),
+            state: Box::new(MemoryStateStore::default()),
+            audit: Rc::new(MemoryAuditLog::default()),
+        }
+    }
+
+    /// The key-value layout on a bucket and container kept as files under
+    /// `data_dir/kv`, as `SPORE_STORAGE=kv-dir` selects. They stand in for
+    /// the WASI hosts, so the layout can be tried with just a directory.
+    pub fn kv_dir(data_dir: impl AsRef<Path>, room: &str) -> Result<Storage, Error> {
+        rooms::validate_id(room)?;
+        let kv_dir = data_dir.as_ref().join("kv");
+        let bucket = || DirBucket::new(kv_dir.join("bucket"));
+        let uploads = match room {
+            DEFAULT_ROOM => kv_dir.join("uploads"),
+            _ => kv_dir.join(format!("uploads-{}", room)),
+        };
+        Ok(Storage {
+            room: room.to_string(),
+            messages: Box::new(KvMessageStore::for_room(bucket(), room)),
+            blobs: sealed(Box::new(DirBlobStore::new(uploads)))?,
+            state: Box::new(KvStateStore::new(bucket())),
+            audit: Rc::new(KvAuditLog::new(bucket())),
+        })
+    }
+
+    /// `wasi:keyvalue` for messages and `wasi:blobstore` for uploads. The
+    /// bucket and container can be chosen with `SPORE_KV_BUCKET` and
+    /// `SPORE_BLOB_CONTAINER`; rooms other than the default one get their
+    /// own key prefix and a container named `<container>-<room>`.
+    #[cfg(feature = "wasi-storage")]
+    pub fn open_wasi(room: &str) -> Result<Storage, Error> {
+        rooms::validate_id(room)?;
+        let bucket = std::env::var("SPORE_KV_BUCKET").unwrap_or_default();
+        let mut container = std::env::var("SPORE_BLOB_CONTAINER")
+            .unwrap_or_else(|_| "spore-box-uploads".to_string());
+        if room != DEFAULT_ROOM {
+            container = format!("{}-{}", container, room);
+        }
+        Ok(Storage {
+            room: room.to_string(),
+            messages: Box::new(KvMessageStore::for_room(WasiBucket::open(&bucket)?, room)),
+            blobs: sealed(Box::new(WasiContainer::open(&container)?))?,
+            state: Box::new(KvStateStore::new(WasiBucket::open(&bucket)?)),
+            audit: Rc::new(KvAuditLog::new(WasiBucket::open(&bucket)?)),
+        })
+    }
+
+    /// The backend selected at compile time.
+    pub fn open_default() -> Result<Storage, Error> {
+        Storage::open_default_room(DEFAULT_ROOM)
+    }
+
+    /// `room` on the backend selected at compile time, unless
+    /// `SPORE_STORAGE=kv-dir` asks for the key-value layout in `data/kv`.
+    pub fn open_default_room(room: &str) -> Result<Storage, Error> {
+        if std::env::var(STORAGE_VAR).is_ok_and(|v| v == KV_DIR) {
+            return Storage::kv_dir(DATA_DIR, room);
+        }
+
+        #[cfg(feature = "wasi-storage")]
+        return Storage::open_wasi(room);
+
+        #[cfg(not(feature = "wasi-storage"))]
+        return Storage::open_room(DATA_DIR, room);
+    }
+}
+
+/// Wraps `blobs` to encrypt uploads when a key is configured. Message
+/// records are sealed by `schema` on their way to any store.
+fn sealed(blobs: Box<dyn BlobStore>) -> Result<Box<dyn BlobStore>, Error> {
+    Ok(Box::new(SealedBlobStore::new(blobs, crypt::configured()?)))
+}
+
+/// Rejects names that could escape the blob namespace.
+pub fn validate_blob_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid blob name: {:?}", name),
+        ));
+    }
+    Ok(())
+}
+
+pub struct JsonlMessageStore {
+    path: PathBuf,
+    index: LogIndex,
+}
+
+impl JsonlMessageStore {
+    pub fn new(path: impl Into<PathBuf>) -> JsonlMessageStore {
+        let path = path.into();
+        let index = LogIndex::for_log(&path);
+        JsonlMessageStore { path, index }
+    }
+
+    /// The record on the line at `range`, or `None` if it cannot be read.
+    fn read_record(&self, range: Range<u64>) -> Result<Option<Message>, Error> {
+        let mut file = std::fs::File::open(&self.path)?;
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut line = vec![0u8; (range.end - range.start) as usize];
+        file.read_exact(&mut line)?;
+        Ok(serde_json::from_slice::<StoredMessage>(&line)
+            .ok()
+            .map(|s| s.0))
+    }
+
+    /// Runs `f` over the raw log lines under the append lock and replaces the
+    /// log with its output.
+    fn rewrite(
+        &self,
+        mut f: impl FnMut(usize, &[u8], &mut Vec<u8>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let lock = jsonl::FileLock::acquire(&self.path)?;
+        let content = match std::fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut rewritten = Vec::with_capacity(content.len());
+        for (index, line) in content.split(|b| *b == b'\n').enumerate() {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            lock.keep_alive()?;
+            f(index + 1, line, &mut rewritten)?;
+        }
+        jsonl::write_atomically(&self.path, &rewritten)
+    }
+}
+
+impl MessageStore for JsonlMessageStore {
+    fn load(&self) -> Result<Vec<Message>, Error> {
+        let (messages, corrupt) = self.scan()?;
+        if corrupt.is_empty() {
+            return Ok(messages);
+        }
+
+        // Never drop unreadable records silently: move them aside and say so.
+        let report = self.recover()?;
+        for line in &report.quarantined {
+            eprintln!(
+                "Quarantined corrupt line {} of {}: {}",
+                line.line_number,
+                self.path.display(),
+                line.error
+            );
+        }
+        if let Some(quarantine_file) = &report.quarantine_file {
+            eprintln!(
+                "Moved {} corrupt line(s) to {}",
+                report.quarantined.len(),
+                quarantine_file
+            );
+        }
+        Ok(messages)
+    }
+
+    fn append(&self, message: &Message) -> Result<(), Error> {
+        let lock = jsonl::FileLock::acquire(&self.path)?;
+        let range = jsonl::append_locked(&lock, &self.path, &schema::seal(message)?)?;
+
+        // The record is durable at this point; a stale index only costs the
+        // next reader a rebuild.
+        if let Err(e) = self
+            .index
+            .push(&lock, range, &message.timestamp, &message.id)
+        {
+            eprintln!("Failed to update {} index: {}", self.path.display(), e);
+            let _ = self.index.invalidate();
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Message>, Error> {
+        let ranges = match self.index.ranges_of(id) {
+            Ok(ranges) => ranges,
+            Err(e) => {
+                eprintln!("Index unusable for {}: {}", self.path.display(), e);
+                return Ok(self.load()?.into_iter().find(|m| m.id == id));
+            }
+        };
+        for range in ranges {
+            if let Some(message) = self.read_record(range)?
+                && message.id == id
+            {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Walks the log back from its end and stops once the running maximum
+    /// timestamp shows that nothing further back can be newer.
+    fn latest(&self, keep: &dyn Fn(&Message) -> bool) -> Result<Option<Message>, Error> {
+        let mut best: Option<Message> = None;
+        let walked = self.index.walk_back(|entry| {
+            let best_ms = best
+                .as_ref()
+                .and_then(|m| index::timestamp_ms(&m.timestamp));
+            if best_ms.is_some_and(|best_ms| entry.max_timestamp_ms < best_ms) {
+                return Ok(false);
+            }
+            if let Some(message) = self.read_record(entry.range())?
+                && keep(&message)
+                && best
+                    .as_ref()
+                    .is_none_or(|b| timestamp_key(&message.timestamp) > timestamp_key(&b.timestamp))
+            {
+                best = Some(message);
+            }
+            Ok(true)
+        });
+        match walked {
+            Ok(()) => Ok(best),
+            Err(e) => {
+                eprintln!("Index unusable for {}: {}", self.path.display(), e);
+                Ok(self
+                    .load()?
+                    .into_iter()
+                    .filter(|m| keep(m))
+                    .max_by_key(|m| timestamp_key(&m.timestamp)))
+            }
+        }
+    }
+
+    fn since(&self, since: &str) -> Result<Vec<Message>, Error> {
+        let Some(since_ms) = index::timestamp_ms(since) else {
+            return self.load();
+        };
+
+        let offset = match self.index.start_offset(since_ms) {
+            Ok(offset) => offset,
+            Err(e) => {
+                eprintln!("Index unusable for {}: {}", self.path.display(), e);
+                return Ok(filter_since(self.load()?, since));
+            }
+        };
+
+        let (stored, corrupt) = jsonl::read_from::<StoredMessage>(&self.path, offset)?;
+        if !corrupt.is_empty() {
+            // Let the full read quarantine the damage.
+            return Ok(filter_since(self.load()?, since));
+        }
+        Ok(filter_since(
+            stored.into_iter().map(|s| s.0).collect(),
+            since,
+        ))
+    }
+
+    fn migrate(&self) -> Result<MigrationReport, Error> {
+        // Quarantine corrupt lines first so the rewrite only sees records.
+        self.load()?;
+
+        let mut report = MigrationReport::new();
+        self.rewrite(|line_number, line, out| {
+            let (message, original) = schema::upgrade_bytes(line).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Line {} of {}: {}", line_number, self.path.display(), e),
+                )
+            })?;
+            report.record(original);
+            if schema::needs_sealing(line) {
+                report.sealed += 1;
+            }
+            serde_json::to_writer(&mut *out, &schema::seal(&message)?)?;
+            out.push(b'\n');
+            Ok(())
+        })?;
+        Ok(report)
+    }
+
+    fn update(&self, updated: &[Message]) -> Result<usize, Error> {
+        let by_id: BTreeMap<&str, &Message> = updated.iter().map(|m| (m.id.as_str(), m)).collect();
+        let mut replaced = 0;
+        self.rewrite(|_, line, out| {
+            match schema::upgrade_bytes(line) {
+                Ok((current, _)) if by_id.contains_key(current.id.as_str()) => {
+                    serde_json::to_writer(&mut *out, &schema::seal(by_id[current.id.as_str()])?)?;
+                    replaced += 1;
+                }
+                // Anything else, readable or not, is kept byte for byte.
+                _ => out.extend_from_slice(line),
+            }
+            out.push(b'\n');
+            Ok(())
+        })?;
+        Ok(replaced)
+    }
+
+    fn remove(&self, ids: &[&str]) -> Result<usize, Error> {
+        let mut removed = 0;
+        self.rewrite(|_, line, out| {
+            if let Ok((current, _)) = schema::upgrade_bytes(line)
+                && ids.contains(&current.id.as_str())
+            {
+                removed += 1;
+                return Ok(());
+            }
+            out.extend_from_slice(line);
+            out.push(b'\n');
+            Ok(())
+        })?;
+        Ok(removed)
+    }
+
+    fn scan(&self) -> Result<(Vec<Message>, Vec<CorruptLine>), Error> {
+        let (stored, corrupt) = jsonl::read::<StoredMessage>(&self.path)?;
+        Ok((stored.into_iter().map(|s| s.0).collect(), corrupt))
+    }
+
+    fn recover(&self) -> Result<RecoveryReport, Error> {
+        // Records from a newer release or sealed under another key are not
+        // corrupt; leave them alone so a downgrade or a missing key cannot
+        // destroy them.
+        let (_, corrupt) = self.scan()?;
+        for line in &corrupt {
+            if let Err(e) = schema::upgrade_bytes(line.content.as_bytes())
+                && e.is_foreign()
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Line {} of {}: {}",
+                        line.line_number,
+                        self.path.display(),
+                        e
+                    ),
+                ));
+            }
+        }
+
+        jsonl::recover::<StoredMessage>(&self.path)
+    }
+}
+
+pub struct DirBlobStore {
+    dir: PathBuf,
+}
+
+impl DirBlobStore {
+    pub fn new(dir: impl Into<PathBuf>) -> DirBlobStore {
+        DirBlobStore { dir: dir.into() }
+    }
+}
+
+impl BlobStore for DirBlobStore {
+    fn put(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        validate_blob_name(name)?;
+        std::fs::create_dir_all(&self.dir)?;
+        // Through a temporary file, so rewriting an upload in place (as
+        // sealing does) cannot leave half of it behind.
+        let tmp = self.dir.join(format!(".{}.tmp", name));
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(tmp, self.dir.join(name))
+    }
+
+    fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+        validate_blob_name(name)?;
+        std::fs::read(self.dir.join(name))
+    }
+
+    fn delete(&self, name: &str) -> Result<(), Error> {
+        validate_blob_name(name)?;
+        std::fs::remove_file(self.dir.join(name))
+    }
+
+    fn size(&self, name: &str) -> Result<u64, Error> {
+        validate_blob_name(name)?;
+        Ok(std::fs::metadata(self.dir.join(name))?.len())
+    }
+
+    fn modified(&self, name: &str) -> Result<Option<SystemTime>, Error> {
+        validate_blob_name(name)?;
+        Ok(Some(std::fs::metadata(self.dir.join(name))?.modified()?))
+    }
+
+    fn get_range(&self, name: &str, range: Range<u64>) -> Result<Vec<u8>, Error> {
+        validate_blob_name(name)?;
+        let mut file = std::fs::File::open(self.dir.join(name))?;
+        let len = file.metadata()?.len();
+        let start = range.start.min(len);
+        file.seek(SeekFrom::Start(start))?;
+        let mut data = Vec::with_capacity((range.end.min(len) - start.min(range.end)) as usize);
+        file.take(range.end.saturating_sub(start))
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_file() && validate_blob_name(&name).is_ok() {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryMessageStore {
+    messages: RefCell<Vec<Message>>,
+}
+
+impl MessageStore for MemoryMessageStore {
+    fn load(&self) -> Result<Vec<Message>, Error> {
+        Ok(self.messages.borrow().clone())
+    }
+
+    fn append(&self, message: &Message) -> Result<(), Error> {
+        self.messages.borrow_mut().push(message.clone());
+        Ok(())
+    }
+
+    fn migrate(&self) -> Result<MigrationReport, Error> {
+        let mut report = MigrationReport::new();
+        for _ in self.messages.borrow().iter() {
+            report.record(schema::CURRENT_VERSION);
+        }
+        Ok(report)
+    }
+
+    fn update(&self, updated: &[Message]) -> Result<usize, Error> {
+        let mut replaced = 0;
+        for message in self.messages.borrow_mut().iter_mut() {
+            if let Some(new) = updated.iter().find(|m| m.id == message.id) {
+                *message = new.clone();
+                replaced += 1;
+            }
+        }
+        Ok(replaced)
+    }
+
+    fn remove(&self, ids: &[&str]) -> Result<usize, Error> {
+        let mut messages = self.messages.borrow_mut();
+        let before = messages.len();
+        messages.retain(|m| !ids.contains(&m.id.as_str()));
+        Ok(before - messages.len())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryBlobStore {
+    blobs: RefCell<BTreeMap<String, Vec<u8>>>,
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn put(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        validate_blob_name(name)?;
+        self.blobs
+            .borrow_mut()
+            .insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+        validate_blob_name(name)?;
+        self.blobs
+            .borrow()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No blob {}", name)))
+    }
+
+    fn delete(&self, name: &str) -> Result<(), Error> {
+        validate_blob_name(name)?;
+        match self.blobs.borrow_mut().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(ErrorKind::NotFound, format!("No blob {}", name))),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        Ok(self.blobs.borrow().keys().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{ScratchDir, ids, message};
+
+    #[test]
+    fn memory_messages_round_trip_in_order() {
+        let store = MemoryMessageStore::default();
+        assert!(store.load().unwrap().is_empty());
+        store.append(&message("a", "2024-01-01T00:00:00Z")).unwrap();
+        store.append(&message("b", "2024-01-02T00:00:00Z")).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(ids(&loaded), ["a", "b"]);
+        assert_eq!(loaded[1].content, "text of b");
+    }
+
+    #[test]
+    fn since_returns_only_newer_messages() {
+        let store = MemoryMessageStore::default();
+        store.append(&message("a", "2024-01-01T00:00:00Z")).unwrap();
+        store
+            .append(&message("b", "2024-01-02T00:00:00+00:00"))
+            .unwrap();
+        store.append(&message("c", "not a time")).unwrap();
+
+        assert_eq!(
+            ids(&store.since("2024-01-01T00:00:00Z").unwrap()),
+            ["b", "c"]
+        );
+        assert_eq!(
+            ids(&store.since("2024-01-02T01:00:00+01:00").unwrap()),
+            ["c"]
+        );
+        assert_eq!(ids(&store.since("garbage").unwrap()), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn update_replaces_records_by_id() {
+        let store = MemoryMessageStore::default();
+        store.append(&message("a", "2024-01-01T00:00:00Z")).unwrap();
+        store.append(&message("b", "2024-01-02T00:00:00Z")).unwrap();
+
+        let mut edited = message("b", "2024-01-02T00:00:00Z");
+        edited.content = "edited".to_string();
+        let unknown = message("z", "2024-01-03T00:00:00Z");
+        assert_eq!(store.update(&[edited, unknown]).unwrap(), 1);
+
+        let loaded = store.load().unwrap();
+        assert_eq!(ids(&loaded), ["a", "b"]);
+        assert_eq!(loaded[1].content, "edited");
+    }
+
+    #[test]
+    fn remove_deletes_records_by_id() {
+        let store = MemoryMessageStore::default();
+        for id in ["a", "b", "c"] {
+            store.append(&message(id, "2024-01-01T00:00:00Z")).unwrap();
+        }
+        assert_eq!(store.remove(&["a", "c", "z"]).unwrap(), 2);
+        assert_eq!(ids(&store.load().unwrap()), ["b"]);
+        assert_eq!(store.remove(&["a"]).unwrap(), 0);
+    }
+
+    #[test]
+    fn memory_blobs_round_trip() {
+        let store = MemoryBlobStore::default();
+        store.put("b.txt", b"hello world").unwrap();
+        store.put("a.png", b"\x89PNG").unwrap();
+
+        assert_eq!(store.get("b.txt").unwrap(), b"hello world");
+        assert_eq!(store.size("b.txt").unwrap(), 11);
+        assert_eq!(store.get_range("b.txt", 6..100).unwrap(), b"world");
+        assert_eq!(store.list().unwrap(), ["a.png", "b.txt"]);
+
+        store.delete("b.txt").unwrap();
+        assert_eq!(store.get("b.txt").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(
+            store.delete("b.txt").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn blob_names_cannot_escape() {
+        let store = MemoryBlobStore::default();
+        for name in ["", ".hidden", "../x", "a/b", "a\\b"] {
+            assert_eq!(
+                store.put(name, b"x").unwrap_err().kind(),
+                ErrorKind::InvalidInput,
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn kv_dir_storage_outlives_the_instance() {
+        let dir = ScratchDir::new("kv-dir");
+        let storage = Storage::kv_dir(dir.path(), "notes").unwrap();
+        assert_eq!(storage.room, "notes");
+        storage
+            .messages
+            .append(&message("a", "2024-01-01T00:00:00Z"))
+            .unwrap();
+        storage.blobs.put("a.txt", b"hello").unwrap();
+        drop(storage);
+
+        // As the next request, in a fresh instance, would open it.
+        let storage = Storage::kv_dir(dir.path(), "notes").unwrap();
+        storage
+            .messages
+            .append(&message("b", "2024-01-02T00:00:00Z"))
+            .unwrap();
+        assert_eq!(ids(&storage.messages.load().unwrap()), ["a", "b"]);
+        assert_eq!(storage.blobs.get("a.txt").unwrap(), b"hello");
+        let other = Storage::kv_dir(dir.path(), DEFAULT_ROOM).unwrap();
+        assert!(other.messages.load().unwrap().is_empty());
+        assert!(Storage::kv_dir(dir.path(), "../escape").is_err());
+    }
+
+    #[test]
+    fn in_memory_storage_starts_empty_in_the_default_room() {
+        let storage = Storage::in_memory();
+        assert_eq!(storage.room, DEFAULT_ROOM);
+        assert!(storage.messages.load().unwrap().is_empty());
+        assert!(storage.blobs.list().unwrap().is_empty());
+    }
+}
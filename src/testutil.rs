@@ -0,0 +1,58 @@
+//! Helpers shared by the unit tests.
+
+use crate::message::Message;
+use std::path::{Path, PathBuf};
+
+/// A fresh directory under the system temp dir, removed again on drop.
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    pub fn new(name: &str) -> ScratchDir {
+        let path = std::env::temp_dir().join(format!(
+            "spore-box-{}-{}",
+            name,
+            uuid::Uuid::new_v4().simple()
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        ScratchDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A text message from "tester" to everyone.
+pub fn message(id: &str, timestamp: &str) -> Message {
+    Message {
+        id: id.to_string(),
+        content: format!("text of {}", id),
+        sender: "tester".to_string(),
+        device_id: None,
+        timestamp: timestamp.to_string(),
+        msg_type: "text".to_string(),
+        filename: None,
+        file_size: None,
+        mime_type: None,
+        broken: None,
+        recipients: Vec::new(),
+        reply_to: None,
+        event: None,
+    }
+}
+
+pub fn ids(messages: &[Message]) -> Vec<&str> {
+    messages.iter().map(|m| m.id.as_str()).collect()
+}
@@ -0,0 +1,527 @@
+//! Optional second factor for passphrase logins: RFC 6238 time-based
+//! one-time passwords (HMAC-SHA1, 30 second steps, 6 digits), which is what
+//! authenticator apps expect.
+//!
+//! Enrolment stores a pending secret until the first code confirms it. Ten
+//! single-use recovery codes are handed out then; only their hashes are
+//! kept. Each time step is accepted once, so an observed code cannot be
+//! replayed.
+
+use crate::state::{self, StateStore};
+use crate::url::percent_encode;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use qrcode::QrCode;
+use qrcode::render::svg;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::Error;
+
+pub const TOTP: &str = "totp";
+
+const ISSUER: &str = "Spore Box";
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps either side of now that are still accepted, for clock drift.
+const SKEW: i64 = 1;
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstvwxyz";
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct TotpState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending_secret: Option<String>,
+    #[serde(default)]
+    recovery_codes: Vec<RecoveryCode>,
+    /// Last time step a code was accepted for.
+    #[serde(default)]
+    last_step: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RecoveryCode {
+    hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    used_at: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum TotpError {
+    NotEnabled,
+    AlreadyEnabled,
+    NotEnrolling,
+    InvalidCode,
+    Storage(Error),
+}
+
+impl TotpError {
+    /// Stable identifier for API clients.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TotpError::NotEnabled => "totp-not-enabled",
+            TotpError::AlreadyEnabled => "totp-already-enabled",
+            TotpError::NotEnrolling => "totp-not-enrolling",
+            TotpError::InvalidCode => "invalid-totp-code",
+            TotpError::Storage(_) => "storage-error",
+        }
+    }
+}
+
+impl std::fmt::Display for TotpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TotpError::NotEnabled => write!(f, "Two-factor authentication is not enabled"),
+            TotpError::AlreadyEnabled => {
+                write!(f, "Two-factor authentication is already enabled")
+            }
+            TotpError::NotEnrolling => write!(f, "Start enrolment before confirming a code"),
+            TotpError::InvalidCode => write!(f, "The code is wrong, expired or already used"),
+            TotpError::Storage(e) => write!(f, "Storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TotpError {}
+
+impl From<Error> for TotpError {
+    fn from(e: Error) -> TotpError {
+        TotpError::Storage(e)
+    }
+}
+
+/// Lets `TotpError` travel through `state::update`, which speaks `io::Error`.
+fn into_io(e: TotpError) -> Error {
+    Error::other(e)
+}
+
+fn from_io(e: Error) -> TotpError {
+    if e.get_ref().is_some_and(|inner| inner.is::<TotpError>()) {
+        *e.into_inner().unwrap().downcast::<TotpError>().unwrap()
+    } else {
+        TotpError::Storage(e)
+    }
+}
+
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+pub fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.chars().filter(|c| !matches!(c, ' ' | '-' | '=')) {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a as char == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// RFC 4226 HOTP value of `secret` for `counter`.
+pub fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[19] & 0x0f) as usize;
+    let value = u32::from_be_bytes(digest[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+fn step_at(now: DateTime<Utc>) -> i64 {
+    now.timestamp().div_euclid(STEP_SECONDS)
+}
+
+/// The step within the skew window that `code` is valid for, if any.
+fn matching_step(secret: &str, code: &str, now: DateTime<Utc>) -> Option<i64> {
+    let code = code.trim().replace(' ', "");
+    // Exactly `DIGITS` digits: parsing alone would also take "+123456" or
+    // "0123456" for 123456.
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let secret = base32_decode(secret)?;
+    let current = step_at(now);
+    (current - SKEW..=current + SKEW).find(|step| *step >= 0 && hotp(&secret, *step as u64) == code)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    URL_SAFE_NO_PAD.encode(Sha256::digest(normalized.as_bytes()))
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], Error> {
+    let mut bytes = [0u8; N];
+    getrandom::fill(&mut bytes).map_err(|e| Error::other(e.to_string()))?;
+    Ok(bytes)
+}
+
+/// `len` characters from `RECOVERY_ALPHABET`. Bytes past the largest
+/// multiple of the alphabet's length are drawn again, so that every
+/// character is equally likely.
+fn random_recovery_chars(len: usize) -> Result<String, Error> {
+    let limit = 256 - 256 % RECOVERY_ALPHABET.len();
+    let mut chars = String::new();
+    while chars.len() < len {
+        for byte in random_bytes::<16>()? {
+            if (byte as usize) < limit && chars.len() < len {
+                chars.push(RECOVERY_ALPHABET[byte as usize % RECOVERY_ALPHABET.len()] as char);
+            }
+        }
+    }
+    Ok(chars)
+}
+
+fn new_recovery_codes() -> Result<(Vec<String>, Vec<RecoveryCode>), Error> {
+    let mut codes = Vec::new();
+    let mut stored = Vec::new();
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let chars = random_recovery_chars(10)?;
+        let code = format!("{}-{}", &chars[..5], &chars[5..]);
+        stored.push(RecoveryCode {
+            hash: hash_recovery_code(&code),
+            used_at: None,
+        });
+        codes.push(code);
+    }
+    Ok((codes, stored))
+}
+
+impl TotpState {
+    /// Accepts a current TOTP code or an unused recovery code, using it up.
+    fn accept(&mut self, code: &str, now: DateTime<Utc>) -> Result<(), TotpError> {
+        let secret = self.secret.as_deref().ok_or(TotpError::NotEnabled)?;
+        if let Some(step) = matching_step(secret, code, now) {
+            if step <= self.last_step {
+                return Err(TotpError::InvalidCode);
+            }
+            self.last_step = step;
+            return Ok(());
+        }
+
+        let hash = hash_recovery_code(code);
+        let recovery = self
+            .recovery_codes
+            .iter_mut()
+            .find(|r| r.used_at.is_none() && r.hash == hash)
+            .ok_or(TotpError::InvalidCode)?;
+        recovery.used_at = Some(now.to_rfc3339());
+        eprintln!("A TOTP recovery code was used");
+        Ok(())
+    }
+}
+
+fn modify<R>(
+    store: &dyn StateStore,
+    f: impl FnOnce(&mut TotpState) -> Result<R, TotpError>,
+) -> Result<R, TotpError> {
+    state::update(store, TOTP, |totp: &mut TotpState| f(totp).map_err(into_io)).map_err(from_io)
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TotpStatus {
+    pub enabled: bool,
+    pub enrolling: bool,
+    pub recovery_codes_left: usize,
+}
+
+pub fn status(store: &dyn StateStore) -> Result<TotpStatus, Error> {
+    let totp: TotpState = state::load(store, TOTP)?;
+    Ok(TotpStatus {
+        enabled: totp.secret.is_some(),
+        enrolling: totp.pending_secret.is_some(),
+        recovery_codes_left: totp
+            .recovery_codes
+            .iter()
+            .filter(|r| r.used_at.is_none())
+            .count(),
+    })
+}
+
+pub fn is_enabled(store: &dyn StateStore) -> Result<bool, Error> {
+    Ok(status(store)?.enabled)
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Enrolment {
+    pub secret: String,
+    pub otpauth_url: String,
+    /// `otpauth_url` as an SVG QR code for authenticator apps.
+    pub qr_svg: String,
+}
+
+/// Starts (or restarts) enrolment with a fresh secret. `account` is the
+/// label authenticator apps show next to the issuer.
+pub fn enroll(store: &dyn StateStore, account: &str) -> Result<Enrolment, TotpError> {
+    let secret = base32_encode(&random_bytes::<SECRET_LEN>()?);
+    modify(store, |totp| {
+        if totp.secret.is_some() {
+            return Err(TotpError::AlreadyEnabled);
+        }
+        totp.pending_secret = Some(secret.clone());
+        Ok(())
+    })?;
+
+    let label = format!("{}:{}", ISSUER, account);
+    let otpauth_url = format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(&label),
+        secret,
+        percent_encode(ISSUER),
+        DIGITS,
+        STEP_SECONDS
+    );
+    let qr_svg = QrCode::new(otpauth_url.as_bytes())
+        .map_err(|e| Error::other(e.to_string()))?
+        .render::<svg::Color>()
+        .min_dimensions(240, 240)
+        .build();
+    Ok(Enrolment {
+        secret,
+        otpauth_url,
+        qr_svg,
+    })
+}
+
+/// Finishes enrolment with a code from the new secret and returns the
+/// recovery codes, which are not shown again.
+pub fn confirm(
+    store: &dyn StateStore,
+    code: &str,
+    now: DateTime<Utc>,
+) -> Result<Vec<String>, TotpError> {
+    let (codes, stored) = new_recovery_codes()?;
+    modify(store, |totp| {
+        if totp.secret.is_some() {
+            return Err(TotpError::AlreadyEnabled);
+        }
+        let pending = totp.pending_secret.take().ok_or(TotpError::NotEnrolling)?;
+        let step = matching_step(&pending, code, now).ok_or(TotpError::InvalidCode)?;
+        totp.secret = Some(pending);
+        totp.last_step = step;
+        totp.recovery_codes = stored;
+        Ok(())
+    })?;
+    eprintln!("Two-factor authentication enabled");
+    Ok(codes)
+}
+
+/// Checks a login's second factor.
+pub fn verify(store: &dyn StateStore, code: &str, now: DateTime<Utc>) -> Result<(), TotpError> {
+    modify(store, |totp| totp.accept(code, now))
+}
+
+pub fn disable(store: &dyn StateStore, code: &str, now: DateTime<Utc>) -> Result<(), TotpError> {
+    modify(store, |totp| {
+        totp.accept(code, now)?;
+        *totp = TotpState::default();
+        Ok(())
+    })?;
+    eprintln!("Two-factor authentication disabled");
+    Ok(())
+}
+
+/// Turns two-factor login off without a code, for an owner who lost both
+/// the authenticator and the recovery codes. Only reachable offline.
+pub fn reset(store: &dyn StateStore) -> Result<(), Error> {
+    state::update(store, TOTP, |totp: &mut TotpState| {
+        *totp = TotpState::default();
+        Ok(())
+    })
+}
+
+/// Replaces all recovery codes, after checking a current code.
+pub fn regenerate_recovery_codes(
+    store: &dyn StateStore,
+    code: &str,
+    now: DateTime<Utc>,
+) -> Result<Vec<String>, TotpError> {
+    let (codes, stored) = new_recovery_codes()?;
+    modify(store, |totp| {
+        totp.accept(code, now)?;
+        totp.recovery_codes = stored;
+        Ok(())
+    })?;
+    Ok(codes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    /// The SHA-1 secret of RFC 6238 appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn at(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    fn code_at(secret: &[u8], timestamp: i64) -> String {
+        format!("{:06}", hotp(secret, step_at(at(timestamp)) as u64))
+    }
+
+    /// An enabled state whose last accepted step is well in the past.
+    fn enabled(secret: &[u8]) -> TotpState {
+        TotpState {
+            secret: Some(base32_encode(secret)),
+            ..TotpState::default()
+        }
+    }
+
+    #[test]
+    fn rfc_6238_sha1_vectors() {
+        // The RFC lists eight digits; six digits are their last six.
+        for (timestamp, expected) in [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ] {
+            assert_eq!(
+                code_at(RFC_SECRET, timestamp),
+                expected[2..],
+                "at {timestamp}"
+            );
+        }
+    }
+
+    #[test]
+    fn base32_round_trips() {
+        let secret = base32_encode(RFC_SECRET);
+        assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_decode(&secret.to_lowercase()).unwrap(), RFC_SECRET);
+    }
+
+    #[test]
+    fn accepts_one_step_of_skew() {
+        let secret = base32_encode(RFC_SECRET);
+        let now = 1111111111;
+        for offset in [-STEP_SECONDS, 0, STEP_SECONDS] {
+            let code = code_at(RFC_SECRET, now + offset);
+            assert!(
+                matching_step(&secret, &code, at(now)).is_some(),
+                "offset {offset}"
+            );
+        }
+        for offset in [-2 * STEP_SECONDS, 2 * STEP_SECONDS] {
+            let code = code_at(RFC_SECRET, now + offset);
+            assert_eq!(
+                matching_step(&secret, &code, at(now)),
+                None,
+                "offset {offset}"
+            );
+        }
+    }
+
+    #[test]
+    fn codes_need_exactly_six_digits() {
+        let secret = base32_encode(RFC_SECRET);
+        let now = at(1234567890);
+        let code = code_at(RFC_SECRET, 1234567890);
+        assert_eq!(code, "005924");
+        assert!(matching_step(&secret, &code, now).is_some());
+        assert!(matching_step(&secret, " 005 924 ", now).is_some());
+        for wrong in [
+            "5924", "0005924", "+05924", "-05924", "00592४", "00 5924x", "",
+        ] {
+            assert_eq!(matching_step(&secret, wrong, now), None, "{wrong:?}");
+        }
+    }
+
+    #[test]
+    fn codes_are_not_replayed() {
+        let mut totp = enabled(RFC_SECRET);
+        let now = 1234567890;
+        let code = code_at(RFC_SECRET, now);
+        totp.accept(&code, at(now)).unwrap();
+        assert_eq!(totp.last_step, step_at(at(now)));
+        assert!(matches!(
+            totp.accept(&code, at(now)),
+            Err(TotpError::InvalidCode)
+        ));
+
+        // Nor is the previous step's code once a later one was used.
+        let earlier = code_at(RFC_SECRET, now - STEP_SECONDS);
+        assert!(matches!(
+            totp.accept(&earlier, at(now)),
+            Err(TotpError::InvalidCode)
+        ));
+        let next = code_at(RFC_SECRET, now + STEP_SECONDS);
+        totp.accept(&next, at(now + STEP_SECONDS)).unwrap();
+    }
+
+    #[test]
+    fn recovery_codes_are_used_once() {
+        let store = MemoryStateStore::default();
+        enroll(&store, "owner").unwrap();
+        let pending: TotpState = state::load(&store, TOTP).unwrap();
+        let secret = base32_decode(pending.pending_secret.as_deref().unwrap()).unwrap();
+        let now = 1234567890;
+        let codes = confirm(&store, &code_at(&secret, now), at(now)).unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(is_enabled(&store).unwrap());
+
+        verify(&store, &codes[0].to_uppercase(), at(now)).unwrap();
+        assert!(matches!(
+            verify(&store, &codes[0], at(now)),
+            Err(TotpError::InvalidCode)
+        ));
+        assert_eq!(
+            status(&store).unwrap().recovery_codes_left,
+            RECOVERY_CODE_COUNT - 1
+        );
+    }
+
+    #[test]
+    fn recovery_codes_use_the_alphabet() {
+        let (codes, _) = new_recovery_codes().unwrap();
+        for code in codes {
+            assert_eq!(code.len(), 11);
+            assert_eq!(&code[5..6], "-");
+            assert!(
+                code.bytes()
+                    .filter(|b| *b != b'-')
+                    .all(|b| RECOVERY_ALPHABET.contains(&b))
+            );
+        }
+    }
+}